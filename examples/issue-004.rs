@@ -7,6 +7,7 @@ use std::{
 use yakvdb::{
     api::Store,
     disk::{block::Block, file::File as YakFile},
+    util::hex::unhex,
 };
 
 fn path<P: AsRef<Path>>(path: P) -> PathBuf {
@@ -21,13 +22,12 @@ fn lines<P: AsRef<Path>>(path: P) -> io::Result<io::Lines<BufReader<File>>> {
 
 fn data<P: AsRef<Path>>(path: P) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
     Ok(lines(path)?
-        .into_iter()
-        .flatten()
+        .map_while(Result::ok)
         .skip_while(|line| line.starts_with('#'))
         .filter_map(|line| {
             let mut it = line.split(' ');
-            let key = hex::decode(it.next()?).ok()?;
-            let val = hex::decode(it.next()?).ok()?;
+            let key = unhex(it.next()?)?;
+            let val = unhex(it.next()?)?;
             Some((key, val))
         })
         .collect())
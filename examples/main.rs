@@ -53,9 +53,9 @@ impl Storage for SelfStorage {
     }
 }
 
-struct LSKV(RefCell<yalskv::Store>);
+struct Lskv(RefCell<yalskv::Store>);
 
-impl Storage for LSKV {
+impl Storage for Lskv {
     fn insert(&self, key: &[u8], val: &[u8]) {
         self.0.borrow_mut().insert(key, val).ok();
     }
@@ -215,7 +215,10 @@ fn benchmark<S: Storage>(storage: S, count: usize) {
 mod sharded {
     use std::io;
 
-    use yakvdb::{disk::{file::File, block::Block}, api::{error::Error, Store}};
+    use yakvdb::{
+        api::{error::Error, Store},
+        disk::{block::Block, file::File},
+    };
 
     struct Shard {
         file: File<Block>,
@@ -252,15 +255,12 @@ mod sharded {
 
     impl ShardedStore {
         pub fn new(num_shards: u8, base_path: &str) -> Self {
-            let shards = (0..num_shards).into_iter()
+            let shards = (0..num_shards)
                 .map(|id| format!("{base_path}/{id:#04x}.db"))
                 .map(|path| Shard::new(&path).unwrap())
                 .collect();
 
-            Self {
-                num_shards,
-                shards,
-            }
+            Self { num_shards, shards }
         }
 
         fn shard(&self, key: &[u8]) -> &Shard {
@@ -327,12 +327,9 @@ fn main() {
         std::fs::create_dir(path).ok();
 
         let db = yalskv::Store::open("target/yalskv").unwrap();
-        info!(
-            "target={} file={:?} count={}",
-            target, path, count
-        );
+        info!("target={} file={:?} count={}", target, path, count);
 
-        benchmark(LSKV(RefCell::new(db)), count);
+        benchmark(Lskv(RefCell::new(db)), count);
         std::fs::remove_dir_all(path).ok();
     }
 
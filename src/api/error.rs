@@ -5,7 +5,18 @@ use std::result;
 #[derive(Debug)]
 pub enum Error {
     IO(io::Error),
+    /// IO error along with the operation that failed (e.g. `load page 42`).
+    Io {
+        ctx: String,
+        source: io::Error,
+    },
     Tree(u32, String),
+    /// Entry does not fit into a page (`val_len` is the stored length, after compression).
+    TooLarge {
+        key_len: usize,
+        val_len: usize,
+        free: u32,
+    },
     Other(String),
 }
 
@@ -15,7 +26,17 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IO(io) => write!(f, "IO error: '{}'.", io),
+            Error::Io { ctx, source } => write!(f, "IO error ({}): '{}'.", ctx, source),
             Error::Tree(id, msg) => write!(f, "Tree error (page: {}): '{}'.", id, msg),
+            Error::TooLarge {
+                key_len,
+                val_len,
+                free,
+            } => write!(
+                f,
+                "Entry does not fit into the page: key={} val={} free={}.",
+                key_len, val_len, free
+            ),
             Error::Other(msg) => write!(f, "Other error: '{}'.", msg),
         }
     }
@@ -25,6 +46,7 @@ impl std::error::Error for Error {
     fn cause(&self) -> Option<&dyn std::error::Error> {
         match self {
             Error::IO(io) => Some(io),
+            Error::Io { source, .. } => Some(source),
             _ => None,
         }
     }
@@ -2,19 +2,287 @@ pub mod error;
 pub mod page;
 pub mod tree;
 
-use crate::{
-    api::error::Result,
-    disk::{block::Block, file::File},
-};
+use crate::api::error::{Error, Result};
+use crate::disk::file::File;
+use crate::util::hex::hex;
+use log::error;
+use parking_lot::MappedRwLockReadGuard;
+use std::ops::Deref;
+use std::sync::Arc;
 
-pub type KV = File<Block>;
+#[cfg(not(feature = "compact-header"))]
+pub type KV = File<crate::disk::block::Block>;
 
+/// With `compact-header` feature the default store uses pages without the reserved header word.
+#[cfg(feature = "compact-header")]
+pub type KV = File<crate::disk::block::BlockCompact>;
+
+/// Storage statistics, see `Store::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Total size of the file in bytes (including the file header).
+    pub file_bytes: u64,
+    /// Number of pages allocated in the file (including the free ones).
+    pub page_count: u32,
+    /// Number of free (empty) pages available for reuse.
+    pub empty_pages: u32,
+    /// Number of key-value entries (stored in the leaf pages).
+    pub entry_count: u64,
+    /// Mean of `Page::full` (percent) across the live (non-free) pages.
+    pub avg_full: f64,
+    /// Total number of free bytes lost to gaps between entries (see `Page::fragmentation`)
+    /// across the live pages.
+    pub fragmented_bytes: u64,
+}
+
+/// Value returned by `Store::lookup_cow`: either borrowed from the cached page (the guard keeps
+/// the page cache read-locked, see `Store::get_ref`), or owned when it could not be borrowed.
+/// Works like `std::borrow::Cow`, which cannot hold a lock guard.
+pub enum ValueRef<'a> {
+    Borrowed(MappedRwLockReadGuard<'a, [u8]>),
+    Owned(Vec<u8>),
+}
+
+impl ValueRef<'_> {
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self, ValueRef::Borrowed(_))
+    }
+
+    /// Copy the borrowed value (releasing the guard), the owned one is returned as is.
+    pub fn into_owned(self) -> Vec<u8> {
+        match self {
+            ValueRef::Borrowed(val) => val.to_vec(),
+            ValueRef::Owned(val) => val,
+        }
+    }
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ValueRef::Borrowed(val) => val,
+            ValueRef::Owned(val) => val,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Operations on the store within `Store::transaction`: mutations made through the context are
+/// committed together once the transaction closure returns `Ok`, or rolled back otherwise.
+pub struct TxnCtx<'a> {
+    ops: &'a dyn TxnOps,
+}
+
+impl<'a> TxnCtx<'a> {
+    pub(crate) fn new(ops: &'a dyn TxnOps) -> Self {
+        Self { ops }
+    }
+
+    /// Same as `Store::lookup`, changes made earlier in the transaction are visible.
+    pub fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.ops.txn_lookup(key)
+    }
+
+    pub fn insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.ops.txn_insert(key, val)
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        self.ops.txn_remove(key)
+    }
+}
+
+/// Operations behind `TxnCtx`, run while the store is locked by the transaction.
+pub(crate) trait TxnOps {
+    fn txn_lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn txn_insert(&self, key: &[u8], val: &[u8]) -> Result<()>;
+    fn txn_remove(&self, key: &[u8]) -> Result<()>;
+}
+
+/// Value read by `Store::get_for_update`: the store stays write-locked until the guard is
+/// dropped, so no other writer can change the value before it is replaced with `set`.
+pub struct UpdateGuard<'a> {
+    key: Vec<u8>,
+    val: Option<Vec<u8>>,
+    ops: Box<dyn UpdateOps + 'a>,
+}
+
+impl<'a> UpdateGuard<'a> {
+    pub(crate) fn new(key: &[u8], val: Option<Vec<u8>>, ops: Box<dyn UpdateOps + 'a>) -> Self {
+        Self {
+            key: key.to_vec(),
+            val,
+            ops,
+        }
+    }
+
+    /// Current value, none if the key is absent.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.val.as_deref()
+    }
+
+    /// Write the new value (committed, see `Durability`) and release the lock.
+    pub fn set(mut self, val: &[u8]) -> Result<()> {
+        self.ops.write(&self.key, val)
+    }
+}
+
+/// Write behind `UpdateGuard`, run while the store is still locked by the guard.
+pub(crate) trait UpdateOps {
+    fn write(&mut self, key: &[u8], val: &[u8]) -> Result<()>;
+}
+
+/// Key-value store with byte-ordered keys. Empty keys are not supported: operations taking
+/// a key return `Error::Other("empty key")` for it (bounds and prefixes may still be empty).
+///
+/// Only the primitives (point lookup and modification, ordered navigation, atomic operations)
+/// are required, the rest have default bodies built on them. Implementations override those
+/// that can be done cheaper (e.g. in a single descent) than the defaults.
 pub trait Store {
     fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Strict `lookup`: absent key results in `Error::Other("not found")`.
+    fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.lookup(key)?
+            .ok_or_else(|| Error::Other("not found".to_string()))
+    }
+
+    /// Batched lookup: results are in the same order as the keys.
+    fn get_multi(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.lookup(key)).collect()
+    }
+
+    /// Same as `get_multi`, but the keys must already be in ascending order (duplicates are fine),
+    /// so no sorting is needed. Unsorted keys result in an error in debug builds (wrong misses
+    /// otherwise).
+    fn lookup_sorted(&self, sorted_keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        if cfg!(debug_assertions) && sorted_keys.windows(2).any(|w| w[0] > w[1]) {
+            return Err(Error::Other("keys are not sorted".to_string()));
+        }
+        self.get_multi(sorted_keys)
+    }
+
+    /// Get a value without copying it: the returned guard borrows the value from the cached page.
+    /// The page cache stays read-locked while the guard is alive, so drop it before any other
+    /// operation on the same store from the same thread (otherwise it might deadlock).
+    fn get_ref(&self, key: &[u8]) -> Result<Option<MappedRwLockReadGuard<'_, [u8]>>>;
+
+    /// Get the length of the value (none if the key is absent) without copying the value.
+    /// For compressed values it is the decompressed length, so the value might get decompressed.
+    fn value_len(&self, key: &[u8]) -> Result<Option<u32>> {
+        Ok(self.lookup(key)?.map(|val| val.len() as u32))
+    }
+
+    /// Same as `get_ref`, but compressed values are decompressed into an owned copy instead of
+    /// failing, so it works for any value: zero-copy where possible, one copy otherwise.
+    /// Keep the value only briefly, the same deadlock caveat as for `get_ref` applies.
+    fn lookup_cow(&self, key: &[u8]) -> Result<Option<ValueRef<'_>>> {
+        Ok(self.lookup(key)?.map(ValueRef::Owned))
+    }
+
     fn insert(&self, key: &[u8], val: &[u8]) -> Result<()>;
+
+    /// Modify the stored value bytes in place (the length stays the same), e.g. a field of
+    /// a fixed-size record. Returns false if the key is absent. Compressed values cannot be
+    /// modified in place (error). `f` must not call other operations on the same store.
+    fn with_value_mut<F>(&self, key: &[u8], f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut [u8]);
+
+    /// Insert (or replace) all entries from the iterator, consumed lazily one entry at a time.
+    /// Dirty pages are committed once at the end (see `Durability`), not after every entry.
+    /// Stops at the first failed entry (entries before it stay inserted), the error names its key.
+    fn extend<I>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        for (key, val) in iter {
+            self.insert(&key, &val)
+                .map_err(|e| Error::Other(format!("key {}: {}", hex(&key), e)))?;
+        }
+        Ok(())
+    }
+
+    /// Insert the entry only if the key is absent (the existing value is left untouched).
+    /// Returns true if the entry was inserted. Decided atomically, in a single descent.
+    fn put_if_absent(&self, key: &[u8], val: &[u8]) -> Result<bool>;
+
     fn remove(&self, key: &[u8]) -> Result<()>;
 
-    fn is_empty(&self) -> bool;
+    /// Remove all entries with keys in range `[lo, hi)`, returns the number of removed entries.
+    /// Entries are removed under one write lock, committing once at the end.
+    fn remove_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize>;
+
+    /// Remove all entries with keys starting with given prefix (a namespace), returns the number
+    /// of removed entries. Same as `remove_range` up to the prefix successor (see
+    /// `util::successor`), or up to the end for a prefix made of `0xFF` bytes only.
+    fn remove_prefix(&self, prefix: &[u8]) -> Result<usize>;
+
+    /// Remove all entries for which `f` returns false, returns the number of removed entries.
+    /// Keys to remove are collected by a single scan first (so `f` must not call other operations
+    /// on the same store), then removed under one write lock, committing once at the end.
+    /// Entries changed concurrently with the scan are removed (or kept) as the scan saw them.
+    fn retain<F>(&self, f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool;
+
+    /// Atomically replace the value with the one computed from the current value (none if
+    /// the key is absent). If `f` returns none, the entry is removed. No other operation on
+    /// the same store can interleave, so `f` must not call it (it might deadlock).
+    fn update<F>(&self, key: &[u8], f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>;
+
+    /// Atomically set the value to `new` (none means remove) only if the current value
+    /// equals `expected` (none means the key is absent). Returns true if the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool>;
+
+    /// Read the value and keep the store write-locked until the returned guard is dropped (or its
+    /// value replaced with `UpdateGuard::set`): a narrower alternative to `update` for an expensive
+    /// modify step, run outside of any closure. Dropping the guard without `set` changes nothing.
+    /// The guard must not outlive other operations on the same store from the same thread.
+    fn get_for_update(&self, key: &[u8]) -> Result<UpdateGuard<'_>>;
+
+    /// Run `f` as a transaction: the changes it makes through the context are committed together
+    /// (see `Durability`) if it returns `Ok`. If it returns `Err` (or panics), all of them are
+    /// rolled back, leaving the store (including the file on the disk) as it was before.
+    /// The store is locked while `f` runs, so `f` must not call other operations on it.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&TxnCtx<'_>) -> Result<T>;
+
+    /// Atomically exchange the values of two keys. Returns false if either key is absent
+    /// (nothing is changed), swapping the key with itself is a no-op returning true.
+    fn swap(&self, a: &[u8], b: &[u8]) -> Result<bool>;
+
+    fn is_empty(&self) -> bool {
+        match self.min() {
+            Ok(min) => min.is_none(),
+            Err(e) => {
+                error!("is_empty failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Collect storage statistics, visiting every live page.
+    fn stats(&self) -> Result<Stats>;
+
+    /// Release memory after a burst of operations (e.g. bulk import): flush dirty pages,
+    /// then drop all cached pages but the root one, and shrink the internal buffers.
+    fn release(&self) -> Result<()>;
 
     /// Get lowest/smallest key stored in the tree, or none if tree is empty.
     fn min(&self) -> Result<Option<Vec<u8>>>;
@@ -22,9 +290,428 @@ pub trait Store {
     /// Get highest/biggest key stored in the tree, or none if tree is empty.
     fn max(&self) -> Result<Option<Vec<u8>>>;
 
+    /// Get the entry with the lowest/smallest key, or none if tree is empty.
+    /// Same single descent as `min`, so there is no need to pair `min` with `lookup`.
+    fn first(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.min()?.map_or(Ok(None), |key| entry(self, key))
+    }
+
+    /// Get the entry with the highest/biggest key, or none if tree is empty.
+    fn last(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.max()?.map_or(Ok(None), |key| entry(self, key))
+    }
+
     /// Get smallest key that is strictly greater than given one, if any.
     fn above(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
     /// Get biggest key that is strictly lesser than given one, if any.
     fn below(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Same as `above`, but the value is returned as well (found in the same descent).
+    fn above_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.above(key)?.map_or(Ok(None), |key| entry(self, key))
+    }
+
+    /// Same as `below`, but the value is returned as well (found in the same descent).
+    fn below_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.below(key)?.map_or(Ok(None), |key| entry(self, key))
+    }
+
+    /// Visit entries in ascending key order starting at `from` (inclusive, or the min key
+    /// if none), until `f` returns `false`. The store might stay locked while `f` runs, so `f`
+    /// must not call other operations on the same store (it might deadlock).
+    fn scan<F>(&self, from: Option<&[u8]>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut next = match from {
+            Some(from) => match self.lookup(from)? {
+                Some(val) => Some((from.to_vec(), val)),
+                None => self.above_entry(from)?,
+            },
+            None => self.first()?,
+        };
+        while let Some((key, val)) = next {
+            if !f(&key, &val) {
+                break;
+            }
+            next = self.above_entry(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Get up to `limit` entries in ascending key order starting at `start` (inclusive or not),
+    /// for cursor-based pagination: the last key seen is passed as the (exclusive) start of
+    /// the next page.
+    fn scan_from(
+        &self,
+        start: &[u8],
+        inclusive: bool,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result);
+        }
+        self.scan(bound(start), |key, val| {
+            if !inclusive && key == start {
+                return true;
+            }
+            result.push((key.to_vec(), val.to_vec()));
+            result.len() < limit
+        })?;
+        Ok(result)
+    }
+
+    /// Iterate over all keys in ascending order, lazily. No lock is held between the steps.
+    /// Stops (with an error logged) at the first failure.
+    fn keys(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        walk(self, |key, _| key)
+    }
+
+    /// Iterate over all values in ascending key order, same as `keys`.
+    fn values(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        walk(self, |_, val| val)
+    }
+
+    /// Get all entries with keys starting with given prefix in ascending key order
+    /// (empty prefix matches all entries).
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Walking from the ceil of the prefix itself never needs its successor key,
+        // that might not exist (e.g. for a prefix made of 0xFF bytes only).
+        let mut result = Vec::new();
+        self.scan(bound(prefix), |key, val| {
+            if !key.starts_with(prefix) {
+                return false;
+            }
+            result.push((key.to_vec(), val.to_vec()));
+            true
+        })?;
+        Ok(result)
+    }
+
+    /// Get values of all entries with keys in range `[lo, hi)` in ascending key order
+    /// (keys are not copied, for value-only aggregations).
+    fn values_in(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.fold_range(lo, hi, Vec::new(), |mut result, _, val| {
+            result.push(val.to_vec());
+            result
+        })
+    }
+
+    /// Fold entries with keys in range `[lo, hi)` in ascending key order, `f` borrows keys and
+    /// values from the `scan` (so this is the low-allocation way to aggregate over a range).
+    fn fold_range<B, F>(&self, lo: &[u8], hi: &[u8], init: B, mut f: F) -> Result<B>
+    where
+        F: FnMut(B, &[u8], &[u8]) -> B,
+    {
+        let mut acc = Some(init);
+        self.scan(bound(lo), |key, val| {
+            if key >= hi {
+                return false;
+            }
+            acc = acc.take().map(|acc| f(acc, key, val));
+            true
+        })?;
+        Ok(acc.expect("accumulator is always put back"))
+    }
+
+    /// Count entries with keys in range `[lo, hi)`.
+    fn count_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        self.fold_range(lo, hi, 0, |count, _, _| count + 1)
+    }
+
+    /// Estimate the number of entries with keys in range `[lo, hi)` (e.g. for query planning).
+    /// Unless the store can do it cheaper, the estimate is the exact `count_range`.
+    fn estimate_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        self.count_range(lo, hi)
+    }
+
+    /// Coarse index of range `[lo, hi)` (e.g. to build a sparse index): the keys within the range
+    /// that node pages reference leaf pages by, in ascending order. Each one is the max key of
+    /// a leaf, so it is an existing key, but value bytes (and leaves) are not read.
+    fn range_key_bounds(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Count entries with keys starting with given prefix, without reading any values.
+    fn count_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let mut count = 0;
+        self.scan(bound(prefix), |key, _| {
+            if !key.starts_with(prefix) {
+                return false;
+            }
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    }
+
+    /// Find the first missing key of a dense key space (e.g. to allocate sequential ids): walk
+    /// the keys from `start` on, stepping with `successor` (see `util::next_u64`), and return
+    /// the first expected key that is absent. None if `successor` runs out of keys first.
+    /// Successors are expected to be ascending in byte order.
+    fn first_gap<F>(&self, start: &[u8], successor: F) -> Result<Option<Vec<u8>>>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>>,
+    {
+        let mut expected = Some(start.to_vec());
+        self.scan(Some(start), |key, _| {
+            match expected.as_deref() {
+                // keys in between the expected ones (e.g. of another key space) are skipped
+                Some(next) if key < next => true,
+                Some(next) if key == next => {
+                    expected = successor(key);
+                    expected.is_some()
+                }
+                _ => false,
+            }
+        })?;
+        Ok(expected)
+    }
 }
+
+/// Scan start for a bound or a prefix: the empty one (not a valid key) starts at the min key.
+fn bound(key: &[u8]) -> Option<&[u8]> {
+    Some(key).filter(|key| !key.is_empty())
+}
+
+/// Pair the key (found by one of `Store` navigation primitives) with its value.
+fn entry<S: Store + ?Sized>(store: &S, key: Vec<u8>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    Ok(store.lookup(&key)?.map(|val| (key, val)))
+}
+
+/// Default `Store::keys`/`Store::values`: walk the entries one `above_entry` at a time.
+fn walk<'a, S, T, F>(store: &'a S, pick: F) -> Box<dyn Iterator<Item = T> + 'a>
+where
+    S: Store + ?Sized,
+    F: Fn(Vec<u8>, Vec<u8>) -> T + 'a,
+{
+    let mut next = Some(store.first());
+    Box::new(std::iter::from_fn(move || match next.take()? {
+        Ok(Some((key, val))) => {
+            next = Some(store.above_entry(&key));
+            Some(pick(key, val))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Iteration failed: {}", e);
+            None
+        }
+    }))
+}
+
+/// Implement `Store` for a shared handle to a store (anything that dereferences to it),
+/// delegating every operation to the store itself.
+macro_rules! delegate_store {
+    ($($handle:tt)*) => {
+        impl<S: Store> Store for $($handle)* {
+            fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+                (**self).lookup(key)
+            }
+
+            fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+                (**self).get(key)
+            }
+
+            fn get_multi(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+                (**self).get_multi(keys)
+            }
+
+            fn lookup_sorted(&self, sorted_keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+                (**self).lookup_sorted(sorted_keys)
+            }
+
+            fn get_ref(&self, key: &[u8]) -> Result<Option<MappedRwLockReadGuard<'_, [u8]>>> {
+                (**self).get_ref(key)
+            }
+
+            fn value_len(&self, key: &[u8]) -> Result<Option<u32>> {
+                (**self).value_len(key)
+            }
+
+            fn lookup_cow(&self, key: &[u8]) -> Result<Option<ValueRef<'_>>> {
+                (**self).lookup_cow(key)
+            }
+
+            fn insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+                (**self).insert(key, val)
+            }
+
+            fn with_value_mut<F>(&self, key: &[u8], f: F) -> Result<bool>
+            where
+                F: FnOnce(&mut [u8]),
+            {
+                (**self).with_value_mut(key, f)
+            }
+
+            fn extend<I>(&self, iter: I) -> Result<()>
+            where
+                I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+            {
+                (**self).extend(iter)
+            }
+
+            fn put_if_absent(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+                (**self).put_if_absent(key, val)
+            }
+
+            fn remove(&self, key: &[u8]) -> Result<()> {
+                (**self).remove(key)
+            }
+
+            fn remove_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+                (**self).remove_range(lo, hi)
+            }
+
+            fn remove_prefix(&self, prefix: &[u8]) -> Result<usize> {
+                (**self).remove_prefix(prefix)
+            }
+
+            fn retain<F>(&self, f: F) -> Result<usize>
+            where
+                F: FnMut(&[u8], &[u8]) -> bool,
+            {
+                (**self).retain(f)
+            }
+
+            fn update<F>(&self, key: &[u8], f: F) -> Result<()>
+            where
+                F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+            {
+                (**self).update(key, f)
+            }
+
+            fn compare_and_swap(
+                &self,
+                key: &[u8],
+                expected: Option<&[u8]>,
+                new: Option<&[u8]>,
+            ) -> Result<bool> {
+                (**self).compare_and_swap(key, expected, new)
+            }
+
+            fn get_for_update(&self, key: &[u8]) -> Result<UpdateGuard<'_>> {
+                (**self).get_for_update(key)
+            }
+
+            fn transaction<F, T>(&self, f: F) -> Result<T>
+            where
+                F: FnOnce(&TxnCtx<'_>) -> Result<T>,
+            {
+                (**self).transaction(f)
+            }
+
+            fn swap(&self, a: &[u8], b: &[u8]) -> Result<bool> {
+                (**self).swap(a, b)
+            }
+
+            fn is_empty(&self) -> bool {
+                (**self).is_empty()
+            }
+
+            fn stats(&self) -> Result<Stats> {
+                (**self).stats()
+            }
+
+            fn release(&self) -> Result<()> {
+                (**self).release()
+            }
+
+            fn min(&self) -> Result<Option<Vec<u8>>> {
+                (**self).min()
+            }
+
+            fn max(&self) -> Result<Option<Vec<u8>>> {
+                (**self).max()
+            }
+
+            fn first(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                (**self).first()
+            }
+
+            fn last(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                (**self).last()
+            }
+
+            fn above(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+                (**self).above(key)
+            }
+
+            fn below(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+                (**self).below(key)
+            }
+
+            fn above_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                (**self).above_entry(key)
+            }
+
+            fn below_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+                (**self).below_entry(key)
+            }
+
+            fn scan<F>(&self, from: Option<&[u8]>, f: F) -> Result<()>
+            where
+                F: FnMut(&[u8], &[u8]) -> bool,
+            {
+                (**self).scan(from, f)
+            }
+
+            fn scan_from(
+                &self,
+                start: &[u8],
+                inclusive: bool,
+                limit: usize,
+            ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+                (**self).scan_from(start, inclusive, limit)
+            }
+
+            fn keys(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+                (**self).keys()
+            }
+
+            fn values(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+                (**self).values()
+            }
+
+            fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+                (**self).scan_prefix(prefix)
+            }
+
+            fn values_in(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>> {
+                (**self).values_in(lo, hi)
+            }
+
+            fn fold_range<B, F>(&self, lo: &[u8], hi: &[u8], init: B, f: F) -> Result<B>
+            where
+                F: FnMut(B, &[u8], &[u8]) -> B,
+            {
+                (**self).fold_range(lo, hi, init, f)
+            }
+
+            fn count_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+                (**self).count_range(lo, hi)
+            }
+
+            fn estimate_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+                (**self).estimate_range(lo, hi)
+            }
+
+            fn range_key_bounds(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>> {
+                (**self).range_key_bounds(lo, hi)
+            }
+
+            fn count_prefix(&self, prefix: &[u8]) -> Result<usize> {
+                (**self).count_prefix(prefix)
+            }
+
+            fn first_gap<F>(&self, start: &[u8], successor: F) -> Result<Option<Vec<u8>>>
+            where
+                F: Fn(&[u8]) -> Option<Vec<u8>>,
+            {
+                (**self).first_gap(start, successor)
+            }
+        }
+    };
+}
+
+delegate_store!(&S);
+delegate_store!(Arc<S>);
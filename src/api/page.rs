@@ -4,6 +4,7 @@ pub struct Slot {
     pub(crate) klen: u32,
     pub(crate) vlen: u32, // if >0 value is stored in the same page as a key (leaf page)
     pub(crate) page: u32, // if >0 key holds a reference to another page (node page)
+    pub(crate) compressed: bool, // if true value is stored compressed (kept as high bit of vlen)
 }
 
 impl Slot {
@@ -13,15 +14,39 @@ impl Slot {
             klen,
             vlen,
             page,
+            compressed: false,
         }
     }
 
     pub(crate) fn empty() -> Self {
         Self::new(0, 0, 0, 0)
     }
+
+    /// Slot holds a reference to another page (node page entry).
+    pub fn is_ref(&self) -> bool {
+        self.page != 0
+    }
+
+    /// Slot holds a value stored in the same page (leaf page entry).
+    pub fn is_val(&self) -> bool {
+        self.page == 0
+    }
 }
 
-pub trait Page: AsRef<[u8]> + AsMut<[u8]> {
+pub trait Page: AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static {
+    /// Byte order of the page layout. Must stay fixed per file, so it is recorded in the file header.
+    const LITTLE_ENDIAN: bool = false;
+
+    /// Page header without the reserved word. Recorded in the file header as well.
+    const COMPACT_HEADER: bool = false;
+
+    /// Slots with `u16` fields (page ids are limited to `u16`). Recorded in the file header as well.
+    const SMALL_SLOTS: bool = false;
+
+    /// Front-coded keys (shared prefix length and the rest of the key). Recorded in the file
+    /// header as well.
+    const PREFIX_KEYS: bool = false;
+
     fn reserve(capacity: u32) -> Self;
     fn create(id: u32, cap: u32) -> Self;
 
@@ -36,23 +61,48 @@ pub trait Page: AsRef<[u8]> + AsMut<[u8]> {
 
     fn slot(&self, idx: u32) -> Option<Slot>;
 
+    /// Page holds values only, no references to other pages (empty page is a leaf as well).
+    fn is_leaf(&self) -> bool {
+        (0..self.len()).all(|idx| self.slot(idx).map(|slot| slot.is_val()).unwrap_or(true))
+    }
+
+    /// Page holds references to other pages.
+    fn is_node(&self) -> bool {
+        !self.is_leaf()
+    }
+
     fn min(&self) -> &[u8];
     fn max(&self) -> &[u8];
     fn key(&self, idx: u32) -> &[u8];
     fn val(&self, idx: u32) -> &[u8];
+    /// Mutable value bytes in the slot of a given index (value length cannot change).
+    fn val_mut(&mut self, idx: u32) -> &mut [u8];
 
     /// Get total number of unoccupied bytes in the page.
     /// Use `fits` to check if page really has enough free space to store a key-value pair.
     fn free(&self) -> u32;
 
-    /// Get integer percent value (0..=100) of how full the page is.
-    /// Effectively this is equal to `((len() - HEAD) - free()) * 100 / (len() - HEAD)`.
+    /// Get number of bytes available for slots, keys and values (capacity without the header).
+    fn usable(&self) -> u32;
+
+    /// Get integer percent value (0..=100) of how full the page is, rounded up (so that a page
+    /// filled even slightly over N percent does not report N).
+    /// Effectively this is equal to `ceil((usable() - free()) * 100 / usable())`.
     fn full(&self) -> u8;
 
     /// Check if payload (key and value) of given size can fit the page,
-    /// taking into account necessary housekeeping overhead.
+    /// taking into account necessary housekeeping overhead. Fragmented free bytes count as well,
+    /// the page is defragmented on put when the contiguous free region is too small.
     fn fits(&self, len: u32) -> bool;
 
+    /// Get number of free bytes lying in gaps between entries (left after removed entries),
+    /// outside of the contiguous free region between the slots and the entries.
+    fn fragmentation(&self) -> u32;
+
+    /// Move all entries next to each other at the end of the page, so that all free bytes
+    /// form a single contiguous region (and `fragmentation` becomes zero).
+    fn defragment(&mut self);
+
     /// Find a slot with exact match to a given key (if any).
     fn find(&self, key: &[u8]) -> Option<u32>;
 
@@ -63,17 +113,30 @@ pub trait Page: AsRef<[u8]> + AsMut<[u8]> {
     /// Returns slot index if operation was successful.
     fn put_val(&mut self, key: &[u8], val: &[u8]) -> Option<u32>;
 
+    /// Put a key-value pair into the page, where the value is already compressed by the caller.
+    /// Returns slot index if operation was successful.
+    fn put_compressed(&mut self, key: &[u8], val: &[u8]) -> Option<u32>;
+
+    /// Check if the value in the slot of a given index is stored compressed.
+    fn is_compressed(&self, idx: u32) -> bool;
+
     /// Put a key-page-reference pair into the page.
     /// Returns slot index if operation was successful.
     fn put_ref(&mut self, key: &[u8], page: u32) -> Option<u32>;
 
     /// Remove the slot of a given index.
-    /// Automatic defragmentation is performed to maximize available capacity.
+    /// Defragmentation is performed lazily, once too much of the capacity is lost to gaps.
     fn remove(&mut self, idx: u32);
 
     /// Make an owned copy of all entries in the page: (key, val, page).
+    /// Values are copied as stored, so compressed values stay compressed.
     fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)>;
 
     /// Fill whole page (but header) with zeroes.
     fn clear(&mut self);
+
+    /// Check that the page is well-formed: the capacity matches the page bytes, and all slots
+    /// (with their keys and values) lie within the page. Other methods might panic otherwise,
+    /// so pages read from a damaged file must be checked first (see `File::repair`).
+    fn is_valid(&self) -> bool;
 }
@@ -1,6 +1,7 @@
 use crate::api::error::Result;
 use crate::api::page::Page;
 use parking_lot::{MappedRwLockReadGuard, MappedRwLockWriteGuard};
+use std::io::Write;
 
 pub trait Tree<P: Page> {
     /// Get an immutable reference to a root page.
@@ -39,7 +40,28 @@ pub trait Tree<P: Page> {
     /// Merge page `src_id` into page `dst_id`, effectively removing page `src_id`.
     fn merge(&self, src_id: u32, dst_id: u32) -> Result<()>;
 
+    /// Evaluate the page of given id against split and merge thresholds: split it if it is
+    /// over-full, or merge it into a sibling page if both are under-full. Intended for use
+    /// after manual page edits. Dirty pages are not flushed, call `flush` for that.
+    /// Caller is expected to hold the tree write lock (see `File::rebalance_page`).
+    fn rebalance(&self, id: u32, parent_id: u32) -> Result<()>;
+
+    /// Walk the whole tree and check its structural invariants: keys in each page are strictly
+    /// ascending, each reference key equals the max key of the referenced page, no page is
+    /// referenced twice, and every page available for reuse is indeed empty (is not referenced
+    /// and is not marked for reuse twice).
+    /// Returns an error describing the first violation found.
+    fn verify(&self) -> Result<()>;
+
     /// Dump tree representation to a string where keys values are presented as hex strings.
     /// Intended to use for debugging purposes only.
     fn dump(&self) -> String;
+
+    /// Stream the same representation as `dump` to the writer, without building it in memory.
+    /// With `max_depth` set, pages deeper than that are skipped (the root is at depth 0).
+    fn dump_to(&self, w: &mut impl Write, max_depth: Option<u32>) -> Result<()>;
+
+    /// Dump tree representation in Graphviz DOT format: a node per page (with id, fullness
+    /// and hex keys) and an edge per page reference. Intended to use for debugging purposes only.
+    fn dump_dot(&self) -> String;
 }
@@ -1,19 +1,55 @@
 use crate::api::page::{Page, Slot};
 use crate::util::bsearch::bsearch;
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
 use std::mem::size_of;
-
-pub struct Block {
+use std::sync::OnceLock;
+
+/// Slotted page, `LE` selects byte order of all `u32` fields (header and slots),
+/// `COMPACT` selects 3-word page header (id, length, size) without the reserved word,
+/// `SMALL` selects `u16` slot fields (halves the per-entry overhead, see `BlockSmall`),
+/// `PREFIX` selects front-coded keys (see `BlockPrefix`).
+pub struct GenericBlock<
+    const LE: bool,
+    const COMPACT: bool = false,
+    const SMALL: bool = false,
+    const PREFIX: bool = false,
+> {
     buf: BytesMut,
+    /// Decoded keys of a page with front-coded keys (decoded on first access, reset on change).
+    keys: OnceLock<Vec<Vec<u8>>>,
 }
 
-impl AsMut<[u8]> for Block {
+/// Page with big-endian `u32` fields.
+pub type Block = GenericBlock<false>;
+
+/// Page with little-endian `u32` fields (no byte-swapping on little-endian hardware).
+pub type BlockLE = GenericBlock<true>;
+
+/// Page with big-endian `u32` fields and compact header (less overhead for tiny pages).
+pub type BlockCompact = GenericBlock<false, true>;
+
+/// Page with big-endian `u16` slot fields, page capacity must not exceed `u16::MAX` and the
+/// referenced page ids must be below `u16::MAX` (so the file is limited to that many pages).
+pub type BlockSmall = GenericBlock<false, false, true>;
+
+/// Page with big-endian `u32` fields and front-coded keys: each key is stored as the length
+/// of the prefix shared with the previous key in the page (`u16`) followed by the rest of it,
+/// so keys with long common prefixes take less space. Keys are decoded (and kept in memory)
+/// on first access, and the whole page is re-encoded on every insert or remove.
+pub type BlockPrefix = GenericBlock<false, false, false, true>;
+
+impl<const LE: bool, const COMPACT: bool, const SMALL: bool, const PREFIX: bool> AsMut<[u8]>
+    for GenericBlock<LE, COMPACT, SMALL, PREFIX>
+{
     fn as_mut(&mut self) -> &mut [u8] {
+        self.keys.take();
         &mut self.buf[..]
     }
 }
 
-impl AsRef<[u8]> for Block {
+impl<const LE: bool, const COMPACT: bool, const SMALL: bool, const PREFIX: bool> AsRef<[u8]>
+    for GenericBlock<LE, COMPACT, SMALL, PREFIX>
+{
     fn as_ref(&self) -> &[u8] {
         &self.buf[..]
     }
@@ -22,10 +58,58 @@ impl AsRef<[u8]> for Block {
 const ID_OFFSET: usize = 0;
 const CAP_OFFSET: usize = 4;
 const SIZE_OFFSET: usize = 8;
+const RESERVED_OFFSET: usize = 12;
 const RESERVED: u32 = 0xC0DE1542;
 
-impl Block {
-    fn put_entry(&mut self, key: &[u8], val: &[u8], page: u32) -> Option<u32> {
+/// Percent of page capacity that might be lost to gaps (left after removed entries)
+/// before the page gets compacted.
+const COMPACT_THRESHOLD: u32 = 25;
+
+/// High bit of the stored `vlen` marks compressed value.
+const COMPRESSED: u32 = 0x8000_0000;
+
+/// With small slots the `vlen` has no spare bit, so a compressed value is marked by this
+/// page reference instead (value slots never reference a page).
+const SMALL_COMPRESSED: u16 = u16::MAX;
+
+impl<const LE: bool, const COMPACT: bool, const SMALL: bool, const PREFIX: bool>
+    GenericBlock<LE, COMPACT, SMALL, PREFIX>
+{
+    /// Page header: id, length, size (and reserved word unless the header is compact).
+    pub(crate) const HEAD: usize = if COMPACT { 3 * U32 } else { 4 * U32 };
+
+    /// Slot: offset, klen, vlen, page (`u16` each if slots are small, `u32` otherwise).
+    pub(crate) const SLOT: usize = if SMALL { SMALL_SLOT } else { SLOT };
+
+    /// Length of the shared prefix stored in front of each front-coded key.
+    const SHARED: usize = if PREFIX { U16 } else { 0 };
+
+    fn wrap(buf: BytesMut) -> Self {
+        Self {
+            buf,
+            keys: OnceLock::new(),
+        }
+    }
+
+    fn put_entry(&mut self, key: &[u8], val: &[u8], page: u32, compressed: bool) -> Option<u32> {
+        // Overwrite of the value of the same size happens in place: no slots are moved.
+        if let Some(idx) = self.find(key) {
+            if let Some(mut slot) = self
+                .slot(idx)
+                .filter(|slot| slot.vlen as usize == val.len())
+            {
+                slot.page = page;
+                slot.compressed = compressed;
+                put_slot::<LE, COMPACT, SMALL>(&mut self.buf, idx, &slot);
+                put_slice(&mut self.buf, (slot.offset + slot.klen) as usize, val);
+                return Some(idx);
+            }
+        }
+
+        if PREFIX {
+            return self.put_prefixed(key, val, page, compressed);
+        }
+
         if !self.fits((key.len() + val.len()) as u32) {
             return None;
         }
@@ -40,29 +124,24 @@ impl Block {
         let size = self.len();
         let idx = self.ceil(key).unwrap_or(size);
 
-        let mut slots = (0..size)
-            .into_iter()
-            .filter_map(|idx| self.slot(idx))
-            .collect::<Vec<_>>();
-
         let klen = key.len() as u32;
         let vlen = val.len() as u32;
-        let end = slots
-            .iter()
-            .map(|slot| slot.offset)
-            .min()
-            .unwrap_or_else(|| self.cap());
-        let offset = end - klen - vlen;
-        let slot = Slot::new(offset, klen, vlen, page);
+        let lo = Self::HEAD as u32 + (size + 1) * Self::SLOT as u32;
+        if self.lowest() < lo + klen + vlen {
+            // There is enough free space in total, but it is fragmented.
+            self.compact();
+        }
 
-        slots.insert(idx as usize, slot);
-        slots
-            .into_iter()
-            .enumerate()
-            .for_each(|(idx, slot)| put_slot(&mut self.buf, idx as u32, &slot));
+        let offset = self.lowest() - klen - vlen;
+        let mut slot = Slot::new(offset, klen, vlen, page);
+        slot.compressed = compressed;
 
-        let n = self.len() + 1;
-        put_size(&mut self.buf, n);
+        // Only the slots after the new one are shifted, key and value bytes stay in place.
+        let at = Self::HEAD + idx as usize * Self::SLOT;
+        let to = Self::HEAD + size as usize * Self::SLOT;
+        self.buf.copy_within(at..to, at + Self::SLOT);
+        put_slot::<LE, COMPACT, SMALL>(&mut self.buf, idx, &slot);
+        put_size::<LE>(&mut self.buf, size + 1);
 
         put_slice(&mut self.buf, offset as usize, key);
         if !val.is_empty() {
@@ -71,48 +150,223 @@ impl Block {
 
         Some(idx)
     }
+
+    /// Insert (or replace) the entry, re-encoding all keys of the page. Needs no more space for
+    /// the other keys: the shared prefix of the key following the new one can only get longer.
+    fn put_prefixed(&mut self, key: &[u8], val: &[u8], page: u32, compressed: bool) -> Option<u32> {
+        let mut entries = self.entries();
+        let entry = (key.to_vec(), val.to_vec(), page, compressed);
+        let idx = match entries.binary_search_by(|(k, _, _, _)| k.as_slice().cmp(key)) {
+            Ok(idx) => {
+                entries[idx] = entry;
+                idx
+            }
+            Err(idx) => {
+                entries.insert(idx, entry);
+                idx
+            }
+        };
+        if Self::HEAD + Self::encoded(&entries) > self.cap() as usize {
+            return None;
+        }
+        self.rewrite(&entries);
+        Some(idx as u32)
+    }
+
+    /// Decoded entries of the page: (key, val, page, compressed).
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>, u32, bool)> {
+        (0..self.len())
+            .filter_map(|idx| self.slot(idx).map(|slot| (idx, slot)))
+            .map(|(idx, slot)| {
+                (
+                    self.key(idx).to_vec(),
+                    get_val(&self.buf, &slot).to_vec(),
+                    slot.page,
+                    slot.compressed,
+                )
+            })
+            .collect()
+    }
+
+    /// Number of bytes taken by the slots, front-coded keys and values of the entries.
+    fn encoded(entries: &[(Vec<u8>, Vec<u8>, u32, bool)]) -> usize {
+        let mut prev: &[u8] = &[];
+        let mut total = 0;
+        for (key, val, _, _) in entries {
+            total += Self::SLOT + Self::SHARED + key.len() - shared(prev, key) + val.len();
+            prev = key;
+        }
+        total
+    }
+
+    /// Replace all entries of the page with given (sorted) ones, front-coding the keys.
+    fn rewrite(&mut self, entries: &[(Vec<u8>, Vec<u8>, u32, bool)]) {
+        let lo = Self::HEAD;
+        let blank = vec![0u8; self.cap() as usize - lo];
+        put_slice(&mut self.buf, lo, &blank);
+        put_size::<LE>(&mut self.buf, entries.len() as u32);
+
+        let data = Self::encoded(entries) - entries.len() * Self::SLOT;
+        let mut offset = self.cap() as usize - data;
+        let mut prev: &[u8] = &[];
+        for (idx, (key, val, page, compressed)) in entries.iter().enumerate() {
+            let n = shared(prev, key);
+            let suffix = &key[n..];
+            put_u16::<LE>(&mut self.buf, offset, n as u16);
+            put_slice(&mut self.buf, offset + U16, suffix);
+            put_slice(&mut self.buf, offset + U16 + suffix.len(), val);
+
+            let klen = (U16 + suffix.len()) as u32;
+            let mut slot = Slot::new(offset as u32, klen, val.len() as u32, *page);
+            slot.compressed = *compressed;
+            put_slot::<LE, COMPACT, SMALL>(&mut self.buf, idx as u32, &slot);
+            offset += klen as usize + val.len();
+            prev = key;
+        }
+        self.keys.take();
+    }
+
+    /// Decode all front-coded keys of the page. Shared prefix lengths are capped by the length
+    /// of the previous key, so a damaged page does not panic here (see `is_valid`).
+    fn decode(&self) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = Vec::with_capacity(self.len() as usize);
+        for slot in (0..self.len()).filter_map(|idx| self.slot(idx)) {
+            let raw = get_key(&self.buf, &slot);
+            let mut key = Vec::new();
+            if raw.len() >= U16 {
+                let prev = keys.last().map(Vec::as_slice).unwrap_or_default();
+                let n = get_u16::<LE>(&self.buf, slot.offset as usize) as usize;
+                key.extend_from_slice(&prev[..n.min(prev.len())]);
+                key.extend_from_slice(&raw[U16..]);
+            }
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// Lowest offset occupied by an entry (or page capacity if page is empty).
+    fn lowest(&self) -> u32 {
+        (0..self.len())
+            .filter_map(|idx| self.slot(idx))
+            .map(|slot| slot.offset)
+            .min()
+            .unwrap_or_else(|| self.cap())
+    }
+
+    /// Total number of bytes occupied by keys and values.
+    fn used(&self) -> u32 {
+        (0..self.len())
+            .filter_map(|idx| self.slot(idx))
+            .map(|slot| slot.klen + slot.vlen)
+            .sum()
+    }
+
+    /// Number of unoccupied bytes between entries (left after removed entries).
+    fn gaps(&self) -> u32 {
+        self.cap() - self.lowest() - self.used()
+    }
+
+    /// Move all entries to the end of the page leaving no gaps between them.
+    fn compact(&mut self) {
+        let size = self.len();
+        let mut slots = (0..size)
+            .filter_map(|idx| self.slot(idx))
+            .collect::<Vec<_>>();
+
+        let copy = slots
+            .iter()
+            .map(|slot| {
+                (
+                    get_key(&self.buf, slot).to_vec(),
+                    get_val(&self.buf, slot).to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let lo = Self::HEAD + size as usize * Self::SLOT;
+        let blank = vec![0u8; self.cap() as usize - lo];
+        put_slice(&mut self.buf, lo, &blank);
+
+        let total: u32 = slots.iter().map(|slot| slot.klen + slot.vlen).sum();
+        let mut offset = self.cap() - total;
+        for (i, (key, val)) in copy.iter().enumerate() {
+            slots.get_mut(i).unwrap().offset = offset;
+            put_slice(&mut self.buf, offset as usize, key);
+            offset += key.len() as u32;
+            if !val.is_empty() {
+                put_slice(&mut self.buf, offset as usize, val);
+                offset += val.len() as u32;
+            }
+        }
+
+        slots.into_iter().enumerate().for_each(|(idx, slot)| {
+            put_slot::<LE, COMPACT, SMALL>(&mut self.buf, idx as u32, &slot)
+        });
+    }
 }
 
-impl Page for Block {
+impl<const LE: bool, const COMPACT: bool, const SMALL: bool, const PREFIX: bool> Page
+    for GenericBlock<LE, COMPACT, SMALL, PREFIX>
+{
+    const LITTLE_ENDIAN: bool = LE;
+    const COMPACT_HEADER: bool = COMPACT;
+    const SMALL_SLOTS: bool = SMALL;
+    const PREFIX_KEYS: bool = PREFIX;
+
     fn reserve(capacity: u32) -> Self {
         let mut buf = BytesMut::with_capacity(capacity as usize);
         buf.extend_from_slice(&vec![0u8; capacity as usize]);
-        Self { buf }
+        Self::wrap(buf)
     }
 
     fn create(id: u32, cap: u32) -> Self {
+        debug_assert!(!SMALL || cap <= u16::MAX as u32, "page too large: {}", cap);
         let mut buf = BytesMut::with_capacity(cap as usize);
-        buf.put_u32(id);
-        buf.put_u32(cap);
-        buf.put_u32(0);
-        buf.put_u32(RESERVED);
-        assert_eq!(buf.len(), HEAD);
-        buf.extend_from_slice(&vec![0u8; cap as usize - HEAD]);
-        Self { buf }
+        buf.extend_from_slice(&vec![0u8; cap as usize]);
+        put_u32::<LE>(&mut buf, ID_OFFSET, id);
+        put_u32::<LE>(&mut buf, CAP_OFFSET, cap);
+        if !COMPACT {
+            put_u32::<LE>(&mut buf, RESERVED_OFFSET, RESERVED);
+        }
+        Self::wrap(buf)
     }
 
     fn id(&self) -> u32 {
-        get_u32(&self.buf, ID_OFFSET)
+        get_u32::<LE>(&self.buf, ID_OFFSET)
     }
 
     fn cap(&self) -> u32 {
-        get_u32(&self.buf, CAP_OFFSET)
+        get_u32::<LE>(&self.buf, CAP_OFFSET)
     }
 
     fn len(&self) -> u32 {
-        get_u32(&self.buf, SIZE_OFFSET)
+        get_u32::<LE>(&self.buf, SIZE_OFFSET)
     }
 
     fn slot(&self, idx: u32) -> Option<Slot> {
         if idx >= self.len() {
             return None;
         }
-        let pos = HEAD + U32 * 4 * idx as usize;
-        let offset = get_u32(&self.buf, pos);
-        let klen = get_u32(&self.buf, pos + 4);
-        let vlen = get_u32(&self.buf, pos + 8);
-        let page = get_u32(&self.buf, pos + 12);
-        Some(Slot::new(offset, klen, vlen, page))
+        let pos = Self::HEAD + Self::SLOT * idx as usize;
+        if SMALL {
+            let offset = get_u16::<LE>(&self.buf, pos) as u32;
+            let klen = get_u16::<LE>(&self.buf, pos + 2) as u32;
+            let vlen = get_u16::<LE>(&self.buf, pos + 4) as u32;
+            let page = get_u16::<LE>(&self.buf, pos + 6);
+            if page == SMALL_COMPRESSED {
+                let mut slot = Slot::new(offset, klen, vlen, 0);
+                slot.compressed = true;
+                return Some(slot);
+            }
+            return Some(Slot::new(offset, klen, vlen, page as u32));
+        }
+        let offset = get_u32::<LE>(&self.buf, pos);
+        let klen = get_u32::<LE>(&self.buf, pos + 4);
+        let vlen = get_u32::<LE>(&self.buf, pos + 8);
+        let page = get_u32::<LE>(&self.buf, pos + 12);
+        let mut slot = Slot::new(offset, klen, vlen & !COMPRESSED, page);
+        slot.compressed = vlen & COMPRESSED != 0;
+        Some(slot)
     }
 
     fn min(&self) -> &[u8] {
@@ -124,6 +378,14 @@ impl Page for Block {
     }
 
     fn key(&self, idx: u32) -> &[u8] {
+        if PREFIX {
+            return self
+                .keys
+                .get_or_init(|| self.decode())
+                .get(idx as usize)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+        }
         self.slot(idx)
             .map(|slot| {
                 let at = slot.offset as usize;
@@ -143,30 +405,44 @@ impl Page for Block {
             .unwrap_or_default()
     }
 
-    fn free(&self) -> u32 {
-        let size = self.len();
-        if size == 0 {
-            return self.cap() - HEAD as u32;
+    fn val_mut(&mut self, idx: u32) -> &mut [u8] {
+        match self.slot(idx) {
+            Some(slot) => {
+                let at = slot.offset as usize + slot.klen as usize;
+                let to = at + slot.vlen as usize;
+                &mut self.buf[at..to]
+            }
+            None => &mut [],
         }
-        let lo = HEAD as u32 + size * SLOT as u32;
-        let hi = (0..size)
-            .into_iter()
-            .filter_map(|idx| self.slot(idx))
-            .map(|slot| slot.offset)
-            .min()
-            .unwrap_or(lo);
+    }
+
+    fn free(&self) -> u32 {
+        self.cap() - Self::HEAD as u32 - self.len() * Self::SLOT as u32 - self.used()
+    }
 
-        assert!(lo <= hi);
-        hi - lo
+    fn usable(&self) -> u32 {
+        self.cap() - Self::HEAD as u32
     }
 
     fn full(&self) -> u8 {
-        let len = self.cap() - HEAD as u32;
-        ((len - self.free()) * 100 / len) as u8
+        let len = self.usable();
+        ((len - self.free()) as u64 * 100).div_ceil(len as u64) as u8
     }
 
+    /// With front-coded keys the key is assumed to share no prefix with its neighbours.
     fn fits(&self, len: u32) -> bool {
-        self.free() >= len + SLOT as u32
+        self.free() >= len + (Self::SLOT + Self::SHARED) as u32
+    }
+
+    fn fragmentation(&self) -> u32 {
+        self.gaps()
+    }
+
+    /// Front-coded keys are re-encoded on every change, so such pages have no gaps.
+    fn defragment(&mut self) {
+        if self.gaps() > 0 {
+            self.compact();
+        }
     }
 
     fn find(&self, key: &[u8]) -> Option<u32> {
@@ -198,11 +474,21 @@ impl Page for Block {
     }
 
     fn put_val(&mut self, key: &[u8], val: &[u8]) -> Option<u32> {
-        self.put_entry(key, val, 0)
+        self.put_entry(key, val, 0, false)
+    }
+
+    fn put_compressed(&mut self, key: &[u8], val: &[u8]) -> Option<u32> {
+        self.put_entry(key, val, 0, true)
+    }
+
+    fn is_compressed(&self, idx: u32) -> bool {
+        self.slot(idx)
+            .map(|slot| slot.compressed)
+            .unwrap_or_default()
     }
 
     fn put_ref(&mut self, key: &[u8], page: u32) -> Option<u32> {
-        self.put_entry(key, &[], page)
+        self.put_entry(key, &[], page, false)
     }
 
     fn remove(&mut self, idx: u32) {
@@ -211,54 +497,36 @@ impl Page for Block {
             return;
         }
 
-        let mut slots = (0..size)
-            .into_iter()
-            .filter_map(|idx| self.slot(idx))
-            .collect::<Vec<_>>();
+        if PREFIX {
+            // Needs no more space: the key following the removed one gets a shorter shared
+            // prefix, but by no more bytes than the removed key's suffix held.
+            let mut entries = self.entries();
+            entries.remove(idx as usize);
+            self.rewrite(&entries);
+            return;
+        }
 
-        let removed = slots.remove(idx as usize);
+        let removed = self.slot(idx).unwrap();
         let blank = vec![0u8; (removed.klen + removed.vlen) as usize];
         put_slice(&mut self.buf, removed.offset as usize, &blank);
 
-        put_size(&mut self.buf, size - 1);
-
-        let total: u32 = slots.iter().map(|slot| slot.klen + slot.vlen).sum();
-        let mut offset = self.cap() - total;
+        let at = Self::HEAD + idx as usize * Self::SLOT;
+        let to = Self::HEAD + size as usize * Self::SLOT;
+        self.buf.copy_within((at + Self::SLOT)..to, at);
+        put_slot::<LE, COMPACT, SMALL>(&mut self.buf, size - 1, &Slot::empty());
+        put_size::<LE>(&mut self.buf, size - 1);
 
-        let copy = slots
-            .iter()
-            .map(|slot| {
-                (
-                    get_key(&self.buf, slot).to_vec(),
-                    get_val(&self.buf, slot).to_vec(),
-                )
-            })
-            .collect::<Vec<_>>();
-
-        for (i, (key, val)) in copy.iter().enumerate() {
-            slots.get_mut(i).unwrap().offset = offset;
-            put_slice(&mut self.buf, offset as usize, key);
-            offset += key.len() as u32;
-            if !val.is_empty() {
-                put_slice(&mut self.buf, offset as usize, val);
-                offset += val.len() as u32;
-            }
+        if self.gaps() as u64 * 100 > self.usable() as u64 * COMPACT_THRESHOLD as u64 {
+            self.compact();
         }
-
-        slots.push(Slot::empty());
-        slots
-            .into_iter()
-            .enumerate()
-            .for_each(|(idx, slot)| put_slot(&mut self.buf, idx as u32, &slot));
     }
 
     fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
         (0..self.len())
-            .into_iter()
-            .filter_map(|idx| self.slot(idx))
-            .map(|slot| {
+            .filter_map(|idx| self.slot(idx).map(|slot| (idx, slot)))
+            .map(|(idx, slot)| {
                 (
-                    get_key(&self.buf, &slot).to_vec(),
+                    self.key(idx).to_vec(),
                     get_val(&self.buf, &slot).to_vec(),
                     slot.page,
                 )
@@ -267,30 +535,77 @@ impl Page for Block {
     }
 
     fn clear(&mut self) {
+        self.keys.take();
         let len = self.cap() as usize;
-        let mut tmp = BytesMut::with_capacity(len);
-        tmp.put_u32(self.id());
-        tmp.put_u32(self.cap());
-        tmp.put_u32(0);
-        tmp.put_u32(RESERVED);
-        self.buf[..HEAD].copy_from_slice(tmp.as_ref());
-        let blank = vec![0xFFu8; len - HEAD];
-        self.buf[HEAD..].copy_from_slice(&blank);
+        put_size::<LE>(&mut self.buf, 0);
+        if !COMPACT {
+            put_u32::<LE>(&mut self.buf, RESERVED_OFFSET, RESERVED);
+        }
+        let blank = vec![0xFFu8; len - Self::HEAD];
+        self.buf[Self::HEAD..].copy_from_slice(&blank);
     }
 
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    fn is_valid(&self) -> bool {
+        let cap = self.buf.len();
+        let slots = Self::HEAD + Self::SLOT * self.len() as usize;
+        if self.cap() as usize != cap || slots > cap {
+            return false;
+        }
+        let mut prev = 0;
+        (0..self.len()).all(|idx| match self.slot(idx) {
+            Some(slot) => {
+                let at = slot.offset as usize;
+                let fits = at >= slots && at + slot.klen as usize + slot.vlen as usize <= cap;
+                if !PREFIX || !fits {
+                    return fits;
+                }
+                let klen = slot.klen as usize;
+                let n = get_u16::<LE>(&self.buf, at) as usize;
+                let ok = klen >= U16 && n <= prev;
+                prev = n + klen.saturating_sub(U16);
+                ok
+            }
+            None => false,
+        })
+    }
 }
 
 const U32: usize = size_of::<u32>();
-const SLOT: usize = size_of::<Slot>();
-const HEAD: usize = 4 * U32; // page header: id, length, size, reserved
+const U16: usize = size_of::<u16>();
+const SLOT: usize = 4 * U32; // slot: offset, klen, vlen, page
+const SMALL_SLOT: usize = 4 * U16; // small slot: offset, klen, vlen, page
+
+/// Length of the common prefix of two keys (capped to fit the `u16` stored with the key).
+fn shared(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b)
+        .take_while(|(x, y)| x == y)
+        .count()
+        .min(u16::MAX as usize)
+}
+
+fn get_u16<const LE: bool>(buf: &BytesMut, pos: usize) -> u16 {
+    let mut src = [0u8; U16];
+    src.copy_from_slice(&buf[pos..(pos + U16)]);
+    if LE {
+        u16::from_le_bytes(src)
+    } else {
+        u16::from_be_bytes(src)
+    }
+}
 
-fn get_u32(buf: &BytesMut, pos: usize) -> u32 {
+fn get_u32<const LE: bool>(buf: &BytesMut, pos: usize) -> u32 {
     let mut src = [0u8; U32];
     src.copy_from_slice(&buf[pos..(pos + U32)]);
-    u32::from_be_bytes(src)
+    if LE {
+        u32::from_le_bytes(src)
+    } else {
+        u32::from_be_bytes(src)
+    }
 }
 
 fn get_key<'a>(buf: &'a BytesMut, slot: &'a Slot) -> &'a [u8] {
@@ -302,9 +617,13 @@ fn get_val<'a>(buf: &'a BytesMut, slot: &'a Slot) -> &'a [u8] {
         ..(slot.offset as usize + slot.klen as usize + slot.vlen as usize)]
 }
 
-fn put_u32(buf: &mut BytesMut, pos: usize, val: u32) {
+fn put_u32<const LE: bool>(buf: &mut BytesMut, pos: usize, val: u32) {
     let dst = &mut buf[pos..(pos + U32)];
-    dst.copy_from_slice(&val.to_be_bytes());
+    if LE {
+        dst.copy_from_slice(&val.to_le_bytes());
+    } else {
+        dst.copy_from_slice(&val.to_be_bytes());
+    }
 }
 
 fn put_slice(buf: &mut BytesMut, pos: usize, src: &[u8]) {
@@ -312,16 +631,52 @@ fn put_slice(buf: &mut BytesMut, pos: usize, src: &[u8]) {
     dst.copy_from_slice(src);
 }
 
-fn put_size(buf: &mut BytesMut, val: u32) {
-    put_u32(buf, SIZE_OFFSET, val);
+fn put_size<const LE: bool>(buf: &mut BytesMut, val: u32) {
+    put_u32::<LE>(buf, SIZE_OFFSET, val);
 }
 
-fn put_slot(buf: &mut BytesMut, idx: u32, slot: &Slot) {
-    let pos = HEAD + idx as usize * SLOT;
-    put_u32(buf, pos, slot.offset);
-    put_u32(buf, pos + 4, slot.klen);
-    put_u32(buf, pos + 8, slot.vlen);
-    put_u32(buf, pos + 12, slot.page);
+fn put_u16<const LE: bool>(buf: &mut BytesMut, pos: usize, val: u16) {
+    let dst = &mut buf[pos..(pos + U16)];
+    if LE {
+        dst.copy_from_slice(&val.to_le_bytes());
+    } else {
+        dst.copy_from_slice(&val.to_be_bytes());
+    }
+}
+
+fn put_slot<const LE: bool, const COMPACT: bool, const SMALL: bool>(
+    buf: &mut BytesMut,
+    idx: u32,
+    slot: &Slot,
+) {
+    let pos = GenericBlock::<LE, COMPACT, SMALL>::HEAD
+        + idx as usize * GenericBlock::<LE, COMPACT, SMALL>::SLOT;
+    if SMALL {
+        debug_assert!(
+            slot.page < SMALL_COMPRESSED as u32,
+            "page id: {}",
+            slot.page
+        );
+        let page = if slot.compressed {
+            SMALL_COMPRESSED
+        } else {
+            slot.page as u16
+        };
+        put_u16::<LE>(buf, pos, slot.offset as u16);
+        put_u16::<LE>(buf, pos + 2, slot.klen as u16);
+        put_u16::<LE>(buf, pos + 4, slot.vlen as u16);
+        put_u16::<LE>(buf, pos + 6, page);
+        return;
+    }
+    put_u32::<LE>(buf, pos, slot.offset);
+    put_u32::<LE>(buf, pos + 4, slot.klen);
+    let vlen = if slot.compressed {
+        slot.vlen | COMPRESSED
+    } else {
+        slot.vlen
+    };
+    put_u32::<LE>(buf, pos + 8, vlen);
+    put_u32::<LE>(buf, pos + 12, slot.page);
 }
 
 #[cfg(test)]
@@ -329,13 +684,17 @@ mod tests {
     use super::*;
     use crate::util;
     use rand::prelude::*;
+    use rand::rngs::StdRng;
     use std::collections::HashSet;
 
+    const HEAD: usize = Block::HEAD;
+
     #[test]
     fn test_sizes() {
         assert_eq!(U32, 4);
         assert_eq!(SLOT, 16);
         assert_eq!(HEAD, 16);
+        assert_eq!(BlockCompact::HEAD, 12);
     }
 
     #[test]
@@ -346,7 +705,6 @@ mod tests {
         let len = size * size_of::<u64>() * 4;
 
         let mut keys = (0..size)
-            .into_iter()
             .map(|_| rng.gen::<u64>().to_be_bytes().to_vec())
             .collect::<Vec<_>>();
 
@@ -363,7 +721,6 @@ mod tests {
         keys.sort();
 
         let read = (0..size)
-            .into_iter()
             .map(|idx| page.key(idx as u32).to_vec())
             .collect::<Vec<_>>();
 
@@ -377,10 +734,7 @@ mod tests {
         let size = 64;
         let len = size * size_of::<u64>() * 10;
 
-        let keys = (0..size)
-            .into_iter()
-            .map(|_| rng.gen::<u64>())
-            .collect::<HashSet<_>>();
+        let keys = (0..size).map(|_| rng.gen::<u64>()).collect::<HashSet<_>>();
 
         let pairs = keys
             .iter()
@@ -412,7 +766,6 @@ mod tests {
         let len = size * size_of::<u64>() * 10;
 
         let keys = (0..size)
-            .into_iter()
             .map(|_| {
                 let x = rng.gen::<u64>();
                 x - (x % 100)
@@ -467,6 +820,32 @@ mod tests {
         assert_eq!(page.full(), 100);
     }
 
+    #[test]
+    fn test_full_rounds_up() {
+        let mut page = Block::create(42, 256);
+        assert_eq!(page.usable(), 240);
+        for i in 0..3u8 {
+            page.put_val(&[i], &[i; 32]).unwrap();
+        }
+        page.put_val(&[3], &[3; 29]).unwrap();
+        // (3 * (16 + 1 + 32) + (16 + 1 + 29)) / 240 = 193 / 240 = 80.4%
+        assert_eq!(page.free(), 240 - 193);
+        assert_eq!(page.full(), 81);
+
+        page.clear();
+        assert_eq!(page.full(), 0);
+        page.put_val(&[0], &[0; 7]).unwrap();
+        assert_eq!(page.full(), 10); // 24 / 240
+
+        // percent of a page over ~42MB does not fit u32
+        let cap = 64 << 20;
+        let mut page = Block::create(42, cap);
+        page.put_val(&[0], &vec![0; cap as usize / 2]).unwrap();
+        assert_eq!(page.full(), 51);
+        page.remove(0);
+        assert_eq!(page.full(), 0);
+    }
+
     #[test]
     fn test_page() {
         let k1 = b"bb-cc-dd-ee";
@@ -494,7 +873,6 @@ mod tests {
         assert_eq!(page.put_ref(k3, p3), Some(2));
 
         let slots = (0..page.len())
-            .into_iter()
             .filter_map(|idx| page.slot(idx))
             .collect::<Vec<_>>();
 
@@ -597,11 +975,384 @@ mod tests {
         assert_eq!(page.find(&data[2].0), Some(1));
     }
 
+    #[test]
+    fn test_remove_orders() {
+        fn naive(entries: &[(Vec<u8>, Vec<u8>)], cap: u32) -> Block {
+            let mut page = Block::create(42, cap);
+            for (k, v) in entries {
+                page.put_val(k, v).unwrap();
+            }
+            page
+        }
+
+        let count = 32;
+        let pairs = util::data(count, 42);
+        let cap = (count * (16 + SLOT)) as u32 + HEAD as u32;
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random = (0..count).collect::<Vec<_>>();
+        random.shuffle(&mut rng);
+        let orders = vec![
+            (0..count).collect::<Vec<_>>(),
+            (0..count).rev().collect::<Vec<_>>(),
+            random,
+        ];
+
+        for order in orders {
+            let mut page = naive(&pairs, cap);
+            let mut left = pairs.clone();
+            left.sort();
+            for (i, idx) in order.iter().enumerate() {
+                let key = &pairs[*idx].0;
+                page.remove(page.find(key).unwrap());
+                left.retain(|(k, _)| k != key);
+
+                let expected = naive(&left, cap);
+                assert_eq!(page.copy(), expected.copy());
+                assert_eq!(page.free(), expected.free());
+
+                if i % 4 == 0 {
+                    // Re-inserting must succeed even if free space is fragmented.
+                    let (k, v) = &pairs[*idx];
+                    page.put_val(k, v).unwrap();
+                    page.remove(page.find(k).unwrap());
+                }
+            }
+            assert!(page.is_empty());
+            assert_eq!(page.free(), cap - HEAD as u32);
+        }
+    }
+
+    #[test]
+    fn test_compressed_flag() {
+        let mut page = Block::create(42, 256);
+        page.put_val(b"a", b"plain").unwrap();
+        page.put_compressed(b"b", b"packed").unwrap();
+        page.put_val(b"c", b"plain").unwrap();
+
+        assert!(!page.is_compressed(0));
+        assert!(page.is_compressed(1));
+        assert!(!page.is_compressed(2));
+        assert_eq!(page.val(1), b"packed");
+
+        let free = 256 - HEAD as u32 - 3 * SLOT as u32 - 3 - 5 - 6 - 5;
+        assert_eq!(page.free(), free);
+
+        page.remove(0);
+        assert!(page.is_compressed(0));
+        assert_eq!(page.val(0), b"packed");
+        page.put_val(b"b", b"overwritten").unwrap();
+        assert!(!page.is_compressed(0));
+    }
+
+    #[test]
+    fn test_overwrite_in_place() {
+        let mut page = Block::create(42, 256);
+        for key in [b"a", b"b", b"c"] {
+            page.put_val(key, &0u64.to_be_bytes()).unwrap();
+        }
+        let slots = (0..page.len())
+            .map(|idx| page.slot(idx))
+            .collect::<Vec<_>>();
+        let free = page.free();
+
+        for i in 1..=1000u64 {
+            assert_eq!(page.put_val(b"b", &i.to_be_bytes()), Some(1));
+            assert_eq!(page.val(1), &i.to_be_bytes());
+            assert_eq!(page.free(), free);
+        }
+        let after = (0..page.len())
+            .map(|idx| page.slot(idx))
+            .collect::<Vec<_>>();
+        assert_eq!(after, slots);
+        assert_eq!(page.val(0), &0u64.to_be_bytes());
+        assert_eq!(page.val(2), &0u64.to_be_bytes());
+
+        // same size, but compressed: flag is updated in place as well
+        page.put_compressed(b"c", b"packed!!").unwrap();
+        assert!(page.is_compressed(2));
+        assert_eq!(page.slot(2).unwrap().offset, slots[2].unwrap().offset);
+        assert_eq!(page.val(2), b"packed!!");
+
+        // different size goes through remove and insert
+        page.put_val(b"b", b"short").unwrap();
+        assert_eq!(page.val(1), b"short");
+        assert_ne!(page.slot(1).unwrap().offset, slots[1].unwrap().offset);
+    }
+
+    #[test]
+    fn test_insert_shifts_slots_only() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut keys = (0..100u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        keys.shuffle(&mut rng);
+
+        let mut page = BlockLE::create(42, 4096);
+        let mut offsets = Vec::new();
+        for key in keys.iter() {
+            let val = key.repeat(2);
+            let idx = page.put_val(key, &val).unwrap();
+            assert_eq!(page.key(idx), key);
+            offsets.push((key, page.slot(idx).unwrap().offset));
+            // no gaps to compact: entries inserted earlier are not moved
+            for (key, offset) in offsets.iter() {
+                let idx = page.find(*key).unwrap();
+                assert_eq!(page.slot(idx).unwrap().offset, *offset);
+                assert_eq!(page.val(idx), key.repeat(2));
+            }
+        }
+
+        keys.sort();
+        let expected = keys
+            .iter()
+            .map(|key| (key.to_vec(), key.repeat(2), 0))
+            .collect::<Vec<_>>();
+        assert_eq!(page.copy(), expected);
+        assert!(page.is_valid());
+        assert_eq!(page.fragmentation(), 0);
+
+        // overwrite with a value of another size keeps the key unique
+        page.put_val(&keys[50], b"x").unwrap();
+        assert_eq!(page.len(), 100);
+        assert_eq!(page.val(50), b"x");
+        page.put_ref(&[0xFF; 4], 7).unwrap();
+        assert_eq!(page.slot(100).unwrap().page, 7);
+    }
+
+    #[test]
+    fn test_leaf_node() {
+        let mut page = Block::create(42, 256);
+        assert!(page.is_leaf());
+        assert!(!page.is_node());
+
+        page.put_val(b"a", b"1").unwrap();
+        page.put_val(b"b", b"2").unwrap();
+        assert!(page.is_leaf());
+        assert!(page.slot(0).unwrap().is_val());
+        assert!(!page.slot(0).unwrap().is_ref());
+
+        let mut node = Block::create(43, 256);
+        node.put_ref(b"m", 2).unwrap();
+        node.put_ref(b"z", 3).unwrap();
+        assert!(node.is_node());
+        assert!(!node.is_leaf());
+        assert!(node.slot(1).unwrap().is_ref());
+        assert!(!node.slot(1).unwrap().is_val());
+
+        node.remove(1);
+        node.remove(0);
+        assert!(node.is_leaf());
+    }
+
+    #[test]
+    fn test_compact_header() {
+        let mut full = Block::create(42, 128);
+        let mut compact = BlockCompact::create(42, 128);
+        assert_eq!(compact.free(), full.free() + 4);
+
+        for (k, v) in [(&b"b"[..], &b"22"[..]), (b"a", b"1"), (b"c", b"333")] {
+            full.put_val(k, v).unwrap();
+            compact.put_val(k, v).unwrap();
+        }
+        assert_eq!(compact.id(), 42);
+        assert_eq!(compact.len(), 3);
+        assert_eq!(compact.free(), full.free() + 4);
+        assert_eq!(compact.copy(), full.copy());
+        assert_eq!(
+            &compact.buf[12..(12 + SLOT)],
+            &full.buf[16..(16 + SLOT)],
+            "slots follow the 3-word header"
+        );
+
+        compact.clear();
+        assert!(compact.is_empty());
+        assert_eq!(compact.id(), 42);
+        assert_eq!(compact.cap(), 128);
+    }
+
+    fn fill<P: Page>(page: &mut P) -> u64 {
+        let mut count = 0u64;
+        while page.put_val(&count.to_be_bytes(), b"val").is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn test_small_slots() {
+        assert_eq!(BlockSmall::SLOT, 8);
+        assert_eq!(BlockSmall::HEAD, HEAD);
+
+        let full = fill(&mut Block::create(42, 256));
+        let small = fill(&mut BlockSmall::create(42, 256));
+        assert_eq!(full, 8); // (256 - 16) / (16 + 8 + 3)
+        assert_eq!(small, 12); // (256 - 16) / (8 + 8 + 3)
+
+        let mut page = BlockSmall::create(42, 256);
+        page.put_val(b"b", b"22").unwrap();
+        page.put_compressed(b"c", b"333").unwrap();
+        page.put_ref(b"d", u16::MAX as u32 - 1).unwrap();
+        page.put_val(b"a", b"1").unwrap();
+        assert_eq!(
+            page.copy(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec(), 0),
+                (b"b".to_vec(), b"22".to_vec(), 0),
+                (b"c".to_vec(), b"333".to_vec(), 0),
+                (b"d".to_vec(), vec![], u16::MAX as u32 - 1),
+            ]
+        );
+        assert!(page.is_compressed(2));
+        assert!(!page.is_compressed(1));
+        assert!(page.slot(2).unwrap().is_val());
+        assert!(page.slot(3).unwrap().is_ref());
+
+        page.remove(0);
+        assert_eq!(page.key(0), b"b");
+        assert!(page.is_compressed(1));
+        assert_eq!(page.slot(2).unwrap().page, u16::MAX as u32 - 1);
+    }
+
+    #[test]
+    fn test_val_mut() {
+        let mut page = Block::create(42, 256);
+        page.put_val(b"a", b"123").unwrap();
+        page.put_val(b"b", b"456").unwrap();
+        page.val_mut(0).copy_from_slice(b"abc");
+        assert_eq!(page.val(0), b"abc");
+        assert_eq!(page.val(1), b"456");
+        assert_eq!(page.key(1), b"b");
+        assert!(page.val_mut(2).is_empty());
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let mut page = Block::create(42, 256);
+        assert!(page.is_valid());
+        page.put_val(b"a", b"123").unwrap();
+        page.put_val(b"b", b"456").unwrap();
+        assert!(page.is_valid());
+
+        let mut copy = Block::reserve(256);
+        copy.as_mut().copy_from_slice(page.as_ref());
+        put_u32::<false>(&mut copy.buf, SIZE_OFFSET, 1000);
+        assert!(!copy.is_valid());
+
+        copy.as_mut().copy_from_slice(page.as_ref());
+        // key length of the second slot reaches past the page end
+        put_u32::<false>(&mut copy.buf, Block::HEAD + Block::SLOT + 4, 250);
+        assert!(!copy.is_valid());
+
+        copy.as_mut().copy_from_slice(page.as_ref());
+        put_u32::<false>(&mut copy.buf, CAP_OFFSET, 128);
+        assert!(!copy.is_valid());
+    }
+
+    #[test]
+    fn test_defragment() {
+        fn check<const LE: bool, const COMPACT: bool, const SMALL: bool, const PREFIX: bool>() {
+            let mut page = GenericBlock::<LE, COMPACT, SMALL, PREFIX>::create(42, 2048);
+            let key = |i: usize| format!("key{:03}", i * 7 % 30).into_bytes();
+            // interleaved inserts and removes leave gaps between the entries
+            for i in 0..20 {
+                page.put_val(&key(i), &[i as u8; 16]).unwrap();
+            }
+            for i in (0..20).step_by(3) {
+                page.remove(page.find(&key(i)).unwrap());
+            }
+            for i in 20..30 {
+                page.put_val(&key(i), &[i as u8; 16]).unwrap();
+            }
+            for i in (1..30).step_by(4) {
+                if let Some(idx) = page.find(&key(i)) {
+                    page.remove(idx);
+                }
+            }
+
+            let entries = page.copy();
+            let free = page.free();
+            // front-coded keys are re-encoded on every change, leaving no gaps
+            assert_eq!(page.fragmentation() > 0, !PREFIX);
+            page.defragment();
+            assert_eq!(page.fragmentation(), 0);
+            assert_eq!(page.free(), free);
+            assert_eq!(page.copy(), entries);
+            assert!(page.is_valid());
+
+            // all free bytes form a single region between the slots and the entries
+            let slots = GenericBlock::<LE, COMPACT, SMALL, PREFIX>::HEAD as u32
+                + page.len() * GenericBlock::<LE, COMPACT, SMALL, PREFIX>::SLOT as u32;
+            assert_eq!(page.lowest() - slots, page.free());
+        }
+
+        check::<false, false, false, false>();
+        check::<true, false, false, false>();
+        check::<false, true, false, false>();
+        check::<false, false, true, false>();
+        check::<false, false, false, true>();
+    }
+
+    #[test]
+    fn test_front_coded_keys() {
+        let keys = (0..100)
+            .map(|i| format!("user/profile/{:06}", i).into_bytes())
+            .collect::<Vec<_>>();
+        fn fill<P: Page>(page: &mut P, keys: &[Vec<u8>]) -> usize {
+            keys.iter()
+                .rev()
+                .take_while(|key| page.put_val(key, b"val").is_some())
+                .count()
+        }
+        let full = fill(&mut Block::create(42, 512), &keys);
+        let prefix = fill(&mut BlockPrefix::create(42, 512), &keys);
+        assert_eq!(full, 13); // (512 - 16) / (16 + 19 + 3)
+        assert_eq!(prefix, 21); // (512 - 16) / (16 + 2 + 1 + 3), the first key is stored whole
+
+        let mut page = BlockPrefix::create(42, 512);
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut shuffled = keys[..16].to_vec();
+        shuffled.shuffle(&mut rng);
+        for key in shuffled.iter() {
+            page.put_val(key, &key[13..]).unwrap();
+        }
+        assert!(page.is_valid());
+        for (i, key) in keys[..16].iter().enumerate() {
+            assert_eq!(page.key(i as u32), key.as_slice());
+            assert_eq!(page.val(i as u32), &key[13..]);
+            assert_eq!(page.find(key), Some(i as u32));
+        }
+        assert_eq!(page.min(), keys[0].as_slice());
+        assert_eq!(page.max(), keys[15].as_slice());
+        assert_eq!(page.ceil(b"user/profile/0000055"), Some(6));
+        assert_eq!(page.find(b"user/profile/"), None);
+
+        page.remove(0);
+        page.remove(9);
+        page.put_compressed(&keys[5], b"zzz").unwrap();
+        page.put_ref(b"user/zzz", 7).unwrap();
+        let copy = page.copy();
+        assert_eq!(copy.len(), 15);
+        assert_eq!(copy[0].0, keys[1]);
+        assert_eq!(copy[4], (keys[5].clone(), b"zzz".to_vec(), 0));
+        assert_eq!(copy[9].0, keys[11]);
+        assert_eq!(copy[14], (b"user/zzz".to_vec(), vec![], 7));
+        assert!(page.is_compressed(4));
+
+        let mut other = BlockPrefix::reserve(512);
+        other.as_mut().copy_from_slice(page.as_ref());
+        assert!(other.is_valid());
+        assert_eq!(other.copy(), copy);
+
+        // shared prefix of the first key reaches past the (absent) previous key
+        let offset = other.slot(0).unwrap().offset as usize;
+        put_u16::<false>(&mut other.buf, offset, 3);
+        assert!(!other.is_valid());
+    }
+
     #[test]
     fn test_large_key() {
         let mut page = Block::create(1, 64);
         let buf = vec![42u8; 256];
-        let opt = page.put_entry(&buf, &buf, 0);
+        let opt = page.put_entry(&buf, &buf, 0, false);
         assert!(opt.is_none());
     }
 }
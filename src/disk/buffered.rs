@@ -0,0 +1,757 @@
+use crate::api::error::{Error, Result};
+use crate::api::{Stats, Store, TxnCtx, TxnOps, UpdateGuard, UpdateOps, ValueRef};
+use crate::util::successor;
+use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Pending changes: the new value of the key, none for a removed key (a tombstone).
+type Overlay = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Store that keeps the writes in memory until `commit`: inserts and removals go into an ordered
+/// overlay of pending changes, reads see the overlay first and the underlying store (e.g. `File`)
+/// below it, so a removed key stays hidden even though the underlying store still has it.
+/// Pending changes are lost if the store is dropped without `commit`.
+pub struct BufferedStore<S: Store> {
+    base: S,
+    overlay: RwLock<Overlay>,
+}
+
+impl<S: Store> BufferedStore<S> {
+    pub fn new(base: S) -> Self {
+        Self {
+            base,
+            overlay: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Number of pending (not committed) changes.
+    pub fn pending(&self) -> usize {
+        self.overlay.read().len()
+    }
+
+    /// Apply the pending changes to the underlying store in ascending key order, as a single
+    /// transaction (see `Store::transaction`): if it fails, nothing is applied and the changes
+    /// stay pending. Returns the number of applied changes.
+    pub fn commit(&self) -> Result<usize> {
+        let mut overlay = self.overlay.write();
+        self.base.transaction(|txn| {
+            for (key, val) in overlay.iter() {
+                match val {
+                    Some(val) => txn.insert(key, val)?,
+                    None => txn.remove(key)?,
+                }
+            }
+            Ok(())
+        })?;
+        let count = overlay.len();
+        overlay.clear();
+        Ok(count)
+    }
+
+    fn check_key(key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            Err(Error::Other("empty key".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn value(&self, overlay: &Overlay, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match overlay.get(key) {
+            Some(val) => Ok(val.clone()),
+            None => self.base.lookup(key),
+        }
+    }
+
+    /// Entry with the smallest key strictly greater than given one (or the first entry).
+    fn next_entry(
+        &self,
+        overlay: &Overlay,
+        key: Option<&[u8]>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut base = match key {
+            Some(key) => self.base.above_entry(key)?,
+            None => self.base.first()?,
+        };
+        // Keys present in the overlay are shadowed: either replaced or removed.
+        while let Some(key) = base
+            .as_ref()
+            .map(|(key, _)| key.clone())
+            .filter(|key| overlay.contains_key(key))
+        {
+            base = self.base.above_entry(&key)?;
+        }
+
+        let lower = key.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+        let over = overlay
+            .range::<[u8], _>((lower, Bound::Unbounded))
+            .find_map(|(key, val)| val.as_ref().map(|val| (key.clone(), val.clone())));
+        Ok(match (base, over) {
+            (Some(base), Some(over)) => Some(if over.0 < base.0 { over } else { base }),
+            (base, over) => base.or(over),
+        })
+    }
+
+    /// Entry with the biggest key strictly lesser than given one (or the last entry).
+    fn prev_entry(
+        &self,
+        overlay: &Overlay,
+        key: Option<&[u8]>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut base = match key {
+            Some(key) => self.base.below_entry(key)?,
+            None => self.base.last()?,
+        };
+        while let Some(key) = base
+            .as_ref()
+            .map(|(key, _)| key.clone())
+            .filter(|key| overlay.contains_key(key))
+        {
+            base = self.base.below_entry(&key)?;
+        }
+
+        let upper = key.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+        let over = overlay
+            .range::<[u8], _>((Bound::Unbounded, upper))
+            .rev()
+            .find_map(|(key, val)| val.as_ref().map(|val| (key.clone(), val.clone())));
+        Ok(match (base, over) {
+            (Some(base), Some(over)) => Some(if over.0 > base.0 { over } else { base }),
+            (base, over) => base.or(over),
+        })
+    }
+
+    /// Same as `Store::scan`, over the overlay merged with the underlying store.
+    fn merged<F>(&self, overlay: &Overlay, from: Option<&[u8]>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let lower = from.map(Bound::Included).unwrap_or(Bound::Unbounded);
+        let mut pending = overlay
+            .range::<[u8], _>((lower, Bound::Unbounded))
+            .peekable();
+        let mut proceed = true;
+        self.base.scan(from, |key, val| {
+            // Overlay entries up to the current key go first, the equal one shadows it.
+            while let Some((k, v)) = pending.peek() {
+                if k.as_slice() > key {
+                    break;
+                }
+                if let Some(v) = v {
+                    if !f(k, v) {
+                        proceed = false;
+                        return false;
+                    }
+                }
+                let shadowed = k.as_slice() == key;
+                pending.next();
+                if shadowed {
+                    return true;
+                }
+            }
+            proceed = f(key, val);
+            proceed
+        })?;
+
+        if proceed {
+            for (key, val) in pending {
+                if let Some(val) = val {
+                    if !f(key, val) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys of the live entries in range `[lo, hi)` (up to the end if `hi` is none).
+    fn keys_in(&self, overlay: &Overlay, lo: &[u8], hi: Option<&[u8]>) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        self.merged(overlay, Some(lo), |key, _| {
+            if hi.is_some_and(|hi| key >= hi) {
+                return false;
+            }
+            keys.push(key.to_vec());
+            true
+        })?;
+        Ok(keys)
+    }
+
+    fn remove_from(&self, lo: &[u8], hi: Option<&[u8]>) -> Result<usize> {
+        let mut overlay = self.overlay.write();
+        let keys = self.keys_in(&overlay, lo, hi)?;
+        let count = keys.len();
+        for key in keys {
+            overlay.insert(key, None);
+        }
+        Ok(count)
+    }
+}
+
+/// Changes staged by `Store::transaction` on top of the overlay, merged into it on success.
+struct Staged<'a, S: Store> {
+    store: &'a BufferedStore<S>,
+    overlay: &'a Overlay,
+    changes: RefCell<Overlay>,
+}
+
+impl<S: Store> TxnOps for Staged<'_, S> {
+    fn txn_lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.changes.borrow().get(key) {
+            Some(val) => Ok(val.clone()),
+            None => self.store.value(self.overlay, key),
+        }
+    }
+
+    fn txn_insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        BufferedStore::<S>::check_key(key)?;
+        self.changes
+            .borrow_mut()
+            .insert(key.to_vec(), Some(val.to_vec()));
+        Ok(())
+    }
+
+    fn txn_remove(&self, key: &[u8]) -> Result<()> {
+        BufferedStore::<S>::check_key(key)?;
+        self.changes.borrow_mut().insert(key.to_vec(), None);
+        Ok(())
+    }
+}
+
+/// The overlay write-locked by `Store::get_for_update` until the guard is dropped.
+struct Locked<'a>(RwLockWriteGuard<'a, Overlay>);
+
+impl UpdateOps for Locked<'_> {
+    fn write(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.0.insert(key.to_vec(), Some(val.to_vec()));
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for BufferedStore<S> {
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Self::check_key(key)?;
+        let overlay = self.overlay.read();
+        self.value(&overlay, key)
+    }
+
+    /// Pending values are borrowed from the overlay, which stays read-locked while the guard
+    /// is alive (so writes to this store from the same thread would deadlock).
+    fn get_ref(&self, key: &[u8]) -> Result<Option<MappedRwLockReadGuard<'_, [u8]>>> {
+        Self::check_key(key)?;
+        let overlay = self.overlay.read();
+        match overlay.get(key) {
+            Some(Some(_)) => Ok(Some(RwLockReadGuard::map(overlay, |overlay| {
+                overlay
+                    .get(key)
+                    .and_then(|val| val.as_deref())
+                    .unwrap_or_default()
+            }))),
+            Some(None) => Ok(None),
+            None => {
+                drop(overlay);
+                self.base.get_ref(key)
+            }
+        }
+    }
+
+    fn value_len(&self, key: &[u8]) -> Result<Option<u32>> {
+        Self::check_key(key)?;
+        let overlay = self.overlay.read();
+        match overlay.get(key) {
+            Some(val) => Ok(val.as_ref().map(|val| val.len() as u32)),
+            None => {
+                drop(overlay);
+                self.base.value_len(key)
+            }
+        }
+    }
+
+    fn lookup_cow(&self, key: &[u8]) -> Result<Option<ValueRef<'_>>> {
+        Self::check_key(key)?;
+        let overlay = self.overlay.read();
+        match overlay.get(key) {
+            Some(Some(_)) => Ok(Some(ValueRef::Borrowed(RwLockReadGuard::map(
+                overlay,
+                |overlay| {
+                    overlay
+                        .get(key)
+                        .and_then(|val| val.as_deref())
+                        .unwrap_or_default()
+                },
+            )))),
+            Some(None) => Ok(None),
+            None => {
+                drop(overlay);
+                self.base.lookup_cow(key)
+            }
+        }
+    }
+
+    fn insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        Self::check_key(key)?;
+        self.overlay
+            .write()
+            .insert(key.to_vec(), Some(val.to_vec()));
+        Ok(())
+    }
+
+    /// The value is copied into the overlay (if it is not pending yet) and modified there.
+    fn with_value_mut<F>(&self, key: &[u8], f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        Self::check_key(key)?;
+        let mut overlay = self.overlay.write();
+        let mut val = match self.value(&overlay, key)? {
+            Some(val) => val,
+            None => return Ok(false),
+        };
+        f(&mut val);
+        overlay.insert(key.to_vec(), Some(val));
+        Ok(true)
+    }
+
+    fn extend<I>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut overlay = self.overlay.write();
+        for (key, val) in iter {
+            Self::check_key(&key)?;
+            overlay.insert(key, Some(val));
+        }
+        Ok(())
+    }
+
+    fn put_if_absent(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        Self::check_key(key)?;
+        let mut overlay = self.overlay.write();
+        if self.value(&overlay, key)?.is_some() {
+            return Ok(false);
+        }
+        overlay.insert(key.to_vec(), Some(val.to_vec()));
+        Ok(true)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        Self::check_key(key)?;
+        self.overlay.write().insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn remove_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        self.remove_from(lo, Some(hi))
+    }
+
+    fn remove_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        self.remove_from(prefix, successor(prefix).as_deref())
+    }
+
+    fn retain<F>(&self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut overlay = self.overlay.write();
+        let mut keys = Vec::new();
+        self.merged(&overlay, None, |key, val| {
+            if !f(key, val) {
+                keys.push(key.to_vec());
+            }
+            true
+        })?;
+        let count = keys.len();
+        for key in keys {
+            overlay.insert(key, None);
+        }
+        Ok(count)
+    }
+
+    fn update<F>(&self, key: &[u8], f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        Self::check_key(key)?;
+        let mut overlay = self.overlay.write();
+        let val = self.value(&overlay, key)?;
+        let val = f(val.as_deref());
+        overlay.insert(key.to_vec(), val);
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        Self::check_key(key)?;
+        let mut overlay = self.overlay.write();
+        if self.value(&overlay, key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        overlay.insert(key.to_vec(), new.map(|val| val.to_vec()));
+        Ok(true)
+    }
+
+    fn get_for_update(&self, key: &[u8]) -> Result<UpdateGuard<'_>> {
+        Self::check_key(key)?;
+        let overlay = self.overlay.write();
+        let val = self.value(&overlay, key)?;
+        Ok(UpdateGuard::new(key, val, Box::new(Locked(overlay))))
+    }
+
+    /// Changes made in the transaction become pending (not committed to the underlying store)
+    /// all together once `f` returns `Ok`, otherwise the pending changes stay as they were.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&TxnCtx<'_>) -> Result<T>,
+    {
+        let mut overlay = self.overlay.write();
+        let staged = Staged {
+            store: self,
+            overlay: &overlay,
+            changes: RefCell::new(BTreeMap::new()),
+        };
+        let result = f(&TxnCtx::new(&staged))?;
+        let changes = staged.changes.into_inner();
+        overlay.extend(changes);
+        Ok(result)
+    }
+
+    fn swap(&self, a: &[u8], b: &[u8]) -> Result<bool> {
+        Self::check_key(a)?;
+        Self::check_key(b)?;
+        let mut overlay = self.overlay.write();
+        let (val_a, val_b) = match (self.value(&overlay, a)?, self.value(&overlay, b)?) {
+            (Some(val_a), Some(val_b)) => (val_a, val_b),
+            _ => return Ok(false),
+        };
+        if a != b {
+            overlay.insert(a.to_vec(), Some(val_b));
+            overlay.insert(b.to_vec(), Some(val_a));
+        }
+        Ok(true)
+    }
+
+    /// Statistics of the underlying store: pending changes are not included.
+    fn stats(&self) -> Result<Stats> {
+        self.base.stats()
+    }
+
+    /// Release memory of the underlying store: pending changes are kept (see `commit`).
+    fn release(&self) -> Result<()> {
+        self.base.release()
+    }
+
+    fn min(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.first()?.map(|(key, _)| key))
+    }
+
+    fn max(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.last()?.map(|(key, _)| key))
+    }
+
+    fn first(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let overlay = self.overlay.read();
+        self.next_entry(&overlay, None)
+    }
+
+    fn last(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let overlay = self.overlay.read();
+        self.prev_entry(&overlay, None)
+    }
+
+    fn above(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.above_entry(key)?.map(|(key, _)| key))
+    }
+
+    fn below(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.below_entry(key)?.map(|(key, _)| key))
+    }
+
+    fn above_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let overlay = self.overlay.read();
+        self.next_entry(&overlay, Some(key))
+    }
+
+    fn below_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let overlay = self.overlay.read();
+        self.prev_entry(&overlay, Some(key))
+    }
+
+    /// The overlay stays read-locked during the scan, so `f` must not write to this store.
+    fn scan<F>(&self, from: Option<&[u8]>, f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let overlay = self.overlay.read();
+        self.merged(&overlay, from, f)
+    }
+
+    /// Estimate of the underlying store, adjusted by the pending inserts and removals in range
+    /// (assuming inserted keys are new and removed ones existed).
+    fn estimate_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        if lo >= hi {
+            return Ok(0);
+        }
+        let overlay = self.overlay.read();
+        let (mut added, mut removed) = (0, 0);
+        for (_, val) in overlay.range::<[u8], _>((Bound::Included(lo), Bound::Excluded(hi))) {
+            match val {
+                Some(_) => added += 1,
+                None => removed += 1,
+            }
+        }
+        let base = self.base.estimate_range(lo, hi)?;
+        Ok((base + added).saturating_sub(removed))
+    }
+
+    fn range_key_bounds(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let overlay = self.overlay.read();
+        let mut keys = self.base.range_key_bounds(lo, hi)?;
+        // Pending inserts are not indexed yet, but pending removals must not be reported.
+        keys.retain(|key| !matches!(overlay.get(key), Some(None)));
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tree::Tree;
+    use crate::disk::block::Block;
+    use crate::disk::file::File;
+    use crate::util;
+
+    fn setup(data: &[(Vec<u8>, Vec<u8>)]) -> File<Block> {
+        let file: File<Block> = File::memory(256);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file
+    }
+
+    fn check(store: &BufferedStore<File<Block>>, model: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        let entries = model
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(store.scan_prefix(&[]).unwrap(), entries);
+        assert_eq!(
+            store.keys().collect::<Vec<_>>(),
+            model.keys().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(store.first().unwrap(), entries.first().cloned());
+        assert_eq!(store.last().unwrap(), entries.last().cloned());
+        assert_eq!(store.count_prefix(&[]).unwrap(), model.len());
+        let bytes = store
+            .fold_range(&[], &[0xFF; 9], 0, |n, key, val| n + key.len() + val.len())
+            .unwrap();
+        assert_eq!(bytes, model.iter().map(|(k, v)| k.len() + v.len()).sum());
+        for key in store.range_key_bounds(&[], &[0xFF; 9]).unwrap() {
+            assert!(model.contains_key(&key));
+        }
+
+        let mut pages = vec![store.scan_from(&[], true, 50).unwrap()];
+        while let Some((last, _)) = pages.last().unwrap().last().cloned() {
+            pages.push(store.scan_from(&last, false, 50).unwrap());
+        }
+        assert_eq!(pages.concat(), entries);
+
+        for (key, _) in entries.iter().step_by(7) {
+            let above = model
+                .range::<[u8], _>((Bound::Excluded(key.as_slice()), Bound::Unbounded))
+                .next()
+                .map(|(k, v)| (k.clone(), v.clone()));
+            assert_eq!(store.above_entry(key).unwrap(), above);
+            let below = model
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(key.as_slice())))
+                .next_back()
+                .map(|(k, v)| (k.clone(), v.clone()));
+            assert_eq!(store.below_entry(key).unwrap(), below);
+        }
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        let data = util::data(300, 42);
+        let (old, new) = data.split_at(200);
+        let file = setup(old);
+        let store = BufferedStore::new(file.clone());
+        let mut model = old.iter().cloned().collect::<BTreeMap<_, _>>();
+        for (k, v) in new {
+            store.insert(k, v).unwrap();
+            model.insert(k.clone(), v.clone());
+        }
+
+        let (key, val) = &new[0];
+        assert_eq!(file.lookup(key).unwrap(), None);
+        assert_eq!(store.lookup(key).unwrap().as_ref(), Some(val));
+        assert_eq!(store.get_ref(key).unwrap().as_deref(), Some(val.as_slice()));
+        assert_eq!(
+            store.lookup_cow(key).unwrap().unwrap().as_ref(),
+            val.as_slice()
+        );
+        assert_eq!(store.value_len(key).unwrap(), Some(val.len() as u32));
+
+        // an overwritten base value is shadowed by the pending one
+        let (key, _) = &old[0];
+        store.insert(key, b"pending").unwrap();
+        model.insert(key.clone(), b"pending".to_vec());
+        assert_eq!(store.lookup(key).unwrap(), Some(b"pending".to_vec()));
+        assert!(store.with_value_mut(key, |val| val[0] = b'P').unwrap());
+        model.insert(key.clone(), b"Pending".to_vec());
+        assert_ne!(file.lookup(key).unwrap(), Some(b"Pending".to_vec()));
+
+        assert_eq!(store.pending(), new.len() + 1);
+        check(&store, &model);
+    }
+
+    #[test]
+    fn test_delete_shadowing() {
+        let data = util::data(300, 42);
+        let file = setup(&data);
+        let store = BufferedStore::new(file.clone());
+        let mut model = data.iter().cloned().collect::<BTreeMap<_, _>>();
+        for (k, _) in data.iter().step_by(3) {
+            store.remove(k).unwrap();
+            model.remove(k);
+        }
+
+        let (key, val) = &data[0];
+        assert_eq!(store.lookup(key).unwrap(), None);
+        assert!(store.get_ref(key).unwrap().is_none());
+        assert_eq!(store.value_len(key).unwrap(), None);
+        assert_eq!(file.lookup(key).unwrap().as_ref(), Some(val));
+        assert!(!store.with_value_mut(key, |_| ()).unwrap());
+        assert!(!store.swap(key, &data[1].0).unwrap());
+        assert!(store.put_if_absent(key, b"back").unwrap());
+        model.insert(key.clone(), b"back".to_vec());
+        check(&store, &model);
+
+        let (lo, hi) = (&data[10].0, &data[20].0);
+        let (lo, hi) = if lo < hi { (lo, hi) } else { (hi, lo) };
+        let expected = model
+            .range::<[u8], _>((
+                Bound::Included(lo.as_slice()),
+                Bound::Excluded(hi.as_slice()),
+            ))
+            .count();
+        assert_eq!(store.count_range(lo, hi).unwrap(), expected);
+        assert_eq!(store.remove_range(lo, hi).unwrap(), expected);
+        model.retain(|k, _| k < lo || k >= hi);
+        assert_eq!(store.count_range(lo, hi).unwrap(), 0);
+        check(&store, &model);
+
+        let prefix = &data[30].0[..1];
+        let expected = model.keys().filter(|k| k.starts_with(prefix)).count();
+        assert_eq!(store.remove_prefix(prefix).unwrap(), expected);
+        model.retain(|k, _| !k.starts_with(prefix));
+        assert_eq!(store.count_prefix(prefix).unwrap(), 0);
+        check(&store, &model);
+
+        // removing everything hides the whole underlying store
+        assert_eq!(store.retain(|_, _| false).unwrap(), model.len());
+        assert!(store.is_empty());
+        assert!(!file.is_empty());
+        check(&store, &BTreeMap::new());
+    }
+
+    #[test]
+    fn test_commit() {
+        let data = util::data(300, 42);
+        let file = setup(&data);
+        let store = BufferedStore::new(file.clone());
+        let mut model = data.iter().cloned().collect::<BTreeMap<_, _>>();
+        for (i, (k, v)) in util::data(600, 7).into_iter().enumerate() {
+            if i % 3 == 0 {
+                store.remove(&data[i / 2].0).unwrap();
+                model.remove(&data[i / 2].0);
+            } else {
+                store.insert(&k, &v).unwrap();
+                model.insert(k, v);
+            }
+        }
+        check(&store, &model);
+
+        let pending = store.pending();
+        assert_eq!(store.commit().unwrap(), pending);
+        assert_eq!(store.pending(), 0);
+        file.verify().unwrap();
+        check(&store, &model);
+        let entries = model.into_iter().collect::<Vec<_>>();
+        assert_eq!(file.scan_prefix(&[]).unwrap(), entries);
+        assert_eq!(store.commit().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let data = util::data(100, 42);
+        let file = setup(&data);
+        let store = BufferedStore::new(file);
+        let (key, val) = &data[0];
+
+        let result = store.transaction::<_, ()>(|txn| {
+            txn.remove(key)?;
+            assert_eq!(txn.lookup(key)?, None);
+            txn.insert(b"new", b"value")?;
+            Err(Error::Other("abort".to_string()))
+        });
+        assert!(matches!(result, Err(Error::Other(e)) if e == "abort"));
+        assert_eq!(store.pending(), 0);
+        assert_eq!(store.lookup(key).unwrap().as_ref(), Some(val));
+
+        let found = store
+            .transaction(|txn| {
+                txn.remove(key)?;
+                txn.insert(b"new", b"value")?;
+                txn.lookup(b"new")
+            })
+            .unwrap();
+        assert_eq!(found, Some(b"value".to_vec()));
+        assert_eq!(store.pending(), 2);
+        assert_eq!(store.lookup(key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_for_update() {
+        let data = util::data(100, 42);
+        let file = setup(&data);
+        let store = BufferedStore::new(file.clone());
+        let (key, val) = &data[0];
+
+        let guard = store.get_for_update(key).unwrap();
+        assert_eq!(guard.value(), Some(val.as_slice()));
+        guard.set(b"new").unwrap();
+        assert_eq!(store.lookup(key).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(file.lookup(key).unwrap().as_ref(), Some(val));
+        assert_eq!(store.pending(), 1);
+    }
+
+    #[test]
+    fn test_first_gap() {
+        let id = |n: u64| (n.to_be_bytes().to_vec(), b"used".to_vec());
+        let file = setup(&(0..10).map(id).collect::<Vec<_>>());
+        let store = BufferedStore::new(file);
+        assert_eq!(
+            store.first_gap(&id(0).0, util::next_u64).unwrap(),
+            Some(id(10).0)
+        );
+
+        // pending removal opens a gap, pending insert fills one
+        store.remove(&id(4).0).unwrap();
+        store.insert(&id(10).0, b"used").unwrap();
+        assert_eq!(
+            store.first_gap(&id(0).0, util::next_u64).unwrap(),
+            Some(id(4).0)
+        );
+        assert_eq!(
+            store.first_gap(&id(5).0, util::next_u64).unwrap(),
+            Some(id(11).0)
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use parking_lot::RwLockWriteGuard;
+
+use crate::api::error::Result;
+use crate::api::page::Page;
+use crate::disk::file::File;
+
+/// View into a single entry of the `File`, see `File::entry`.
+///
+/// The tree write lock is held until the entry is dropped, so the value cannot change between
+/// the check and the update (any other operation on the same file from the same thread while
+/// the entry is alive would deadlock).
+pub enum Entry<'a, P: Page> {
+    Occupied(OccupiedEntry<'a, P>),
+    Vacant(VacantEntry<'a, P>),
+}
+
+pub struct OccupiedEntry<'a, P: Page> {
+    file: &'a File<P>,
+    _lock: RwLockWriteGuard<'a, ()>,
+    key: Vec<u8>,
+    val: Vec<u8>,
+}
+
+pub struct VacantEntry<'a, P: Page> {
+    file: &'a File<P>,
+    _lock: RwLockWriteGuard<'a, ()>,
+    key: Vec<u8>,
+}
+
+impl<'a, P: Page> Entry<'a, P> {
+    pub(crate) fn new(
+        file: &'a File<P>,
+        lock: RwLockWriteGuard<'a, ()>,
+        key: &[u8],
+        val: Option<Vec<u8>>,
+    ) -> Self {
+        let key = key.to_vec();
+        match val {
+            Some(val) => Entry::Occupied(OccupiedEntry {
+                file,
+                _lock: lock,
+                key,
+                val,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                file,
+                _lock: lock,
+                key,
+            }),
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Entry::Occupied(e) => &e.key,
+            Entry::Vacant(e) => &e.key,
+        }
+    }
+
+    /// Insert the default value if the entry is vacant, returns the current value.
+    pub fn or_insert(self, default: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Entry::Occupied(e) => Ok(e.val),
+            Entry::Vacant(e) => {
+                e.insert(default)?;
+                Ok(default.to_vec())
+            }
+        }
+    }
+
+    /// Same as `or_insert`, but the default value is only computed if the entry is vacant.
+    pub fn or_insert_with<F: FnOnce() -> Vec<u8>>(self, f: F) -> Result<Vec<u8>> {
+        match self {
+            Entry::Occupied(e) => Ok(e.val),
+            Entry::Vacant(e) => {
+                let val = f();
+                e.insert(&val)?;
+                Ok(val)
+            }
+        }
+    }
+
+    /// Modify and store the value if the entry is occupied.
+    pub fn and_modify<F: FnOnce(&mut Vec<u8>)>(self, f: F) -> Result<Self> {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(&mut e.val);
+                e.file.put(&e.key, &e.val)?;
+                Ok(Entry::Occupied(e))
+            }
+            Entry::Vacant(e) => Ok(Entry::Vacant(e)),
+        }
+    }
+}
+
+impl<P: Page> OccupiedEntry<'_, P> {
+    pub fn get(&self) -> &[u8] {
+        &self.val
+    }
+
+    /// Replace the value, returns the old one.
+    pub fn insert(&mut self, val: &[u8]) -> Result<Vec<u8>> {
+        self.file.put(&self.key, val)?;
+        Ok(std::mem::replace(&mut self.val, val.to_vec()))
+    }
+
+    /// Remove the entry, returns its value.
+    pub fn remove(self) -> Result<Vec<u8>> {
+        self.file.del(&self.key)?;
+        Ok(self.val)
+    }
+}
+
+impl<P: Page> VacantEntry<'_, P> {
+    pub fn insert(self, val: &[u8]) -> Result<()> {
+        self.file.put(&self.key, val)
+    }
+}
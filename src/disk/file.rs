@@ -1,1381 +1,8546 @@
 use crate::api::error::{Error, Result};
 use crate::api::page::Page;
 use crate::api::tree::Tree;
-use crate::api::Store;
+use crate::api::{Stats, Store, TxnCtx, TxnOps, UpdateGuard, UpdateOps, ValueRef};
+use crate::disk::entry::Entry;
+use crate::disk::oplog::{Op, OpLog};
+use crate::disk::storage::Storage;
+use crate::disk::wal::Wal;
 use crate::util::cache::{Cache, LruCache};
-use crate::util::hex::hex;
+use crate::util::hex::{hex, unhex};
+use crate::util::{compress, json, metrics, successor};
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, trace};
-use std::cmp::Reverse;
-use std::collections::{BinaryHeap, HashSet};
+use std::borrow::Cow;
+use std::cmp::{self, Reverse};
+use std::collections::{hash_map, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
-use std::ops::Deref;
-use std::path::Path;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use parking_lot::{
-    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    Condvar, MappedRwLockReadGuard, MappedRwLockWriteGuard, Mutex, RwLock, RwLockReadGuard,
+    RwLockWriteGuard,
 };
 
+/// Outcome of `File::repair`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepairReport {
+    /// Number of pages scanned (all pages but the free-list ones).
+    pub pages: u32,
+    /// Number of damaged pages (not well-formed, or with a wrong page id) that were skipped.
+    pub damaged: u32,
+    /// Number of leaf pages the entries were collected from.
+    pub leaves: u32,
+    /// Number of entries written to the repaired file.
+    pub recovered: u64,
+    /// Number of entries dropped: with empty or repeated keys, or values failing to decompress.
+    pub dropped: u64,
+}
+
+/// Handle of a table (a separate key space in the same file), see `File::open_table`.
+pub type TableHandle<P> = File<P>;
+
+/// Page cache with pluggable eviction policy, see `File::with_cache`.
+pub type PageCache<P> = Box<dyn Cache<u32, P> + Send + Sync>;
+
 pub struct File<P: Page> {
-    /// Underlying file reference where all data is physically stored.
-    file: Arc<RwLock<fs::File>>,
+    /// Underlying file (or in-memory image) where all data is physically stored.
+    file: Arc<RwLock<Box<dyn Storage>>>,
     head: Head,
 
+    /// Tree-level lock: mutations are exclusive, while lookups and traversals are shared.
+    lock: Arc<RwLock<()>>,
+
     /// In-memory page cache. All page access happens only through cached page representation.
-    cache: Arc<RwLock<LruCache<u32, P>>>,
+    cache: Arc<RwLock<PageCache<P>>>,
     dirty: Arc<RwLock<HashSet<u32>>>,
+    /// Dirty pages evicted from the cache while the WAL is enabled: such pages must not be
+    /// written in place before they are committed to the WAL, so they are kept until `flush`.
+    spill: Arc<RwLock<HashMap<u32, P>>>,
 
     /// Min-heap of available page identifiers (this helps avoid "gaps": empty pages inside file).
     empty: Arc<RwLock<BinaryHeap<Reverse<u32>>>>,
+    /// Persisted copy of `empty`, see `save_free_list`.
+    free_list: Arc<RwLock<FreeList>>,
+    /// Deferred scan for empty pages (see `open_fast`): the next page id to scan and the last one,
+    /// none if there is nothing left to scan.
+    scan: Arc<RwLock<Option<(u32, u32)>>>,
+    /// Cached ids of the leftmost and rightmost leaf pages for `min` and `max` (zero if unknown),
+    /// reset when a split or a merge touches a cached page.
+    edges: Arc<RwLock<(u32, u32)>>,
+
+    /// Root page of the tree behind this handle: `ROOT` for the file itself, otherwise the root
+    /// page of a table (see `open_table`).
+    root: u32,
+    /// Page holding the table directory (zero if there is none yet), see `open_table`.
+    tables: Arc<AtomicU32>,
+
+    path: PathBuf,
+
+    /// Optional write-ahead log, every flush goes through it when enabled.
+    wal: Option<Arc<RwLock<Wal>>>,
+    /// Optional log of logical mutations (for replication), see `with_oplog`.
+    oplog: Option<Arc<RwLock<OpLog>>>,
+    /// Rollback journal of the running transaction (if any), see `Store::transaction`.
+    txn: Arc<RwLock<Option<Journal>>>,
+    /// Background flusher (if any), stopped once the last handle sharing it is dropped,
+    /// see `with_flusher`.
+    flusher: Option<Arc<Flusher>>,
+
+    durability: Durability,
+
+    /// Opened with `open_readonly`: all mutations fail, the OS file is not writable.
+    readonly: bool,
+
+    /// Page-level work counters (always on), see `last_op_pages`.
+    counters: Arc<Counters>,
+    last_op: Arc<RwLock<OpPages>>,
 }
 
-const MAGIC: &[u8] = b"YAKVDB42";
+/// Cloning the `File` is cheap: the clone is another handle to the same file (sharing the
+/// page cache, locks and free pages), so it can be moved to other threads.
+impl<P: Page> Clone for File<P> {
+    fn clone(&self) -> Self {
+        Self {
+            file: self.file.clone(),
+            head: self.head.clone(),
+            lock: self.lock.clone(),
+            cache: self.cache.clone(),
+            dirty: self.dirty.clone(),
+            spill: self.spill.clone(),
+            empty: self.empty.clone(),
+            free_list: self.free_list.clone(),
+            scan: self.scan.clone(),
+            edges: self.edges.clone(),
+            root: self.root,
+            tables: self.tables.clone(),
+            path: self.path.clone(),
+            wal: self.wal.clone(),
+            oplog: self.oplog.clone(),
+            txn: self.txn.clone(),
+            flusher: self.flusher.clone(),
+            durability: self.durability,
+            readonly: self.readonly,
+            counters: self.counters.clone(),
+            last_op: self.last_op.clone(),
+        }
+    }
+}
+
+/// Background thread flushing the dirty pages periodically, see `File::with_flusher`.
+struct Flusher {
+    /// Stop flag (and the condition to wake the thread up when it is set).
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn spawn<P: Page>(file: File<P>, interval: Duration) -> io::Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stop.clone();
+        let thread = thread::Builder::new()
+            .name("yakvdb-flusher".to_string())
+            .spawn(move || {
+                let (stopped, wake) = &*signal;
+                loop {
+                    let done = {
+                        let mut stopped = stopped.lock();
+                        if !*stopped {
+                            wake.wait_for(&mut stopped, interval);
+                        }
+                        *stopped
+                    };
+                    if !file.dirty.read().is_empty() {
+                        let _lock = file.lock.write();
+                        if let Err(e) = file.flush() {
+                            error!("Background flush failed: {}", e);
+                        }
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock() = true;
+        wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Background flusher panicked");
+            }
+        }
+    }
+}
+
+/// Ascending iterator over the keys, the values or the entries (see `Store::keys` and
+/// `Store::values`), reading one leaf page at a time. No lock is held between leaves: the next
+/// leaf is found from the successor of the previous leaf's max key. Stops (with an error
+/// logged) at the first failure, use `try_next` to get the error instead.
+struct Column<'a, P: Page, T> {
+    file: &'a File<P>,
+    vals: bool,
+    pick: fn(&[u8], &[u8]) -> T,
+    buf: VecDeque<T>,
+    next: Option<Vec<u8>>,
+    started: bool,
+}
+
+impl<'a, P: Page> Column<'a, P, Vec<u8>> {
+    fn keys(file: &'a File<P>) -> Self {
+        Column::new(file, false, |key, _| key.to_vec())
+    }
+
+    fn values(file: &'a File<P>) -> Self {
+        Column::new(file, true, |_, val| val.to_vec())
+    }
+}
+
+impl<'a, P: Page> Column<'a, P, (Vec<u8>, Vec<u8>)> {
+    fn entries(file: &'a File<P>) -> Self {
+        Column::new(file, true, |key, val| (key.to_vec(), val.to_vec()))
+    }
+}
+
+impl<'a, P: Page, T> Column<'a, P, T> {
+    fn new(file: &'a File<P>, vals: bool, pick: fn(&[u8], &[u8]) -> T) -> Self {
+        Self {
+            file,
+            vals,
+            pick,
+            buf: VecDeque::new(),
+            next: None,
+            started: false,
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(item) = self.buf.pop_front() {
+                return Ok(Some(item));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Buffer the leaf holding the ceil of the next key, returns false if there is none.
+    fn fill(&mut self) -> Result<bool> {
+        let key = if self.started {
+            self.next.take()
+        } else {
+            self.started = true;
+            self.file.min()?
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        let (pick, buf) = (self.pick, &mut self.buf);
+        let last = self.file.scan_leaf(&key, self.vals, &mut |key, val| {
+            buf.push_back(pick(key, val));
+            true
+        })?;
+        self.next = match last {
+            Some(last) => self.file.above(&last)?,
+            None => None,
+        };
+        Ok(true)
+    }
+}
+
+impl<P: Page, T> Iterator for Column<'_, P, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Ok(item) => item,
+            Err(e) => {
+                error!("column: {}", e);
+                self.next = None;
+                None
+            }
+        }
+    }
+}
+
+/// State of the bulk-load (see `File::rebuild`): the page being filled and the (max key, page id)
+/// references to the saved pages of the current level. Page ids are assigned sequentially.
+struct Loader<P> {
+    page: P,
+    level: Vec<(Vec<u8>, u32)>,
+}
+
+#[derive(Default)]
+struct FreeList {
+    /// Chain of free-list pages.
+    pages: Vec<u32>,
+    /// Persisted free page id -> free-list page that keeps it.
+    saved: HashMap<u32, u32>,
+    /// Page ids released (`true`) or taken (`false`) since the last save.
+    changes: Vec<(u32, bool)>,
+}
 
-const HEAD: usize = MAGIC.len() + size_of::<Head>();
+/// State to roll back a transaction to (see `Store::transaction`).
+struct Journal {
+    /// Images of the pages before the transaction modified them.
+    pages: HashMap<u32, Vec<u8>>,
+    /// File length, pages appended by the transaction are cut off on rollback.
+    len: u64,
+    empty: BinaryHeap<Reverse<u32>>,
+    /// Number of pending free-list changes.
+    changes: usize,
+    /// Pages saved so far (see `Counters`): any page saved within the transaction (evicted
+    /// from the cache) means the file must be rewritten on rollback.
+    saved: u64,
+    /// Oplog records, appended only once the transaction is committed.
+    ops: Vec<Op>,
+}
+
+/// Page-level work done by a mutation, see `File::last_op_pages`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct OpPages {
+    /// Pages loaded from the disk (page cache misses).
+    pub loaded: u64,
+    /// Pages written to the disk (on flush or cache eviction).
+    pub saved: u64,
+    /// Pages allocated (reused empty ones or appended to the file).
+    pub allocated: u64,
+    /// Pages split.
+    pub split: u64,
+    /// Pages merged into a peer page.
+    pub merged: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    loaded: AtomicU64,
+    saved: AtomicU64,
+    allocated: AtomicU64,
+    split: AtomicU64,
+    merged: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> OpPages {
+        OpPages {
+            loaded: self.loaded.load(Ordering::Relaxed),
+            saved: self.saved.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            split: self.split.load(Ordering::Relaxed),
+            merged: self.merged.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Defines what happens to the dirty pages after each mutation (`insert`/`remove`).
+/// Stronger guarantees cost throughput: `Sync` waits for the disk on every mutation
+/// and is typically orders of magnitude slower than `Flush`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Durability {
+    /// Keep dirty pages in memory until explicit `flush`/`sync` (or until evicted from cache).
+    /// Fastest, but acknowledged writes are lost if the process crashes.
+    None,
+    /// Write dirty pages to the file (default). Survives a process crash, but not an OS crash.
+    Flush,
+    /// Write dirty pages and `sync_data` the file. Survives an OS crash or a power loss.
+    Sync,
+    /// Keep dirty pages in memory until there are at least given number of them (or until
+    /// explicit `flush`/`sync`), then write them all at once. Pages rewritten by many mutations
+    /// are written only once per batch, but up to a batch of acknowledged writes can be lost
+    /// if the process crashes. The remaining dirty pages are written when the file is dropped.
+    Batch(usize),
+}
+
+/// Value compression mode, chosen when the file is made and recorded in the file header.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Compression {
+    /// Values are stored as is.
+    None,
+    /// Values are compressed with zstd (requires `zstd` feature). Tiny or incompressible
+    /// values are still stored as is, each slot keeps a flag if its value is compressed.
+    Zstd,
+}
+
+/// One-line summary of the store: page size, page count, entry count, tree height and cache
+/// usage (visits every live page). Only try-locks are taken and pages are never loaded into the
+/// cache, so it is safe to format the file while holding a page guard (e.g. from `get_ref`):
+/// if the tree or the cache is locked for writing, the summary says it is busy instead.
+impl<P: Page> fmt::Debug for File<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = match self.lock.try_read() {
+            Some(_lock) => self.summary(),
+            None => None,
+        };
+        let (page_count, entry_count, height) = match summary {
+            Some(summary) => summary,
+            None => return write!(f, "File {{ path: {:?}, busy }}", self.path),
+        };
+        let (cached, capacity) = self
+            .cache
+            .try_read()
+            .map(|cache| (cache.len(), cache.capacity()))
+            .unwrap_or_default();
+        let dirty = self.dirty.try_read().map(|d| d.len()).unwrap_or_default();
+        write!(
+            f,
+            "File {{ path: {:?}, page_bytes: {}, page_count: {}, entry_count: {}, height: {}, cache: {}/{}, dirty: {} }}",
+            self.path, self.head.page_bytes, page_count, entry_count, height, cached, capacity, dirty
+        )
+    }
+}
+
+/// File magic, the same for all format versions: the header version tells formats apart.
+const MAGIC: &[u8] = b"YAKVDB42";
+const MAGIC_LE: &[u8] = b"YAKVDBLE";
+
+/// Binary snapshot header: magic, page_bytes (u32), flags (u32), entry count (u64).
+const SNAPSHOT_MAGIC: &[u8] = b"YAKVSNAP";
+const SNAPSHOT_HEAD: usize = 24;
+
+/// Current file format version, files with a newer version are rejected on open.
+const VERSION: u16 = 1;
+
+/// Largest supported page size: any value stored in a page is then shorter than `2^31` bytes,
+/// so its length never reaches the bit that marks a compressed value (see `Slot`). Pages with
+/// `u16` slot fields are limited to `u16::MAX` bytes (see `BlockSmall`).
+const MAX_PAGE_BYTES: u32 = 1 << 31;
+
+const FLAG_ZSTD: u32 = 1;
+const FLAG_COMPACT: u32 = 2;
+/// Free page ids are persisted in the free-list pages (files without it are scanned on open).
+const FLAG_FREE_LIST: u32 = 4;
+/// Page slots with `u16` fields (see `BlockSmall`).
+const FLAG_SMALL_SLOTS: u32 = 8;
+/// Front-coded keys in pages (see `BlockPrefix`).
+const FLAG_PREFIX_KEYS: u32 = 16;
+
+/// File header: magic, version (u16), zero (u16), page_bytes, page_count, flags, free_page,
+/// tables (u32 each), the rest is zero-filled and reserved for future fields (thresholds, etc).
+const HEAD: usize = 64;
+/// Header of files written before the format version was introduced: magic, page_bytes and
+/// page_count (u32 each), read as version 0 with default flags (see `read_head`).
+const LEGACY_HEAD: usize = 16;
+const FLAGS_OFFSET: u64 = 20;
+const TABLES_OFFSET: u64 = 28;
 const ROOT: u32 = 1;
 
+/// Number of pages read at once while scanning the file for empty pages, see `scan_pages`.
+const SCAN_BATCH: u32 = 64;
+
+/// Page gets split once it is filled over this percent of usable bytes, see `fill`.
 const SPLIT_THRESHOLD: u8 = 80;
+/// Page gets merged with a peer once it is filled under this percent of usable bytes.
 const MERGE_THRESHOLD: u8 = 20;
 
-#[derive(Debug)]
-#[repr(C)]
+#[derive(Debug, Clone)]
 struct Head {
+    version: u16,
     page_bytes: u32,
     page_count: u32,
+    flags: u32,
+    /// First page of the free-list chain (zero if there are no free pages).
+    free_page: u32,
+    /// Table directory page (zero if there are no tables).
+    tables: u32,
+}
+
+impl Head {
+    /// Length of the header in the file: version 0 files have the legacy header.
+    fn size(&self) -> usize {
+        if self.version == 0 {
+            LEGACY_HEAD
+        } else {
+            HEAD
+        }
+    }
+
+    /// Byte offset of the page in the file (page ids start at 1). Computed in `u64`, so it does
+    /// not overflow for any page id and page size (even where `usize` is 32 bits wide).
+    fn offset(&self, id: u32) -> io::Result<u64> {
+        if id == 0 {
+            return Err(io::Error::other("Invalid page id: 0"));
+        }
+        Ok(self.size() as u64 + (id as u64 - 1) * self.page_bytes as u64)
+    }
 }
 
 impl<P: Page> File<P> {
     pub fn make(path: &Path, page_bytes: u32) -> io::Result<Self> {
-        if path.exists() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("File exists: {:?}", path),
+        Self::make_with_compression(path, page_bytes, Compression::None)
+    }
+
+    pub fn make_with_compression(
+        path: &Path,
+        page_bytes: u32,
+        compression: Compression,
+    ) -> io::Result<Self> {
+        if compression == Compression::Zstd && !cfg!(feature = "zstd") {
+            return Err(io::Error::other(
+                "Compression is not supported: enable 'zstd' feature",
             ));
         }
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(path)?;
+        if path.exists() {
+            return Err(io::Error::other(format!("File exists: {:?}", path)));
+        }
+
+        Self::check_page_bytes(page_bytes)?;
+        let head = Self::new_head(page_bytes, compression);
+        create_file(path, Self::image(&head).as_ref(), |file, buf| {
+            file.write_all(buf)?;
+            file.sync_data()
+        })?;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::with_storage(Box::new(file), head, path))
+    }
+
+    /// Same as `make`, but the file is extended up front to hold `preallocate_pages` more pages,
+    /// all of them empty and available for reuse, so the file does not grow page by page
+    /// until the preallocated pages are used up. The free pages are persisted (see `open`).
+    pub fn make_with_capacity(
+        path: &Path,
+        page_bytes: u32,
+        preallocate_pages: u32,
+    ) -> io::Result<Self> {
+        let first = ROOT + 1;
+        let (last, bytes) = first
+            .checked_add(preallocate_pages)
+            .zip((preallocate_pages as usize).checked_mul(page_bytes as usize))
+            .ok_or_else(|| {
+                io::Error::other(format!(
+                    "Too many pages to preallocate: {} (page size {})",
+                    preallocate_pages, page_bytes
+                ))
+            })?;
+        let this = Self::make(path, page_bytes)?;
+        let mut buf = BytesMut::with_capacity(bytes);
+        for id in first..last {
+            buf.put_slice(P::create(id, page_bytes).as_ref());
+        }
+        this.file.write().append(&buf)?;
+        for id in first..last {
+            this.free_id(id);
+        }
+        this.flush_sync()
+            .map_err(|e| io::Error::other(format!("Failed to save free list: {}", e)))?;
+        Ok(this)
+    }
+
+    /// In-memory store (see `MemFile`): the same tree logic, but the file image is kept
+    /// in memory instead of the disk, so nothing is persisted (and WAL is not supported).
+    #[cfg(any(test, feature = "mem"))]
+    pub fn memory(page_bytes: u32) -> Self {
+        let head = Self::new_head(page_bytes, Compression::None);
+        let image = Self::image(&head).to_vec();
+        Self::with_storage(Box::new(image), head, Path::new(""))
+    }
 
-        let head = Head {
+    fn new_head(page_bytes: u32, compression: Compression) -> Head {
+        Head {
+            version: VERSION,
             page_bytes,
             page_count: 1,
-        };
+            flags: match compression {
+                Compression::None => 0,
+                Compression::Zstd => FLAG_ZSTD,
+            } | if P::COMPACT_HEADER { FLAG_COMPACT } else { 0 }
+                | if P::SMALL_SLOTS { FLAG_SMALL_SLOTS } else { 0 }
+                | if P::PREFIX_KEYS { FLAG_PREFIX_KEYS } else { 0 }
+                | FLAG_FREE_LIST,
+            free_page: 0,
+            tables: 0,
+        }
+    }
 
-        let mut buf = BytesMut::with_capacity(HEAD + page_bytes as usize);
-        buf.put_slice(MAGIC);
-        buf.put_u32(head.page_bytes);
-        buf.put_u32(head.page_count);
+    /// Initial file contents: the header and the empty root page.
+    fn image(head: &Head) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(HEAD + head.page_bytes as usize);
+        if P::LITTLE_ENDIAN {
+            buf.put_slice(MAGIC_LE);
+            buf.put_u16_le(head.version);
+            buf.put_u16_le(0);
+            buf.put_u32_le(head.page_bytes);
+            buf.put_u32_le(head.page_count);
+            buf.put_u32_le(head.flags);
+            buf.put_u32_le(head.free_page);
+            buf.put_u32_le(head.tables);
+        } else {
+            buf.put_slice(MAGIC);
+            buf.put_u16(head.version);
+            buf.put_u16(0);
+            buf.put_u32(head.page_bytes);
+            buf.put_u32(head.page_count);
+            buf.put_u32(head.flags);
+            buf.put_u32(head.free_page);
+            buf.put_u32(head.tables);
+        }
+        buf.put_bytes(0, HEAD - buf.len());
 
         let root = P::create(ROOT, head.page_bytes);
         buf.put_slice(root.as_ref());
+        buf
+    }
 
-        file.write_all(buf.as_ref())?;
-        file.flush()?;
-
-        Ok(Self {
-            file: Arc::new(RwLock::new(file)),
+    fn with_storage(storage: Box<dyn Storage>, head: Head, path: &Path) -> Self {
+        let tables = head.tables;
+        Self {
+            file: Arc::new(RwLock::new(storage)),
             head,
-            cache: Arc::new(RwLock::new(LruCache::new(32))),
+            lock: Arc::new(RwLock::new(())),
+            cache: Arc::new(RwLock::new(Box::new(LruCache::new(32)))),
             dirty: Arc::new(RwLock::new(HashSet::with_capacity(32))),
+            spill: Arc::new(RwLock::new(HashMap::new())),
             empty: Arc::new(RwLock::new(BinaryHeap::with_capacity(32))),
-        })
+            free_list: Arc::new(RwLock::new(FreeList::default())),
+            scan: Arc::new(RwLock::new(None)),
+            edges: Arc::new(RwLock::new((0, 0))),
+            root: ROOT,
+            tables: Arc::new(AtomicU32::new(tables)),
+            path: path.to_path_buf(),
+            wal: None,
+            oplog: None,
+            txn: Arc::new(RwLock::new(None)),
+            flusher: None,
+            durability: Durability::Flush,
+            readonly: false,
+            counters: Arc::new(Counters::default()),
+            last_op: Arc::new(RwLock::new(OpPages::default())),
+        }
     }
 
     pub fn open(path: &Path) -> io::Result<Self> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
+        Self::open_with(path, false, false)
+    }
+
+    /// Same as `open`, but a file without persisted free list is not scanned for empty pages
+    /// up front: the scan is deferred, and resumed by `next_id` only when no free page is known,
+    /// until an empty page is found. The free list gets persisted once the scan is complete.
+    /// Files with persisted free list are opened the same way as with `open`.
+    pub fn open_fast(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, false, true)
+    }
+
+    /// Open existing file without write access (so it can be shared with other readers).
+    /// All mutations (and `flush`) return an error. Fails if the WAL needs to be replayed.
+    pub fn open_readonly(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, true, false)
+    }
+
+    fn open_with(path: &Path, readonly: bool, fast: bool) -> io::Result<Self> {
+        let mut file = if readonly {
+            OpenOptions::new().read(true).open(path)?
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(path)?
+        };
 
         let len = file.metadata()?.len() as usize;
-        if len < HEAD {
-            return Err(io::Error::new(io::ErrorKind::Other, "File too short"));
+        if len < LEGACY_HEAD {
+            return Err(io::Error::other("File too short"));
         }
 
-        let mut buf = BytesMut::with_capacity(HEAD);
-        buf.extend_from_slice(&[0u8; HEAD]);
-        file.read_exact(&mut buf[..])?;
+        let head = Self::read_head(&mut file)?;
 
-        let mut magic = [0u8; 8];
-        buf.copy_to_slice(&mut magic);
-        if magic != MAGIC {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("MAGIC mismatch: {:?}", magic),
+        let wal_path = Wal::path(path);
+        if readonly && wal_path.exists() && fs::metadata(&wal_path)?.len() > 0 {
+            return Err(io::Error::other(
+                "WAL must be replayed: open the file for writing first",
             ));
         }
+        if !readonly && wal_path.exists() {
+            let mut wal = Wal::open(&wal_path, head.page_bytes)?;
+            let pages = wal.committed()?;
+            if !pages.is_empty() {
+                debug!("Replaying WAL: {} pages", pages.len());
+                for (id, page) in pages {
+                    file.seek(SeekFrom::Start(head.offset(id)?))?;
+                    file.write_all(&page)?;
+                }
+                file.sync_data()?;
+            }
+            wal.truncate()?;
+        }
 
-        let head = Head {
-            page_bytes: buf.get_u32(),
-            page_count: buf.get_u32(),
-        };
+        Self::open_storage(Box::new(file), head, path, readonly, fast)
+    }
 
-        if head.page_bytes > u16::MAX as u32 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Page size too large: {}", head.page_bytes),
-            ));
+    /// Make an empty tree on top of custom `storage` (that must be empty), e.g. an in-memory
+    /// buffer or a device that is not a regular file. The WAL and the mutation log are not
+    /// supported, as both are kept in files next to the database file.
+    pub fn make_on(mut storage: Box<dyn Storage>, page_bytes: u32) -> io::Result<Self> {
+        if !storage.is_empty()? {
+            return Err(io::Error::other("Storage is not empty"));
+        }
+        Self::check_page_bytes(page_bytes)?;
+        let head = Self::new_head(page_bytes, Compression::None);
+        storage.write_at(Self::image(&head).as_ref(), 0)?;
+        storage.sync_data()?;
+        Ok(Self::with_storage(storage, head, Path::new("")))
+    }
+
+    /// Open the tree kept in custom `storage` (see `make_on`).
+    pub fn open_on(storage: Box<dyn Storage>) -> io::Result<Self> {
+        let len = storage.len()?;
+        if len < LEGACY_HEAD as u64 {
+            return Err(io::Error::other("File too short"));
         }
+        let mut buf = vec![0u8; len.min(HEAD as u64) as usize];
+        storage.read_at(&mut buf, 0)?;
+        let head = Self::read_head(&mut &buf[..])?;
+        Self::open_storage(storage, head, Path::new(""), false, false)
+    }
 
-        if len < HEAD + head.page_bytes as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+    fn open_storage(
+        storage: Box<dyn Storage>,
+        head: Head,
+        path: &Path,
+        readonly: bool,
+        fast: bool,
+    ) -> io::Result<Self> {
+        let len = storage.len()? as usize;
+        let head_bytes = head.size();
+        if len < head_bytes + head.page_bytes as usize {
+            return Err(io::Error::other(
                 "File does not contain one full page".to_string(),
             ));
         }
 
         let mut root = P::reserve(head.page_bytes);
-        file.read_exact(root.as_mut())?;
-
-        let this = Self {
-            file: Arc::new(RwLock::new(file)),
-            head,
-            cache: Arc::new(RwLock::new(LruCache::new(32))),
-            dirty: Arc::new(RwLock::new(HashSet::with_capacity(32))),
-            empty: Arc::new(RwLock::new(BinaryHeap::with_capacity(16))),
-        };
+        storage.read_at(root.as_mut(), head_bytes as u64)?;
 
+        let mut this = Self::with_storage(storage, head, path);
+        this.readonly = readonly;
         this.cache.write().put(ROOT, root);
 
-        let total_pages = (len - HEAD) as u32 / this.head.page_bytes;
+        let total_pages = (len - head_bytes) as u32 / this.head.page_bytes;
+        if this.head.flags & FLAG_FREE_LIST != 0 {
+            this.load_free_list(total_pages)?;
+            return Ok(this);
+        }
+
+        // Files without persisted free list: scan all pages once, then persist the list.
+        if fast {
+            debug!("Deferring scan for empty pages: {}", total_pages);
+            *this.scan.write() = Some((ROOT + 1, total_pages));
+            return Ok(this);
+        }
         debug!("Processing pages for compaction: {}", total_pages);
         if this.head.page_count < total_pages {
-            for id in 2..=total_pages {
-                // skipping the root page (id=1)
-                if let Ok(page) = this.load(this.offset(id), this.head.page_bytes) {
-                    if page.len() == 0 {
-                        debug!("Page id={} is empty", id);
-                        this.empty.write().push(Reverse(id));
+            this.scan_pages(ROOT + 1, total_pages, false)?;
+        }
+        if !readonly {
+            this.save_head(0)?;
+            this.flush()
+                .map_err(|e| io::Error::other(format!("Failed to save free list: {}", e)))?;
+        }
+        Ok(this)
+    }
+
+    /// Read and validate the file header, the file cursor is expected to be at the start.
+    /// Big-endian files written before the format version was introduced have the legacy
+    /// header: their page size is below 64 KiB, so the bytes in place of the version are zero.
+    fn read_head<R: Read>(file: &mut R) -> io::Result<Head> {
+        let mut buf = BytesMut::with_capacity(HEAD);
+        buf.extend_from_slice(&[0u8; LEGACY_HEAD]);
+        file.read_exact(&mut buf[..])?;
+
+        let mut magic = [0u8; 8];
+        buf.copy_to_slice(&mut magic);
+        let expected = if P::LITTLE_ENDIAN { MAGIC_LE } else { MAGIC };
+        if magic != expected {
+            return Err(io::Error::other(format!("MAGIC mismatch: {:?}", magic)));
+        }
+
+        let legacy = !P::LITTLE_ENDIAN && buf[..2] == [0, 0];
+        if !legacy {
+            let mut rest = [0u8; HEAD - LEGACY_HEAD];
+            file.read_exact(&mut rest)?;
+            buf.extend_from_slice(&rest);
+        }
+
+        let head = if legacy {
+            Head {
+                version: 0,
+                page_bytes: buf.get_u32(),
+                page_count: buf.get_u32(),
+                flags: 0,
+                free_page: 0,
+                tables: 0,
+            }
+        } else if P::LITTLE_ENDIAN {
+            let version = buf.get_u16_le();
+            buf.advance(2);
+            Head {
+                version,
+                page_bytes: buf.get_u32_le(),
+                page_count: buf.get_u32_le(),
+                flags: buf.get_u32_le(),
+                free_page: buf.get_u32_le(),
+                tables: buf.get_u32_le(),
+            }
+        } else {
+            let version = buf.get_u16();
+            buf.advance(2);
+            Head {
+                version,
+                page_bytes: buf.get_u32(),
+                page_count: buf.get_u32(),
+                flags: buf.get_u32(),
+                free_page: buf.get_u32(),
+                tables: buf.get_u32(),
+            }
+        };
+
+        if (head.version == 0 && !legacy) || head.version > VERSION {
+            return Err(io::Error::other(format!(
+                "Unsupported format version: {} (supported up to {})",
+                head.version, VERSION
+            )));
+        }
+
+        let known = FLAG_ZSTD | FLAG_COMPACT | FLAG_FREE_LIST | FLAG_SMALL_SLOTS | FLAG_PREFIX_KEYS;
+        if head.flags & !known != 0 {
+            return Err(io::Error::other(format!(
+                "Unsupported flags: {:#x}",
+                head.flags
+            )));
+        }
+
+        if (head.flags & FLAG_COMPACT != 0) != P::COMPACT_HEADER {
+            return Err(io::Error::other("Page header layout mismatch"));
+        }
+
+        if (head.flags & FLAG_SMALL_SLOTS != 0) != P::SMALL_SLOTS {
+            return Err(io::Error::other("Page slot layout mismatch"));
+        }
+
+        if (head.flags & FLAG_PREFIX_KEYS != 0) != P::PREFIX_KEYS {
+            return Err(io::Error::other("Page key layout mismatch"));
+        }
+
+        Self::check_page_bytes(head.page_bytes)?;
+        Ok(head)
+    }
+
+    fn check_page_bytes(page_bytes: u32) -> io::Result<()> {
+        let max = if P::SMALL_SLOTS {
+            u16::MAX as u32
+        } else {
+            MAX_PAGE_BYTES
+        };
+        if page_bytes > max {
+            return Err(io::Error::other(format!(
+                "Page size too large: {} (max {})",
+                page_bytes, max
+            )));
+        }
+        Ok(())
+    }
+
+    /// Find empty pages with ids in range `[from, to]` and make them available for reuse,
+    /// reading `SCAN_BATCH` pages at a time. Pages known to be empty (or in use while empty:
+    /// the table directory and table roots) are skipped, cached pages are checked as cached.
+    /// With `first` set, stops right after the first empty page found.
+    /// Returns the next page id to scan (above `to` once the scan is complete).
+    fn scan_pages(&self, from: u32, to: u32, first: bool) -> io::Result<u32> {
+        let mut skip = self
+            .empty
+            .read()
+            .iter()
+            .map(|id| id.0)
+            .collect::<HashSet<_>>();
+        skip.extend(
+            self.table_pages()
+                .map_err(|e| io::Error::other(e.to_string()))?,
+        );
+
+        let mut id = from;
+        while id <= to {
+            let count = SCAN_BATCH.min(to - id + 1);
+            let pages = self.load_batch(id, count)?;
+            for page in pages {
+                let page_id = id;
+                id += 1;
+                if skip.contains(&page_id) {
+                    continue;
+                }
+                let is_empty = {
+                    let cache = self.cache.read();
+                    let spill = self.spill.read();
+                    match cache.get(&page_id).or_else(|| spill.get(&page_id)) {
+                        Some(cached) => cached.is_empty(),
+                        None => page.is_empty(),
+                    }
+                };
+                if is_empty {
+                    debug!("Page id={} is empty", page_id);
+                    self.free_id(page_id);
+                    if first {
+                        return Ok(id);
                     }
-                } else {
-                    error!("Page failed to load: id={}", id);
                 }
             }
         }
-        Ok(this)
+        Ok(id)
     }
 
-    fn load(&self, offset: usize, length: u32) -> io::Result<P> {
-        let mut page = P::reserve(length);
-        {
-            let mut file = self.file.write();
-            file.seek(SeekFrom::Start(offset as u64))?;
-            file.read_exact(page.as_mut())?;
+    /// Resume the deferred scan for empty pages (see `open_fast`) until an empty page is found.
+    /// Once the scan is complete, all free pages get persisted (as if scanned by `open`).
+    fn resume_scan(&self) -> Result<()> {
+        let mut scan = self.scan.write();
+        let (from, to) = match *scan {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let next = self.scan_pages(from, to, true)?;
+        if next <= to {
+            *scan = Some((next, to));
+            return Ok(());
         }
-        debug!("Loading page {}", page.id());
-        Ok(page)
+        debug!("Scan for empty pages complete: {}", to);
+        *scan = None;
+        let ids = self.empty.read().iter().map(|id| (id.0, true)).collect();
+        self.free_list.write().changes = ids;
+        self.save_head(0)?;
+        Ok(())
     }
 
-    fn save(&self, page: &P) -> io::Result<()> {
-        debug!("Saving page {}", page.id());
-        let offset = self.offset(page.id()) as u64;
-        {
-            let mut file = self.file.write();
-            file.seek(SeekFrom::Start(offset))?;
-            file.write_all(page.as_ref())
+    /// Table directory page and root pages of all tables (see `open_table`).
+    fn table_pages(&self) -> Result<Vec<u32>> {
+        let dir = self.tables.load(Ordering::Relaxed);
+        if dir == 0 {
+            return Ok(Vec::new());
         }
+        let page = self.fetch(dir)?;
+        let mut ids = vec![dir];
+        for idx in 0..page.len() {
+            ids.push(dir_root(page.deref(), idx)?);
+        }
+        Ok(ids)
     }
 
-    fn offset(&self, id: u32) -> usize {
-        HEAD + (id - 1) as usize * self.head.page_bytes as usize
+    /// Load free page ids from the chain of free-list pages (see `save_free_list`).
+    fn load_free_list(&self, total_pages: u32) -> io::Result<()> {
+        let mut list = self.free_list.write();
+        let mut empty = self.empty.write();
+        let mut id = self.head.free_page;
+        while id != 0 {
+            if id <= ROOT || id > total_pages || list.pages.contains(&id) {
+                return Err(io::Error::other(format!("Invalid free-list page: {}", id)));
+            }
+            let page = self.load(id)?;
+            list.pages.push(id);
+
+            let mut next = 0;
+            for idx in 0..page.len() {
+                let mut key = page.key(idx);
+                if key.len() != 4 {
+                    return Err(io::Error::other(format!("Invalid free-list page: {}", id)));
+                }
+                match key.get_u32() {
+                    0 => next = page.slot(idx).map(|slot| slot.page).unwrap_or_default(),
+                    free if free > ROOT && free <= total_pages => {
+                        empty.push(Reverse(free));
+                        list.saved.insert(free, id);
+                    }
+                    free => {
+                        return Err(io::Error::other(format!("Invalid free page: {}", free)));
+                    }
+                }
+            }
+            id = next;
+        }
+        debug!(
+            "Free pages: {} (free-list pages: {})",
+            empty.len(),
+            list.pages.len()
+        );
+        Ok(())
     }
-}
 
-impl<P: Page> Store for File<P> {
-    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        debug!("lookup: {}", hex(key));
-        let mut seen = HashSet::with_capacity(8);
-        let mut page = self.root();
-        loop {
-            let idx_opt = page.ceil(key);
-            if idx_opt.is_none() {
-                return Ok(None);
+    /// Persist changes of free page ids into the chain of free-list pages, so that `open` does
+    /// not need to scan the whole file. Each free-list page keeps the next one as a child of zero
+    /// key (page ids start from 1), all other keys are free page ids. New free-list pages are
+    /// appended to the file (not taken from `empty`), the chain only shrinks on `compact`.
+    fn save_free_list(&self) -> Result<()> {
+        let changes = std::mem::take(&mut self.free_list.write().changes);
+        if self.head.version == 0 {
+            // The legacy header can not reference the list: such files are scanned on open.
+            return Ok(());
+        }
+        if self.scan.read().is_some() {
+            // Partial list must not be persisted, `resume_scan` saves all once the scan is done.
+            return Ok(());
+        }
+        for (id, released) in changes {
+            if released {
+                self.save_free_id(id)?;
+            } else {
+                self.drop_free_id(id)?;
             }
-            let idx = idx_opt.unwrap();
+        }
+        Ok(())
+    }
 
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+    fn save_free_id(&self, id: u32) -> Result<()> {
+        let key = id.to_be_bytes();
+        let pages = self.free_list.read().pages.clone();
+        let mut target = None;
+        for page_id in pages.iter() {
+            if self.fetch(*page_id)?.fits(key.len() as u32) {
+                target = Some(*page_id);
+                break;
             }
-            let slot = slot_opt.unwrap();
+        }
 
-            if slot.page == 0 {
-                // Log how deep the lookup went into the tree depth: seen.len()
-                return if key == page.key(idx) {
-                    Ok(Some(page.val(idx).to_vec()))
-                } else {
-                    Ok(None)
-                };
-            } else {
-                let id = page.id();
-                drop(page);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(id, "Cyclic reference detected".to_string()));
+        let page_id = match target {
+            Some(page_id) => page_id,
+            None => {
+                let page_id = self.append_page()?;
+                set_next(self.fetch_mut(page_id)?.deref_mut(), 0);
+                match pages.last() {
+                    Some(last) => set_next(self.fetch_mut(*last)?.deref_mut(), page_id),
+                    None => self.save_head(page_id)?,
                 }
-                seen.insert(id);
+                self.free_list.write().pages.push(page_id);
+                page_id
+            }
+        };
+        self.fetch_mut(page_id)?.put_ref(&key, 0);
+        self.free_list.write().saved.insert(id, page_id);
+        Ok(())
+    }
 
-                let page_opt = self.page(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(id, format!("Page not found: {}", slot.page)));
-                }
-                page = page_opt.unwrap();
+    fn drop_free_id(&self, id: u32) -> Result<()> {
+        let page_id = self.free_list.write().saved.remove(&id);
+        if let Some(page_id) = page_id {
+            let mut page = self.fetch_mut(page_id)?;
+            if let Some(idx) = page.find(&id.to_be_bytes()) {
+                page.remove(idx);
             }
         }
+        Ok(())
     }
 
-    fn insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
-        debug!("insert: {} -> {}", hex(key), hex(val));
-        let mut page = self.root_mut();
-        let mut seen = HashSet::with_capacity(8);
-        let mut path = Vec::with_capacity(8);
-        loop {
-            let id = page.id();
-            let parent_id = path.last().cloned().map(|(id, _)| id).unwrap_or_default();
+    /// Same as `page_mut`, but a missing (or unreadable) page results in an error.
+    fn fetch_mut(&self, id: u32) -> Result<MappedRwLockWriteGuard<'_, P>> {
+        let cache = self.cached_mut(id).map_err(|source| Error::Io {
+            ctx: format!("load page {}", id),
+            source,
+        })?;
+        self.mark(id);
+        let page = RwLockWriteGuard::try_map(cache, |cache| cache.get_mut(&id))
+            .map_err(|_| Error::Tree(id, "Page not found".to_string()))?;
+        self.journal(id, &page);
+        Ok(page)
+    }
 
-            if page.len() == 0 {
-                // TODO handle keys/values larger than (half-) page size
-                let len = (key.len() + val.len()) as u32;
-                if !page.fits(len) {
-                    return Err(Error::Tree(
-                        page.id(),
-                        format!(
-                            "Entry does not fit into the page: size={} free={}",
-                            len,
-                            page.free()
-                        ),
-                    ));
-                }
-                page.put_val(key, val);
-                drop(page);
-                return Ok(());
-            }
+    /// Append new empty page to the end of the file.
+    fn append_page(&self) -> Result<u32> {
+        let mut file = self.file.write();
+        let len = file.len()?;
+        let id = 1 + ((len - self.head.size() as u64) / self.head.page_bytes as u64) as u32;
+        if P::SMALL_SLOTS && id >= u16::MAX as u32 {
+            return Err(Error::Other(format!(
+                "Too many pages for small slots: {}",
+                id
+            )));
+        }
+        let page = P::create(id, self.head.page_bytes);
+        file.append(page.as_ref()).map_err(|source| Error::Io {
+            ctx: format!("append page {}", id),
+            source,
+        })?;
+        Ok(id)
+    }
 
-            let idx = page.ceil(key).unwrap_or_else(|| page.len() - 1);
+    /// Rewrite header flags (marking the free list as persisted) and the first free-list page.
+    /// The legacy header (version 0) has no room for either, so nothing is written then.
+    fn save_head(&self, free_page: u32) -> io::Result<()> {
+        if self.head.version == 0 {
+            return Ok(());
+        }
+        let flags = self.head.flags | FLAG_FREE_LIST;
+        let mut buf = BytesMut::with_capacity(8);
+        if P::LITTLE_ENDIAN {
+            buf.put_u32_le(flags);
+            buf.put_u32_le(free_page);
+        } else {
+            buf.put_u32(flags);
+            buf.put_u32(free_page);
+        }
+        self.file.write().write_at(&buf, FLAGS_OFFSET)
+    }
 
-            drop(page);
-            if let Some((parent_id, parent_idx)) = path.last().cloned() {
-                let mut parent_page = self.page_mut(parent_id).unwrap();
-                let parent_key = parent_page.key(parent_idx);
-                if key > parent_key {
-                    parent_page.remove(parent_idx);
-                    parent_page.put_ref(key, id);
-                    drop(parent_page);
-                }
-            }
-            page = self.page_mut(id).unwrap();
+    /// Rewrite the table directory page id in the header, see `open_table`.
+    /// Files with the legacy header have no tables (see `make_table`), nothing is written then.
+    fn save_tables(&self, dir: u32) -> io::Result<()> {
+        if self.head.version == 0 {
+            return Ok(());
+        }
+        let mut buf = BytesMut::with_capacity(4);
+        if P::LITTLE_ENDIAN {
+            buf.put_u32_le(dir);
+        } else {
+            buf.put_u32(dir);
+        }
+        self.file.write().write_at(&buf, TABLES_OFFSET)
+    }
 
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
-            }
-            let slot = slot_opt.unwrap();
+    /// Enable the write-ahead log (`<path>.wal`): on `flush` all dirty pages are first
+    /// appended to the log and committed, and only then written to the file in place.
+    /// Committed but not applied pages are replayed on `open`. Dirty pages evicted from the cache
+    /// are kept in memory until the next flush instead of being written in place.
+    pub fn with_wal(mut self) -> io::Result<Self> {
+        if self.readonly {
+            return Err(io::Error::other("read-only"));
+        }
+        if self.flusher.is_some() {
+            return Err(io::Error::other("flusher is running, see with_flusher"));
+        }
+        if self.path.as_os_str().is_empty() {
+            return Err(io::Error::other("not backed by a file"));
+        }
+        let wal = Wal::open(&Wal::path(&self.path), self.head.page_bytes)?;
+        self.wal = Some(Arc::new(RwLock::new(wal)));
+        Ok(self)
+    }
 
-            if slot.page == 0 {
-                let len = (key.len() + val.len()) as u32;
-                if !page.fits(len) {
-                    // TODO handle keys/values larger than (half-) page size
-                    return Err(Error::Tree(
-                        page.id(),
-                        format!(
-                            "Entry does not fit into the page: size={} free={}",
-                            len,
-                            page.free()
-                        ),
-                    ));
-                }
-                page.put_val(key, val);
-                let full = page.full();
-                drop(page);
+    /// Start a background thread writing the dirty pages to the file every `interval`, so that
+    /// with `Durability::None` mutations return without writing pages, while at most `interval`
+    /// worth of acknowledged writes can be lost if the process crashes. The thread takes the tree
+    /// lock for each flush, so it never writes pages of a mutation (or a transaction) in progress.
+    /// It flushes one last time and stops once the last handle sharing it is dropped.
+    /// The thread works with a clone of this handle as it is, so this must be the last builder
+    /// call: enabling the WAL or the oplog afterwards results in an error.
+    pub fn with_flusher(mut self, interval: Duration) -> io::Result<Self> {
+        if self.readonly {
+            return Err(io::Error::other("read-only"));
+        }
+        let flusher = Flusher::spawn(self.clone(), interval)?;
+        self.flusher = Some(Arc::new(flusher));
+        Ok(self)
+    }
 
-                if full > SPLIT_THRESHOLD {
-                    self.split(id, parent_id)?;
-                }
+    /// Enable the mutation log (`<path>.oplog`): every entry inserted or removed through this
+    /// handle (or its clones) is appended to the log, in the order the changes are applied.
+    /// The log can be shipped to a replica and applied there with `replay_oplog`. It is written
+    /// along with the dirty pages (synced with `Durability::Sync`) and never truncated.
+    /// Table handles (see `open_table`) do not log their mutations.
+    pub fn with_oplog(mut self) -> io::Result<Self> {
+        if self.readonly {
+            return Err(io::Error::other("read-only"));
+        }
+        if self.flusher.is_some() {
+            return Err(io::Error::other("flusher is running, see with_flusher"));
+        }
+        if self.path.as_os_str().is_empty() {
+            return Err(io::Error::other("not backed by a file"));
+        }
+        let oplog = OpLog::open(&OpLog::path(&self.path))?;
+        self.oplog = Some(Arc::new(RwLock::new(oplog)));
+        Ok(self)
+    }
 
-                while !path.is_empty() {
-                    let (page_id, _) = path.pop().unwrap();
-                    let (parent_id, _) = path.last().cloned().unwrap_or_default();
-                    let full = {
-                        let page = self.page(page_id).unwrap();
-                        page.full()
-                    };
-                    if full > SPLIT_THRESHOLD {
-                        self.split(page_id, parent_id)?;
-                    }
-                }
+    /// Apply all mutations recorded in the log at `oplog` (see `with_oplog`) in order to the
+    /// database at `path` (e.g. a freshly made one), committing once at the end.
+    /// Returns the number of applied records. A torn record at the end of the log is ignored.
+    pub fn replay_oplog(path: &Path, oplog: &Path) -> Result<usize> {
+        let file = Self::open(path)?;
+        let records = OpLog::open(oplog)?.records()?;
+        let _lock = file.lock.write();
+        file.track(|| {
+            for op in records.iter() {
+                match op {
+                    Op::Insert(key, val) => file.put_dirty(key, val, true)?,
+                    Op::Remove(key) => file.del_dirty(key)?,
+                };
+            }
+            file.commit()
+        })?;
+        Ok(records.len())
+    }
 
-                self.flush()?;
+    /// Append the mutation to the oplog (if enabled), caller is expected to hold the write lock.
+    /// Within a transaction the record is kept until the transaction is committed.
+    fn log(&self, op: Op) -> Result<()> {
+        if let Some(oplog) = self.oplog.as_ref() {
+            if let Some(journal) = self.txn.write().as_mut() {
+                journal.ops.push(op);
                 return Ok(());
-            } else {
-                path.push((id, idx));
-                seen.insert(id);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(
-                        id,
-                        format!("Cyclic reference detected: {:?}", path),
-                    ));
-                }
-
-                drop(page);
-                let page_opt = self.page_mut(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(
-                        slot.page,
-                        format!("Page not found: {}", slot.page),
-                    ));
-                }
-                page = page_opt.unwrap();
             }
+            oplog.write().append(&op)?;
         }
+        Ok(())
     }
 
-    fn remove(&self, key: &[u8]) -> Result<()> {
-        debug!("remove: {}", hex(key));
-        let mut page = self.root_mut();
-        let mut seen = HashSet::with_capacity(8);
-        let mut path = Vec::with_capacity(8);
-        loop {
-            let idx_opt = page.ceil(key);
-            if idx_opt.is_none() {
-                return Ok(());
+    /// Start the transaction journal, caller is expected to hold the tree write lock.
+    /// A deferred scan for empty pages (see `open_fast`) is completed first, as completing it
+    /// rewrites the file header, which is not rolled back.
+    fn begin(&self) -> Result<()> {
+        while self.scan.read().is_some() {
+            self.resume_scan()?;
+        }
+        let journal = Journal {
+            pages: HashMap::new(),
+            len: self.file.read().len()?,
+            empty: self.empty.read().clone(),
+            changes: self.free_list.read().changes.len(),
+            saved: self.counters.saved.load(Ordering::Relaxed),
+            ops: Vec::new(),
+        };
+        *self.txn.write() = Some(journal);
+        Ok(())
+    }
+
+    /// Keep the image of the page about to be modified (unless it is kept already),
+    /// if a transaction is running.
+    fn journal(&self, id: u32, page: &P) {
+        if self.txn.read().is_none() {
+            return;
+        }
+        if let Some(journal) = self.txn.write().as_mut() {
+            if let hash_map::Entry::Vacant(image) = journal.pages.entry(id) {
+                image.insert(page.as_ref().to_vec());
             }
-            let idx = idx_opt.unwrap();
+        }
+    }
 
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+    /// Close the journal and commit the changes made by the transaction.
+    fn end(&self) -> Result<()> {
+        let journal = self.txn.write().take();
+        if let (Some(journal), Some(oplog)) = (journal, self.oplog.as_ref()) {
+            let mut oplog = oplog.write();
+            for op in journal.ops.iter() {
+                oplog.append(op)?;
             }
-            let slot = slot_opt.unwrap();
+        }
+        self.commit()
+    }
 
-            let id = page.id();
-            if slot.page == 0 {
-                debug!("remove: key={} page={} idx={}", hex(key), id, idx);
-                page.remove(idx);
-                drop(page);
+    /// Close the journal and restore the pages (and free pages) as they were before
+    /// the transaction. Pages appended to the file are cut off.
+    fn rollback(&self) -> Result<()> {
+        let journal = match self.txn.write().take() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let count = ((journal.len - self.head.size() as u64) / self.head.page_bytes as u64) as u32;
+        for (id, image) in journal.pages.iter() {
+            self.fetch_mut(*id)?.as_mut().copy_from_slice(image);
+            if *id > count {
+                // Not in the file anymore, the cached copy is left as an empty page.
+                self.dirty.write().remove(id);
+            }
+        }
+        self.file.write().set_len(journal.len)?;
+        *self.empty.write() = journal.empty;
+        self.free_list.write().changes.truncate(journal.changes);
+        *self.edges.write() = (0, 0);
+        if self.counters.saved.load(Ordering::Relaxed) != journal.saved {
+            self.flush()?;
+        }
+        Ok(())
+    }
 
-                // Navigate up-tree and remove/update references if needed
-                let mut page_id = id;
-                for (parent_id, mut idx) in path.iter().cloned().rev() {
-                    let full = self.page(page_id).unwrap().full();
-                    if full < MERGE_THRESHOLD {
-                        let peer_id = {
-                            let parent = self.page(parent_id).unwrap();
-                            let mut peers = Vec::with_capacity(2);
-                            if idx > 0 {
-                                let peer = parent.slot(idx - 1).unwrap().page;
-                                peers.push(peer);
-                            }
-                            if idx < parent.len() - 1 {
-                                let peer = parent.slot(idx + 1).unwrap().page;
-                                peers.push(peer);
-                            }
-                            drop(parent);
+    /// Replace the page cache (`LruCache` of 32 pages by default) with the one using another
+    /// eviction policy. Only the root page is moved over, so call it right after `make`/`open`.
+    pub fn with_cache(self, mut cache: PageCache<P>) -> Self {
+        {
+            let mut current = self.cache.write();
+            debug_assert!(self.dirty.read().is_empty(), "dirty pages in the cache");
+            if let Some(root) = current.get(&ROOT) {
+                let mut copy = P::reserve(self.head.page_bytes);
+                copy.as_mut().copy_from_slice(root.as_ref());
+                cache.put(ROOT, copy);
+            }
+            *current = cache;
+        }
+        self
+    }
 
-                            peers
-                                .into_iter()
-                                .filter_map(|peer_id| {
-                                    let peer = self.page(peer_id).unwrap();
-                                    let full = peer.full();
-                                    if peer.len() > 0 && full < MERGE_THRESHOLD {
-                                        Some((peer_id, full))
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .min_by_key(|(_, full)| *full)
-                                .map(|(peer_id, _)| peer_id)
-                        };
-                        if let Some(peer_id) = peer_id {
-                            trace!(
-                                "merge: found peer_id={} to merge page_id={} (parent_id={})",
-                                peer_id,
-                                page_id,
-                                parent_id
-                            );
-                            let peer_max = {
-                                let peer = self.page(peer_id).unwrap();
-                                peer.max().to_vec()
-                            };
-                            trace!("\t merge: peer_max={}", hex(&peer_max));
-                            let mut parent = self.page_mut(parent_id).unwrap();
-                            parent.remove(idx);
-                            let peer_idx = parent.ceil(&peer_max).unwrap();
-                            trace!("\t merge: parent remove: peer_idx={} idx={}", peer_idx, idx);
-                            parent.remove(peer_idx);
-                            drop(parent);
+    /// Set durability level for all following mutations, see `Durability`.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
 
-                            self.merge(page_id, peer_id)?;
-                            let page_max = {
-                                let peer = self.page(peer_id).unwrap();
-                                peer.max().to_vec()
-                            };
-                            trace!("\t merge: page_max={}", hex(&page_max));
-                            let mut parent = self.page_mut(parent_id).unwrap();
-                            trace!(
-                                "\t merge: parent insert: page_max={}, peer_id={}",
-                                hex(&page_max),
-                                peer_id
-                            );
-                            parent.put_ref(&page_max, peer_id);
-                            idx = parent.ceil(&page_max).unwrap();
-                            page_id = peer_id;
-                        }
-                    }
+    /// Flush all dirty pages and make sure they have reached the disk.
+    pub fn sync(&self) -> Result<()> {
+        let _lock = self.lock.write();
+        self.flush_sync()
+    }
 
-                    let max_opt = {
-                        let page = self.page(page_id).unwrap();
-                        if page.len() > 0 {
-                            Some(page.max().to_vec())
-                        } else {
-                            None
-                        }
-                    };
+    /// Shrink the page cache, the set of dirty pages and the heap of free pages to fit what they
+    /// currently hold: each keeps the capacity it grew to (e.g. during a bulk import) otherwise.
+    /// Nothing is flushed or dropped, see `Store::release` for that (it calls this as well).
+    pub fn trim_memory(&self) {
+        self.cache.write().shrink_to_fit();
+        self.dirty.write().shrink_to_fit();
+        self.empty.write().shrink_to_fit();
+        self.free_list.write().changes.shrink_to_fit();
+    }
 
-                    let mut parent = self.page_mut(parent_id).unwrap();
-                    if let Some(max) = max_opt {
-                        if max < parent.key(idx).to_vec() {
-                            parent.remove(idx);
-                            parent.put_ref(&max, page_id);
-                        }
-                    } else {
-                        parent.remove(idx);
-                    }
-                    drop(parent);
-                    page_id = parent_id;
-                }
+    fn flush_sync(&self) -> Result<()> {
+        self.flush()?;
+        self.file.write().sync_data()?;
+        if let Some(oplog) = self.oplog.as_ref() {
+            oplog.read().sync()?;
+        }
+        Ok(())
+    }
 
-                self.flush()?;
-                return Ok(());
-            } else {
-                path.push((id, idx));
-                seen.insert(id);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(id, "Cyclic reference detected".to_string()));
-                }
-                drop(page);
+    fn commit(&self) -> Result<()> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Flush => self.flush(),
+            Durability::Sync => self.flush_sync(),
+            Durability::Batch(pages) if self.dirty.read().len() >= pages => self.flush(),
+            Durability::Batch(_) => Ok(()),
+        }
+    }
 
-                let page_opt = self.page_mut(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(id, format!("Page not found: {}", slot.page)));
+    /// Write all entries in ascending key order, one `hex(key) hex(val)` pair per line.
+    pub fn export(&self, w: &mut impl Write) -> Result<()> {
+        let mut failed = None;
+        self.scan_with(None, true, |key, val| {
+            match writeln!(w, "{} {}", hex(key), hex(val)) {
+                Ok(()) => true,
+                Err(e) => {
+                    failed = Some(e);
+                    false
                 }
-                page = page_opt.unwrap();
             }
+        })?;
+        if let Some(e) = failed {
+            return Err(e.into());
         }
+        w.flush()?;
+        Ok(())
     }
 
-    fn is_empty(&self) -> bool {
-        self.root().len() == 0
+    /// Insert all entries written by `export`, skipping blank and `#`-comment lines.
+    /// Returns number of inserted entries.
+    pub fn import(&self, r: &mut impl BufRead) -> Result<usize> {
+        let mut count = 0;
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut it = line.split_whitespace();
+            let key = it.next().and_then(unhex);
+            let val = unhex(it.next().unwrap_or_default());
+            match (key, val) {
+                (Some(key), Some(val)) => self.insert(&key, &val)?,
+                _ => return Err(Error::Other(format!("Invalid line {}: '{}'", i + 1, line))),
+            }
+            count += 1;
+        }
+        Ok(count)
     }
 
-    fn min(&self) -> Result<Option<Vec<u8>>> {
-        let mut page = self.root();
-        if page.len() == 0 {
-            return Ok(None);
-        }
-        loop {
-            let slot = page.slot(0).unwrap();
-            if slot.page == 0 {
-                return Ok(Some(page.min().to_vec()));
-            } else {
-                let id = slot.page;
-                drop(page);
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
+    /// Write all entries in ascending key order as a JSON array of `{"k": hex, "v": hex}` objects.
+    /// Entries are written one by one, the whole snapshot is never kept in memory.
+    pub fn export_json(&self, w: &mut impl Write) -> Result<()> {
+        write!(w, "[")?;
+        let mut sep = "";
+        let mut failed = None;
+        self.scan_with(None, true, |key, val| {
+            let result = write!(
+                w,
+                "{}\n{{\"k\":\"{}\",\"v\":\"{}\"}}",
+                sep,
+                hex(key),
+                hex(val)
+            );
+            sep = ",";
+            match result {
+                Ok(()) => true,
+                Err(e) => {
+                    failed = Some(e);
+                    false
                 }
             }
+        })?;
+        if let Some(e) = failed {
+            return Err(e.into());
         }
+        writeln!(w, "\n]")?;
+        w.flush()?;
+        Ok(())
     }
 
-    fn max(&self) -> Result<Option<Vec<u8>>> {
-        let mut page = self.root();
-        if page.len() == 0 {
-            return Ok(None);
+    /// Insert all entries from a JSON snapshot written by `export_json`.
+    /// Returns number of inserted entries.
+    pub fn import_json(&self, r: &mut impl Read) -> Result<usize> {
+        let mut src = String::new();
+        r.read_to_string(&mut src)?;
+        let entries =
+            json::entries(&src).ok_or_else(|| Error::Other("Invalid JSON snapshot".to_string()))?;
+        for (k, v) in entries.iter() {
+            match (unhex(k), unhex(v)) {
+                (Some(key), Some(val)) => self.insert(&key, &val)?,
+                _ => return Err(Error::Other(format!("Invalid hex entry: '{}' '{}'", k, v))),
+            }
         }
-        loop {
-            let last = page.len() - 1;
-            let slot = page.slot(last).unwrap();
-            if slot.page == 0 {
-                return Ok(Some(page.max().to_vec()));
-            } else {
-                let id = slot.page;
-                drop(page);
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
+        Ok(entries.len())
+    }
+
+    /// Write all entries in ascending key order as a compact binary stream (see `restore`):
+    /// header (magic, page size, flags as u32, entry count as u64), then `klen` (u32), key,
+    /// `vlen` (u32) and the (decompressed) value per entry, all integers are big-endian.
+    /// Fails if the number of entries changed while the snapshot was written.
+    pub fn snapshot(&self, w: &mut impl Write) -> Result<()> {
+        let count = self.count_prefix(&[])? as u64;
+        let mut buf = BytesMut::with_capacity(SNAPSHOT_HEAD);
+        buf.put_slice(SNAPSHOT_MAGIC);
+        buf.put_u32(self.head.page_bytes);
+        buf.put_u32(self.head.flags & FLAG_ZSTD);
+        buf.put_u64(count);
+        w.write_all(&buf)?;
+
+        let mut written = 0u64;
+        let mut failed = None;
+        self.scan(None, |key, val| {
+            let mut buf = BytesMut::with_capacity(8 + key.len() + val.len());
+            buf.put_u32(key.len() as u32);
+            buf.put_slice(key);
+            buf.put_u32(val.len() as u32);
+            buf.put_slice(val);
+            written += 1;
+            match w.write_all(&buf) {
+                Ok(()) => true,
+                Err(e) => {
+                    failed = Some(e);
+                    false
                 }
             }
+        })?;
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        if written != count {
+            return Err(Error::Other(format!(
+                "Snapshot entry count changed: {} -> {}",
+                count, written
+            )));
         }
+        w.flush()?;
+        Ok(())
     }
 
-    fn above(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        debug!("above: {}", hex(key));
-
-        let mut path = Vec::with_capacity(8);
-        let mut page = self.root();
-        if page.len() == 0 {
-            return Ok(None);
-        }
-        if page.max() < key {
-            return Ok(None);
+    /// Make a new file at `path` from a snapshot written by `snapshot` (same page size and
+    /// compression). Entries are bulk-loaded, so the file is densely packed (see `rebuild`).
+    pub fn restore(path: &Path, r: &mut impl Read) -> Result<Self> {
+        let mut head = [0u8; SNAPSHOT_HEAD];
+        r.read_exact(&mut head)?;
+        let mut head = &head[..];
+        if &head[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(Error::Other("Invalid snapshot magic".to_string()));
         }
-        loop {
-            let idx = page.ceil(key).unwrap();
-            let slot = page.slot(idx).unwrap();
-            if slot.page == 0 {
-                return if key < page.key(idx) {
-                    Ok(Some(page.key(idx).to_vec()))
-                } else if key == page.key(idx) && idx < page.len() - 1 {
-                    Ok(Some(page.key(idx + 1).to_vec()))
-                } else {
-                    // ceil == key, need to take min value from parent's next adjacent subtree
-                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
-                        page = self.page(parent_id).unwrap();
-                        if parent_idx < page.len() - 1 {
-                            let id = page.slot(parent_idx + 1).unwrap().page;
-                            drop(page);
-                            page = self.page(id).unwrap();
-                            loop {
-                                let slot = page.slot(0).unwrap();
-                                if slot.page == 0 {
-                                    return Ok(Some(page.min().to_vec()));
-                                } else {
-                                    drop(page);
-                                    page = self.page(slot.page).unwrap();
-                                }
-                            }
-                        }
-                    }
+        head.advance(SNAPSHOT_MAGIC.len());
+        let page_bytes = head.get_u32();
+        let compression = if head.get_u32() & FLAG_ZSTD != 0 {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let count = head.get_u64();
 
-                    // the key appears to be the maximum value stored in the tree
-                    Ok(None)
-                };
-            } else {
-                path.push((page.id(), idx));
-                let id = slot.page;
-                drop(page);
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
-                }
+        let out = Self::make_with_compression(path, page_bytes, compression)?;
+        let mut loader = Loader {
+            page: P::create(ROOT + 1, page_bytes),
+            level: Vec::new(),
+        };
+        let mut prev: Option<Vec<u8>> = None;
+        for i in 0..count {
+            let key = read_chunk(r, page_bytes)?;
+            let val = read_chunk(r, page_bytes)?;
+            if key.is_empty() || prev.as_ref().map(|prev| prev >= &key).unwrap_or_default() {
+                return Err(Error::Other(format!(
+                    "Snapshot entry {} is out of order: {}",
+                    i,
+                    hex(&key)
+                )));
             }
+            let (packed, compressed) = out.pack(&val)?;
+            out.bulk_put(&mut loader, &key, &packed, 0, compressed)?;
+            prev = Some(key);
         }
+        out.bulk_finish(loader)?;
+        Ok(out)
     }
 
-    fn below(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        debug!("below: {}", hex(key));
-
-        let mut path = Vec::with_capacity(8);
-        let mut page = self.root();
-        if page.len() == 0 {
-            return Ok(None);
-        }
-        if page.max() < key {
-            return Ok(Some(page.max().to_vec()));
+    /// Move all live pages into the lowest page ids (rewriting references with a page-id remap),
+    /// then truncate the file to the live pages only. Reclaims space freed by merged pages.
+    pub fn compact(&self) -> Result<()> {
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.no_tables("compact")?;
+        self.flush()?;
+
+        let mut live = vec![ROOT];
+        let mut stack = vec![ROOT];
+        while let Some(id) = stack.pop() {
+            let page = self
+                .page(id)
+                .ok_or_else(|| Error::Tree(id, "Page not found".to_string()))?;
+            for idx in 0..page.len() {
+                let child = page.slot(idx).map(|slot| slot.page).unwrap_or_default();
+                if child > 0 {
+                    live.push(child);
+                    stack.push(child);
+                }
+            }
         }
-        loop {
-            let idx = page.ceil(key).unwrap();
-            let slot = page.slot(idx).unwrap();
-            if slot.page == 0 {
-                return if idx > 0 && key > page.key(idx - 1) {
-                    Ok(Some(page.key(idx - 1).to_vec()))
+        live.sort_unstable();
+
+        let remap = live
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| (*id, idx as u32 + 1))
+            .collect::<HashMap<_, _>>();
+
+        let mut moved_pages = Vec::with_capacity(live.len());
+        for id in live.iter() {
+            let new_id = remap[id];
+            let page = self.load(*id)?;
+            let mut moved = P::create(new_id, self.head.page_bytes);
+            for (key, val, child, compressed) in entries(&page) {
+                let child = if child == 0 {
+                    0
                 } else {
-                    // ceil == key, need to take max value from parent's previous adjacent page
-                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
-                        drop(page);
-                        page = self.page(parent_id).unwrap();
-                        if parent_idx > 0 {
-                            let idx = parent_idx - 1;
-                            let id = page.slot(idx).unwrap().page;
-                            drop(page);
-                            page = self.page(id).unwrap();
-                            return Ok(Some(page.max().to_vec()));
-                        }
-                    }
-
-                    // the key seems to be the minimum value stored in the tree
-                    Ok(None)
+                    remap
+                        .get(&child)
+                        .cloned()
+                        .ok_or_else(|| Error::Tree(*id, format!("Page not found: {}", child)))?
                 };
-            } else {
-                path.push((page.id(), idx));
-                let id = slot.page;
-                drop(page);
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
-                }
+                put_entry(&mut moved, &key, &val, child, compressed);
+            }
+            if new_id != *id {
+                debug!("compact: move page {} to {}", id, new_id);
             }
+            moved_pages.push(moved);
         }
+
+        // Moved pages overwrite the free-list pages (and the empty table directory), so the
+        // header must not reference them anymore by the time any moved page is written.
+        self.save_head(0)?;
+        self.save_tables(0)?;
+        self.file.write().sync_data()?;
+        self.write_pages(&moved_pages.iter().collect::<Vec<_>>())?;
+        {
+            let mut file = self.file.write();
+            file.set_len(self.offset(live.len() as u32 + 1)?)?;
+            file.sync_data()?;
+        }
+        self.cache.write().clear();
+        self.dirty.write().clear();
+        self.spill.write().clear();
+        self.empty.write().clear();
+        *self.free_list.write() = FreeList::default();
+        *self.scan.write() = None;
+        *self.edges.write() = (0, 0);
+        Ok(())
     }
-}
 
-impl<P: Page> Tree<P> for File<P> {
-    fn root(&self) -> MappedRwLockReadGuard<'_, P> {
-        self.page(ROOT).unwrap()
+    /// Rewrite all entries (in key order) into a fresh file at `out_path` and return it: pages
+    /// are bulk-loaded bottom-up, filled up to the split threshold, with no free pages left.
+    /// Mutations running concurrently with the rebuild might be missing in the new file.
+    pub fn rebuild(&self, out_path: &Path) -> Result<Self> {
+        self.rebuild_range(out_path, None, None)
     }
 
-    fn page(&self, id: u32) -> Option<MappedRwLockReadGuard<'_, P>> {
-        self.cache(id).ok()?;
-        let page = RwLockReadGuard::map(self.cache.read(), |cache| cache.get(&id).unwrap());
-        Some(page)
+    /// Split the database at `key` into two fresh files (see `rebuild`), e.g. to reshard:
+    /// entries with keys below `key` go to `left_path`, all the others to `right_path`.
+    /// This file is left unchanged. Fails before writing anything if either file exists.
+    /// Mutations running concurrently with the split might be missing in the new files.
+    pub fn split_at(
+        &self,
+        key: &[u8],
+        left_path: &Path,
+        right_path: &Path,
+    ) -> Result<(Self, Self)> {
+        Self::check_key(key)?;
+        for path in [left_path, right_path] {
+            if path.exists() {
+                return Err(io::Error::other(format!("File exists: {:?}", path)).into());
+            }
+        }
+        let left = self.rebuild_range(left_path, None, Some(key))?;
+        let right = self.rebuild_range(right_path, Some(key), None)?;
+        Ok((left, right))
     }
 
-    fn root_mut(&self) -> MappedRwLockWriteGuard<'_, P> {
-        self.mark(ROOT);
-        self.page_mut(ROOT).unwrap()
-    }
+    /// Same as `rebuild`, but only entries with keys in range `[lo, hi)` are written
+    /// (from the first key if `lo` is none, up to the last one if `hi` is none).
+    fn rebuild_range(&self, out_path: &Path, lo: Option<&[u8]>, hi: Option<&[u8]>) -> Result<Self> {
+        self.no_tables("rebuild")?;
+        let compression = if self.head.flags & FLAG_ZSTD != 0 {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let out = Self::make_with_compression(out_path, self.head.page_bytes, compression)?
+            .with_durability(self.durability);
 
-    fn page_mut(&self, id: u32) -> Option<MappedRwLockWriteGuard<'_, P>> {
-        self.cache(id).ok()?;
-        self.mark(id);
-        let page = RwLockWriteGuard::map(self.cache.write(), |cache| cache.get_mut(&id).unwrap());
-        Some(page)
+        let mut loader = Loader {
+            page: P::create(ROOT + 1, out.head.page_bytes),
+            level: Vec::new(),
+        };
+        let mut failed = None;
+        self.scan_with(lo, true, |key, val| {
+            if hi.is_some_and(|hi| key >= hi) {
+                return false;
+            }
+            let result = out
+                .pack(val)
+                .and_then(|(val, compressed)| out.bulk_put(&mut loader, key, &val, 0, compressed));
+            match result {
+                Ok(()) => true,
+                Err(e) => {
+                    failed = Some(e);
+                    false
+                }
+            }
+        })?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        out.bulk_finish(loader)?;
+        Ok(out)
     }
 
-    fn cache(&self, id: u32) -> io::Result<()> {
-        let has_id = self.cache.read().has(&id);
-        if !has_id {
-            let page = self.load(self.offset(id), self.head.page_bytes)?;
-            self.cache.write().put(id, page);
+    /// Copy the database into a new file at `path` (e.g. an on-disk snapshot): the source is
+    /// flushed first, then all entries are bulk-loaded into a compact tree (see `rebuild`),
+    /// which is synced before it is returned. Mutations running concurrently with the copy
+    /// might be missing in it.
+    pub fn clone_to(&self, path: &Path) -> Result<File<P>> {
+        if !self.readonly {
+            self.sync()?;
         }
-        Ok(())
+        let out = self.rebuild(path)?;
+        out.sync()?;
+        Ok(out)
     }
 
-    fn mark(&self, id: u32) {
-        self.dirty.write().insert(id);
+    /// Insert all entries of `other` into this store (the inverse of `split_at`): on key conflicts
+    /// the value from `other` wins. See `merge_from_with` to resolve conflicts otherwise.
+    pub fn merge_from(&self, other: &File<P>) -> Result<()> {
+        self.merge_from_with(other, |_, _, theirs| theirs.to_vec())
     }
 
-    fn flush(&self) -> crate::api::error::Result<()> {
-        let pages = self.dirty.read().iter().cloned().collect::<Vec<_>>();
-        self.dirty.write().clear();
+    /// Same as `merge_from`, but for a key present in both stores the value is the one returned
+    /// by `resolve(key, ours, theirs)`. The write lock is held for the whole merge and dirty pages
+    /// are committed once at the end (see `Durability`), or when an insert fails (then the
+    /// entries merged so far are kept). Mutations of `other` running concurrently with the merge
+    /// might be seen partially. Merging a store sharing the tree lock with this one (the same
+    /// file, a clone or a table handle) is not supported.
+    pub fn merge_from_with<F>(&self, other: &File<P>, mut resolve: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8], &[u8]) -> Vec<u8>,
+    {
+        if Arc::ptr_eq(&self.lock, &other.lock) {
+            return Err(Error::Other("cannot merge a store into itself".to_string()));
+        }
+        self.writable()?;
+        let _lock = self.lock.write();
+        let mut entries = Column::entries(other);
+        self.track(|| {
+            while let Some((key, theirs)) = entries.try_next()? {
+                let merged = self.find_value(&key).and_then(|ours| match ours {
+                    Some(ours) => {
+                        let val = resolve(&key, &ours, &theirs);
+                        self.put_dirty(&key, &val, true)
+                    }
+                    None => self.put_dirty(&key, &theirs, true),
+                });
+                if let Err(e) = merged {
+                    self.commit()?;
+                    return Err(Error::Other(format!("key {}: {}", hex(&key), e)));
+                }
+            }
+            self.commit()
+        })
+    }
 
-        let mut failed = vec![];
-        for id in pages {
-            if let Some(page) = self.page(id) {
-                self.save(page.deref())?;
-                debug!("flush: page={}", id);
-            } else {
-                failed.push(id);
-                error!("flush: no such page={}", id);
+    /// Check if both stores hold the same entries, regardless of the page layout (page ids,
+    /// fill factors, compression). Both are walked in key order, stopping at the first mismatch.
+    /// Mutations running concurrently with the comparison might be seen partially.
+    pub fn content_eq(&self, other: &File<P>) -> Result<bool> {
+        let mut this = Column::entries(self);
+        let mut that = Column::entries(other);
+        loop {
+            match (this.try_next()?, that.try_next()?) {
+                (None, None) => return Ok(true),
+                (Some(a), Some(b)) if a == b => continue,
+                _ => return Ok(false),
             }
         }
+    }
 
-        if failed.is_empty() {
-            Ok(())
+    /// Best-effort salvage of a damaged file: collect the entries of every well-formed leaf page
+    /// (node pages are not trusted, nor is the free list beyond skipping its pages), then
+    /// bulk-load them into a clean tree (see `rebuild`) that replaces the file at `path`.
+    /// The WAL (if any) is discarded. A key found in more than one leaf is taken from the leaf
+    /// with the lowest page id. Entries are collected in memory. Files with tables are not
+    /// supported.
+    pub fn repair(path: &Path) -> Result<RepairReport> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let head = Self::read_head(&mut file)?;
+        let (page_bytes, free_page, tables) = (head.page_bytes, head.free_page, head.tables);
+        let compression = if head.flags & FLAG_ZSTD != 0 {
+            Compression::Zstd
         } else {
-            let pages = failed
-                .into_iter()
-                .map(|id| format!("{}", id))
-                .collect::<Vec<_>>()
-                .join(", ");
-            Err(Error::Other(format!("Missing pages: {}", pages)))
+            Compression::None
+        };
+        let mut src = Self::with_storage(Box::new(file), head, path);
+        src.readonly = true;
+        let total_pages =
+            ((src.file.read().len()? - src.head.size() as u64) / page_bytes as u64) as u32;
+
+        let mut skip = HashSet::new();
+        if tables != 0 {
+            if tables > total_pages || !src.load(tables)?.is_empty() {
+                return Err(Error::Other(
+                    "repair is not supported with tables".to_string(),
+                ));
+            }
+            skip.insert(tables);
         }
-    }
-
-    fn next_id(&self) -> Result<u32> {
-        let is_empty = self.empty.read().is_empty();
-        if !is_empty {
-            let id = self.empty.write().pop().unwrap().0;
-            let temp = P::create(id, self.head.page_bytes);
-            let mut page = self.page_mut(id).unwrap();
-            page.as_mut().copy_from_slice(temp.as_ref());
-            return Ok(id);
+        let mut id = free_page;
+        while id > ROOT && id <= total_pages && skip.insert(id) {
+            let page = src.load(id)?;
+            if !page.is_valid() {
+                break;
+            }
+            id = page
+                .find(&0u32.to_be_bytes())
+                .and_then(|idx| page.slot(idx))
+                .map(|slot| slot.page)
+                .unwrap_or_default();
         }
 
-        let len = self.file.write().metadata().unwrap().len();
-        let id = 1 + ((len - HEAD as u64) / self.head.page_bytes as u64) as u32;
-        let page = P::create(id, self.head.page_bytes);
-        {
-            let mut f = self.file.write();
-            f.seek(SeekFrom::End(0))?;
-            f.write_all(page.as_ref())?;
+        let mut report = RepairReport::default();
+        let mut found = Vec::new();
+        let mut id = ROOT;
+        while id <= total_pages {
+            let count = SCAN_BATCH.min(total_pages - id + 1);
+            for page in src.load_batch(id, count)? {
+                let page_id = id;
+                id += 1;
+                if skip.contains(&page_id) {
+                    continue;
+                }
+                report.pages += 1;
+                if page.id() != page_id || !page.is_valid() {
+                    debug!("repair: page {} is damaged", page_id);
+                    report.damaged += 1;
+                    continue;
+                }
+                if page.is_empty() || page.is_node() {
+                    continue;
+                }
+                report.leaves += 1;
+                for (key, val, _, compressed) in entries(&page) {
+                    if key.is_empty() || (compressed && compress::decompress(&val).is_err()) {
+                        report.dropped += 1;
+                        continue;
+                    }
+                    found.push((key, val, compressed));
+                }
+            }
         }
+        drop(src);
+        // Stable sort: entries of the same key stay ordered by page id.
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".repair");
+        let tmp = PathBuf::from(tmp);
+        let out = Self::make_with_compression(&tmp, page_bytes, compression)?;
+        let mut loader = Loader {
+            page: P::create(ROOT + 1, page_bytes),
+            level: Vec::new(),
+        };
+        let mut prev: Option<&[u8]> = None;
+        for (key, val, compressed) in found.iter() {
+            if prev == Some(key.as_slice()) {
+                report.dropped += 1;
+                continue;
+            }
+            out.bulk_put(&mut loader, key, val, 0, *compressed)?;
+            report.recovered += 1;
+            prev = Some(key);
+        }
+        out.bulk_finish(loader)?;
+        drop(out);
 
-        Ok(id)
+        fs::rename(&tmp, path)?;
+        let wal = Wal::path(path);
+        if wal.exists() {
+            fs::remove_file(wal)?;
+        }
+        debug!("repair: {:?}", report);
+        Ok(report)
     }
 
-    fn free_id(&self, id: u32) {
-        self.empty.write().push(Reverse(id))
+    /// Open the table (a separate key space in the same file) of given name, making it first
+    /// if it does not exist. The table is a tree of its own, with the root page listed in the
+    /// table directory page. The returned handle is a `File` working with that tree only (it
+    /// shares the page cache, locks and free pages with the file), and `stats` cover the whole
+    /// file. The file itself keeps working with its own (main) tree.
+    pub fn open_table(&self, name: &str) -> Result<TableHandle<P>> {
+        if name.is_empty() {
+            return Err(Error::Other("empty table name".to_string()));
+        }
+        let _lock = self.lock.write();
+        let root = match self.table_root(name.as_bytes())? {
+            Some(root) => root,
+            None => self.track(|| self.make_table(name.as_bytes()))?,
+        };
+        debug!("open_table: name={} root={}", name, root);
+        let mut table = self.clone();
+        table.root = root;
+        table.edges = Arc::new(RwLock::new((0, 0)));
+        table.oplog = None;
+        Ok(table)
     }
 
-    fn split(&self, id: u32, parent_id: u32) -> Result<()> {
-        if id == ROOT {
-            let lo_id = self.next_id()?;
-            let hi_id = self.next_id()?;
-            debug!(
-                "split: root={} into lo={} and hi={} (parent={})",
-                id, lo_id, hi_id, parent_id
-            );
-
-            let (copy, lo_max, hi_max) = {
-                let page = self.page(id).unwrap();
-                let copy = page.copy();
-                let half = page.len() as usize / 2;
-                let lo_max = copy.get(half - 1).map(|(k, _, _)| k).cloned().unwrap();
-                let hi_max = copy.last().map(|(k, _, _)| k).cloned().unwrap();
-                (copy, lo_max, hi_max)
-            };
-            let half = copy.len() / 2;
-
-            {
-                let mut lo = self.page_mut(lo_id).unwrap();
-                copy.iter().take(half).for_each(|(key, val, page)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *page,
+    /// Drop the table of given name, freeing all its pages for reuse.
+    /// Returns false if there is no such table. Handles of the table must not be used after.
+    pub fn drop_table(&self, name: &str) -> Result<bool> {
+        self.writable()?;
+        let _lock = self.lock.write();
+        let root = match self.table_root(name.as_bytes())? {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+        self.track(|| {
+            let mut ids = Vec::new();
+            let mut stack = vec![root];
+            let mut seen = HashSet::new();
+            while let Some(id) = stack.pop() {
+                if !seen.insert(id) {
+                    return Err(Error::Tree(
                         id,
-                        lo_id
-                    );
-                    if *page == 0 {
-                        lo.put_val(key, val);
-                    } else {
-                        lo.put_ref(key, *page);
-                    }
-                });
+                        "Page referenced more than once".to_string(),
+                    ));
+                }
+                let page = self.fetch(id)?;
+                stack.extend(
+                    (0..page.len())
+                        .filter_map(|idx| page.slot(idx))
+                        .filter(|slot| slot.is_ref())
+                        .map(|slot| slot.page),
+                );
+                ids.push(id);
             }
-
-            {
-                let mut hi = self.page_mut(hi_id).unwrap();
-                copy.iter().skip(half).for_each(|(key, val, page)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *page,
-                        id,
-                        hi_id
-                    );
-                    if *page == 0 {
-                        hi.put_val(key, val);
-                    } else {
-                        hi.put_ref(key, *page);
-                    }
-                });
+            debug!("drop_table: name={} pages={:?}", name, ids);
+            for id in ids.iter().cloned() {
+                self.fetch_mut(id)?.clear();
+                self.free_id(id);
             }
+            self.forget_edges(&ids);
 
-            {
-                let mut page = self.page_mut(id).unwrap();
-                page.clear();
-                page.put_ref(&lo_max, lo_id);
-                page.put_ref(&hi_max, hi_id);
+            let dir = self.tables.load(Ordering::Relaxed);
+            let mut page = self.fetch_mut(dir)?;
+            if let Some(idx) = page.find(name.as_bytes()) {
+                page.remove(idx);
             }
+            drop(page);
+            self.commit()
+        })?;
+        Ok(true)
+    }
 
-            Ok(())
-        } else {
-            let (copy, max) = {
-                let page = self.page(id).unwrap();
-                (page.copy(), page.max().to_vec())
-            };
-            let half = copy.len() / 2;
-            let peer_id = self.next_id()?;
-            debug!(
-                "split: page={} into peer={} (parent={})",
-                id, peer_id, parent_id
-            );
+    /// Root page id of the table of given name (if any), caller is expected to hold the tree lock.
+    fn table_root(&self, name: &[u8]) -> Result<Option<u32>> {
+        let dir = self.tables.load(Ordering::Relaxed);
+        if dir == 0 {
+            return Ok(None);
+        }
+        let page = self.fetch(dir)?;
+        page.find(name)
+            .map(|idx| dir_root(page.deref(), idx))
+            .transpose()
+    }
 
-            let page_max = {
-                let mut page = self.page_mut(id).unwrap();
-                copy.iter().skip(half).for_each(|(key, _, _)| {
-                    let idx = page.find(key).unwrap();
-                    page.remove(idx);
+    /// Allocate the root page for a new table and list it in the table directory (making the
+    /// directory page first if needed). Caller is expected to hold the tree write lock.
+    fn make_table(&self, name: &[u8]) -> Result<u32> {
+        self.writable()?;
+        if self.head.version == 0 {
+            return Err(Error::Other(
+                "Tables are not supported by format version 0".to_string(),
+            ));
+        }
+        let dir = match self.tables.load(Ordering::Relaxed) {
+            0 => {
+                // Like the free-list pages, the directory page is appended and never moves.
+                let dir = self.append_page()?;
+                self.save_tables(dir)?;
+                self.tables.store(dir, Ordering::Relaxed);
+                dir
+            }
+            dir => dir,
+        };
+        {
+            let page = self.fetch(dir)?;
+            if !page.fits((name.len() + 4) as u32) {
+                return Err(Error::TooLarge {
+                    key_len: name.len(),
+                    val_len: 4,
+                    free: page.free(),
                 });
-                page.max().to_vec()
-            };
+            }
+        }
+        let root = self.next_id()?;
+        self.fetch_mut(dir)?.put_val(name, &root.to_be_bytes());
+        self.commit()?;
+        Ok(root)
+    }
 
-            let peer_max = {
-                let mut peer = self.page_mut(peer_id).unwrap();
-                copy.iter().skip(half).for_each(|(key, val, p)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *p,
-                        id,
-                        peer_id
-                    );
-                    if *p == 0 {
-                        peer.put_val(key, val);
-                    } else {
-                        peer.put_ref(key, *p);
-                    }
-                });
-                peer.max().to_vec()
-            };
+    /// Fail with an error if the file has any tables: `op` works with the main tree only.
+    fn no_tables(&self, op: &str) -> Result<()> {
+        let dir = self.tables.load(Ordering::Relaxed);
+        if dir != 0 && !self.fetch(dir)?.is_empty() {
+            return Err(Error::Other(format!("{} is not supported with tables", op)));
+        }
+        Ok(())
+    }
 
-            {
-                let mut parent = self.page_mut(parent_id).unwrap();
-                let idx = parent.find(&max).unwrap();
-                parent.remove(idx);
-                parent.put_ref(&page_max, id);
-                parent.put_ref(&peer_max, peer_id);
+    /// Complete the bulk-load: build the node levels on top of the saved pages, bottom-up,
+    /// then make the top page the root.
+    fn bulk_finish(&self, mut loader: Loader<P>) -> Result<()> {
+        // Reference pages of the level below until it fits into a single (root) page.
+        while !loader.level.is_empty() {
+            self.bulk_next(&mut loader)?;
+            for (key, id) in std::mem::take(&mut loader.level) {
+                self.bulk_put(&mut loader, &key, &[], id, false)?;
             }
+        }
 
-            self.check(parent_id, id)?;
-            self.check(parent_id, peer_id)?;
-
-            Ok(())
+        let mut root = P::create(ROOT, self.head.page_bytes);
+        for (key, val, child, compressed) in entries(&loader.page) {
+            put_entry(&mut root, &key, &val, child, compressed);
         }
+        self.write_pages(&[&root])?;
+        self.file.write().sync_data()?;
+        self.cache.write().put(ROOT, root);
+        Ok(())
     }
 
-    fn check(&self, parent_id: u32, page_id: u32) -> Result<()> {
-        let page_max = {
-            let page = self.page(page_id).unwrap();
-            page.max().to_vec()
-        };
+    /// Put the entry into the page being bulk-loaded (see `rebuild`),
+    /// starting the next page once the current one is full enough.
+    fn bulk_put(
+        &self,
+        loader: &mut Loader<P>,
+        key: &[u8],
+        val: &[u8],
+        child: u32,
+        compressed: bool,
+    ) -> Result<()> {
+        let len = (key.len() + val.len()) as u32;
+        let page = &loader.page;
+        if !page.is_empty()
+            && (!page.fits(len) || fill(page, SPLIT_THRESHOLD) != cmp::Ordering::Less)
+        {
+            self.bulk_next(loader)?;
+        }
+        put_entry(&mut loader.page, key, val, child, compressed);
+        Ok(())
+    }
 
-        let (parent_key, parent_ref) = {
-            let parent = self.page(parent_id).unwrap();
-            let page_idx = parent.find(&page_max).unwrap();
-            let parent_key = parent.key(page_idx).to_vec();
-            let parent_ref = parent
-                .slot(page_idx)
-                .map(|slot| slot.page)
-                .unwrap_or_default();
-            (parent_key, parent_ref)
-        };
+    /// Save the page being bulk-loaded, reference it in the level and start the next page.
+    fn bulk_next(&self, loader: &mut Loader<P>) -> Result<()> {
+        self.write_pages(&[&loader.page])?;
+        let id = loader.page.id();
+        loader.level.push((loader.page.max().to_vec(), id));
+        loader.page = P::create(id + 1, self.head.page_bytes);
+        Ok(())
+    }
 
-        if parent_key != page_max {
-            log::error!(
-                "parent_key != page_max: parent_key={} page_max={}",
-                hex(&parent_key),
-                hex(&page_max)
-            );
-            return Err(Error::Tree(
-                parent_id,
-                "Parent entry key does not match child page".to_string(),
-            ));
+    /// Same as `page`, but a missing (or unreadable) page or invalid page id results in an error.
+    fn fetch(&self, id: u32) -> Result<MappedRwLockReadGuard<'_, P>> {
+        if id == 0 {
+            return Err(Error::Tree(id, "Invalid page reference".to_string()));
         }
+        let cache = self.cached(id).map_err(|source| Error::Io {
+            ctx: format!("load page {}", id),
+            source,
+        })?;
+        RwLockReadGuard::try_map(cache, |cache| cache.get(&id))
+            .map_err(|_| Error::Tree(id, "Page not found".to_string()))
+    }
 
-        if parent_ref != page_id {
-            log::error!(
-                "parent_ref != page_id: parent_ref={} page_id={}",
-                parent_ref,
-                page_id,
-            );
-            return Err(Error::Tree(
-                parent_id,
-                "Parent entry ref does not match child page".to_string(),
-            ));
+    /// Find the leaf page holding the ceil of given key (none if the key is above the max key).
+    /// Caller is expected to hold the tree lock.
+    fn seek(&self, key: &[u8]) -> Result<Option<MappedRwLockReadGuard<'_, P>>> {
+        let mut descent = Descent::default();
+        let mut page = self.fetch(self.root)?;
+        loop {
+            let idx = match page.ceil(key) {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+            let slot = page
+                .slot(idx)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Slot not found: {}", idx)))?;
+            if slot.is_val() {
+                return Ok(Some(page));
+            }
+            let id = page.id();
+            drop(page);
+            descent.step(id, slot.page)?;
+            page = self.fetch(slot.page)?;
         }
+    }
 
-        Ok(())
+    /// Cached boundary leaf page (see `edges`), unless it is no longer a non-empty leaf.
+    fn edge(&self, id: u32) -> Option<MappedRwLockReadGuard<'_, P>> {
+        if id == 0 {
+            return None;
+        }
+        let page = self.page(id)?;
+        if !page.is_empty() && page.is_leaf() {
+            Some(page)
+        } else {
+            None
+        }
     }
 
-    fn merge(&self, src_id: u32, dst_id: u32) -> Result<()> {
-        debug!("merge: src={} into dst={}", src_id, dst_id);
-        let src_copy = {
-            let page = self.page(src_id).unwrap();
-            page.copy()
+    /// Leftmost (or rightmost if `last` is set) leaf page, or none if the tree is empty.
+    /// Caller is expected to hold the tree lock.
+    fn boundary(&self, last: bool) -> Result<Option<MappedRwLockReadGuard<'_, P>>> {
+        // Handles of the same table do not share the cache, so it is only used for the file root.
+        let cached = if self.root != ROOT {
+            0
+        } else if last {
+            self.edges.read().1
+        } else {
+            self.edges.read().0
         };
-
-        {
-            let mut page = self.page_mut(dst_id).unwrap();
-            for (key, val, p) in src_copy {
-                trace!(
-                    "merge: move k={} v={} p={} from {} to {}",
-                    hex(&key),
-                    hex(&val),
-                    p,
-                    src_id,
-                    dst_id
-                );
-                if p == 0 {
-                    page.put_val(&key, &val);
-                } else {
-                    page.put_ref(&key, p);
+        if let Some(page) = self.edge(cached) {
+            return Ok(Some(page));
+        }
+        let mut descent = Descent::default();
+        let mut page = self.fetch(self.root)?;
+        if page.len() == 0 {
+            return Ok(None);
+        }
+        loop {
+            let idx = if last { page.len() - 1 } else { 0 };
+            let slot = page.slot(idx).unwrap();
+            if slot.is_val() {
+                if self.root == ROOT {
+                    let mut edges = self.edges.write();
+                    if last {
+                        edges.1 = page.id();
+                    } else {
+                        edges.0 = page.id();
+                    }
                 }
+                return Ok(Some(page));
             }
-            page.max().to_vec()
-        };
+            let id = slot.page;
+            descent.step(page.id(), id)?;
+            drop(page);
+            page = self.fetch(id)?;
+        }
+    }
 
-        {
-            let mut page = self.page_mut(src_id).unwrap();
-            page.clear();
+    /// Find the smallest key strictly greater than given one and `pick` the result from its
+    /// leaf page (by slot index), see `Store::above`. Caller is expected to hold the tree lock.
+    fn successor<T, F>(&self, key: &[u8], pick: F) -> Result<Option<T>>
+    where
+        F: Fn(&P, u32) -> Result<T>,
+    {
+        let mut descent = Descent::default();
+        let mut path = Vec::with_capacity(8);
+        let mut page = self.fetch(self.root)?;
+        if page.len() == 0 {
+            return Ok(None);
+        }
+        if page.max() < key {
+            return Ok(None);
+        }
+        loop {
+            let idx = page
+                .ceil(key)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Ceil not found: {}", hex(key))))?;
+            let slot = page
+                .slot(idx)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Slot not found: {}", idx)))?;
+            if slot.is_val() {
+                return if key < page.key(idx) {
+                    pick(&page, idx).map(Some)
+                } else if key == page.key(idx) && idx < page.len() - 1 {
+                    pick(&page, idx + 1).map(Some)
+                } else {
+                    drop(page);
+                    // ceil == key, need to take min value from parent's next adjacent subtree
+                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
+                        let parent = self.fetch(parent_id)?;
+                        if parent_idx + 1 < parent.len() {
+                            let mut id = parent
+                                .slot(parent_idx + 1)
+                                .map(|slot| slot.page)
+                                .unwrap_or_default();
+                            drop(parent);
+                            descent.step(parent_id, id)?;
+                            loop {
+                                let page = self.fetch(id)?;
+                                let slot = page
+                                    .slot(0)
+                                    .ok_or_else(|| Error::Tree(id, "Page is empty".to_string()))?;
+                                if slot.is_val() {
+                                    return pick(&page, 0).map(Some);
+                                }
+                                descent.step(id, slot.page)?;
+                                id = slot.page;
+                            }
+                        }
+                    }
+
+                    // the key appears to be the maximum value stored in the tree
+                    debug_assert!(
+                        self.root().max() <= key,
+                        "above: no successor found for a key below the max: {}",
+                        hex(key)
+                    );
+                    Ok(None)
+                };
+            } else {
+                path.push((page.id(), idx));
+                let id = slot.page;
+                descent.step(page.id(), id)?;
+                drop(page);
+                page = self.fetch(id)?;
+            }
+        }
+    }
+
+    /// Find the biggest key strictly lesser than given one and `pick` the result from its
+    /// leaf page (by slot index), see `Store::below`. Caller is expected to hold the tree lock.
+    fn predecessor<T, F>(&self, key: &[u8], pick: F) -> Result<Option<T>>
+    where
+        F: Fn(&P, u32) -> Result<T>,
+    {
+        let mut descent = Descent::default();
+        let mut path = Vec::with_capacity(8);
+        let mut page = self.fetch(self.root)?;
+        if page.len() == 0 {
+            return Ok(None);
+        }
+        if page.max() < key {
+            // The max key is the answer, kept in the rightmost leaf (the root might be a node).
+            drop(page);
+            return match self.boundary(true)? {
+                Some(page) => pick(&page, page.len() - 1).map(Some),
+                None => Ok(None),
+            };
+        }
+        loop {
+            let idx = page
+                .ceil(key)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Ceil not found: {}", hex(key))))?;
+            let slot = page
+                .slot(idx)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Slot not found: {}", idx)))?;
+            if slot.is_val() {
+                // The ceil is above the previous key (if any), which is thus the answer.
+                return if idx > 0 {
+                    pick(&page, idx - 1).map(Some)
+                } else {
+                    drop(page);
+                    // Ceil is the leaf's min, need to take max value from parent's previous
+                    // adjacent subtree (none for a single-leaf tree: path is empty).
+                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
+                        let parent = self.fetch(parent_id)?;
+                        if parent_idx > 0 {
+                            let mut id = parent
+                                .slot(parent_idx - 1)
+                                .map(|slot| slot.page)
+                                .unwrap_or_default();
+                            drop(parent);
+                            descent.step(parent_id, id)?;
+                            loop {
+                                let page = self.fetch(id)?;
+                                let idx = page
+                                    .len()
+                                    .checked_sub(1)
+                                    .ok_or_else(|| Error::Tree(id, "Page is empty".to_string()))?;
+                                let slot = page
+                                    .slot(idx)
+                                    .ok_or_else(|| Error::Tree(id, "Page is empty".to_string()))?;
+                                if slot.is_val() {
+                                    return pick(&page, idx).map(Some);
+                                }
+                                descent.step(id, slot.page)?;
+                                id = slot.page;
+                            }
+                        }
+                    }
+
+                    // the key seems to be the minimum value stored in the tree
+                    Ok(None)
+                };
+            } else {
+                path.push((page.id(), idx));
+                let id = slot.page;
+                descent.step(page.id(), id)?;
+                drop(page);
+                page = self.fetch(id)?;
+            }
+        }
+    }
+
+    /// Reset cached boundary leaf pages if any of them is among the given pages.
+    fn forget_edges(&self, ids: &[u32]) {
+        let mut edges = self.edges.write();
+        if ids.contains(&edges.0) {
+            edges.0 = 0;
+        }
+        if ids.contains(&edges.1) {
+            edges.1 = 0;
+        }
+    }
+
+    /// Find the leaf page and the slot index holding given key (if any).
+    /// Caller is expected to hold the tree lock.
+    fn leaf(&self, key: &[u8]) -> Result<Option<(MappedRwLockReadGuard<'_, P>, u32)>> {
+        debug!("lookup: {}", hex(key));
+        Ok(self
+            .seek(key)?
+            .and_then(|page| page.find(key).map(|idx| (page, idx))))
+    }
+
+    /// Visit entries of the leaf page holding the ceil of given key, starting from the ceil.
+    /// Returns the max key of the leaf if all its entries were visited, or none if `f` asked
+    /// to stop (or there is nothing to visit). Unless `vals` is set, `f` gets empty values.
+    fn scan_leaf<F>(&self, key: &[u8], vals: bool, f: &mut F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let _lock = self.lock.read();
+        let page = match self.seek(key)? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let idx = page
+            .ceil(key)
+            .ok_or_else(|| Error::Tree(page.id(), format!("Ceil not found: {}", hex(key))))?;
+        for i in idx..page.len() {
+            let proceed = if !vals {
+                f(page.key(i), &[])
+            } else if page.is_compressed(i) {
+                f(page.key(i), &compress::decompress(page.val(i))?)
+            } else {
+                f(page.key(i), page.val(i))
+            };
+            if !proceed {
+                return Ok(None);
+            }
+        }
+        Ok(Some(page.max().to_vec()))
+    }
+
+    /// Count entries with keys in range `[lo, hi)`, see `Store::count_range`. Also returns
+    /// the number of keys compared to `hi` one by one (only the keys of the last leaf).
+    fn count_range_with(&self, lo: &[u8], hi: &[u8]) -> Result<(usize, usize)> {
+        let (mut count, mut examined) = (0, 0);
+        if lo >= hi {
+            return Ok((count, examined));
+        }
+        let mut next = Some(lo.to_vec());
+        while let Some(key) = next {
+            let last = {
+                let _lock = self.lock.read();
+                let page = match self.seek(&key)? {
+                    Some(page) => page,
+                    None => break,
+                };
+                let idx = page.ceil(&key).ok_or_else(|| {
+                    Error::Tree(page.id(), format!("Ceil not found: {}", hex(&key)))
+                })?;
+                if page.max() < hi {
+                    // The rest of the leaf is inside the range: no need to look at the keys.
+                    count += (page.len() - idx) as usize;
+                    page.max().to_vec()
+                } else {
+                    for i in idx..page.len() {
+                        examined += 1;
+                        if page.key(i) >= hi {
+                            break;
+                        }
+                        count += 1;
+                    }
+                    break;
+                }
+            };
+            // No lock is held between leaves: continue from the successor of the last key.
+            next = self.above(&last)?;
+        }
+        Ok((count, examined))
+    }
+
+    /// Estimate the number of entries in range `[lo, hi)`, see `Store::estimate_range`.
+    /// Node pages over the range are visited level by level, but of the leaves only the two
+    /// on the range boundaries are read: the leaves between them are assumed to hold as many
+    /// entries as those two on average.
+    fn estimate(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        if lo >= hi {
+            return Ok(0);
+        }
+        let _lock = self.lock.read();
+        let (level, _) = self.range_leaves(lo, hi)?;
+        if level.is_empty() {
+            return Ok(0);
+        }
+
+        // Only one page guard at a time: loading a page locks the whole cache.
+        let bounds = |id: u32| -> Result<(usize, usize, usize)> {
+            let page = self.fetch(id)?;
+            let n = page.len();
+            let from = page.ceil(lo).unwrap_or(n);
+            let to = page.ceil(hi).unwrap_or(n);
+            Ok((n as usize, from as usize, to as usize))
+        };
+        let (first_len, from, to) = bounds(level[0])?;
+        if level.len() == 1 {
+            return Ok(to.saturating_sub(from));
+        }
+        let (last_len, _, to) = bounds(level[level.len() - 1])?;
+        let inner = (level.len() - 2) * (first_len + last_len) / 2;
+        Ok(first_len - from + inner + to)
+    }
+
+    /// Leaf pages over range `[lo, hi)` in key order, found by visiting node pages level by
+    /// level, along with the keys the lowest node level references them by (the max key of each
+    /// leaf, none if the root is a leaf). Leaves themselves are not read, except the first one
+    /// to tell that the level below is made of leaves. Caller is expected to hold the tree lock.
+    fn range_leaves(&self, lo: &[u8], hi: &[u8]) -> Result<(Vec<u32>, Vec<Vec<u8>>)> {
+        let mut level = vec![self.root];
+        let mut keys = Vec::new();
+        while self.fetch(level[0])?.is_node() {
+            let mut next = Vec::with_capacity(level.len());
+            keys.clear();
+            for (i, id) in level.iter().enumerate() {
+                let page = self.fetch(*id)?;
+                // The key of a child is the max key of its subtree.
+                let from = if i == 0 {
+                    match page.ceil(lo) {
+                        Some(idx) => idx,
+                        None => return Ok((Vec::new(), Vec::new())),
+                    }
+                } else {
+                    0
+                };
+                let to = if i + 1 == level.len() {
+                    page.ceil(hi).unwrap_or(page.len() - 1)
+                } else {
+                    page.len() - 1
+                };
+                for idx in from..=to {
+                    let slot = page.slot(idx).ok_or_else(|| {
+                        Error::Tree(page.id(), format!("Slot not found: {}", idx))
+                    })?;
+                    next.push(slot.page);
+                    keys.push(page.key(idx).to_vec());
+                }
+            }
+            level = next;
+        }
+        Ok((level, keys))
+    }
+
+    /// Get the entry for given key for in-place insert-or-modify (like `std` maps).
+    /// The tree write lock is held until the entry is dropped, so the update is atomic.
+    pub fn entry(&self, key: &[u8]) -> Result<Entry<'_, P>> {
+        debug!("entry: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let lock = self.lock.write();
+        let val = self.find_value(key)?;
+        Ok(Entry::new(self, lock, key, val))
+    }
+
+    /// Rebalance the page of given id (see `Tree::rebalance`) under the tree write lock.
+    pub fn rebalance_page(&self, id: u32, parent_id: u32) -> Result<()> {
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.rebalance(id, parent_id)
+    }
+
+    /// Look up the value along with the ids of the pages visited from the root down to the leaf
+    /// (for diagnostics: tree depth per key, skewed subtrees). For an absent key the path ends
+    /// at the leaf the key would be inserted into.
+    pub fn lookup_path(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Vec<u32>)> {
+        Self::check_key(key)?;
+        let _lock = self.lock.read();
+        let mut descent = Descent::default();
+        let mut path = vec![self.root];
+        let mut page = self.fetch(self.root)?;
+        loop {
+            // Past the max key the path follows the last child, same as `insert` does.
+            let idx = match page.ceil(key) {
+                Some(idx) => idx,
+                None if page.is_empty() => return Ok((None, path)),
+                None => page.len() - 1,
+            };
+            let slot = page
+                .slot(idx)
+                .ok_or_else(|| Error::Tree(page.id(), format!("Slot not found: {}", idx)))?;
+            if slot.is_val() {
+                let val = if page.key(idx) == key {
+                    Some(value(page.deref(), idx)?)
+                } else {
+                    None
+                };
+                return Ok((val, path));
+            }
+            let id = page.id();
+            drop(page);
+            descent.step(id, slot.page)?;
+            path.push(slot.page);
+            page = self.fetch(slot.page)?;
+        }
+    }
+
+    /// Page-level work done by the most recent mutation (`insert`, `remove`, `update`,
+    /// `compare_and_swap` or `remove_range`). Pages loaded by concurrent reads are included.
+    pub fn last_op_pages(&self) -> OpPages {
+        *self.last_op.read()
+    }
+
+    /// Run the mutation and record the page-level work it did, see `last_op_pages`.
+    fn track<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let before = self.counters.snapshot();
+        let result = f();
+        let after = self.counters.snapshot();
+        *self.last_op.write() = OpPages {
+            loaded: after.loaded - before.loaded,
+            saved: after.saved - before.saved,
+            allocated: after.allocated - before.allocated,
+            split: after.split - before.split,
+            merged: after.merged - before.merged,
+        };
+        result
+    }
+
+    /// Remove all entries with keys in range `[lo, hi)` (up to the end if `hi` is none),
+    /// see `Store::remove_range`. Leaves are visited once each: the keys of a leaf are removed
+    /// in place except the last one, which is removed by `del_dirty` to merge the leaf (or free
+    /// it, if the leaf falls entirely inside the range). Dirty pages are committed once.
+    fn remove_from(&self, lo: &[u8], hi: Option<&[u8]>) -> Result<usize> {
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            let mut count = 0;
+            let mut from = lo.to_vec();
+            loop {
+                let (id, keys, done) = {
+                    let page = match self.seek(&from)? {
+                        Some(page) => page,
+                        None => break,
+                    };
+                    let idx = page.ceil(&from).unwrap_or_default();
+                    let mut keys = Vec::new();
+                    let mut done = false;
+                    for idx in idx..page.len() {
+                        let key = page.key(idx);
+                        if hi.is_some_and(|hi| key >= hi) {
+                            done = true;
+                            break;
+                        }
+                        keys.push(key.to_vec());
+                    }
+                    (page.id(), keys, done)
+                };
+                let last = match keys.last() {
+                    Some(last) => last.clone(),
+                    None => break,
+                };
+                {
+                    let mut page = self.fetch_mut(id)?;
+                    for key in keys.iter().take(keys.len() - 1) {
+                        if let Some(idx) = page.find(key) {
+                            page.remove(idx);
+                        }
+                    }
+                }
+                for key in keys.iter().take(keys.len() - 1) {
+                    self.log(Op::Remove(key.clone()))?;
+                }
+                self.del_dirty(&last)?;
+                count += keys.len();
+                if done {
+                    break;
+                }
+                // The least key above the last removed one.
+                from = last;
+                from.push(0);
+            }
+            self.commit()?;
+            Ok(count)
+        })
+    }
+
+    /// Empty keys are not supported: zero key length is reserved for empty slots.
+    fn check_key(key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            Err(Error::Other("empty key".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as `Store::scan`, but unless `vals` is set `f` gets empty values
+    /// (so values are neither copied nor decompressed).
+    fn scan_with<F>(&self, from: Option<&[u8]>, vals: bool, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let mut next = match from {
+            Some(key) => Some(key.to_vec()),
+            None => self.min()?,
+        };
+        while let Some(key) = next {
+            // No lock is held between leaves: continue from the successor of the last visited key.
+            next = match self.scan_leaf(&key, vals, &mut f)? {
+                Some(last) => self.above(&last)?,
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.readonly {
+            Err(Error::Other("read-only".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Look up the keys visiting them in the given order, which must be ascending by key:
+    /// each leaf page is found once and serves all following keys up to its max key.
+    /// Caller is expected to hold the tree lock.
+    fn lookup_ordered(
+        &self,
+        keys: &[&[u8]],
+        order: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut result = vec![None; keys.len()];
+        let mut leaf: Option<MappedRwLockReadGuard<'_, P>> = None;
+        for i in order {
+            let key = keys[i];
+            // Keys are sorted, so the current leaf holds the key if it is not above the leaf max.
+            if leaf.as_ref().map(|page| key > page.max()).unwrap_or(true) {
+                // Release the page first: a cache miss during seek needs the cache write lock.
+                drop(leaf.take());
+                leaf = self.seek(key)?;
+                if leaf.is_none() {
+                    break; // this key and all following ones are above the max key
+                }
+            }
+            if let Some(page) = leaf.as_ref() {
+                if let Some(idx) = page.find(key) {
+                    result[i] = Some(value(page.deref(), idx)?);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Get the (decompressed) value, caller is expected to hold the tree lock.
+    fn find_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.leaf(key)? {
+            Some((page, idx)) => Ok(Some(value(page.deref(), idx)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Insert or replace the entry, caller is expected to hold the tree write lock.
+    pub(crate) fn put(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.put_with(key, val, true).map(|_| ())
+    }
+
+    /// Same as `put`, but unless `replace` is set the existing value is kept (nothing is written).
+    /// Returns true if the entry was inserted (or replaced). Caller is expected to hold the lock.
+    fn put_with(&self, key: &[u8], val: &[u8], replace: bool) -> Result<bool> {
+        let done = self.put_dirty(key, val, replace)?;
+        if done {
+            self.commit()?;
+        }
+        Ok(done)
+    }
+
+    /// Same as `put_with`, but the dirty pages are left for the caller to `commit`.
+    fn put_dirty(&self, key: &[u8], val: &[u8], replace: bool) -> Result<bool> {
+        let done = self.put_tree(key, val, replace)?;
+        if done {
+            self.log(Op::Insert(key.to_vec(), val.to_vec()))?;
+        }
+        Ok(done)
+    }
+
+    /// Same as `put_dirty`, but the mutation is not logged (see `with_oplog`).
+    fn put_tree(&self, key: &[u8], val: &[u8], replace: bool) -> Result<bool> {
+        let (val, compressed) = self.pack(val)?;
+        let val = val.as_ref();
+        let mut page = self.root_mut();
+        let mut descent = Descent::default();
+        let mut path = Vec::with_capacity(8);
+        loop {
+            let id = page.id();
+            let parent_id = path.last().cloned().map(|(id, _)| id).unwrap_or_default();
+
+            if page.len() == 0 {
+                // TODO handle keys/values larger than (half-) page size
+                let len = (key.len() + val.len()) as u32;
+                if !page.fits(len) {
+                    return Err(Error::TooLarge {
+                        key_len: key.len(),
+                        val_len: val.len(),
+                        free: page.free(),
+                    });
+                }
+                put_entry(page.deref_mut(), key, val, 0, compressed);
+                return Ok(true);
+            }
+
+            let idx = page.ceil(key).unwrap_or_else(|| page.len() - 1);
+
+            drop(page);
+            if let Some((parent_id, parent_idx)) = path.last().cloned() {
+                let mut parent_page = self.page_mut(parent_id).unwrap();
+                let parent_key = parent_page.key(parent_idx);
+                if key > parent_key {
+                    parent_page.remove(parent_idx);
+                    parent_page.put_ref(key, id);
+                    drop(parent_page);
+                }
+            }
+            page = self.page_mut(id).unwrap();
+
+            let slot_opt = page.slot(idx);
+            if slot_opt.is_none() {
+                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+            }
+            let slot = slot_opt.unwrap();
+
+            if slot.is_val() {
+                // The key is present only in this leaf, and parent keys have not been changed
+                // on the way down (a parent key only changes for a key above all its keys).
+                if !replace && page.find(key).is_some() {
+                    return Ok(false);
+                }
+                let len = (key.len() + val.len()) as u32;
+                if !page.fits(len) {
+                    // TODO handle keys/values larger than (half-) page size
+                    return Err(Error::TooLarge {
+                        key_len: key.len(),
+                        val_len: val.len(),
+                        free: page.free(),
+                    });
+                }
+                put_entry(page.deref_mut(), key, val, 0, compressed);
+                drop(page);
+                self.split_over(id, parent_id)?;
+
+                while let Some((page_id, _)) = path.pop() {
+                    let (parent_id, _) = path.last().cloned().unwrap_or_default();
+                    self.split_over(page_id, parent_id)?;
+                }
+                return Ok(true);
+            } else {
+                path.push((id, idx));
+                descent.step(id, slot.page)?;
+
+                drop(page);
+                page = self.fetch_mut(slot.page)?;
+            }
+        }
+    }
+
+    /// Remove the entry (if any), caller is expected to hold the tree write lock.
+    /// Returns true if the entry was found and removed.
+    pub(crate) fn del(&self, key: &[u8]) -> Result<bool> {
+        let done = self.del_dirty(key)?;
+        if done {
+            self.commit()?;
+        }
+        Ok(done)
+    }
+
+    /// Same as `del`, but the dirty pages are left for the caller to `commit`.
+    fn del_dirty(&self, key: &[u8]) -> Result<bool> {
+        let done = self.del_tree(key)?;
+        if done {
+            self.log(Op::Remove(key.to_vec()))?;
+        }
+        Ok(done)
+    }
+
+    /// Same as `del_dirty`, but the mutation is not logged (see `with_oplog`).
+    fn del_tree(&self, key: &[u8]) -> Result<bool> {
+        let mut page = self.root_mut();
+        let mut descent = Descent::default();
+        let mut path = Vec::with_capacity(8);
+        loop {
+            let idx_opt = page.ceil(key);
+            if idx_opt.is_none() {
+                return Ok(false);
+            }
+            let idx = idx_opt.unwrap();
+
+            let slot_opt = page.slot(idx);
+            if slot_opt.is_none() {
+                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+            }
+            let slot = slot_opt.unwrap();
+
+            let id = page.id();
+            if slot.is_val() {
+                if page.key(idx) != key {
+                    return Ok(false);
+                }
+                debug!("remove: key={} page={} idx={}", hex(key), id, idx);
+                page.remove(idx);
+                drop(page);
+
+                // Navigate up-tree and remove/update references if needed
+                let mut page_id = id;
+                for (parent_id, mut idx) in path.iter().cloned().rev() {
+                    if let Some(peer_id) = self.merge_under(page_id, parent_id, idx)? {
+                        let page_max = {
+                            let peer = self.page(peer_id).unwrap();
+                            peer.max().to_vec()
+                        };
+                        idx = self.page(parent_id).unwrap().ceil(&page_max).unwrap();
+                        page_id = peer_id;
+                    }
+
+                    let max_opt = {
+                        let page = self.page(page_id).unwrap();
+                        if page.len() > 0 {
+                            Some(page.max().to_vec())
+                        } else {
+                            None
+                        }
+                    };
+
+                    let mut parent = self.page_mut(parent_id).unwrap();
+                    if let Some(max) = max_opt.as_ref() {
+                        if max.as_slice() < parent.key(idx) {
+                            parent.remove(idx);
+                            parent.put_ref(max, page_id);
+                        }
+                    } else {
+                        parent.remove(idx);
+                    }
+                    drop(parent);
+                    if max_opt.is_none() {
+                        // Nothing to merge the empty page into (peers are not under-full),
+                        // and it is no longer referenced by the parent: free it.
+                        self.forget_edges(&[page_id]);
+                        self.free_id(page_id);
+                    }
+                    page_id = parent_id;
+                }
+
+                return Ok(true);
+            } else {
+                path.push((id, idx));
+                descent.step(id, slot.page)?;
+                drop(page);
+
+                page = self.fetch_mut(slot.page)?;
+            }
+        }
+    }
+
+    /// Split the page if it is over the split threshold, caller is expected to hold the tree
+    /// write lock. Returns true if the page was split.
+    fn split_over(&self, id: u32, parent_id: u32) -> Result<bool> {
+        let over = {
+            let page = self.fetch(id)?;
+            fill(page.deref(), SPLIT_THRESHOLD) == cmp::Ordering::Greater
+        };
+        if over {
+            self.split(id, parent_id)?;
+        }
+        Ok(over)
+    }
+
+    /// Merge the page referenced by slot `idx` of the parent page into the least used peer,
+    /// if both are under the merge threshold. Caller is expected to hold the tree write lock.
+    /// Returns the id of the peer the page was merged into, if any.
+    fn merge_under(&self, page_id: u32, parent_id: u32, idx: u32) -> Result<Option<u32>> {
+        let under = {
+            let page = self.fetch(page_id)?;
+            fill(page.deref(), MERGE_THRESHOLD)
+        };
+        if under != cmp::Ordering::Less {
+            return Ok(None);
+        }
+        let peer_id = {
+            let parent = self.page(parent_id).unwrap();
+            let mut peers = Vec::with_capacity(2);
+            if idx > 0 {
+                let peer = parent.slot(idx - 1).unwrap().page;
+                peers.push(peer);
+            }
+            if idx < parent.len() - 1 {
+                let peer = parent.slot(idx + 1).unwrap().page;
+                peers.push(peer);
+            }
+            drop(parent);
+
+            peers
+                .into_iter()
+                .filter_map(|peer_id| {
+                    let peer = self.page(peer_id).unwrap();
+                    let under = fill(peer.deref(), MERGE_THRESHOLD);
+                    if peer.len() > 0 && under == cmp::Ordering::Less {
+                        Some((peer_id, peer.usable() - peer.free()))
+                    } else {
+                        None
+                    }
+                })
+                .min_by_key(|(_, used)| *used)
+                .map(|(peer_id, _)| peer_id)
+        };
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id,
+            None => return Ok(None),
+        };
+        trace!(
+            "merge: found peer_id={} to merge page_id={} (parent_id={})",
+            peer_id,
+            page_id,
+            parent_id
+        );
+        let peer_max = {
+            let peer = self.page(peer_id).unwrap();
+            peer.max().to_vec()
+        };
+        trace!("\t merge: peer_max={}", hex(&peer_max));
+        let mut parent = self.page_mut(parent_id).unwrap();
+        parent.remove(idx);
+        let peer_idx = parent.ceil(&peer_max).unwrap();
+        trace!("\t merge: parent remove: peer_idx={} idx={}", peer_idx, idx);
+        parent.remove(peer_idx);
+        drop(parent);
+
+        self.merge(page_id, peer_id)?;
+        let page_max = {
+            let peer = self.page(peer_id).unwrap();
+            peer.max().to_vec()
+        };
+        trace!("\t merge: page_max={}", hex(&page_max));
+        let mut parent = self.page_mut(parent_id).unwrap();
+        trace!(
+            "\t merge: parent insert: page_max={}, peer_id={}",
+            hex(&page_max),
+            peer_id
+        );
+        parent.put_ref(&page_max, peer_id);
+        Ok(Some(peer_id))
+    }
+
+    /// Compress the value if compression is enabled and it actually makes the value smaller.
+    fn pack<'a>(&self, val: &'a [u8]) -> Result<(Cow<'a, [u8]>, bool)> {
+        if self.head.flags & FLAG_ZSTD == 0 || val.len() < compress::MIN_LEN {
+            return Ok((Cow::Borrowed(val), false));
+        }
+        let packed = compress::compress(val)?;
+        if packed.len() < val.len() {
+            Ok((Cow::Owned(packed), true))
+        } else {
+            Ok((Cow::Borrowed(val), false))
+        }
+    }
+
+    /// Copy of the page: the cached one if any (it might be dirty), otherwise read from the file
+    /// without caching it. None if the cache is locked for writing (or the page is unreadable).
+    fn peek(&self, id: u32) -> Option<P> {
+        let cached = {
+            let cache = self.cache.try_read()?;
+            cache.get(&id).map(|page| {
+                let mut copy = P::reserve(self.head.page_bytes);
+                copy.as_mut().copy_from_slice(page.as_ref());
+                copy
+            })
+        };
+        match cached {
+            Some(page) => Some(page),
+            None => self.load(id).ok(),
+        }
+    }
+
+    /// Page count, entry count and height of the tree for `Debug`, see `peek`.
+    /// Caller is expected to hold the tree lock.
+    fn summary(&self) -> Option<(u32, u64, u32)> {
+        let file_bytes = self.file.try_read()?.len().ok()?;
+        let page_count =
+            ((file_bytes - self.head.size() as u64) / self.head.page_bytes as u64) as u32;
+        let mut entry_count = 0u64;
+        let mut height = 0;
+        let mut seen = HashSet::new();
+        let mut stack = vec![(self.root, 1)];
+        while let Some((id, depth)) = stack.pop() {
+            let page = self.peek(id)?;
+            height = height.max(depth);
+            for idx in 0..page.len() {
+                let slot = page.slot(idx)?;
+                if slot.is_ref() && seen.insert(slot.page) {
+                    stack.push((slot.page, depth + 1));
+                } else if slot.is_val() {
+                    entry_count += 1;
+                }
+            }
+        }
+        Some((page_count, entry_count, height))
+    }
+
+    fn load(&self, id: u32) -> io::Result<P> {
+        let offset = self.offset(id)?;
+        let mut page = P::reserve(self.head.page_bytes);
+        self.counters.loaded.fetch_add(1, Ordering::Relaxed);
+        // Positioned read does not move the file cursor, so shared access is enough.
+        #[cfg(any(unix, windows))]
+        self.file.read().read_at(page.as_mut(), offset)?;
+        #[cfg(not(any(unix, windows)))]
+        self.file.write().read_at(page.as_mut(), offset)?;
+        debug!("Loading page {}", page.id());
+        Ok(page)
+    }
+
+    /// Read `count` consecutive pages starting from page `id` with a single read.
+    fn load_batch(&self, id: u32, count: u32) -> io::Result<Vec<P>> {
+        let offset = self.offset(id)?;
+        let page_bytes = self.head.page_bytes as usize;
+        let mut buf = vec![0u8; page_bytes * count as usize];
+        self.counters
+            .loaded
+            .fetch_add(count as u64, Ordering::Relaxed);
+        #[cfg(any(unix, windows))]
+        self.file.read().read_at(&mut buf, offset)?;
+        #[cfg(not(any(unix, windows)))]
+        self.file.write().read_at(&mut buf, offset)?;
+        Ok(buf
+            .chunks(page_bytes)
+            .map(|chunk| {
+                let mut page = P::reserve(self.head.page_bytes);
+                page.as_mut().copy_from_slice(chunk);
+                page
+            })
+            .collect())
+    }
+
+    fn save(&self, page: &P) -> io::Result<()> {
+        self.counters.saved.fetch_add(1, Ordering::Relaxed);
+        debug!("Saving page {}", page.id());
+        let offset = self.offset(page.id())?;
+        self.file.write().write_at(page.as_ref(), offset)
+    }
+
+    /// Make sure the page of given id is cached (loading it on a miss) and return the cache
+    /// locked, so that the page can not be evicted by another thread before it is used.
+    fn cached(&self, id: u32) -> io::Result<RwLockReadGuard<'_, PageCache<P>>> {
+        {
+            let cache = self.cache.read();
+            if cache.has(&id) {
+                metrics::count(metrics::CACHE_HITS);
+                return Ok(cache);
+            }
+        }
+        self.load_cached(id).map(RwLockWriteGuard::downgrade)
+    }
+
+    /// Same as `cached`, but the cache is locked for writing.
+    fn cached_mut(&self, id: u32) -> io::Result<RwLockWriteGuard<'_, PageCache<P>>> {
+        {
+            let cache = self.cache.write();
+            if cache.has(&id) {
+                metrics::count(metrics::CACHE_HITS);
+                return Ok(cache);
+            }
+        }
+        self.load_cached(id)
+    }
+
+    /// Put the page of given id into the cache (unless another thread did it meanwhile),
+    /// taking a dirty page evicted while the WAL is enabled from `spill` instead of the file.
+    /// The page is read without holding the cache lock, the lock is taken only to put it.
+    fn load_cached(&self, id: u32) -> io::Result<RwLockWriteGuard<'_, PageCache<P>>> {
+        metrics::count(metrics::CACHE_MISSES);
+        let loaded = if self.spill.read().contains_key(&id) {
+            None
+        } else {
+            Some(self.load(id)?)
+        };
+        let mut cache = self.cache.write();
+        if cache.has(&id) {
+            return Ok(cache);
+        }
+        // Pages are spilled (and taken back) only under the cache lock.
+        let page = match (self.spill.write().remove(&id), loaded) {
+            (Some(page), _) | (None, Some(page)) => page,
+            (None, None) => self.load(id)?,
+        };
+        if let Some((evicted_id, evicted_page)) = cache.put(id, page) {
+            if self.wal.is_none() {
+                if self.dirty.write().remove(&evicted_id) {
+                    self.save(&evicted_page)?;
+                }
+            } else if self.dirty.read().contains(&evicted_id) {
+                self.spill.write().insert(evicted_id, evicted_page);
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Write the pages in place. With the WAL enabled, the pages are appended to the log (and
+    /// committed) first, so the file never holds only some of them once the log is replayed.
+    fn write_pages(&self, pages: &[&P]) -> Result<()> {
+        let mut wal = self.wal.as_ref().map(|wal| wal.write());
+        if let Some(wal) = wal.as_mut() {
+            wal.append(pages.iter().map(|page| (page.id(), page.as_ref())))
+                .map_err(|source| Error::Io {
+                    ctx: "append to WAL".to_string(),
+                    source,
+                })?;
+        }
+        for page in pages {
+            self.save(page).map_err(|source| Error::Io {
+                ctx: format!("save page {}", page.id()),
+                source,
+            })?;
+        }
+        if let Some(wal) = wal.as_mut() {
+            self.file.write().sync_data()?;
+            wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    fn offset(&self, id: u32) -> io::Result<u64> {
+        self.head.offset(id)
+    }
+}
+
+impl<P: Page> Store for File<P> {
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _timer = metrics::op("lookup");
+        Self::check_key(key)?;
+        let _lock = self.lock.read();
+        self.find_value(key)
+    }
+
+    /// Keys are looked up in sorted order, so keys falling into the same leaf page are found
+    /// without descending from the root.
+    fn get_multi(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        for key in keys {
+            Self::check_key(key)?;
+        }
+        let _lock = self.lock.read();
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| keys[*i]);
+        self.lookup_ordered(keys, order)
+    }
+
+    /// Single left-to-right pass, no leaf page is visited twice.
+    fn lookup_sorted(&self, sorted_keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        for key in sorted_keys {
+            Self::check_key(key)?;
+        }
+        if cfg!(debug_assertions) {
+            if let Some(pair) = sorted_keys.windows(2).find(|pair| pair[0] > pair[1]) {
+                return Err(Error::Other(format!(
+                    "keys are not sorted: {} > {}",
+                    hex(pair[0]),
+                    hex(pair[1])
+                )));
+            }
+        }
+        let _lock = self.lock.read();
+        self.lookup_ordered(sorted_keys, 0..sorted_keys.len())
+    }
+
+    fn get_ref(&self, key: &[u8]) -> Result<Option<MappedRwLockReadGuard<'_, [u8]>>> {
+        Self::check_key(key)?;
+        let _lock = self.lock.read();
+        match self.leaf(key)? {
+            Some((page, idx)) if page.is_compressed(idx) => Err(Error::Tree(
+                page.id(),
+                "Value is compressed and cannot be borrowed, use lookup".to_string(),
+            )),
+            Some((page, idx)) => Ok(Some(MappedRwLockReadGuard::map(page, |page| page.val(idx)))),
+            None => Ok(None),
+        }
+    }
+
+    fn value_len(&self, key: &[u8]) -> Result<Option<u32>> {
+        Self::check_key(key)?;
+        let _lock = self.lock.read();
+        match self.leaf(key)? {
+            Some((page, idx)) if page.is_compressed(idx) => {
+                Ok(Some(compress::decompressed_len(page.val(idx))? as u32))
+            }
+            Some((page, idx)) => Ok(page.slot(idx).map(|slot| slot.vlen)),
+            None => Ok(None),
+        }
+    }
+
+    fn lookup_cow(&self, key: &[u8]) -> Result<Option<ValueRef<'_>>> {
+        Self::check_key(key)?;
+        let _lock = self.lock.read();
+        match self.leaf(key)? {
+            Some((page, idx)) if page.is_compressed(idx) => {
+                Ok(Some(ValueRef::Owned(compress::decompress(page.val(idx))?)))
+            }
+            Some((page, idx)) => Ok(Some(ValueRef::Borrowed(MappedRwLockReadGuard::map(
+                page,
+                |page| page.val(idx),
+            )))),
+            None => Ok(None),
+        }
+    }
+
+    fn with_value_mut<F>(&self, key: &[u8], f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        debug!("with_value_mut: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            let (id, idx) = match self.leaf(key)? {
+                Some((page, idx)) if page.is_compressed(idx) => {
+                    return Err(Error::Tree(
+                        page.id(),
+                        "Value is compressed and cannot be modified in place".to_string(),
+                    ))
+                }
+                Some((page, idx)) => (page.id(), idx),
+                None => return Ok(false),
+            };
+            let mut page = self.fetch_mut(id)?;
+            f(page.val_mut(idx));
+            let val = page.val(idx).to_vec();
+            drop(page);
+            self.log(Op::Insert(key.to_vec(), val))?;
+            self.commit()?;
+            Ok(true)
+        })
+    }
+
+    fn insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let _timer = metrics::op("insert");
+        debug!("insert: {} -> {}", hex(key), hex(val));
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| self.put(key, val))
+    }
+
+    fn extend<I>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            for (key, val) in iter {
+                if let Err(e) = Self::check_key(&key).and_then(|_| self.put_dirty(&key, &val, true))
+                {
+                    self.commit()?;
+                    return Err(Error::Other(format!("key {}: {}", hex(&key), e)));
+                }
+            }
+            self.commit()
+        })
+    }
+
+    fn put_if_absent(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| self.put_with(key, val, false))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        let _timer = metrics::op("remove");
+        debug!("remove: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| self.del(key).map(|_| ()))
+    }
+
+    fn remove_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        debug!("remove_range: {}..{}", hex(lo), hex(hi));
+        self.remove_from(lo, Some(hi))
+    }
+
+    fn remove_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        debug!("remove_prefix: {}", hex(prefix));
+        self.remove_from(prefix, successor(prefix).as_deref())
+    }
+
+    fn retain<F>(&self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.writable()?;
+        let mut keys = Vec::new();
+        self.scan(None, |key, val| {
+            if !f(key, val) {
+                keys.push(key.to_vec());
+            }
+            true
+        })?;
+        debug!("retain: removing {} entries", keys.len());
+
+        let _lock = self.lock.write();
+        self.track(|| {
+            let mut count = 0;
+            for key in keys {
+                if self.del_dirty(&key)? {
+                    count += 1;
+                }
+            }
+            self.commit()?;
+            Ok(count)
+        })
+    }
+
+    fn update<F>(&self, key: &[u8], f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        debug!("update: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            let old = self.find_value(key)?;
+            match f(old.as_deref()) {
+                Some(val) => self.put(key, &val),
+                None => self.del(key).map(|_| ()),
+            }
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        debug!("compare_and_swap: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            let old = self.find_value(key)?;
+            if old.as_deref() != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(val) => self.put(key, val)?,
+                None => {
+                    self.del(key)?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn get_for_update(&self, key: &[u8]) -> Result<UpdateGuard<'_>> {
+        debug!("get_for_update: {}", hex(key));
+        Self::check_key(key)?;
+        self.writable()?;
+        let lock = self.lock.write();
+        let val = self.find_value(key)?;
+        let ops = Locked {
+            file: self,
+            _lock: lock,
+        };
+        Ok(UpdateGuard::new(key, val, Box::new(ops)))
+    }
+
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&TxnCtx<'_>) -> Result<T>,
+    {
+        debug!("transaction");
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            self.begin()?;
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&TxnCtx::new(self)))) {
+                Ok(Ok(result)) => {
+                    self.end()?;
+                    Ok(result)
+                }
+                Ok(Err(e)) => match self.rollback() {
+                    Ok(()) => Err(e),
+                    Err(rollback) => Err(Error::Other(format!(
+                        "rollback failed: {} (after: {})",
+                        rollback, e
+                    ))),
+                },
+                Err(cause) => {
+                    if let Err(e) = self.rollback() {
+                        error!("transaction: rollback failed: {}", e);
+                    }
+                    panic::resume_unwind(cause)
+                }
+            }
+        })
+    }
+
+    fn swap(&self, a: &[u8], b: &[u8]) -> Result<bool> {
+        debug!("swap: {} <-> {}", hex(a), hex(b));
+        Self::check_key(a)?;
+        Self::check_key(b)?;
+        self.writable()?;
+        let _lock = self.lock.write();
+        self.track(|| {
+            let (val_a, val_b) = match (self.find_value(a)?, self.find_value(b)?) {
+                (Some(val_a), Some(val_b)) => (val_a, val_b),
+                _ => return Ok(false),
+            };
+            if a == b {
+                return Ok(true);
+            }
+            // Both values are committed together, a failed swap is rolled back (as a transaction).
+            self.begin()?;
+            match self
+                .put_dirty(a, &val_b, true)
+                .and_then(|_| self.put_dirty(b, &val_a, true))
+            {
+                Ok(_) => self.end().map(|_| true),
+                Err(e) => match self.rollback() {
+                    Ok(()) => Err(e),
+                    Err(rollback) => Err(Error::Other(format!(
+                        "rollback failed: {} (after: {})",
+                        rollback, e
+                    ))),
+                },
+            }
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        let _lock = self.lock.read();
+        self.root().len() == 0
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let _lock = self.lock.read();
+        let file_bytes = self.file.read().len()?;
+        let page_count =
+            ((file_bytes - self.head.size() as u64) / self.head.page_bytes as u64) as u32;
+        let empty = self
+            .empty
+            .read()
+            .iter()
+            .map(|id| id.0)
+            .collect::<HashSet<_>>();
+        let free_list = self.free_list.read().pages.clone();
+        let tables = self.tables.load(Ordering::Relaxed);
+
+        let mut live = 0u32;
+        let mut full = 0u64;
+        let mut entry_count = 0u64;
+        let mut fragmented_bytes = 0u64;
+        let pages = (ROOT..=page_count)
+            .filter(|id| !empty.contains(id) && !free_list.contains(id) && *id != tables);
+        for id in pages {
+            let page = self.fetch(id)?;
+            live += 1;
+            full += page.full() as u64;
+            fragmented_bytes += page.fragmentation() as u64;
+            entry_count += (0..page.len())
+                .filter_map(|idx| page.slot(idx))
+                .filter(|slot| slot.is_val())
+                .count() as u64;
+        }
+
+        Ok(Stats {
+            file_bytes,
+            page_count,
+            empty_pages: empty.len() as u32,
+            entry_count,
+            avg_full: full as f64 / live.max(1) as f64,
+            fragmented_bytes,
+        })
+    }
+
+    fn release(&self) -> Result<()> {
+        let _lock = self.lock.write();
+        if !self.readonly {
+            self.flush()?;
+        }
+        let mut cache = self.cache.write();
+        let root = cache.get(&ROOT).map(|root| {
+            let mut copy = P::reserve(self.head.page_bytes);
+            copy.as_mut().copy_from_slice(root.as_ref());
+            copy
+        });
+        cache.clear();
+        if let Some(root) = root {
+            cache.put(ROOT, root);
+        }
+        drop(cache);
+        self.trim_memory();
+        Ok(())
+    }
+
+    fn min(&self) -> Result<Option<Vec<u8>>> {
+        let _lock = self.lock.read();
+        Ok(self.boundary(false)?.map(|page| page.min().to_vec()))
+    }
+
+    fn max(&self) -> Result<Option<Vec<u8>>> {
+        let _lock = self.lock.read();
+        Ok(self.boundary(true)?.map(|page| page.max().to_vec()))
+    }
+
+    fn first(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let _lock = self.lock.read();
+        match self.boundary(false)? {
+            Some(page) => Ok(Some((page.key(0).to_vec(), value(&*page, 0)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn last(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let _lock = self.lock.read();
+        match self.boundary(true)? {
+            Some(page) => {
+                let idx = page.len() - 1;
+                Ok(Some((page.key(idx).to_vec(), value(&*page, idx)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn above(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        debug!("above: {}", hex(key));
+        let _lock = self.lock.read();
+        self.successor(key, |page, idx| Ok(page.key(idx).to_vec()))
+    }
+
+    fn below(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        debug!("below: {}", hex(key));
+        let _lock = self.lock.read();
+        self.predecessor(key, |page, idx| Ok(page.key(idx).to_vec()))
+    }
+
+    fn above_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        debug!("above_entry: {}", hex(key));
+        let _lock = self.lock.read();
+        self.successor(key, |page, idx| {
+            Ok((page.key(idx).to_vec(), value(page, idx)?))
+        })
+    }
+
+    fn below_entry(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        debug!("below_entry: {}", hex(key));
+        let _lock = self.lock.read();
+        self.predecessor(key, |page, idx| {
+            Ok((page.key(idx).to_vec(), value(page, idx)?))
+        })
+    }
+
+    /// Each leaf page is read-locked only while it is visited.
+    fn scan<F>(&self, from: Option<&[u8]>, f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.scan_with(from, true, f)
+    }
+
+    /// One leaf page at a time (value bytes are not read), no lock is held between leaves.
+    fn keys(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        Box::new(Column::keys(self))
+    }
+
+    /// One leaf page at a time (keys are not copied), no lock is held between leaves.
+    fn values(&self) -> Box<dyn Iterator<Item = Vec<u8>> + '_> {
+        Box::new(Column::values(self))
+    }
+
+    /// Leaves that lie inside the range entirely are counted without looking at their keys,
+    /// so the cost is close to the number of leaves.
+    fn count_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        self.count_range_with(lo, hi).map(|(count, _)| count)
+    }
+
+    /// Reads only the node pages over the range and the two leaves on its boundaries, so it
+    /// is much cheaper than `count_range`. For uniformly distributed keys the estimate is within
+    /// a factor of 2 of the exact count (for ranges spanning a few leaves at least).
+    fn estimate_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        self.estimate(lo, hi)
+    }
+
+    fn range_key_bounds(&self, lo: &[u8], hi: &[u8]) -> Result<Vec<Vec<u8>>> {
+        if lo >= hi {
+            return Ok(Vec::new());
+        }
+        let _lock = self.lock.read();
+        let (_, mut keys) = self.range_leaves(lo, hi)?;
+        // The last leaf might extend past the range.
+        keys.retain(|key| key.as_slice() >= lo && key.as_slice() < hi);
+        Ok(keys)
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let mut count = 0;
+        self.scan_with(Some(prefix), false, |key, _| {
+            if !key.starts_with(prefix) {
+                return false;
+            }
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    }
+
+    fn first_gap<F>(&self, start: &[u8], successor: F) -> Result<Option<Vec<u8>>>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>>,
+    {
+        let mut expected = Some(start.to_vec());
+        self.scan_with(Some(start), false, |key, _| {
+            match expected.as_deref() {
+                // keys in between the expected ones (e.g. of another key space) are skipped
+                Some(next) if key < next => true,
+                Some(next) if key == next => {
+                    expected = successor(key);
+                    expected.is_some()
+                }
+                _ => false,
+            }
+        })?;
+        Ok(expected)
+    }
+}
+
+/// Operations of `TxnCtx`, the tree write lock is held by `Store::transaction`.
+impl<P: Page> TxnOps for File<P> {
+    fn txn_lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Self::check_key(key)?;
+        self.find_value(key)
+    }
+
+    fn txn_insert(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        Self::check_key(key)?;
+        self.put_dirty(key, val, true).map(|_| ())
+    }
+
+    fn txn_remove(&self, key: &[u8]) -> Result<()> {
+        Self::check_key(key)?;
+        self.del_dirty(key).map(|_| ())
+    }
+}
+
+/// The file write-locked by `Store::get_for_update` until the guard is dropped.
+struct Locked<'a, P: Page> {
+    file: &'a File<P>,
+    _lock: RwLockWriteGuard<'a, ()>,
+}
+
+impl<P: Page> UpdateOps for Locked<'_, P> {
+    fn write(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        // Committed before the lock is released, so no dirty page outlives the guard.
+        self.file.track(|| self.file.put(key, val))
+    }
+}
+
+impl<P: Page> Drop for File<P> {
+    fn drop(&mut self) {
+        // The flusher thread holds a clone of its own: stop it first (if this handle is the last
+        // one sharing it), so that the clone is gone by the time the handles are counted.
+        drop(self.flusher.take());
+        // Clones share the pages, the last one to go writes the remainder of the batch.
+        if !matches!(self.durability, Durability::Batch(_)) || Arc::strong_count(&self.lock) > 1 {
+            return;
+        }
+        let _lock = self.lock.write();
+        if let Err(e) = self.flush() {
+            error!("Failed to flush on drop: {}", e);
+        }
+    }
+}
+
+impl<P: Page> Tree<P> for File<P> {
+    fn root(&self) -> MappedRwLockReadGuard<'_, P> {
+        self.page(self.root).unwrap()
+    }
+
+    fn page(&self, id: u32) -> Option<MappedRwLockReadGuard<'_, P>> {
+        self.fetch(id).ok()
+    }
+
+    fn root_mut(&self) -> MappedRwLockWriteGuard<'_, P> {
+        self.mark(self.root);
+        self.page_mut(self.root).unwrap()
+    }
+
+    fn page_mut(&self, id: u32) -> Option<MappedRwLockWriteGuard<'_, P>> {
+        self.fetch_mut(id).ok()
+    }
+
+    fn cache(&self, id: u32) -> io::Result<()> {
+        self.cached(id).map(|_| ())
+    }
+
+    fn mark(&self, id: u32) {
+        self.dirty.write().insert(id);
+    }
+
+    fn flush(&self) -> crate::api::error::Result<()> {
+        let _timer = metrics::op("flush");
+        self.writable()?;
+        self.save_free_list()?;
+        let ids = self.dirty.read().iter().cloned().collect::<Vec<_>>();
+
+        // Dirty pages are never loaded here: a cache miss would evict (and lose) another one.
+        let mut failed = vec![];
+        {
+            let cache = self.cache.read();
+            let spill = self.spill.read();
+            let mut pages = Vec::with_capacity(ids.len());
+            for id in ids {
+                match cache.get(&id).or_else(|| spill.get(&id)) {
+                    Some(page) => {
+                        debug!("flush: page={}", id);
+                        pages.push(page);
+                    }
+                    None => {
+                        failed.push(id);
+                        error!("flush: no such page={}", id);
+                    }
+                }
+            }
+            self.write_pages(&pages)?;
+        }
+        self.dirty.write().clear();
+        self.spill.write().clear();
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            let pages = failed
+                .into_iter()
+                .map(|id| format!("{}", id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::Other(format!("Missing pages: {}", pages)))
+        }
+    }
+
+    fn next_id(&self) -> Result<u32> {
+        self.counters.allocated.fetch_add(1, Ordering::Relaxed);
+        if self.empty.read().is_empty() {
+            self.resume_scan()?;
+        }
+        // Checked and taken under the same lock: another thread might drain the heap otherwise.
+        let reused = self.empty.write().pop();
+        if let Some(Reverse(id)) = reused {
+            self.free_list.write().changes.push((id, false));
+            let temp = P::create(id, self.head.page_bytes);
+            let mut page = self.fetch_mut(id)?;
+            page.as_mut().copy_from_slice(temp.as_ref());
+            return Ok(id);
+        }
+
+        self.append_page()
+    }
+
+    fn free_id(&self, id: u32) {
+        let mut empty = self.empty.write();
+        debug_assert!(
+            empty.iter().all(|free| free.0 != id),
+            "Page freed twice: {}",
+            id
+        );
+        empty.push(Reverse(id));
+        drop(empty);
+        self.free_list.write().changes.push((id, true));
+    }
+
+    fn split(&self, id: u32, parent_id: u32) -> Result<()> {
+        self.counters.split.fetch_add(1, Ordering::Relaxed);
+        metrics::count(metrics::SPLITS);
+        self.forget_edges(&[id]);
+        if id == self.root {
+            let lo_id = self.next_id()?;
+            let hi_id = self.next_id()?;
+            debug!(
+                "split: root={} into lo={} and hi={} (parent={})",
+                id, lo_id, hi_id, parent_id
+            );
+
+            let (copy, lo_max, hi_max) = {
+                let page = self.page(id).unwrap();
+                let copy = entries(page.deref());
+                let half = page.len() as usize / 2;
+                let lo_max = copy.get(half - 1).map(|(k, _, _, _)| k).cloned().unwrap();
+                let hi_max = copy.last().map(|(k, _, _, _)| k).cloned().unwrap();
+                (copy, lo_max, hi_max)
+            };
+            let half = copy.len() / 2;
+
+            {
+                let mut lo = self.page_mut(lo_id).unwrap();
+                copy.iter()
+                    .take(half)
+                    .for_each(|(key, val, page, compressed)| {
+                        trace!(
+                            "split: move k={} v={} p={} from {} to {}",
+                            hex(key),
+                            hex(val),
+                            *page,
+                            id,
+                            lo_id
+                        );
+                        put_entry(lo.deref_mut(), key, val, *page, *compressed);
+                    });
+            }
+
+            {
+                let mut hi = self.page_mut(hi_id).unwrap();
+                copy.iter()
+                    .skip(half)
+                    .for_each(|(key, val, page, compressed)| {
+                        trace!(
+                            "split: move k={} v={} p={} from {} to {}",
+                            hex(key),
+                            hex(val),
+                            *page,
+                            id,
+                            hi_id
+                        );
+                        put_entry(hi.deref_mut(), key, val, *page, *compressed);
+                    });
+            }
+
+            {
+                let mut page = self.page_mut(id).unwrap();
+                page.clear();
+                page.put_ref(&lo_max, lo_id);
+                page.put_ref(&hi_max, hi_id);
+            }
+
+            Ok(())
+        } else {
+            let (copy, max) = {
+                let page = self.page(id).unwrap();
+                (entries(page.deref()), page.max().to_vec())
+            };
+            let half = copy.len() / 2;
+            let peer_id = self.next_id()?;
+            debug!(
+                "split: page={} into peer={} (parent={})",
+                id, peer_id, parent_id
+            );
+
+            let page_max = {
+                let mut page = self.page_mut(id).unwrap();
+                copy.iter().skip(half).for_each(|(key, _, _, _)| {
+                    let idx = page.find(key).unwrap();
+                    page.remove(idx);
+                });
+                page.max().to_vec()
+            };
+
+            let peer_max = {
+                let mut peer = self.page_mut(peer_id).unwrap();
+                copy.iter()
+                    .skip(half)
+                    .for_each(|(key, val, p, compressed)| {
+                        trace!(
+                            "split: move k={} v={} p={} from {} to {}",
+                            hex(key),
+                            hex(val),
+                            *p,
+                            id,
+                            peer_id
+                        );
+                        put_entry(peer.deref_mut(), key, val, *p, *compressed);
+                    });
+                peer.max().to_vec()
+            };
+
+            {
+                let mut parent = self.page_mut(parent_id).unwrap();
+                let idx = child_idx(parent.deref(), &max, id).ok_or_else(|| {
+                    Error::Tree(parent_id, format!("Child page not found: {}", id))
+                })?;
+                parent.remove(idx);
+                parent.put_ref(&page_max, id);
+                parent.put_ref(&peer_max, peer_id);
+            }
+
+            self.check(parent_id, id)?;
+            self.check(parent_id, peer_id)?;
+
+            Ok(())
+        }
+    }
+
+    fn check(&self, parent_id: u32, page_id: u32) -> Result<()> {
+        let page_max = {
+            let page = self.page(page_id).unwrap();
+            page.max().to_vec()
+        };
+
+        let (parent_key, parent_ref) = {
+            let parent = self.page(parent_id).unwrap();
+            let page_idx = child_idx(parent.deref(), &page_max, page_id).ok_or_else(|| {
+                Error::Tree(parent_id, format!("Child page not found: {}", page_id))
+            })?;
+            let parent_key = parent.key(page_idx).to_vec();
+            let parent_ref = parent
+                .slot(page_idx)
+                .map(|slot| slot.page)
+                .unwrap_or_default();
+            (parent_key, parent_ref)
+        };
+
+        if parent_key != page_max {
+            log::error!(
+                "parent_key != page_max: parent_key={} page_max={}",
+                hex(&parent_key),
+                hex(&page_max)
+            );
+            return Err(Error::Tree(
+                parent_id,
+                "Parent entry key does not match child page".to_string(),
+            ));
+        }
+
+        if parent_ref != page_id {
+            log::error!(
+                "parent_ref != page_id: parent_ref={} page_id={}",
+                parent_ref,
+                page_id,
+            );
+            return Err(Error::Tree(
+                parent_id,
+                "Parent entry ref does not match child page".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn merge(&self, src_id: u32, dst_id: u32) -> Result<()> {
+        self.counters.merged.fetch_add(1, Ordering::Relaxed);
+        metrics::count(metrics::MERGES);
+        self.forget_edges(&[src_id, dst_id]);
+        debug!("merge: src={} into dst={}", src_id, dst_id);
+        let src_copy = {
+            let page = self.page(src_id).unwrap();
+            entries(page.deref())
+        };
+
+        {
+            let mut page = self.page_mut(dst_id).unwrap();
+            for (key, val, p, compressed) in src_copy {
+                trace!(
+                    "merge: move k={} v={} p={} from {} to {}",
+                    hex(&key),
+                    hex(&val),
+                    p,
+                    src_id,
+                    dst_id
+                );
+                put_entry(page.deref_mut(), &key, &val, p, compressed);
+            }
+            page.max().to_vec()
+        };
+
+        {
+            let mut page = self.page_mut(src_id).unwrap();
+            page.clear();
+        }
+
+        self.free_id(src_id);
+        Ok(())
+    }
+
+    fn rebalance(&self, id: u32, parent_id: u32) -> Result<()> {
+        if self.split_over(id, parent_id)? || id == self.root {
+            return Ok(());
+        }
+        let idx = {
+            let parent = self
+                .page(parent_id)
+                .ok_or_else(|| Error::Tree(parent_id, "Page not found".to_string()))?;
+            (0..parent.len())
+                .find(|idx| parent.slot(*idx).map(|slot| slot.page) == Some(id))
+                .ok_or_else(|| Error::Tree(parent_id, format!("Page not referenced: {}", id)))?
+        };
+        self.merge_under(id, parent_id, idx)?;
+        Ok(())
+    }
+
+    fn verify(&self) -> Result<()> {
+        let _lock = self.lock.read();
+        let mut seen = HashSet::with_capacity(64);
+        seen.insert(self.root);
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            let page = self
+                .page(id)
+                .ok_or_else(|| Error::Tree(id, "Page not found".to_string()))?;
+            for idx in 1..page.len() {
+                if page.key(idx - 1) >= page.key(idx) {
+                    return Err(Error::Tree(
+                        id,
+                        format!("Keys are not ascending at slot {}", idx),
+                    ));
+                }
+            }
+            let refs = (0..page.len())
+                .filter_map(|idx| {
+                    page.slot(idx)
+                        .map(|slot| (page.key(idx).to_vec(), slot.page))
+                })
+                .filter(|(_, child_id)| *child_id > 0)
+                .collect::<Vec<_>>();
+            drop(page);
+
+            for (key, child_id) in refs {
+                if !seen.insert(child_id) {
+                    return Err(Error::Tree(
+                        id,
+                        format!("Page referenced more than once: {}", child_id),
+                    ));
+                }
+                let child = self
+                    .page(child_id)
+                    .ok_or_else(|| Error::Tree(id, format!("Page not found: {}", child_id)))?;
+                if child.is_empty() || child.max() != key.as_slice() {
+                    return Err(Error::Tree(
+                        id,
+                        format!(
+                            "Reference key {} does not match max key of page {}",
+                            hex(&key),
+                            child_id
+                        ),
+                    ));
+                }
+                stack.push(child_id);
+            }
+        }
+
+        let empty = self.empty.read().iter().map(|id| id.0).collect::<Vec<_>>();
+        let mut free = HashSet::with_capacity(empty.len());
+        for id in empty {
+            if !free.insert(id) {
+                return Err(Error::Tree(id, "Page is marked empty twice".to_string()));
+            }
+            if seen.contains(&id) {
+                return Err(Error::Tree(
+                    id,
+                    "Referenced page is marked empty".to_string(),
+                ));
+            }
+            let is_empty = self.page(id).map(|page| page.is_empty()).unwrap_or(true);
+            if !is_empty {
+                return Err(Error::Tree(
+                    id,
+                    "Page marked empty is not empty".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dump(&self) -> String {
+        let mut acc = Vec::with_capacity(1024);
+        self.dump_to(&mut acc, None).unwrap();
+        String::from_utf8(acc).unwrap()
+    }
+
+    fn dump_to(&self, w: &mut impl Write, max_depth: Option<u32>) -> Result<()> {
+        fn dump_page<P: Page>(
+            file: &File<P>,
+            w: &mut impl Write,
+            page_id: u32,
+            parent_id: u32,
+            depth: u32,
+            max_depth: Option<u32>,
+        ) -> Result<()> {
+            if page_id == 0 || max_depth.map(|max| depth > max).unwrap_or_default() {
+                return Ok(());
+            }
+
+            // The page is copied and released before visiting children (which might get loaded).
+            let (copy, full) = {
+                let page = file
+                    .page(page_id)
+                    .ok_or_else(|| Error::Tree(page_id, "Page not found".to_string()))?;
+                (page.copy(), page.full())
+            };
+
+            let prefix = "\t".repeat(depth as usize);
+            if copy.is_empty() {
+                writeln!(w, "{}page={}: empty", prefix, page_id)?;
+            } else {
+                writeln!(
+                    w,
+                    "{}page={}: (parent={}) {}% full",
+                    prefix, page_id, parent_id, full
+                )?;
+                for (k, v, p) in copy.iter() {
+                    writeln!(w, "{}{}, {}, {}", prefix, hex(k), hex(v), p)?;
+                }
+            }
+
+            for (_, _, id) in copy {
+                dump_page(file, w, id, page_id, depth + 1, max_depth)?;
+            }
+            Ok(())
+        }
+
+        dump_page(self, w, self.root, 0, 0, max_depth)
+    }
+
+    fn dump_dot(&self) -> String {
+        let mut acc = String::with_capacity(1024);
+        acc.push_str("digraph tree {\n\tnode [shape=box, fontname=monospace];\n");
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            let page = match self.page(id) {
+                Some(page) => page,
+                None => continue,
+            };
+            let copy = page.copy();
+            let keys = copy
+                .iter()
+                .map(|(k, _, _)| format!("{}\\l", hex(k)))
+                .collect::<String>();
+            acc.push_str(&format!(
+                "\tp{} [label=\"page={} ({}% full)\\n{}\"];\n",
+                id,
+                id,
+                page.full(),
+                keys
+            ));
+            for (_, _, child) in copy.iter().filter(|(_, _, p)| *p != 0) {
+                acc.push_str(&format!("\tp{} -> p{};\n", id, child));
+                stack.push(*child);
+            }
+        }
+        acc.push_str("}\n");
+        acc
+    }
+}
+
+/// Write initial contents into a temporary file and move it to `path` only when fully written,
+/// so a failed write never leaves a partial file at `path` (the temporary file is removed).
+fn create_file<W>(path: &Path, buf: &[u8], write: W) -> io::Result<()>
+where
+    W: FnOnce(&mut fs::File, &[u8]) -> io::Result<()>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let res = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp)
+        .and_then(|mut file| write(&mut file, buf))
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+/// Index of the parent entry referencing the child page: the parent key is expected to be equal
+/// to the child's max key, but look the reference up by child page id otherwise (so a mismatch
+/// is reported as an error by the caller instead of panicking).
+fn child_idx<P: Page>(parent: &P, key: &[u8], child: u32) -> Option<u32> {
+    parent
+        .find(key)
+        .filter(|idx| parent.slot(*idx).map(|slot| slot.page) == Some(child))
+        .or_else(|| {
+            (0..parent.len()).find(|idx| parent.slot(*idx).map(|slot| slot.page) == Some(child))
+        })
+}
+
+/// Set the link to the next page of the free-list chain (see `File::save_free_list`).
+fn set_next<P: Page>(page: &mut P, next: u32) {
+    let key = [0u8; 4];
+    if let Some(idx) = page.find(&key) {
+        page.remove(idx);
+    }
+    page.put_ref(&key, next);
+}
+
+/// Read a length-prefixed (u32, big-endian) chunk of bytes, see `File::snapshot`.
+/// Chunks longer than `max` bytes are rejected (no entry is larger than a page).
+fn read_chunk(r: &mut impl Read, max: u32) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > max {
+        return Err(io::Error::other(format!(
+            "Snapshot chunk too large: {}",
+            len
+        )));
+    }
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Pages visited on the way down from the root. Each descent (lookup, insert, remove, etc)
+/// goes through `step`, so a corrupted tree with a cycle fails the same way everywhere.
+#[derive(Default)]
+struct Descent(HashSet<u32>);
+
+impl Descent {
+    /// Step down from page `from` to its child page `to`.
+    fn step(&mut self, from: u32, to: u32) -> Result<()> {
+        self.0.insert(from);
+        if self.0.contains(&to) {
+            return Err(Error::Tree(
+                from,
+                format!("Cyclic reference detected: {}", to),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Root page id of the table in the given slot of the table directory page, see `open_table`.
+fn dir_root<P: Page>(page: &P, idx: u32) -> Result<u32> {
+    let val = page.val(idx);
+    val.try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| Error::Tree(page.id(), format!("Invalid table root: {}", hex(val))))
+}
+
+/// Compare bytes in use (slots, keys and values) to the given percent of usable page bytes.
+/// Exact, unlike comparing the rounded `Page::full` percent to the threshold.
+fn fill<P: Page>(page: &P, percent: u8) -> cmp::Ordering {
+    let used = (page.usable() - page.free()) as u64 * 100;
+    used.cmp(&(page.usable() as u64 * percent as u64))
+}
+
+/// Copy the value at given slot, decompressing it if needed.
+fn value<P: Page>(page: &P, idx: u32) -> Result<Vec<u8>> {
+    if page.is_compressed(idx) {
+        Ok(compress::decompress(page.val(idx))?)
+    } else {
+        Ok(page.val(idx).to_vec())
+    }
+}
+
+/// Make an owned copy of all entries in the page: (key, val, page, compressed).
+fn entries<P: Page>(page: &P) -> Vec<(Vec<u8>, Vec<u8>, u32, bool)> {
+    page.copy()
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (key, val, child))| (key, val, child, page.is_compressed(idx as u32)))
+        .collect()
+}
+
+/// Put an entry (as copied by `entries`) into the page.
+fn put_entry<P: Page>(page: &mut P, key: &[u8], val: &[u8], child: u32, compressed: bool) {
+    if child > 0 {
+        page.put_ref(key, child);
+    } else if compressed {
+        page.put_compressed(key, val);
+    } else {
+        page.put_val(key, val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::block::{Block, BlockCompact, BlockLE, BlockPrefix, BlockSmall};
+    use crate::util;
+    use crate::util::hex::hex;
+    use rand::prelude::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use std::ops::Deref;
+
+    fn get<P: Page>(page: &P, key: &[u8]) -> Option<(Vec<u8>, u32)> {
+        page.find(key)
+            .map(|idx| (page.val(idx).to_vec(), page.slot(idx).unwrap().page))
+    }
+
+    #[test]
+    fn test_page() {
+        let path = &util::temp("test_page");
+        let size: u32 = 256;
+
+        let data = vec![
+            (b"aaa".to_vec(), b"zxczxczxc".to_vec(), 0),
+            (b"bbb".to_vec(), b"asdasdasd".to_vec(), 0),
+            (b"ccc".to_vec(), b"qweqweqwe".to_vec(), 0),
+            (b"ddd".to_vec(), b"123123123".to_vec(), 0),
+            (b"xxx".to_vec(), vec![], 3333),
+            (b"yyy".to_vec(), vec![], 2222),
+            (b"zzz".to_vec(), vec![], 1111),
+        ];
+
+        {
+            let file: File<Block> = File::make(path, size).unwrap();
+            {
+                let mut page = file.root_mut();
+                for (k, v, p) in data.iter() {
+                    if *p == 0 {
+                        page.put_val(k, v);
+                    } else {
+                        page.put_ref(k, *p);
+                    }
+                }
+            };
+            let page = file.root();
+            file.save(page.deref()).unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        let mut page = file.load(ROOT).unwrap();
+
+        assert_eq!(page.copy(), data);
+
+        for (k, v, p) in data.iter() {
+            assert_eq!(get(&page, k), Some((v.to_vec(), *p)));
+        }
+
+        page.remove(page.find(b"aaa").unwrap());
+        assert_eq!(get(&page, b"aaa"), None);
+
+        page.remove(page.find(b"zzz").unwrap());
+        assert_eq!(get(&page, b"zzz"), None);
+    }
+
+    #[test]
+    fn test_file() {
+        let path = &util::temp("test_file");
+        let size: u32 = 256;
+
+        let data = [
+            (b"uno".to_vec(), b"la squadra azzurra".to_vec()),
+            (b"due".to_vec(), b"it's coming home".to_vec()),
+            (b"tre".to_vec(), b"red devils".to_vec()),
+        ];
+
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+            file.remove(k).unwrap();
+        }
+
+        for (k, _) in data.iter() {
+            assert!(file.lookup(k).unwrap().is_none());
+        }
+
+        let root = file.root();
+        assert_eq!(root.copy(), vec![]);
+    }
+
+    #[test]
+    fn test_split() {
+        let path = &util::temp("test_split");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 25;
+        let data = (0..count)
+            .map(|i| {
+                let c = b'a' + (i % (b'z' - b'a' + 1) as u64) as u8;
+                (vec![c; 8], vec![c; 8])
+            })
+            .collect::<Vec<_>>();
+
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let path = &util::temp("test_merge");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 25;
+        let data = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = (0..count)
+                .map(|i| {
+                    let c = b'a' + (i % (b'z' - b'a' + 1) as u64) as u8;
+                    (vec![c; 8], vec![c; 8])
+                })
+                .collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        for (k, v) in data.iter() {
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+        debug!("{}", file.dump());
+
+        let keys = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        let mut removed = HashSet::with_capacity(size as usize);
+        for key in keys.iter() {
+            debug!("remove: key={}", hex(key));
+            file.remove(key).unwrap();
+            removed.insert(key.to_vec());
+            for (k, v) in data.iter() {
+                if removed.contains(k) {
+                    assert!(file.lookup(k).unwrap().is_none());
+                } else {
+                    assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+                }
+            }
+        }
+        debug!("{}", file.dump());
+
+        let root = file.root();
+        let copy = root.copy();
+        assert_eq!(copy, vec![]);
+    }
+
+    #[test]
+    fn test_cascading_merges() {
+        let path = &util::temp("test_cascading_merges");
+
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_cache(Box::new(LruCache::new(100_000)));
+        let data = util::data(5000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let mut keys = data.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        keys.sort();
+
+        // Thin out every leaf first (nothing merges while peers stay over the threshold),
+        // then empty whole subtrees from both ends towards the middle, so merges cascade
+        // up through the node levels and pages end up emptied next to full peers.
+        let (first, last): (Vec<_>, Vec<_>) =
+            keys.iter().enumerate().partition(|(i, _)| i % 4 != 3);
+        let mut order = first.into_iter().map(|(_, k)| k).collect::<Vec<_>>();
+        let mut rest = last.into_iter().map(|(_, k)| k).collect::<VecDeque<_>>();
+        while let (Some(lo), Some(hi)) = (rest.pop_front(), rest.pop_back()) {
+            order.push(lo);
+            order.push(hi);
+        }
+        order.extend(rest);
+
+        for (i, key) in order.into_iter().enumerate() {
+            file.remove(key).unwrap();
+            if i % 100 == 0 {
+                // no page is both free and referenced, nor freed twice
+                file.verify().unwrap();
+            }
+        }
+        file.verify().unwrap();
+        assert!(file.is_empty());
+
+        // every page but the root and the free-list pages is available for reuse
+        let total = ((file.file.read().len().unwrap() - HEAD as u64) / 256) as usize;
+        let free_list = file.free_list.read().pages.len();
+        assert_eq!(file.empty.read().len() + free_list + 1, total);
+    }
+
+    #[test]
+    fn test_rebalance_split() {
+        let path = &util::temp("test_rebalance_split");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        {
+            let mut root = file.root_mut();
+            for i in 0..4u8 {
+                root.put_val(&[i], &[i; 32]).unwrap();
+            }
+        }
+        file.mark(ROOT);
+        assert!(fill(file.root().deref(), SPLIT_THRESHOLD) == cmp::Ordering::Greater);
+        assert!(file.root().is_leaf());
+
+        file.rebalance_page(ROOT, 0).unwrap();
+        assert!(file.root().is_node());
+        assert_eq!(file.root().len(), 2);
+        file.verify().unwrap();
+        for i in 0..4u8 {
+            assert_eq!(file.get(&[i]).unwrap(), vec![i; 32]);
+        }
+
+        // Neither over- nor under-full: nothing changes.
+        let dump = file.dump();
+        let lo = file.root().slot(0).unwrap().page;
+        file.rebalance_page(lo, ROOT).unwrap();
+        assert_eq!(file.dump(), dump);
+
+        // The tree method expects the caller to hold the lock already.
+        {
+            let _lock = file.lock.write();
+            file.rebalance(lo, ROOT).unwrap();
+        }
+        assert_eq!(file.dump(), dump);
+    }
+
+    #[test]
+    fn test_rebalance_merge() {
+        let path = &util::temp("test_rebalance_merge");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for i in 0..10u8 {
+            file.insert(&[i], &[i; 8]).unwrap();
+        }
+        assert!(file.root().is_node());
+        let (lo, hi) = {
+            let root = file.root();
+            (root.slot(0).unwrap().page, root.slot(1).unwrap().page)
+        };
+
+        // Drop all but the max key (that the parent references) from both leaves.
+        let mut kept = Vec::new();
+        for id in [lo, hi] {
+            let mut page = file.page_mut(id).unwrap();
+            while page.len() > 1 {
+                page.remove(0);
+            }
+            kept.push(page.max().to_vec());
+            drop(page);
+            file.mark(id);
+        }
+        assert!(fill(file.page(lo).unwrap().deref(), MERGE_THRESHOLD) == cmp::Ordering::Less);
+
+        file.rebalance_page(lo, ROOT).unwrap();
+        assert_eq!(file.root().len(), 1);
+        assert_eq!(file.root().slot(0).unwrap().page, hi);
+        assert!(file.page(lo).unwrap().is_empty());
+        file.verify().unwrap();
+        for key in kept {
+            assert_eq!(file.get(&key).unwrap(), vec![key[0]; 8]);
+        }
+
+        assert!(file.rebalance_page(lo, ROOT).is_err());
+    }
+
+    #[test]
+    fn test_below_single_leaf() {
+        let path = &util::temp("test_below_single_leaf");
+
+        let file: File<Block> = File::make(path, 4096).unwrap();
+        assert_eq!(file.below(&[20]).unwrap(), None);
+        for key in [10u8, 20, 30] {
+            file.insert(&[key], &[key]).unwrap();
+        }
+        assert!(file.root().is_leaf());
+
+        // below min
+        assert_eq!(file.below(&[]).unwrap(), None);
+        assert_eq!(file.below(&[5]).unwrap(), None);
+        // equal to an entry
+        assert_eq!(file.below(&[10]).unwrap(), None);
+        assert_eq!(file.below(&[20]).unwrap(), Some(vec![10]));
+        assert_eq!(file.below(&[30]).unwrap(), Some(vec![20]));
+        // between two entries
+        assert_eq!(file.below(&[15]).unwrap(), Some(vec![10]));
+        assert_eq!(file.below(&[20, 0]).unwrap(), Some(vec![20]));
+        // above max
+        assert_eq!(file.below(&[31]).unwrap(), Some(vec![30]));
+        assert_eq!(file.below(&[30, 0]).unwrap(), Some(vec![30]));
+    }
+
+    #[test]
+    fn test_above_below_entry() {
+        let path = &util::temp("test_above_below_entry");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.above_entry(b"key").unwrap(), None);
+        assert_eq!(file.below_entry(b"key").unwrap(), None);
+
+        let data = util::data(500, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert!(file.root().is_node());
+
+        let entry = |key: Option<Vec<u8>>| {
+            key.map(|key| {
+                let val = file.lookup(&key).unwrap().unwrap();
+                (key, val)
+            })
+        };
+        let (min, max) = (file.min().unwrap().unwrap(), file.max().unwrap().unwrap());
+        let mut probes = vec![vec![], vec![0], min.clone(), max.clone(), vec![0xFF; 64]];
+        for (k, _) in data.iter().step_by(7) {
+            let mut next = k.clone();
+            next.push(0);
+            probes.push(k.clone());
+            probes.push(next);
+        }
+        for key in probes.iter() {
+            let above = file.above_entry(key).unwrap();
+            assert_eq!(above, entry(file.above(key).unwrap()), "{}", hex(key));
+            let below = file.below_entry(key).unwrap();
+            assert_eq!(below, entry(file.below(key).unwrap()), "{}", hex(key));
+        }
+        assert_eq!(file.above_entry(&max).unwrap(), None);
+        assert_eq!(file.below_entry(&min).unwrap(), None);
+        assert_eq!(file.below_entry(&[0xFF; 64]).unwrap(), file.last().unwrap());
+    }
+
+    #[test]
+    fn test_tables() {
+        let path = &util::temp("test_tables");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        file.insert(b"main", b"0").unwrap();
+        let a = file.open_table("a").unwrap();
+        let b = file.open_table("b").unwrap();
+        let data = util::data(200, 42);
+        for (i, (k, v)) in data.iter().enumerate() {
+            if i % 2 == 0 {
+                a.insert(k, v).unwrap();
+            } else {
+                b.insert(k, v).unwrap();
+            }
+        }
+        assert!(a.root().is_node());
+
+        let check = |file: &File<Block>| {
+            let a = file.open_table("a").unwrap();
+            let b = file.open_table("b").unwrap();
+            for (table, rem) in [(&a, 0), (&b, 1)] {
+                table.verify().unwrap();
+                let mut expected = data
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| i % 2 == rem)
+                    .map(|(_, kv)| kv.clone())
+                    .collect::<Vec<_>>();
+                expected.sort();
+                let mut entries = Vec::new();
+                table
+                    .scan(None, |k, v| {
+                        entries.push((k.to_vec(), v.to_vec()));
+                        true
+                    })
+                    .unwrap();
+                assert_eq!(entries, expected);
+                assert_eq!(table.min().unwrap(), Some(expected[0].0.clone()));
+                assert_eq!(
+                    table.max().unwrap(),
+                    expected.last().map(|(k, _)| k.clone())
+                );
+                assert_eq!(table.lookup(b"main").unwrap(), None);
+            }
+            let (k, _) = &data[1];
+            assert_eq!(a.lookup(k).unwrap(), None);
+            assert!(b.lookup(k).unwrap().is_some());
+            assert_eq!(file.count_prefix(&[]).unwrap(), 1);
+            assert_eq!(file.get(b"main").unwrap(), b"0");
+            file.verify().unwrap();
+        };
+        check(&file);
+        assert!(file.compact().is_err());
+        drop((a, b, file));
+
+        let file: File<Block> = File::open(path).unwrap();
+        check(&file);
+
+        let empty = file.stats().unwrap().empty_pages;
+        assert!(file.drop_table("a").unwrap());
+        assert!(!file.drop_table("a").unwrap());
+        assert!(file.stats().unwrap().empty_pages > empty);
+        let b = file.open_table("b").unwrap();
+        assert_eq!(b.count_prefix(&[]).unwrap(), 100);
+        let a = file.open_table("a").unwrap();
+        assert!(a.is_empty());
+        assert!(file.open_table("").is_err());
+    }
+
+    #[test]
+    fn test_retain() {
+        let path = &util::temp("test_retain");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let even = |v: &[u8]| v[v.len() - 1].is_multiple_of(2);
+        let expected = data.iter().filter(|(_, v)| even(v)).count();
+
+        let removed = file.retain(|_, v| even(v)).unwrap();
+        assert_eq!(removed, data.len() - expected);
+        assert_eq!(file.count_prefix(&[]).unwrap(), expected);
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            let found = file.lookup(k).unwrap();
+            if even(v) {
+                assert_eq!(found.as_ref(), Some(v));
+            } else {
+                assert_eq!(found, None);
+            }
+        }
+
+        assert_eq!(file.retain(|_, v| even(v)).unwrap(), 0);
+        assert_eq!(file.retain(|_, _| false).unwrap(), expected);
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let path = &util::temp("test_keys_values");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.keys().count(), 0);
+        assert_eq!(file.values().count(), 0);
+
+        let mut data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        data.sort();
+        assert!(file.root().is_node());
+
+        let keys = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        let vals = data.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+        assert_eq!(file.keys().collect::<Vec<_>>(), keys);
+        assert_eq!(file.values().collect::<Vec<_>>(), vals);
+        assert_eq!(file.keys().nth(10), Some(keys[10].clone()));
+    }
+
+    #[test]
+    fn test_repair() {
+        let path = &util::temp("test_repair");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert!(file.root().is_node());
+        drop(file);
+
+        let check = |report: RepairReport, damaged: u32| {
+            assert_eq!(report.recovered, data.len() as u64);
+            assert_eq!(report.dropped, 0);
+            assert_eq!(report.damaged, damaged);
+            let file: File<Block> = File::open(path).unwrap();
+            file.verify().unwrap();
+            assert_eq!(file.count_prefix(&[]).unwrap(), data.len());
+            for (k, v) in data.iter() {
+                assert_eq!(file.get(k).unwrap(), *v);
+            }
+        };
+
+        // the first reference of the root points back to the root
+        {
+            let mut f = OpenOptions::new().write(true).open(path).unwrap();
+            f.seek(SeekFrom::Start(HEAD as u64 + Block::HEAD as u64 + 12))
+                .unwrap();
+            f.write_all(&ROOT.to_be_bytes()).unwrap();
+        }
+        let file: File<Block> = File::open(path).unwrap();
+        assert!(file.verify().is_err());
+        drop(file);
+        check(File::<Block>::repair(path).unwrap(), 0);
+
+        // the root page is garbage
+        {
+            let mut f = OpenOptions::new().write(true).open(path).unwrap();
+            f.seek(SeekFrom::Start(HEAD as u64 + 8)).unwrap();
+            f.write_all(&[0xAB; 64]).unwrap();
+        }
+        check(File::<Block>::repair(path).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_above() {
+        let path = &util::temp("test_above");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 10;
+        let mut data = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = (0..count)
+                .map(|i| {
+                    let b = (i + 1) * count;
+                    (vec![b; 8], vec![b; 8])
+                })
+                .collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        for (k, v) in data.iter() {
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+
+        data.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, data[0].0.to_vec());
+        assert_eq!(max, data.last().unwrap().0.to_vec());
+        assert!(file.above(&max).unwrap().is_none());
+
+        let asc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut val = file.min().unwrap().unwrap().to_vec();
+            result.push(val.clone());
+            while let Some(next) = file.above(&val).unwrap() {
+                result.push(next.to_vec());
+                val = next.to_vec();
+            }
+            result
+        };
+        assert_eq!(
+            asc,
+            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        );
+
+        assert_eq!(file.above(&max).unwrap(), None);
+        let mut max = max;
+        *max.last_mut().unwrap() += 1;
+        assert_eq!(file.above(&max).unwrap(), None);
+    }
+
+    #[test]
+    fn test_below() {
+        let path = &util::temp("test_below");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 10;
+        let mut data = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = (0..count)
+                .map(|i| {
+                    let b = (i + 1) * count;
+                    (vec![b; 8], vec![b; 8])
+                })
+                .collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        for (k, v) in data.iter() {
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+
+        data.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, data[0].0.to_vec());
+        assert_eq!(max, data.last().unwrap().0.to_vec());
+        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+
+        data.reverse();
+        let desc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut val = file.max().unwrap().unwrap().to_vec();
+            result.push(val.clone());
+            while let Some(next) = file.below(&val).unwrap() {
+                result.push(next.to_vec());
+                val = next.to_vec();
+            }
+            result
+        };
+        assert_eq!(
+            desc,
+            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        );
+
+        assert_eq!(file.below(&min).unwrap(), None);
+        let mut min = min;
+        *min.last_mut().unwrap() -= 1;
+        assert_eq!(file.below(&min).unwrap(), None);
+    }
+
+    #[test]
+    fn test_1k() {
+        let path = &util::temp("test_1k");
+
+        let size: u32 = 4096;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 1000;
+        let data = util::data(count, 42);
+
+        for (i, (k, v)) in data.iter().enumerate() {
+            debug!("({:05}) insert: key={} val={}", i, hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+
+        let mut sorted = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
+        sorted.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, sorted[0]);
+        assert_eq!(max, sorted.last().cloned().unwrap());
+        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+        assert_eq!(file.above(&max).unwrap().map(|v| v.to_vec()), None);
+        let asc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut this = file.min().unwrap().unwrap().to_vec();
+            result.push(this.clone());
+            while let Some(next) = file.above(&this).unwrap() {
+                result.push(next.to_vec());
+                this = next.to_vec();
+            }
+            result
+        };
+        let desc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut this = file.max().unwrap().unwrap().to_vec();
+            result.push(this.clone());
+            while let Some(next) = file.below(&this).unwrap() {
+                result.push(next.to_vec());
+                this = next.to_vec();
+            }
+            result
+        };
+
+        for (i, (put, got)) in sorted.iter().zip(asc.iter()).enumerate() {
+            assert_eq!(
+                put,
+                got,
+                "ASC: index={}: expected '{}' but got '{}'",
+                i,
+                hex(put),
+                hex(got)
+            );
+        }
+        assert_eq!(asc, sorted);
+
+        sorted.reverse();
+        for (i, (put, got)) in sorted.iter().zip(desc.iter()).enumerate() {
+            assert_eq!(
+                put,
+                got,
+                "DESC: index={}: expected '{}' but got '{}'",
+                i,
+                hex(put),
+                hex(got)
+            );
+        }
+        assert_eq!(desc, sorted);
+
+        for (i, (key, _)) in data.iter().enumerate() {
+            debug!("({:05}) remove: key={}", i, hex(key));
+            file.remove(key).unwrap();
+        }
+
+        for (i, (key, _)) in data.iter().enumerate() {
+            debug!("({:05}) lookup: key={}", i, hex(key));
+            let found = file.lookup(key).unwrap().map(|v| hex(&v));
+            assert_eq!(found, None);
+        }
+        file.verify().unwrap();
+
+        let copy = file.root().copy();
+        debug!("{}", file.dump());
+        assert!(copy.is_empty());
+    }
+
+    #[test]
+    fn test_wal() {
+        let path = &util::temp("test_wal");
+        let full = &util::temp("test_wal_full");
+        let wal_path = Wal::path(path);
+
+        let size: u32 = 256;
+        let data = util::data(100, 42);
+        let half = data.len() / 2;
+
+        {
+            let file: File<Block> = File::make(path, size).unwrap().with_wal().unwrap();
+            for (k, v) in data.iter().take(half) {
+                file.insert(k, v).unwrap();
+            }
+        }
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+        // Pages of the complete tree act as committed but never applied WAL records.
+        let pages = {
+            let file: File<Block> = File::make(full, size).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            let count = (fs::metadata(full).unwrap().len() as usize - HEAD) / size as usize;
+            (1..=count as u32)
+                .map(|id| (id, file.load(id).unwrap().as_ref().to_vec()))
+                .collect::<Vec<_>>()
+        };
+
+        {
+            let mut wal = Wal::open(&wal_path, size).unwrap();
+            wal.append(pages.iter().map(|(id, page)| (*id, page.as_slice())))
+                .unwrap();
+        }
+        {
+            // Crash in the middle of appending to the WAL: such records are never committed.
+            let mut f = OpenOptions::new().append(true).open(&wal_path).unwrap();
+            f.write_all(&pages[0].0.to_be_bytes()).unwrap();
+            f.write_all(&pages[0].1[..(size as usize / 2)]).unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_wal_evicted() {
+        let path = &util::temp("test_wal_evicted");
+
+        let data = util::data(1000, 42);
+        let (kept, lost) = data.split_at(data.len() / 2);
+        {
+            let file: File<Block> = File::make(path, 256).unwrap();
+            for (k, v) in kept.iter() {
+                file.insert(k, v).unwrap();
+            }
+        }
+
+        {
+            // Dirty pages do not fit into the cache, but must not reach the file uncommitted.
+            let file: File<Block> = File::open(path)
+                .unwrap()
+                .with_cache(Box::new(LruCache::new(4)))
+                .with_wal()
+                .unwrap()
+                .with_durability(Durability::None);
+            for (k, v) in lost.iter() {
+                file.insert(k, v).unwrap();
+            }
+            for (k, _) in kept.iter().step_by(2) {
+                file.remove(k).unwrap();
+            }
+            // Crash: the handle is dropped without a flush.
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        assert_eq!(file.stats().unwrap().entry_count, kept.len() as u64);
+        for (k, v) in kept.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        drop(file);
+
+        // Once flushed, all the evicted pages are written (through the WAL).
+        {
+            let file: File<Block> = File::open(path)
+                .unwrap()
+                .with_cache(Box::new(LruCache::new(4)))
+                .with_wal()
+                .unwrap()
+                .with_durability(Durability::None);
+            for (k, v) in lost.iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.flush().unwrap();
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+            }
+        }
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_oplog() {
+        let path = &util::temp("test_oplog");
+        let replica = &util::temp("test_oplog_replica");
+        let oplog = OpLog::path(path);
+
+        let size: u32 = 256;
+        let data = util::data(300, 42);
+        let file: File<Block> = File::make(path, size).unwrap().with_oplog().unwrap();
+        for (k, v) in data.iter().take(100) {
+            file.insert(k, v).unwrap();
+        }
+        file.extend(data.iter().skip(100).cloned()).unwrap();
+        for (k, _) in data.iter().step_by(3) {
+            file.remove(k).unwrap();
+        }
+        file.remove(b"absent").unwrap();
+        let (key, val) = &data[1];
+        file.update(key, |_| Some(b"updated".to_vec())).unwrap();
+        file.with_value_mut(&data[2].0, |val| val[0] ^= 0xFF)
+            .unwrap();
+        file.retain(|key, _| key[0] % 2 == 0).unwrap();
+        file.insert(key, val).unwrap();
+        file.open_table("table")
+            .unwrap()
+            .insert(b"table-key", b"table-val")
+            .unwrap();
+
+        drop(File::<Block>::make(replica, size).unwrap());
+        let count = File::<Block>::replay_oplog(replica, &oplog).unwrap();
+        let records = OpLog::open(&oplog).unwrap().records().unwrap();
+        assert_eq!(count, records.len());
+        assert!(!records.contains(&Op::Remove(b"absent".to_vec())));
+        assert!(!records.contains(&Op::Insert(b"table-key".to_vec(), b"table-val".to_vec())));
+        let copy: File<Block> = File::open(replica).unwrap();
+        copy.verify().unwrap();
+        assert!(file.content_eq(&copy).unwrap());
+        assert!(!copy.is_empty());
+        drop(copy);
+
+        // Crash in the middle of appending to the oplog: the torn record is ignored.
+        let len = fs::metadata(&oplog).unwrap().len();
+        {
+            let mut f = OpenOptions::new().append(true).open(&oplog).unwrap();
+            f.write_all(&[1, 0, 0, 0, 3, b'k']).unwrap();
+        }
+        fs::remove_file(replica).unwrap();
+        drop(File::<Block>::make(replica, size).unwrap());
+        assert_eq!(File::<Block>::replay_oplog(replica, &oplog).unwrap(), count);
+        assert!(file.content_eq(&File::open(replica).unwrap()).unwrap());
+        assert!(fs::metadata(&oplog).unwrap().len() > len);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let path = &util::temp("test_transaction");
+        let oplog = OpLog::path(path);
+
+        let data = util::data(300, 42);
+        {
+            let file: File<Block> = File::make(path, 256).unwrap().with_oplog().unwrap();
+            for (k, v) in data.iter().take(100) {
+                file.insert(k, v).unwrap();
+            }
+            let len = fs::metadata(&oplog).unwrap().len();
+
+            let result = file
+                .transaction(|txn| {
+                    for (k, v) in data.iter().skip(100) {
+                        txn.insert(k, v)?;
+                    }
+                    txn.remove(&data[0].0)?;
+                    assert_eq!(txn.lookup(&data[0].0)?, None);
+                    assert_eq!(txn.lookup(&data[200].0)?.as_ref(), Some(&data[200].1));
+                    assert_eq!(fs::metadata(&oplog).unwrap().len(), len);
+                    Ok(42)
+                })
+                .unwrap();
+            assert_eq!(result, 42);
+            assert!(fs::metadata(&oplog).unwrap().len() > len);
+            assert!(file.transaction(|txn| txn.insert(b"", b"empty")).is_err());
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        assert_eq!(file.lookup(&data[0].0).unwrap(), None);
+        for (k, v) in data.iter().skip(1) {
+            assert_eq!(file.lookup(k).unwrap().as_ref(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let path = &util::temp("test_transaction_rollback");
+
+        let data = util::data(500, 42);
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_cache(Box::new(LruCache::new(4)));
+        for (k, v) in data.iter().take(200) {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter().take(100).step_by(2) {
+            file.remove(k).unwrap();
+        }
+        let bytes = fs::read(path).unwrap();
+        let entries = file.scan_prefix(b"").unwrap();
+
+        let saved = file.counters.saved.load(Ordering::Relaxed);
+        let result: Result<()> = file.transaction(|txn| {
+            for (k, v) in data.iter().skip(200) {
+                txn.insert(k, v)?;
+            }
+            for (k, _) in data.iter().take(200) {
+                txn.remove(k)?;
+            }
+            Err(Error::Other("abort".to_string()))
+        });
+        assert!(matches!(result, Err(Error::Other(e)) if e == "abort"));
+        // pages evicted from the small cache got written within the transaction
+        assert!(file.counters.saved.load(Ordering::Relaxed) > saved);
+        assert_eq!(fs::read(path).unwrap(), bytes);
+        file.verify().unwrap();
+        assert_eq!(file.scan_prefix(b"").unwrap(), entries);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            file.transaction::<_, ()>(|txn| {
+                for (k, v) in data.iter().skip(200) {
+                    txn.insert(k, v)?;
+                }
+                panic!("boom");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(fs::read(path).unwrap(), bytes);
+        assert_eq!(file.scan_prefix(b"").unwrap(), entries);
+
+        // the store is usable after rollback: pages cut off from the file get reused
+        for (k, v) in data.iter().skip(200) {
+            file.insert(k, v).unwrap();
+        }
+        file.verify().unwrap();
+        drop(file);
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        assert_eq!(file.stats().unwrap().entry_count, 450);
+    }
+
+    #[test]
+    fn test_sync() {
+        let path = &util::temp("test_sync");
+
+        let size: u32 = 256;
+        let data = util::data(100, 42);
+
+        {
+            let file: File<Block> = File::make(path, size)
+                .unwrap()
+                .with_durability(Durability::Sync);
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.sync().unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_durability_none() {
+        let path = &util::temp("test_durability_none");
+
+        let size: u32 = 256;
+        let data = util::data(1000, 42);
+
+        {
+            let file: File<Block> = File::make(path, size)
+                .unwrap()
+                .with_durability(Durability::None);
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.sync().unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_durability_batch() {
+        let path = &util::temp("test_durability_batch");
+
+        let size: u32 = 256;
+        let data = util::data(5000, 42);
+        let saved = |file: &File<Block>| file.counters.saved.load(Ordering::Relaxed);
+
+        // every insert writes its pages
+        let file: File<Block> = File::make(path, size)
+            .unwrap()
+            .with_cache(Box::new(LruCache::new(100_000)));
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let unbatched = saved(&file);
+        assert!(unbatched >= data.len() as u64);
+        drop(file);
+
+        let path = &util::temp("test_durability_batch_100");
+        {
+            let file: File<Block> = File::make(path, size)
+                .unwrap()
+                .with_cache(Box::new(LruCache::new(100_000)))
+                .with_durability(Durability::Batch(100));
+            let mut flushes = 0;
+            for (k, v) in data.iter() {
+                let before = saved(&file);
+                file.insert(k, v).unwrap();
+                if saved(&file) > before {
+                    flushes += 1;
+                    assert!(file.dirty.read().is_empty());
+                }
+            }
+            // each flush writes a batch of ~100 pages (one insert might dirty a few at once)
+            let batched = saved(&file);
+            assert!(flushes > 0);
+            assert!(batched >= flushes * 100, "{} {}", batched, flushes);
+            assert!(batched <= flushes * 110, "{} {}", batched, flushes);
+            assert!(batched * 2 < unbatched, "{} {}", batched, unbatched);
+            assert!(!file.dirty.read().is_empty());
+
+            // the clone going away does not flush, the last handle does
+            drop(file.clone());
+            assert!(!file.dirty.read().is_empty());
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_flusher() {
+        let path = &util::temp("test_flusher");
+
+        let data = util::data(1000, 42);
+        let (head, tail) = data.split_at(500);
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::None)
+            .with_flusher(Duration::from_millis(20))
+            .unwrap();
+        for (k, v) in head.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        // no explicit flush: the pages reach the file within a few intervals
+        let mut waited = 0;
+        while !file.dirty.read().is_empty() && waited < 100 {
+            thread::sleep(Duration::from_millis(20));
+            waited += 1;
+        }
+        assert!(file.dirty.read().is_empty());
+        {
+            let copy: File<Block> = File::open(path).unwrap();
+            for (k, v) in head.iter() {
+                assert_eq!(copy.lookup(k).unwrap().unwrap().deref(), v);
+            }
+        }
+
+        // the clone keeps the flusher running, dropping the last handle flushes the rest
+        let clone = file.clone();
+        drop(file);
+        for (k, v) in tail.iter() {
+            clone.insert(k, v).unwrap();
+        }
+        drop(clone);
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        file.verify().unwrap();
+        assert!(File::<Block>::open_readonly(path)
+            .unwrap()
+            .with_flusher(Duration::from_millis(20))
+            .is_err());
+        drop(file);
+
+        // the flusher (that never wakes up here) does not keep the last handle from writing
+        // the remainder of the batch on drop
+        let path = &util::temp("test_flusher_batch");
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::Batch(1000))
+            .with_flusher(Duration::from_secs(3600))
+            .unwrap();
+        for (k, v) in head.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert!(!file.dirty.read().is_empty());
+        let lock = Arc::downgrade(&file.lock);
+        drop(file);
+        assert!(lock.upgrade().is_none());
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in head.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        assert!(file
+            .with_flusher(Duration::from_millis(20))
+            .unwrap()
+            .with_wal()
+            .is_err());
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<File<Block>>();
+    }
+
+    #[test]
+    fn test_shared_handles() {
+        fn fill<S: Store>(store: S, data: &[(Vec<u8>, Vec<u8>)]) -> usize {
+            for (k, v) in data.iter() {
+                store.insert(k, v).unwrap();
+            }
+            store.count_prefix(&[]).unwrap()
+        }
+
+        let path = &util::temp("test_shared_handles");
+
+        let file: Arc<File<Block>> = Arc::new(File::make(path, 256).unwrap());
+        let data = util::data(1000, 42);
+        let (head, tail) = data.split_at(500);
+        let handles = head
+            .chunks(100)
+            .map(|chunk| {
+                let file = file.clone();
+                let chunk = chunk.to_vec();
+                std::thread::spawn(move || fill(file, &chunk))
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert!(handle.join().unwrap() >= 100);
+        }
+        assert_eq!(fill(file.as_ref(), tail), 1000);
+        assert_eq!(fill(&file, &[]), 1000);
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let path = &util::temp("test_concurrent");
+
+        let size: u32 = 4096;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let data = util::data(2000, 42);
+        let (head, tail) = data.split_at(data.len() / 2);
+        for (k, v) in head.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        std::thread::scope(|scope| {
+            let file = &file;
+            scope.spawn(move || {
+                for (k, v) in tail.iter() {
+                    file.insert(k, v).unwrap();
+                }
+            });
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    for (k, v) in head.iter() {
+                        assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+                    }
+                });
+            }
+        });
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_lookups() {
+        let path = &util::temp("test_concurrent_lookups");
+
+        // Many more pages than the cache holds: readers keep evicting each other's pages.
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(3000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert!(file.stats().unwrap().page_count > 32 * 10);
+
+        std::thread::scope(|scope| {
+            for t in 0..16u64 {
+                let (file, data) = (&file, &data);
+                scope.spawn(move || {
+                    let mut order = (0..data.len()).collect::<Vec<_>>();
+                    order.shuffle(&mut StdRng::seed_from_u64(t));
+                    for i in order {
+                        let (k, v) = &data[i];
+                        assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+                        if i % 10 == 0 {
+                            assert!(file.above(k).unwrap().is_none_or(|above| &above > k));
+                        }
+                    }
+                });
+            }
+        });
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_page_ids() {
+        let path = &util::temp("test_concurrent_page_ids");
+
+        // Pages are not evicted: the tree lock is not held here, as it is by tree operations.
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_cache(Box::new(LruCache::new(100_000)));
+        let live = RwLock::new(HashSet::new());
+        std::thread::scope(|scope| {
+            for t in 0..8u64 {
+                let (file, live) = (&file, &live);
+                scope.spawn(move || {
+                    let mut held = Vec::new();
+                    for i in 0..500u64 {
+                        if (i * 7 + t) % 3 == 0 && !held.is_empty() {
+                            let id = held.swap_remove(((i + t) as usize) % held.len());
+                            assert!(live.write().remove(&id));
+                            file.free_id(id);
+                        } else {
+                            let id = file.next_id().unwrap();
+                            assert!(live.write().insert(id), "duplicate id: {}", id);
+                            held.push(id);
+                        }
+                    }
+                });
+            }
+        });
+        let live = live.into_inner();
+        let pages = file.stats().unwrap().page_count;
+        assert!(live.iter().all(|id| *id > ROOT && *id <= pages));
+        // freed pages were reused before the file was grown
+        assert!((pages as usize) < 8 * 500, "{}", pages);
+    }
+
+    #[test]
+    fn test_clone() {
+        let path = &util::temp("test_clone");
+
+        let file: File<Block> = File::make(path, 1024).unwrap();
+        let data = util::data(2000, 42);
+        let (lo, hi) = data.split_at(data.len() / 2);
+        let (lo, hi) = (lo.to_vec(), hi.to_vec());
+
+        let threads = vec![lo.clone(), hi.clone()]
+            .into_iter()
+            .map(|data| {
+                let file = file.clone();
+                std::thread::spawn(move || {
+                    for (k, v) in data.iter() {
+                        file.insert(k, v).unwrap();
+                        assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+                    }
+                    for (k, _) in data.iter().step_by(2) {
+                        file.remove(k).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        file.verify().unwrap();
+        for (idx, (k, v)) in lo.iter().chain(hi.iter()).enumerate() {
+            let expected = if idx % 2 == 0 { None } else { Some(v.clone()) };
+            assert_eq!(file.lookup(k).unwrap(), expected, "idx={}", idx);
+        }
+    }
+
+    #[test]
+    fn test_min_max_edges() {
+        let path = &util::temp("test_min_max_edges");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.min().unwrap(), None);
+        assert_eq!(file.max().unwrap(), None);
+
+        let mut oracle = std::collections::BTreeSet::new();
+        for i in 0..1000u32 {
+            // keys grow at both ends: 0x80.. upwards and 0x7f.. downwards
+            let key = if i % 2 == 0 {
+                (0x8000_0000u32 + i).to_be_bytes()
+            } else {
+                (0x7fff_ffffu32 - i).to_be_bytes()
+            };
+            file.insert(&key, &i.to_be_bytes()).unwrap();
+            oracle.insert(key.to_vec());
+
+            if i % 3 == 0 {
+                if let Some(lo) = oracle.iter().next().cloned() {
+                    file.remove(&lo).unwrap();
+                    oracle.remove(&lo);
+                }
+            }
+            if i % 5 == 0 {
+                if let Some(hi) = oracle.iter().next_back().cloned() {
+                    file.remove(&hi).unwrap();
+                    oracle.remove(&hi);
+                }
+            }
+
+            assert_eq!(
+                file.min().unwrap().as_ref(),
+                oracle.iter().next(),
+                "i={}",
+                i
+            );
+            assert_eq!(
+                file.max().unwrap().as_ref(),
+                oracle.iter().next_back(),
+                "i={}",
+                i
+            );
+        }
+        file.verify().unwrap();
+
+        let (lo, hi) = *file.edges.read();
+        assert!(lo != 0 && hi != 0 && lo != hi, "lo={} hi={}", lo, hi);
+
+        file.compact().unwrap();
+        assert_eq!(*file.edges.read(), (0, 0));
+        assert_eq!(file.min().unwrap().as_ref(), oracle.iter().next());
+        assert_eq!(file.max().unwrap().as_ref(), oracle.iter().next_back());
+    }
+
+    #[test]
+    fn test_first_last() {
+        let path = &util::temp("test_first_last");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.first().unwrap(), None);
+        assert_eq!(file.last().unwrap(), None);
+
+        let mut oracle = std::collections::BTreeMap::new();
+        for (i, (k, v)) in util::data(1000, 42).into_iter().enumerate() {
+            file.insert(&k, &v).unwrap();
+            oracle.insert(k, v);
+            if i % 4 == 0 {
+                let lo = oracle.keys().next().cloned().unwrap();
+                file.remove(&lo).unwrap();
+                oracle.remove(&lo);
+            }
+
+            let first = file.first().unwrap();
+            let last = file.last().unwrap();
+            assert_eq!(first.as_ref().map(|(k, _)| k), file.min().unwrap().as_ref());
+            assert_eq!(last.as_ref().map(|(k, _)| k), file.max().unwrap().as_ref());
+            assert_eq!(
+                first,
+                oracle.iter().next().map(|(k, v)| (k.clone(), v.clone()))
+            );
+            assert_eq!(
+                last,
+                oracle
+                    .iter()
+                    .next_back()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+            );
+        }
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_swap() {
+        let path = &util::temp("test_swap");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        // keys far apart in the order are stored in different leaves
+        let mut keys = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        keys.sort();
+        let (a, b) = (&keys[0], &keys[keys.len() - 1]);
+        let (val_a, val_b) = (file.get(a).unwrap(), file.get(b).unwrap());
+        assert!(file.swap(a, b).unwrap());
+        assert_eq!(file.get(a).unwrap(), val_b);
+        assert_eq!(file.get(b).unwrap(), val_a);
+
+        assert!(!file.swap(a, b"missing").unwrap());
+        assert!(!file.swap(b"missing", b).unwrap());
+        assert_eq!(file.get(a).unwrap(), val_b);
+        assert_eq!(file.get(b).unwrap(), val_a);
+
+        assert!(file.swap(a, a).unwrap());
+        assert_eq!(file.get(a).unwrap(), val_b);
+
+        file.insert(b"x", b"short").unwrap();
+        file.insert(b"y", &[42u8; 32]).unwrap();
+        assert!(file.swap(b"x", b"y").unwrap());
+        assert_eq!(file.get(b"x").unwrap(), vec![42u8; 32]);
+        assert_eq!(file.get(b"y").unwrap(), b"short");
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_swap_failed() {
+        let path = &util::temp("test_swap_failed");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let big = vec![42u8; 150];
+        file.insert(b"a", &big).unwrap();
+        for i in 0..100u32 {
+            file.insert(format!("b{:04}", i).as_bytes(), b"v").unwrap();
+        }
+
+        // the small value fits into the leaf of "a", but the big one does not fit into the
+        // (much fuller) leaf of "b0050": the second write fails
+        let res = file.swap(b"a", b"b0050");
+        assert!(matches!(res, Err(Error::TooLarge { val_len: 150, .. })));
+        assert_eq!(file.get(b"a").unwrap(), big);
+        assert_eq!(file.get(b"b0050").unwrap(), b"v");
+        file.verify().unwrap();
+
+        drop(file);
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.get(b"a").unwrap(), big);
+        assert_eq!(file.get(b"b0050").unwrap(), b"v");
+    }
+
+    /// Trivial first-in-first-out eviction policy.
+    struct FifoCache {
+        map: HashMap<u32, Block>,
+        order: std::collections::VecDeque<u32>,
+        cap: usize,
+    }
+
+    impl Cache<u32, Block> for FifoCache {
+        fn has(&self, key: &u32) -> bool {
+            self.map.contains_key(key)
+        }
+
+        fn get(&self, key: &u32) -> Option<&Block> {
+            self.map.get(key)
+        }
+
+        fn get_mut(&mut self, key: &u32) -> Option<&mut Block> {
+            self.map.get_mut(key)
+        }
+
+        fn put(&mut self, key: u32, value: Block) -> Option<(u32, Block)> {
+            if self.map.insert(key, value).is_some() {
+                return None;
+            }
+            self.order.push_back(key);
+            if self.order.len() > self.cap {
+                let evicted = self.order.pop_front().unwrap();
+                return self.map.remove_entry(&evicted);
+            }
+            None
+        }
+
+        fn len(&self) -> usize {
+            self.map.len()
+        }
+
+        fn keys(&self) -> Vec<u32> {
+            self.order.iter().cloned().collect()
+        }
+
+        fn capacity(&self) -> usize {
+            self.cap
+        }
+
+        fn clear(&mut self) {
+            self.map.clear();
+            self.order.clear();
+        }
+    }
+
+    #[test]
+    fn test_with_cache() {
+        let path = &util::temp("test_with_cache");
+
+        let cache = FifoCache {
+            map: HashMap::new(),
+            order: Default::default(),
+            cap: 4,
+        };
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::None)
+            .with_cache(Box::new(cache));
+        assert_eq!(file.cache.read().capacity(), 4);
+
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter().step_by(3) {
+            file.remove(k).unwrap();
+        }
+        file.verify().unwrap();
+        assert!(file.cache.read().len() <= 4);
+        for (i, (k, v)) in data.iter().enumerate() {
+            let val = file.lookup(k).unwrap();
+            if i % 3 == 0 {
+                assert_eq!(val, None);
+            } else {
+                assert_eq!(val.unwrap().deref(), v);
+            }
+        }
+
+        file.release().unwrap();
+        assert!(file.cache.read().len() <= 1);
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            if i % 3 != 0 {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_boundary() {
+        let path = &util::temp("test_split_boundary");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for i in 0..3u8 {
+            file.insert(&[i], &[i; 32]).unwrap();
+        }
+        assert!(file.root().is_leaf());
+        assert_eq!(
+            fill(file.root().deref(), SPLIT_THRESHOLD),
+            cmp::Ordering::Less
+        );
+
+        // 193 of 240 usable bytes (80.4%): the truncated percent would be exactly 80
+        file.insert(&[3], &[3; 29]).unwrap();
+        assert!(file.root().is_node());
+        file.verify().unwrap();
+        for i in 0..3u8 {
+            assert_eq!(file.get(&[i]).unwrap(), vec![i; 32]);
+        }
+        assert_eq!(file.get(&[3]).unwrap(), vec![3; 29]);
+    }
+
+    #[test]
+    fn test_split_threshold() {
+        let path = &util::temp("test_split_threshold");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for i in 0..3u8 {
+            file.insert(&[i], &[i; 32]).unwrap();
+        }
+        // 192 of 240 usable bytes: exactly 80%, not over the threshold
+        file.insert(&[3], &[3; 28]).unwrap();
+        assert!(file.root().is_leaf());
+        assert_eq!(file.root().full(), SPLIT_THRESHOLD);
+    }
+
+    fn check_extend(name: &str, count: u32) {
+        let path = &util::temp(name);
+
+        let file: File<Block> = File::make(path, 4096).unwrap();
+        let iter = (0..count).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8]));
+        file.extend(iter).unwrap();
+        assert_eq!(file.stats().unwrap().entry_count, count as u64);
+        assert!(file.dirty.read().is_empty());
+        assert_eq!(file.min().unwrap().unwrap(), 0u32.to_be_bytes());
+        assert_eq!(file.max().unwrap().unwrap(), (count - 1).to_be_bytes());
+
+        let err = file
+            .extend(vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (vec![], b"2".to_vec()),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Other error: 'key : Other error: 'empty key'.'."
+        );
+        assert_eq!(file.get(b"a").unwrap(), b"1");
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for i in (0..count).step_by(997) {
+            assert_eq!(file.get(&i.to_be_bytes()).unwrap(), vec![i as u8]);
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        check_extend("test_extend", 10_000);
+    }
+
+    #[test]
+    #[ignore = "slow in debug builds, run with: cargo test --release -- --ignored"]
+    fn test_extend_million() {
+        check_extend("test_extend_million", 1_000_000);
+    }
+
+    #[test]
+    fn test_cyclic_reference() {
+        let path = &util::temp("test_cyclic_reference");
+
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::None);
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let mut keys = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        keys.sort();
+
+        // Every child of the root references the root instead of its own children.
+        let children = file.root().copy();
+        for (_, _, child) in children {
+            let mut page = file.page_mut(child).unwrap();
+            assert!(page.is_node(), "tree must be at least 3 levels deep");
+            for (key, _, _) in page.copy() {
+                page.put_ref(&key, ROOT).unwrap();
+            }
+        }
+        *file.edges.write() = (0, 0);
+
+        let key = &keys[keys.len() / 2];
+        let cyclic = |res: Result<()>| match res {
+            Err(Error::Tree(_, msg)) => assert!(msg.starts_with("Cyclic reference"), "{}", msg),
+            res => panic!("cycle not detected: {:?}", res),
+        };
+        cyclic(file.lookup(key).map(|_| ()));
+        cyclic(file.get_multi(&[key]).map(|_| ()));
+        cyclic(file.insert(key, b"val"));
+        cyclic(file.remove(key));
+        cyclic(file.min().map(|_| ()));
+        cyclic(file.max().map(|_| ()));
+        cyclic(file.first().map(|_| ()));
+        cyclic(file.above(key).map(|_| ()));
+        cyclic(file.below(key).map(|_| ()));
+        cyclic(file.scan(None, |_, _| true));
+        cyclic(file.count_prefix(&key[..1]).map(|_| ()));
+        assert!(file.verify().is_err());
+    }
+
+    #[test]
+    fn test_make_with_capacity() {
+        let path = &util::temp("test_make_with_capacity");
+
+        let file: File<Block> = File::make_with_capacity(path, 256, 1000).unwrap();
+        let before = file.stats().unwrap();
+        assert_eq!(before.empty_pages, 1000);
+        assert!(before.page_count > 1000);
+        assert_eq!(before.entry_count, 0);
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.stats().unwrap().empty_pages, 1000);
+        let data = util::data(1800, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.verify().unwrap();
+
+        let after = file.stats().unwrap();
+        assert_eq!(after.file_bytes, before.file_bytes, "file must not grow");
+        assert!(
+            after.empty_pages > 300 && after.empty_pages < 700,
+            "about half of the pages used: {}",
+            after.empty_pages
+        );
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.stats().unwrap(), after);
+        for (k, v) in data.iter() {
+            assert_eq!(file.get(k).unwrap(), *v);
+        }
+        drop(file);
+
+        let huge = &util::temp("test_make_with_capacity_huge");
+        let err = File::<Block>::make_with_capacity(huge, 256, u32::MAX)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Too many pages"), "{}", err);
+        assert!(!huge.exists());
+    }
+
+    #[test]
+    fn test_values_in() {
+        let path = &util::temp("test_values_in");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let mut keys = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        keys.sort();
+
+        let bounds = vec![
+            (keys[100].clone(), keys[200].clone()),
+            (keys[0].clone(), keys[999].clone()),
+            (vec![], vec![0xFF; 9]),
+            (keys[500].clone(), keys[500].clone()),
+            (keys[600].clone(), keys[300].clone()),
+        ];
+        for (lo, hi) in bounds {
+            let mut range = Vec::new();
+            file.scan(Some(&lo), |key, val| {
+                if key < hi.as_slice() {
+                    range.push((key.to_vec(), val.to_vec()));
+                }
+                key < hi.as_slice()
+            })
+            .unwrap();
+            let expected = range.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+            assert_eq!(file.values_in(&lo, &hi).unwrap(), expected);
+        }
+        assert_eq!(file.values_in(&keys[10], &keys[20]).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_fold_range() {
+        let path = &util::temp("test_fold_range");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let mut data = (0..1000u64)
+            .map(|i| {
+                (
+                    (i * 7919 % 1000).to_be_bytes().to_vec(),
+                    (i * i).to_be_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        data.sort();
+
+        let sum =
+            |acc: u64, _: &[u8], val: &[u8]| acc + u64::from_be_bytes(val.try_into().unwrap());
+        for (lo, hi) in [
+            (100, 200),
+            (0, 1000),
+            (0, 1),
+            (500, 500),
+            (600, 300),
+            (999, 1000),
+        ] {
+            // keys are sorted, so the range is a slice of the data (empty if lo >= hi)
+            let expected: u64 = data[lo..hi.max(lo)]
+                .iter()
+                .map(|(_, v)| u64::from_be_bytes(*v))
+                .sum();
+            let hi = if hi == 1000 {
+                vec![0xFF; 9]
+            } else {
+                data[hi].0.clone()
+            };
+            assert_eq!(file.fold_range(&data[lo].0, &hi, 0, sum).unwrap(), expected);
+        }
+
+        let count = file
+            .fold_range(&[], &[0xFF; 9], 0, |n, _, _| n + 1)
+            .unwrap();
+        assert_eq!(count, 1000);
+    }
+
+    #[test]
+    fn test_debug() {
+        let path = &util::temp("test_debug");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert!(format!("{:?}", file).contains("entry_count: 0, height: 1,"));
+
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let debug = format!("{:?}", file);
+        assert!(debug.contains("page_bytes: 256,"), "{}", debug);
+        assert!(debug.contains("entry_count: 1000,"), "{}", debug);
+        assert!(debug.contains("cache: 32/32,"), "{}", debug);
+        assert!(!debug.contains('\n'));
+
+        // Holding a page guard does not deadlock
+        let val = file.get_ref(&data[0].0).unwrap().unwrap();
+        assert!(format!("{:?}", file).contains("entry_count: 1000,"));
+        drop(val);
+
+        let _lock = file.lock.write();
+        assert!(format!("{:?}", file).ends_with(", busy }"));
+    }
+
+    #[test]
+    fn test_offset() {
+        let head = |version, page_bytes| Head {
+            version,
+            page_bytes,
+            page_count: 1,
+            flags: 0,
+            free_page: 0,
+            tables: 0,
+        };
+        assert_eq!(head(VERSION, 4096).offset(ROOT).unwrap(), HEAD as u64);
+        assert_eq!(head(VERSION, 256).offset(3).unwrap(), HEAD as u64 + 512);
+        assert_eq!(head(0, 256).offset(3).unwrap(), LEGACY_HEAD as u64 + 512);
+        assert!(head(VERSION, 4096).offset(0).is_err());
+
+        // ~256 TiB into the file: would overflow 32-bit `usize` many times over
+        let max = u16::MAX as u64 * (u32::MAX as u64 - 1) + HEAD as u64;
+        assert_eq!(
+            head(VERSION, u16::MAX as u32).offset(u32::MAX).unwrap(),
+            max
+        );
+        assert!(max > u32::MAX as u64);
+
+        let path = &util::temp("test_offset");
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert!(file.load(0).is_err());
+        assert!(file.page(0).is_none());
+        assert!(file.fetch(0).is_err());
+        assert_eq!(file.load(ROOT).unwrap().id(), ROOT);
+    }
+
+    #[test]
+    fn test_lookup_sorted() {
+        let path = &util::temp("test_lookup_sorted");
+
+        let file: File<Block> = File::make(path, 4096)
+            .unwrap()
+            .with_durability(Durability::None);
+        let data = util::data(20_000, 42);
+        for (k, v) in data.iter().step_by(2) {
+            file.insert(k, v).unwrap();
+        }
+        // half of the keys are present, the other half is missing
+        let mut keys = data.iter().map(|(k, _)| k.as_slice()).collect::<Vec<_>>();
+        keys.sort();
+        keys.truncate(10_000);
+
+        file.release().unwrap();
+        let before = file.counters.snapshot().loaded;
+        let found = file.lookup_sorted(&keys).unwrap();
+        let loaded = file.counters.snapshot().loaded - before;
+        let pages = file.stats().unwrap().page_count as u64;
+        assert!(loaded <= pages, "loaded={} pages={}", loaded, pages);
+
+        for (key, val) in keys.iter().zip(found.iter()) {
+            assert_eq!(val, &file.lookup(key).unwrap());
+        }
+        let hits = found.iter().filter(|val| val.is_some()).count();
+        assert!(hits > 4_000 && hits < 6_000, "hits={}", hits);
+        assert_eq!(found, file.get_multi(&keys).unwrap());
+
+        assert_eq!(
+            file.lookup_sorted(&[]).unwrap(),
+            Vec::<Option<Vec<u8>>>::new()
+        );
+        let dup = vec![keys[0], keys[0], &[0xFF; 9][..]];
+        assert_eq!(
+            file.lookup_sorted(&dup).unwrap(),
+            file.get_multi(&dup).unwrap()
+        );
+        #[cfg(debug_assertions)]
+        assert!(file.lookup_sorted(&[keys[1], keys[0]]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = &util::temp("test_snapshot");
+        let copy = &util::temp("test_snapshot_copy");
+
+        let file: File<Block> = File::make(path, 512).unwrap();
+        let data = util::data(10_000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter().step_by(7) {
+            file.remove(k).unwrap();
+        }
+
+        let mut snapshot = Vec::new();
+        file.snapshot(&mut snapshot).unwrap();
+        let count = file.count_prefix(&[]).unwrap();
+        assert_eq!(snapshot.len(), SNAPSHOT_HEAD + count * (4 + 8 + 4 + 8));
+        let mut export = Vec::new();
+        file.export(&mut export).unwrap();
+        assert!(snapshot.len() < export.len());
+
+        let restored: File<Block> = File::restore(copy, &mut snapshot.as_slice()).unwrap();
+        restored.verify().unwrap();
+        assert_eq!(restored.head.page_bytes, 512);
+        assert_eq!(
+            restored.scan_prefix(&[]).unwrap(),
+            file.scan_prefix(&[]).unwrap()
+        );
+        drop(restored);
+
+        let restored: File<Block> = File::open(copy).unwrap();
+        let mut again = Vec::new();
+        restored.snapshot(&mut again).unwrap();
+        assert_eq!(again, snapshot);
+
+        let broken = &util::temp("test_snapshot_broken");
+        let mut bad = snapshot.clone();
+        bad[0] = b'X';
+        assert!(File::<Block>::restore(broken, &mut bad.as_slice()).is_err());
+        let truncated = &snapshot[..snapshot.len() - 1];
+        let broken = &util::temp("test_snapshot_broken");
+        assert!(File::<Block>::restore(broken, &mut &truncated[..]).is_err());
+    }
+
+    #[test]
+    fn test_with_value_mut() {
+        let path = &util::temp("test_with_value_mut");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(300, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let (key, val) = &data[100];
+        assert!(file
+            .with_value_mut(key, |bytes| {
+                assert_eq!(bytes, val.as_slice());
+                bytes[0] ^= 0xFF;
+            })
+            .unwrap());
+        assert!(!file.with_value_mut(b"missing", |_| unreachable!()).unwrap());
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        let mut expected = val.clone();
+        expected[0] ^= 0xFF;
+        assert_eq!(file.get(key).unwrap(), expected);
+        for (k, v) in data.iter().filter(|(k, _)| k != key) {
+            assert_eq!(file.get(k).unwrap(), *v);
+        }
+    }
+
+    #[test]
+    fn test_count_range() {
+        let path = &util::temp("test_count_range");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(2000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let mut keys = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        keys.sort();
+
+        let bounds = vec![
+            (keys[10].clone(), keys[1990].clone()),
+            (keys[0].clone(), keys[1].clone()),
+            (vec![], vec![0xFF; 9]),
+            (keys[500].clone(), keys[500].clone()),
+            (keys[600].clone(), keys[300].clone()),
+            (vec![0xFF; 9], vec![0xFF; 10]),
+        ];
+        for (lo, hi) in bounds {
+            let expected = keys
+                .iter()
+                .filter(|k| k.as_slice() >= lo.as_slice() && k.as_slice() < hi.as_slice())
+                .count();
+            assert_eq!(file.count_range(&lo, &hi).unwrap(), expected);
+        }
+
+        // Only the last leaf of a wide range has its keys compared one by one.
+        let (count, examined) = file.count_range_with(&keys[10], &keys[1990]).unwrap();
+        assert_eq!(count, 1980);
+        assert!(examined <= file.seek(&keys[1990]).unwrap().unwrap().len() as usize);
+        assert!(examined * 100 < count);
+    }
+
+    #[test]
+    fn test_estimate_range() {
+        let path = &util::temp("test_estimate_range");
+
+        let file: File<Block> = File::make(path, 512)
+            .unwrap()
+            .with_durability(Durability::None);
+        let mut keys = util::data(10000, 42)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            file.insert(key, &[42u8; 8]).unwrap();
+        }
+        keys.sort();
+
+        assert_eq!(file.estimate_range(&keys[0], &keys[0]).unwrap(), 0);
+        assert_eq!(file.estimate_range(&keys[1], &keys[0]).unwrap(), 0);
+        assert_eq!(file.estimate_range(&[0xFF; 9], &[0xFF; 10]).unwrap(), 0);
+        // exact within a single leaf
+        assert_eq!(file.estimate_range(&keys[100], &keys[103]).unwrap(), 3);
+
+        // uniform keys: within a factor of 2 of the exact count
+        let mut ranges = vec![
+            (0, 9999),
+            (0, 100),
+            (5000, 5500),
+            (1234, 2345),
+            (9000, 9999),
+        ];
+        ranges.extend((0..40).map(|i| (i * 120, 9999 - i * 80)));
+        for (lo, hi) in ranges {
+            let exact = file.count_range(&keys[lo], &keys[hi]).unwrap();
+            let estimate = file.estimate_range(&keys[lo], &keys[hi]).unwrap();
+            assert_eq!(exact, hi - lo);
+            assert!(
+                estimate * 2 >= exact && estimate <= exact * 2,
+                "estimate={} exact={}",
+                estimate,
+                exact
+            );
+        }
+        let all = file.estimate_range(&[], &[0xFF; 9]).unwrap();
+        assert!((5000..=20000).contains(&all), "{}", all);
+    }
+
+    /// Image shared between handles, so the tree can be reopened from the same bytes.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Storage for Shared {
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.0.lock().len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            Storage::read_at(&*self.0.lock(), buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            Storage::write_at(&mut *self.0.lock(), buf, offset)
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            Storage::set_len(&mut *self.0.lock(), len)
+        }
+    }
+
+    #[test]
+    fn test_custom_storage() {
+        let image = Shared::default();
+        let file: File<Block> = File::make_on(Box::new(image.clone()), 256).unwrap();
+        assert!(File::<Block>::make_on(Box::new(image.clone()), 256).is_err());
+        assert!(file.clone().with_wal().is_err());
+
+        let mut data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().as_ref(), Some(v));
+        }
+        data.shuffle(&mut StdRng::seed_from_u64(3));
+        for (k, _) in data.iter().take(500) {
+            file.remove(k).unwrap();
+        }
+        file.verify().unwrap();
+        drop(file);
+
+        let file: File<Block> = File::open_on(Box::new(image.clone())).unwrap();
+        file.verify().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let expected = if i < 500 { None } else { Some(v) };
+            assert_eq!(file.lookup(k).unwrap().as_ref(), expected);
+        }
+        assert_eq!(
+            file.stats().unwrap().file_bytes,
+            image.0.lock().len() as u64
+        );
+
+        assert!(File::<Block>::open_on(Box::new(Vec::new())).is_err());
+    }
+
+    /// Shared image failing every read and write of one page (none while it is zero).
+    #[derive(Clone, Default)]
+    struct Faulty {
+        image: Shared,
+        page: Arc<AtomicU32>,
+    }
+
+    impl Faulty {
+        fn check(&self, offset: u64) -> io::Result<()> {
+            match self.page.load(Ordering::Relaxed) {
+                0 => Ok(()),
+                id if offset == HEAD as u64 + (id as u64 - 1) * 256 => {
+                    Err(io::Error::other("injected"))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Storage for Faulty {
+        fn len(&self) -> io::Result<u64> {
+            self.image.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.check(offset)?;
+            self.image.read_at(buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.check(offset)?;
+            self.image.write_at(buf, offset)
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.image.set_len(len)
+        }
+    }
+
+    #[test]
+    fn test_io_error_context() {
+        let storage = Faulty::default();
+        let file: File<Block> = File::make_on(Box::new(storage.clone()), 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let (key, val) = &data[500];
+        let leaf = file.seek(key).unwrap().unwrap().id();
+        assert_ne!(leaf, ROOT);
+
+        storage.page.store(leaf, Ordering::Relaxed);
+        let e = file.insert(key, &val[1..]).unwrap_err();
+        assert!(matches!(e, Error::Io { .. }));
+        assert!(
+            e.to_string().contains(&format!("save page {}", leaf)),
+            "{}",
+            e
+        );
+        drop(file);
+
+        let file: File<Block> = File::open_on(Box::new(storage.clone())).unwrap();
+        let e = file.lookup(key).unwrap_err();
+        assert!(
+            e.to_string().contains(&format!("load page {}", leaf)),
+            "{}",
+            e
+        );
+        match e {
+            Error::Io { source, .. } => assert_eq!(source.to_string(), "injected"),
+            e => panic!("{}", e),
+        }
+
+        storage.page.store(0, Ordering::Relaxed);
+        assert_eq!(file.lookup(key).unwrap().as_ref(), Some(val));
+    }
+
+    #[test]
+    fn test_range_key_bounds() {
+        let path = &util::temp("test_range_key_bounds");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let mut keys = util::data(5000, 42)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            file.insert(key, &[42u8; 32]).unwrap();
+        }
+        keys.sort();
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        let before = file.counters.snapshot().loaded;
+        let all = file.range_key_bounds(&[], &[0xFF; 9]).unwrap();
+        let loaded = file.counters.snapshot().loaded - before;
+        // one separator per leaf, but (almost) only node pages were read
+        assert!(all.len() > 100, "{}", all.len());
+        assert!(
+            loaded * 4 < all.len() as u64,
+            "loaded={} leaves={}",
+            loaded,
+            all.len()
+        );
+
+        for (lo, hi) in [
+            (0, 4999),
+            (100, 200),
+            (2500, 2600),
+            (4000, 4001),
+            (300, 300),
+        ] {
+            let (lo, hi) = (&keys[lo], &keys[hi]);
+            let bounds = file.range_key_bounds(lo, hi).unwrap();
+            assert!(bounds.windows(2).all(|w| w[0] < w[1]));
+            for key in bounds.iter() {
+                assert!(key >= lo && key < hi);
+                assert!(keys.binary_search(key).is_ok(), "{}", hex(key));
+            }
+            let expected = all.iter().filter(|key| *key >= lo && *key < hi);
+            assert!(bounds.iter().eq(expected));
+        }
+        assert_eq!(file.range_key_bounds(&keys[1], &keys[0]).unwrap().len(), 0);
+
+        let file: File<Block> = File::memory(256);
+        file.insert(b"key", b"val").unwrap();
+        assert!(file.range_key_bounds(&[], &[0xFF]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_first_gap() {
+        let path = &util::temp("test_first_gap");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let id = |n: u64| n.to_be_bytes().to_vec();
+        assert_eq!(file.first_gap(&id(0), util::next_u64).unwrap(), Some(id(0)));
+
+        for n in [0, 1, 2, 4, 5] {
+            file.insert(&id(n), b"used").unwrap();
+        }
+        assert_eq!(file.first_gap(&id(0), util::next_u64).unwrap(), Some(id(3)));
+        assert_eq!(file.first_gap(&id(3), util::next_u64).unwrap(), Some(id(3)));
+        assert_eq!(file.first_gap(&id(4), util::next_u64).unwrap(), Some(id(6)));
+
+        // keys in between the ids are skipped, not taken for a gap
+        file.insert(&[0, 0, 0, 0, 0, 0, 0, 1, 42], b"other")
+            .unwrap();
+        file.insert(&id(3), b"used").unwrap();
+        assert_eq!(file.first_gap(&id(0), util::next_u64).unwrap(), Some(id(6)));
+
+        // dense up to the end of the key space
+        let data = (0..1000).map(|n| id(u64::MAX - n)).collect::<Vec<_>>();
+        for key in data.iter() {
+            file.insert(key, b"used").unwrap();
+        }
+        assert_eq!(file.first_gap(&data[999], util::next_u64).unwrap(), None);
+        file.remove(&data[500]).unwrap();
+        assert_eq!(
+            file.first_gap(&data[999], util::next_u64).unwrap(),
+            Some(data[500].clone())
+        );
+    }
+
+    #[test]
+    fn test_lookup_path() {
+        let path = &util::temp("test_lookup_path");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.lookup_path(b"key").unwrap(), (None, vec![ROOT]));
+
+        let data = util::data(2000, 42);
+        for (k, v) in data.iter().step_by(2) {
+            file.insert(k, v).unwrap();
+        }
+        let height = {
+            let mut height = 1;
+            let mut id = ROOT;
+            while let Some(child) = file.page(id).unwrap().slot(0).filter(|s| !s.is_val()) {
+                id = child.page;
+                height += 1;
+            }
+            height
+        };
+        assert!(height > 2);
+
+        let max = file.max().unwrap().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let (val, ids) = file.lookup_path(k).unwrap();
+            assert_eq!(ids.first(), Some(&ROOT));
+            assert_eq!(ids.len(), height);
+            let leaf = file.page(*ids.last().unwrap()).unwrap();
+            assert!(leaf.is_leaf());
+            if i % 2 == 0 {
+                assert_eq!(val.as_ref(), Some(v));
+                assert!(leaf.find(k).is_some());
+            } else {
+                // absent key: the leaf is where it belongs
+                assert_eq!(val, None);
+                assert!(leaf.find(k).is_none());
+                assert!(leaf.ceil(k).is_some() || leaf.max() == max.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_release() {
+        let path = &util::temp("test_release");
+
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::None);
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert!(file.cache.read().len() > 1);
+        assert!(!file.dirty.read().is_empty());
+
+        file.release().unwrap();
+        assert_eq!(file.cache.read().len(), 1);
+        assert!(file.dirty.read().is_empty());
+
+        let before = file.counters.loaded.load(Ordering::Relaxed);
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        assert!(file.counters.loaded.load(Ordering::Relaxed) > before);
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_trim_memory() {
+        let path = &util::temp("test_trim_memory");
+
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_cache(Box::new(LruCache::new(100_000)))
+            .with_durability(Durability::None);
+        let data = util::data(5000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.sync().unwrap();
+        assert!(file.dirty.read().is_empty());
+        assert!(file.dirty.read().capacity() > 1000);
+
+        // free pages are released, then reused
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+        }
+        assert!(file.empty.read().len() > 500);
+        for (k, v) in data.iter().take(4000) {
+            file.insert(k, v).unwrap();
+        }
+        file.sync().unwrap();
+        let free = file.empty.read().len();
+        assert!(file.empty.read().capacity() > free * 2);
+
+        file.trim_memory();
+        assert!(file.dirty.read().capacity() < 100);
+        assert!(file.empty.read().capacity() < free * 2);
+        assert_eq!(file.empty.read().len(), free);
+
+        file.verify().unwrap();
+        for (k, v) in data.iter().take(4000) {
+            assert_eq!(file.lookup(k).unwrap().as_ref(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        let path = &util::temp("test_put_if_absent");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert!(file.put_if_absent(b"a", b"1").unwrap());
+        assert_eq!(file.lookup(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(!file.put_if_absent(b"a", b"2").unwrap());
+        assert_eq!(file.lookup(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let data = util::data(500, 42);
+        for (k, v) in data.iter() {
+            assert!(file.put_if_absent(k, v).unwrap());
+        }
+        for (k, _) in data.iter() {
+            assert!(!file.put_if_absent(k, b"other").unwrap());
+            assert_eq!(file.last_op_pages().split, 0);
+        }
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        assert!(file.put_if_absent(b"", b"1").is_err());
+    }
+
+    #[test]
+    fn test_prefix_keys() {
+        let path = &util::temp("test_prefix_keys");
+
+        // keys that are prefixes of each other: a, ab, ab\0, abc, ... for many bases
+        let mut keys = Vec::new();
+        for i in 0..200u32 {
+            let base = i.to_be_bytes().to_vec();
+            for suffix in [
+                &b""[..],
+                b"a",
+                b"ab",
+                b"ab\x00",
+                b"abc",
+                b"\x00",
+                b"\x00\x00",
+            ] {
+                let mut key = base.clone();
+                key.extend_from_slice(suffix);
+                keys.push(key);
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(42);
+        keys.shuffle(&mut rng);
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            file.insert(key, &(i as u32).to_be_bytes()).unwrap();
+            if i % 20 == 0 {
+                file.verify().unwrap();
+            }
+        }
+        file.verify().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                file.lookup(key).unwrap(),
+                Some((i as u32).to_be_bytes().to_vec())
+            );
+        }
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+        let mut scanned = Vec::new();
+        file.scan(None, |key, _| {
+            scanned.push(key.to_vec());
+            true
+        })
+        .unwrap();
+        assert_eq!(scanned, sorted);
+
+        let (removed, kept) = keys.split_at(keys.len() * 3 / 4);
+        for (i, key) in removed.iter().enumerate() {
+            file.remove(key).unwrap();
+            if i % 20 == 0 {
+                file.verify().unwrap();
+            }
+        }
+        file.verify().unwrap();
+        for key in removed.iter() {
+            assert_eq!(file.lookup(key).unwrap(), None);
+        }
+        for key in kept.iter() {
+            assert!(file.lookup(key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_clone_to() {
+        let path = &util::temp("test_clone_to");
+        let copy_path = &util::temp("test_clone_to_copy");
+
+        let file: File<Block> = File::make(path, 256)
+            .unwrap()
+            .with_durability(Durability::None);
+        let mut data = util::data(3000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        data.shuffle(&mut StdRng::seed_from_u64(7));
+        for (k, _) in data.iter().take(2000) {
+            file.remove(k).unwrap();
+        }
+        assert!(file.stats().unwrap().empty_pages > 0);
+
+        let copy = file.clone_to(copy_path).unwrap();
+        copy.verify().unwrap();
+        assert!(copy.content_eq(&file).unwrap());
+        let (before, after) = (file.stats().unwrap(), copy.stats().unwrap());
+        assert!(
+            after.page_count <= before.page_count,
+            "{:?} {:?}",
+            before,
+            after
+        );
+        assert_eq!(after.empty_pages, 0);
+        assert!(file.clone_to(copy_path).is_err());
+
+        // both the source (flushed before copying) and the copy are persisted
+        drop(file);
+        drop(copy);
+        let file: File<Block> = File::open(path).unwrap();
+        let copy: File<Block> = File::open(copy_path).unwrap();
+        assert!(copy.content_eq(&file).unwrap());
+        for (k, v) in data.iter().skip(2000) {
+            assert_eq!(copy.lookup(k).unwrap().as_ref(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_split_at() {
+        let path = &util::temp("test_split_at");
+        let left_path = &util::temp("test_split_at_left");
+        let right_path = &util::temp("test_split_at_right");
+
+        let file: File<Block> = File::make(path, 512)
+            .unwrap()
+            .with_durability(Durability::None);
+        let data = util::data(10000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.sync().unwrap();
+        let before = file.stats().unwrap();
+        let entries = file.scan_prefix(&[]).unwrap();
+        let median = &entries[5000].0;
+
+        let (left, right) = file.split_at(median, left_path, right_path).unwrap();
+        left.verify().unwrap();
+        right.verify().unwrap();
+        let lo = left.scan_prefix(&[]).unwrap();
+        let hi = right.scan_prefix(&[]).unwrap();
+        assert_eq!(lo.len(), 5000);
+        assert_eq!(hi.len(), 5000);
+        assert!(lo.iter().all(|(k, _)| k < median));
+        assert_eq!(&hi[0].0, median);
+        assert_eq!([lo, hi].concat(), entries);
+
+        // the original is left as it was, existing files are not overwritten
+        file.verify().unwrap();
+        assert_eq!(file.stats().unwrap(), before);
+        let other_path = &util::temp("test_split_at_other");
+        assert!(file.split_at(median, other_path, left_path).is_err());
+        assert!(!other_path.exists());
+        drop(left);
+        drop(right);
+
+        let left: File<Block> = File::open(left_path).unwrap();
+        let right: File<Block> = File::open(right_path).unwrap();
+        for (k, v) in data.iter() {
+            let half = if k < median { &left } else { &right };
+            assert_eq!(half.lookup(k).unwrap().as_ref(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let path = &util::temp("test_merge_from");
+        let other_path = &util::temp("test_merge_from_other");
+        let mine_path = &util::temp("test_merge_from_mine");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let other: File<Block> = File::make(other_path, 256).unwrap();
+        let data = util::data(3000, 42);
+        // overlapping halves: [0, 2000) here, [1000, 3000) there with other values
+        for (k, v) in data.iter().take(2000) {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter().skip(1000) {
+            other.insert(k, &k.repeat(2)).unwrap();
+        }
+
+        file.merge_from(&other).unwrap();
+        file.verify().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let expected = if i < 1000 { v.clone() } else { k.repeat(2) };
+            assert_eq!(file.lookup(k).unwrap(), Some(expected));
+        }
+        assert_eq!(other.stats().unwrap().entry_count, 2000);
+
+        // own values win with a custom resolver, and merged values are persisted
+        let mine: File<Block> = File::make(mine_path, 256).unwrap();
+        for (k, v) in data.iter().take(2000) {
+            mine.insert(k, v).unwrap();
+        }
+        mine.merge_from_with(&other, |_, ours, theirs| [ours, theirs].concat())
+            .unwrap();
+        drop(mine);
+        let mine: File<Block> = File::open(mine_path).unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let expected = match i {
+                0..=999 => v.clone(),
+                1000..=1999 => [v.clone(), k.repeat(2)].concat(),
+                _ => k.repeat(2),
+            };
+            assert_eq!(mine.lookup(k).unwrap(), Some(expected));
+        }
+
+        assert!(file.merge_from(&file.clone()).is_err());
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let path = &util::temp("test_rebuild");
+        let out_path = &util::temp("test_rebuild_out");
+        let empty_path = &util::temp("test_rebuild_empty");
+        let copy_path = &util::temp("test_rebuild_copy");
+
+        let file: File<Block> = File::make(path, 512).unwrap();
+        let data = util::data(3000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter().step_by(2) {
+            file.remove(k).unwrap();
+        }
+
+        let out = file.rebuild(out_path).unwrap();
+        out.verify().unwrap();
+
+        let before = file.stats().unwrap();
+        let after = out.stats().unwrap();
+        assert_eq!(after.entry_count, before.entry_count);
+        assert!(
+            after.file_bytes < before.file_bytes,
+            "{:?} {:?}",
+            before,
+            after
+        );
+        assert!(
+            after.page_count < before.page_count,
+            "{:?} {:?}",
+            before,
+            after
+        );
+        assert_eq!(after.empty_pages, 0);
+
+        let dump = |file: &File<Block>| {
+            let mut entries = Vec::new();
+            file.scan(None, |k, v| {
+                entries.push((k.to_vec(), v.to_vec()));
+                true
+            })
+            .unwrap();
+            entries
+        };
+        assert_eq!(dump(&out), dump(&file));
+        for (k, _) in data.iter() {
+            assert_eq!(out.lookup(k).unwrap(), file.lookup(k).unwrap());
+        }
+        assert_eq!(out.min().unwrap(), file.min().unwrap());
+        assert_eq!(out.max().unwrap(), file.max().unwrap());
+        drop(out);
+
+        let out: File<Block> = File::open(out_path).unwrap();
+        out.verify().unwrap();
+        for (k, v) in data.iter().skip(1).step_by(2) {
+            assert_eq!(out.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        for (k, v) in data.iter().step_by(2) {
+            out.insert(k, v).unwrap();
+        }
+        out.verify().unwrap();
+
+        let empty: File<Block> = File::make(empty_path, 512).unwrap();
+        let copy = empty.rebuild(copy_path).unwrap();
+        assert!(copy.is_empty());
+        copy.verify().unwrap();
+    }
+
+    #[test]
+    fn test_content_eq() {
+        let path = &util::temp("test_content_eq");
+        let out_path = &util::temp("test_content_eq_out");
+        let other_path = &util::temp("test_content_eq_other");
+
+        let file: File<Block> = File::make(path, 512).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let out = file.rebuild(out_path).unwrap();
+        assert_ne!(
+            out.stats().unwrap().page_count,
+            file.stats().unwrap().page_count
+        );
+        assert!(file.content_eq(&out).unwrap());
+        assert!(out.content_eq(&file).unwrap());
+
+        let other: File<Block> = File::make(other_path, 256).unwrap();
+        assert!(!file.content_eq(&other).unwrap());
+        for (k, v) in data.iter().rev() {
+            other.insert(k, v).unwrap();
+        }
+        assert!(file.content_eq(&other).unwrap());
+
+        let (key, val) = &data[500];
+        other.insert(key, b"changed").unwrap();
+        assert!(!file.content_eq(&other).unwrap());
+        other.remove(key).unwrap();
+        assert!(!file.content_eq(&other).unwrap());
+        other.insert(key, val).unwrap();
+        assert!(file.content_eq(&other).unwrap());
+    }
+
+    #[test]
+    fn test_get_ref() {
+        let path = &util::temp("test_get_ref");
+
+        let size: u32 = 4096;
+        let file: File<Block> = File::make(path, size).unwrap();
+
+        let key = b"key".to_vec();
+        let val = (0..1024).map(|i| i as u8).collect::<Vec<_>>();
+        file.insert(&key, &val).unwrap();
+
+        {
+            let guard = file.get_ref(&key).unwrap().unwrap();
+            assert_eq!(guard.deref(), val.as_slice());
+
+            let page = file.root().as_ref().as_ptr_range();
+            assert!(page.contains(&guard.as_ptr()));
+            assert!(file.cache.try_write().is_none());
+        }
+        assert!(file.cache.try_write().is_some());
+
+        assert!(file.get_ref(b"nope").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let src_path = &util::temp("test_export");
+        let dst_path = &util::temp("test_import");
+
+        let size: u32 = 4096;
+        let src: File<Block> = File::make(src_path, size).unwrap();
+        let data = util::data(500, 42);
+        for (k, v) in data.iter() {
+            src.insert(k, v).unwrap();
+        }
+
+        let mut buf = b"# comment\n\n".to_vec();
+        src.export(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf).lines().count(),
+            data.len() + 2
+        );
+
+        let dst: File<Block> = File::make(dst_path, size).unwrap();
+        assert_eq!(dst.import(&mut buf.as_slice()).unwrap(), data.len());
+
+        let mut copy = Vec::new();
+        dst.export(&mut copy).unwrap();
+        assert_eq!(copy, buf[11..].to_vec());
+        for (k, v) in data.iter() {
+            assert_eq!(dst.lookup(k).unwrap().unwrap().deref(), v);
+        }
+
+        assert!(dst.import(&mut b"zz 00\n".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_export_import_json() {
+        let src_path = &util::temp("test_export_json");
+        let dst_path = &util::temp("test_import_json");
+
+        let size: u32 = 4096;
+        let src: File<Block> = File::make(src_path, size).unwrap();
+        let data = util::data(300, 42);
+        for (k, v) in data.iter() {
+            src.insert(k, v).unwrap();
+        }
+
+        let mut buf = Vec::new();
+        src.export_json(&mut buf).unwrap();
+
+        let dst: File<Block> = File::make(dst_path, size).unwrap();
+        assert_eq!(dst.import_json(&mut buf.as_slice()).unwrap(), data.len());
+        for (k, v) in data.iter() {
+            assert_eq!(dst.lookup(k).unwrap().unwrap().deref(), v);
+        }
+
+        let mut copy = Vec::new();
+        dst.export_json(&mut copy).unwrap();
+        assert_eq!(copy, buf);
+
+        assert!(dst
+            .import_json(&mut b"[{\"k\":\"zz\",\"v\":\"\"}]".as_slice())
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let path = &util::temp("test_verify");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+        let data = util::data(100, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.verify().unwrap();
+
+        let (key, child_id) = {
+            let root = file.root();
+            (root.key(0).to_vec(), root.slot(0).unwrap().page)
+        };
+
+        file.free_id(child_id);
+        assert!(file.verify().is_err());
+        file.empty.write().clear();
+        file.verify().unwrap();
+
+        {
+            let mut root = file.root_mut();
+            root.put_ref(b"\x00", child_id);
+        }
+        assert!(file.verify().is_err());
+
+        {
+            let mut root = file.root_mut();
+            let idx = root.find(b"\x00").unwrap();
+            root.remove(idx);
+            let idx = root.find(&key).unwrap();
+            root.remove(idx);
+            let mut key = key.clone();
+            key.push(0);
+            root.put_ref(&key, child_id);
+        }
+        assert!(file.verify().is_err());
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = &util::temp("test_compact");
+
+        let size: u32 = 1024;
+        let file: File<Block> = File::make(path, size).unwrap();
+        let data = util::data(10000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        let (removed, kept) = data.split_at(data.len() * 9 / 10);
+        for (k, _) in removed.iter() {
+            file.remove(k).unwrap();
+        }
+
+        let before = fs::metadata(path).unwrap().len();
+        file.compact().unwrap();
+        let after = fs::metadata(path).unwrap().len();
+        assert!(after < before, "before={} after={}", before, after);
+
+        file.verify().unwrap();
+        for (k, v) in kept.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        for (k, _) in removed.iter() {
+            assert!(file.lookup(k).unwrap().is_none());
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (k, v) in kept.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        drop(file);
+
+        // With the WAL enabled, moved pages go through the log (and it is truncated after).
+        let file: File<Block> = File::open(path).unwrap().with_wal().unwrap();
+        for (k, v) in removed.iter() {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in removed.iter() {
+            file.remove(k).unwrap();
+        }
+        file.compact().unwrap();
+        assert_eq!(fs::metadata(Wal::path(path)).unwrap().len(), 0);
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        assert_eq!(file.stats().unwrap().entry_count, kept.len() as u64);
+        for (k, v) in kept.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_free_list() {
+        let path = &util::temp("test_free_list");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(2000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let (removed, kept) = data.split_at(data.len() * 9 / 10);
+        for (k, _) in removed.iter() {
+            file.remove(k).unwrap();
+        }
+        file.verify().unwrap();
+
+        let sorted = |file: &File<Block>| {
+            let mut ids = file.empty.read().iter().map(|id| id.0).collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids
+        };
+        let empty = sorted(&file);
+        let chain = file.free_list.read().pages.clone();
+        assert!(chain.len() > 1, "{:?}", chain);
+        let pages = (fs::metadata(path).unwrap().len() as usize - HEAD) / 256;
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(sorted(&file), empty);
+        assert_eq!(file.free_list.read().pages, chain);
+        // only the free-list pages are loaded, not the whole file
+        assert_eq!(
+            file.counters.loaded.load(Ordering::Relaxed),
+            chain.len() as u64
+        );
+        assert!(
+            chain.len() * 10 < pages,
+            "chain={} pages={}",
+            chain.len(),
+            pages
+        );
+
+        file.verify().unwrap();
+        for (k, v) in kept.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+
+        // free pages are reused after reopening instead of growing the file
+        let len = fs::metadata(path).unwrap().len();
+        for (k, v) in removed.iter().take(100) {
+            file.insert(k, v).unwrap();
+        }
+        assert_eq!(fs::metadata(path).unwrap().len(), len);
+        file.verify().unwrap();
+
+        // files without persisted free list are scanned once, then the list is saved
+        let empty = sorted(&file);
+        drop(file);
+        {
+            let mut f = OpenOptions::new().write(true).open(path).unwrap();
+            f.seek(SeekFrom::Start(FLAGS_OFFSET)).unwrap();
+            f.write_all(&[0u8; 8]).unwrap();
+        }
+        let file: File<Block> = File::open(path).unwrap();
+        let scanned = sorted(&file);
+        assert!(empty.iter().all(|id| scanned.contains(id)));
+        assert!(file.counters.loaded.load(Ordering::Relaxed) as usize >= pages - 1);
+        drop(file);
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(sorted(&file), scanned);
+        assert!((file.counters.loaded.load(Ordering::Relaxed) as usize) < pages / 10);
+        file.verify().unwrap();
+
+        file.compact().unwrap();
+        assert!(file.free_list.read().pages.is_empty());
+        let file: File<Block> = File::open(path).unwrap();
+        assert!(file.empty.read().is_empty());
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_open_fast() {
+        let path = &util::temp("test_open_fast");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(2000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let (removed, kept) = data.split_at(1500);
+        for (k, _) in removed.iter() {
+            file.remove(k).unwrap();
+        }
+        let free = file.empty.read().len();
+        assert!(free > 100);
+        drop(file);
+        {
+            // no persisted free list
+            let mut f = OpenOptions::new().write(true).open(path).unwrap();
+            f.seek(SeekFrom::Start(FLAGS_OFFSET)).unwrap();
+            f.write_all(&[0u8; 8]).unwrap();
+        }
+        let len = fs::metadata(path).unwrap().len();
+        let pages = ((len - HEAD as u64) / 256) as u32;
+
+        let file: File<Block> = File::open_fast(path).unwrap();
+        assert!(file.empty.read().is_empty());
+        assert_eq!(*file.scan.read(), Some((ROOT + 1, pages)));
+        assert_eq!(file.counters.loaded.load(Ordering::Relaxed), 0);
+        for (k, v) in kept.iter() {
+            assert_eq!(file.get(k).unwrap(), *v);
+        }
+
+        // freed ids are found by the deferred scan and reused instead of growing the file
+        for (k, v) in removed.iter().take(700) {
+            file.insert(k, v).unwrap();
+        }
+        assert!(file.counters.allocated.load(Ordering::Relaxed) > 10);
+        assert_eq!(fs::metadata(path).unwrap().len(), len);
+        file.verify().unwrap();
+        drop(file);
+
+        // the scan is not complete, so the free list is not persisted yet
+        let file: File<Block> = File::open_fast(path).unwrap();
+        assert!(file.scan.read().is_some());
+        // until the scan runs out of pages: then all free pages are persisted
+        file.resume_scan().unwrap();
+        while file.scan.read().is_some() {
+            file.empty.write().clear();
+            file.resume_scan().unwrap();
+        }
+        drop(file);
+        let file: File<Block> = File::open_fast(path).unwrap();
+        assert!(file.scan.read().is_none());
+        for (k, v) in kept.iter().chain(removed.iter().take(700)) {
+            assert_eq!(file.get(k).unwrap(), *v);
+        }
+        file.verify().unwrap();
+    }
+
+    #[test]
+    fn test_above_below_edges() {
+        let path = &util::temp("test_above_below_edges");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+        let data = util::data(200, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        assert_ne!(file.root().slot(0).unwrap().page, 0);
+
+        let keys = data
+            .iter()
+            .map(|(k, _)| k.clone())
+            .collect::<std::collections::BTreeSet<_>>();
+
+        let mut probes = vec![vec![], vec![0x00], vec![0xFF; 9], vec![0xFF; 8]];
+        for key in keys.iter() {
+            let mut longer = key.clone();
+            longer.push(0);
+            probes.push(longer);
+            probes.push(key[..4].to_vec());
+            let mut lo = key.clone();
+            *lo.last_mut().unwrap() = lo.last().unwrap().wrapping_sub(1);
+            probes.push(lo);
+            let mut hi = key.clone();
+            *hi.last_mut().unwrap() = hi.last().unwrap().wrapping_add(1);
+            probes.push(hi);
+            probes.push(key.clone());
+        }
+
+        for probe in probes.iter() {
+            let above = keys
+                .range::<Vec<u8>, _>((std::ops::Bound::Excluded(probe), std::ops::Bound::Unbounded))
+                .next()
+                .cloned();
+            let below = keys.range::<Vec<u8>, _>(..probe).next_back().cloned();
+            assert_eq!(file.above(probe).unwrap(), above, "above {}", hex(probe));
+            assert_eq!(file.below(probe).unwrap(), below, "below {}", hex(probe));
+        }
+    }
+
+    #[test]
+    fn test_endianness() {
+        let be_path = &util::temp("test_endianness_be");
+        let le_path = &util::temp("test_endianness_le");
+
+        let size: u32 = 256;
+        let data = util::data(100, 42);
+        {
+            let be: File<Block> = File::make(be_path, size).unwrap();
+            let le: File<BlockLE> = File::make(le_path, size).unwrap();
+            for (k, v) in data.iter() {
+                be.insert(k, v).unwrap();
+                le.insert(k, v).unwrap();
+            }
+            assert_eq!(be.root().id(), ROOT);
+            assert_eq!(le.root().id(), ROOT);
+            assert_eq!(&be.root().as_ref()[0..4], &[0, 0, 0, 1]);
+            assert_eq!(&le.root().as_ref()[0..4], &[1, 0, 0, 0]);
+        }
+
+        let be: File<Block> = File::open(be_path).unwrap();
+        let le: File<BlockLE> = File::open(le_path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(be.lookup(k).unwrap().unwrap().deref(), v);
+            assert_eq!(le.lookup(k).unwrap().unwrap().deref(), v);
         }
 
-        self.free_id(src_id);
-        Ok(())
+        assert!(File::<BlockLE>::open(be_path).is_err());
+        assert!(File::<Block>::open(le_path).is_err());
     }
 
-    fn dump(&self) -> String {
-        fn dump_page<P: Page>(
-            file: &File<P>,
-            page_id: u32,
-            parent_id: u32,
-            acc: &mut String,
-            prefix: String,
-            tab: String,
-        ) {
-            if page_id == 0 {
-                return;
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression() {
+        let path = &util::temp("test_compression");
+
+        let size: u32 = 4096;
+        let data = util::data(100, 42);
+        let plain = vec![42u8; 1024];
+        {
+            let file: File<Block> =
+                File::make_with_compression(path, size, Compression::Zstd).unwrap();
+            file.insert(b"plain", &plain).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
             }
 
-            let page = file.page(page_id).unwrap();
-            let copy = page.copy();
-            let full = page.full();
+            let root = file.root();
+            let idx = root.find(b"plain").unwrap();
+            assert!(root.is_compressed(idx));
+            assert!(root.slot(idx).unwrap().vlen < 64);
+            drop(root);
+
+            assert!(file.get_ref(b"plain").is_err());
+            assert_eq!(file.lookup(b"plain").unwrap().unwrap(), plain);
+
+            let val = file.lookup_cow(b"plain").unwrap().unwrap();
+            assert!(!val.is_borrowed());
+            assert_eq!(val.deref(), plain.as_slice());
+            drop(val);
+            let (k, v) = &data[0];
+            let val = file.lookup_cow(k).unwrap().unwrap();
+            assert!(val.is_borrowed());
+            assert_eq!(val.into_owned(), *v);
+            file.verify().unwrap();
+        }
 
-            acc.push_str(&if copy.is_empty() {
-                format!("{}page={}: empty", prefix, page_id)
-            } else {
-                let entries = copy
-                    .iter()
-                    .map(|(k, v, p)| format!("{}{}, {}, {}", prefix, hex(k), hex(v), p))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                format!(
-                    "{}page={}: (parent={}) {}% full\n{}",
-                    prefix, page_id, parent_id, full, entries
-                )
-            });
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.lookup(b"plain").unwrap().unwrap(), plain);
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
 
-            acc.push('\n');
-            let links = copy.iter().map(|(_, _, p)| p).cloned();
-            links.for_each(|id| {
-                let mut p = prefix.clone();
-                p.push_str(&tab);
-                dump_page(file, id, page_id, acc, p, tab.clone());
-            });
+    #[test]
+    fn test_value_len() {
+        let path = &util::temp("test_value_len");
+
+        let file: File<Block> = File::make(path, 4096).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let large = vec![42u8; 1500];
+        file.insert(b"large", &large).unwrap();
+        file.insert(b"empty", &[]).unwrap();
+
+        let keys = data.iter().map(|(k, _)| k.as_slice());
+        for key in keys.chain([&b"large"[..], b"empty", b"none"]) {
+            let expected = file.lookup(key).unwrap().map(|v| v.len() as u32);
+            assert_eq!(file.value_len(key).unwrap(), expected);
         }
+        assert_eq!(file.value_len(b"large").unwrap(), Some(1500));
+        assert_eq!(file.value_len(b"empty").unwrap(), Some(0));
+        assert_eq!(file.value_len(b"none").unwrap(), None);
+        assert!(file.value_len(b"").is_err());
 
-        let mut acc = String::with_capacity(1024);
-        dump_page(self, ROOT, 0, &mut acc, "".to_string(), "\t".to_string());
-        acc
+        #[cfg(feature = "zstd")]
+        {
+            let path = &util::temp("test_value_len_zstd");
+            let file: File<Block> =
+                File::make_with_compression(path, 4096, Compression::Zstd).unwrap();
+            file.insert(b"large", &large).unwrap();
+            file.insert(b"small", b"val").unwrap();
+            let (page, idx) = file.leaf(b"large").unwrap().unwrap();
+            assert!(page.is_compressed(idx));
+            assert!(page.slot(idx).unwrap().vlen < 1500);
+            drop(page);
+            assert_eq!(file.value_len(b"large").unwrap(), Some(1500));
+            assert_eq!(file.value_len(b"small").unwrap(), Some(3));
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::disk::block::Block;
-    use crate::util;
-    use crate::util::hex::hex;
-    use rand::prelude::StdRng;
-    use rand::seq::SliceRandom;
-    use rand::SeedableRng;
-    use std::ops::Deref;
+    #[test]
+    fn test_lookup_cow() {
+        let path = &util::temp("test_lookup_cow");
 
-    fn get<P: Page>(page: &P, key: &[u8]) -> Option<(Vec<u8>, u32)> {
-        page.find(key)
-            .map(|idx| (page.val(idx).to_vec(), page.slot(idx).unwrap().page))
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(500, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.release().unwrap();
+        for (k, v) in data.iter() {
+            let val = file.lookup_cow(k).unwrap().unwrap();
+            assert!(val.is_borrowed());
+            assert_eq!(val.as_ref(), v.as_slice());
+        }
+        assert!(file.lookup_cow(b"none").unwrap().is_none());
+        assert!(file.lookup_cow(b"").is_err());
+
+        let owned = ValueRef::Owned(data[0].1.clone());
+        assert!(!owned.is_borrowed());
+        assert_eq!(owned.deref(), data[0].1.as_slice());
     }
 
     #[test]
-    fn test_page() {
-        let path = Path::new("target/test_page.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    fn test_scan() {
+        let path = &util::temp("test_scan");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for i in 0..1000u32 {
+            file.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
         }
-        let size: u32 = 256;
+        assert!(file.root().len() > 1);
 
-        let data = vec![
-            (b"aaa".to_vec(), b"zxczxczxc".to_vec(), 0),
-            (b"bbb".to_vec(), b"asdasdasd".to_vec(), 0),
-            (b"ccc".to_vec(), b"qweqweqwe".to_vec(), 0),
-            (b"ddd".to_vec(), b"123123123".to_vec(), 0),
-            (b"xxx".to_vec(), vec![], 3333),
-            (b"yyy".to_vec(), vec![], 2222),
-            (b"zzz".to_vec(), vec![], 1111),
-        ];
+        let num = |mut bytes: &[u8]| bytes.get_u32() as u64;
 
-        {
-            let file: File<Block> = File::make(path, size).unwrap();
-            {
-                let mut page = file.root_mut();
-                for (k, v, p) in data.iter() {
-                    if *p == 0 {
-                        page.put_val(k, v);
-                    } else {
-                        page.put_ref(k, *p);
-                    }
-                }
-            };
-            let page = file.root();
-            file.save(page.deref()).unwrap();
+        // 100 + 101 + ... + 199 = 14950
+        let (mut sum, mut visited) = (0u64, 0usize);
+        file.scan(Some(&100u32.to_be_bytes()), |key, val| {
+            assert_eq!(key, val);
+            sum += num(val);
+            visited += 1;
+            sum < 14950
+        })
+        .unwrap();
+        assert_eq!(sum, 14950);
+        assert_eq!(visited, 100);
+
+        let mut keys = Vec::with_capacity(1000);
+        file.scan(None, |key, _| {
+            keys.push(num(key));
+            true
+        })
+        .unwrap();
+        assert_eq!(keys, (0..1000u64).collect::<Vec<_>>());
+
+        let mut visited = 0;
+        file.scan(Some(&1000u32.to_be_bytes()), |_, _| {
+            visited += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(visited, 0);
+    }
+
+    #[test]
+    fn test_scan_from() {
+        let path = &util::temp("test_scan_from");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for (k, v) in util::data(1050, 42) {
+            file.insert(&k, &v).unwrap();
         }
+        let all = file.scan_prefix(&[]).unwrap();
 
-        let file: File<Block> = File::open(path).unwrap();
-        let mut page = file.load(file.offset(ROOT), size).unwrap();
+        // cursor-based pagination: the last seen key is the exclusive start of the next page
+        let mut pages = vec![file.scan_from(&[], true, 100).unwrap()];
+        while let Some((last, _)) = pages.last().unwrap().last().cloned() {
+            pages.push(file.scan_from(&last, false, 100).unwrap());
+        }
+        assert_eq!(pages.len(), 12);
+        assert!(pages[..10].iter().all(|page| page.len() == 100));
+        assert_eq!(pages[10].len(), 50);
+        assert_eq!(pages.concat(), all);
+
+        let (key, _) = &all[500];
+        assert_eq!(file.scan_from(key, true, 3).unwrap(), all[500..503]);
+        assert_eq!(file.scan_from(key, false, 3).unwrap(), all[501..504]);
+        // a start that is not a stored key works either way
+        let mut probe = key.clone();
+        probe.push(0);
+        assert_eq!(file.scan_from(&probe, true, 2).unwrap(), all[501..503]);
+        assert_eq!(file.scan_from(&probe, false, 2).unwrap(), all[501..503]);
+        assert!(file.scan_from(key, true, 0).unwrap().is_empty());
+        assert!(file.scan_from(&all[1049].0, false, 10).unwrap().is_empty());
+    }
 
-        assert_eq!(page.copy(), data);
+    #[test]
+    fn test_scan_prefix() {
+        let path = &util::temp("test_scan_prefix");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for user in ["user:1", "user:10", "user:2", "use", "users", "admin:1"] {
+            for field in ["", ":age", ":name"] {
+                keys.push(format!("{user}{field}").into_bytes());
+            }
+        }
+        keys.push(vec![0xFF]);
+        keys.push(vec![0xFF, 0xFF]);
+        keys.push(vec![0xFF, 0xFF, 0x00]);
+        for key in keys.iter() {
+            file.insert(key, &[key.len() as u8]).unwrap();
+        }
+        keys.sort();
 
-        for (k, v, p) in data.iter() {
-            assert_eq!(get(&page, k), Some((v.to_vec(), *p)));
+        let expect = |prefix: &[u8]| {
+            keys.iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| (key.clone(), vec![key.len() as u8]))
+                .collect::<Vec<_>>()
+        };
+        for prefix in [
+            &b"user:1"[..],
+            b"user:",
+            b"user",
+            b"us",
+            b"user:1:",
+            b"admin",
+            b"none",
+            &[0xFF],
+            &[0xFF, 0xFF],
+            &[0xFF, 0xFF, 0xFF],
+        ] {
+            assert_eq!(file.scan_prefix(prefix).unwrap(), expect(prefix));
         }
+        assert_eq!(file.scan_prefix(b"user:1").unwrap().len(), 6);
+        assert_eq!(file.scan_prefix(&[0xFF, 0xFF]).unwrap().len(), 2);
 
-        page.remove(page.find(b"aaa").unwrap());
-        assert_eq!(get(&page, b"aaa"), None);
+        let all = file.scan_prefix(&[]).unwrap();
+        assert_eq!(all.len(), keys.len());
+        assert_eq!(all, expect(&[]));
+    }
 
-        page.remove(page.find(b"zzz").unwrap());
-        assert_eq!(get(&page, b"zzz"), None);
+    #[test]
+    fn test_update() {
+        let path = &util::temp("test_update");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let incr = |val: Option<&[u8]>| {
+            let n = val.map(|mut bytes| bytes.get_u64()).unwrap_or_default();
+            Some((n + 1).to_be_bytes().to_vec())
+        };
+
+        std::thread::scope(|scope| {
+            let file = &file;
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    for _ in 0..250 {
+                        file.update(b"counter", incr).unwrap();
+                    }
+                });
+            }
+        });
+        let val = file.lookup(b"counter").unwrap().unwrap();
+        assert_eq!(val.as_slice().get_u64(), 1000);
+
+        file.insert(b"other", b"value").unwrap();
+        file.update(b"counter", |val| {
+            assert!(val.is_some());
+            None
+        })
+        .unwrap();
+        assert_eq!(file.lookup(b"counter").unwrap(), None);
+        assert_eq!(file.lookup(b"other").unwrap().unwrap(), b"value");
+
+        file.update(b"missing", |val| {
+            assert!(val.is_none());
+            None
+        })
+        .unwrap();
+        assert_eq!(file.lookup(b"missing").unwrap(), None);
+        file.verify().unwrap();
     }
 
     #[test]
-    fn test_file() {
-        let path = Path::new("target/test_file.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
-        let size: u32 = 256;
+    fn test_compare_and_swap() {
+        let path = &util::temp("test_compare_and_swap");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+
+        // insert when absent
+        assert!(file.compare_and_swap(b"key", None, Some(b"one")).unwrap());
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"one");
+        assert!(!file.compare_and_swap(b"key", None, Some(b"two")).unwrap());
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"one");
+
+        // swap
+        assert!(file
+            .compare_and_swap(b"key", Some(b"one"), Some(b"two"))
+            .unwrap());
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"two");
+
+        // mismatch
+        assert!(!file
+            .compare_and_swap(b"key", Some(b"one"), Some(b"three"))
+            .unwrap());
+        assert!(!file
+            .compare_and_swap(b"absent", Some(b"one"), Some(b"three"))
+            .unwrap());
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"two");
+        assert_eq!(file.lookup(b"absent").unwrap(), None);
+
+        // delete
+        assert!(!file.compare_and_swap(b"key", Some(b"one"), None).unwrap());
+        assert!(file.compare_and_swap(b"key", Some(b"two"), None).unwrap());
+        assert_eq!(file.lookup(b"key").unwrap(), None);
+        assert!(file.compare_and_swap(b"key", None, None).unwrap());
+
+        // concurrent increments: each CAS failure retries with a fresh value
+        file.insert(b"counter", &0u64.to_be_bytes()).unwrap();
+        std::thread::scope(|scope| {
+            let file = &file;
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let old = file.lookup(b"counter").unwrap().unwrap();
+                            let new = (old.as_slice().get_u64() + 1).to_be_bytes();
+                            if file
+                                .compare_and_swap(b"counter", Some(&old), Some(&new))
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let val = file.lookup(b"counter").unwrap().unwrap();
+        assert_eq!(val.as_slice().get_u64(), 400);
+    }
 
-        let data = vec![
-            (b"uno".to_vec(), b"la squadra azzurra".to_vec()),
-            (b"due".to_vec(), b"it's coming home".to_vec()),
-            (b"tre".to_vec(), b"red devils".to_vec()),
-        ];
+    #[test]
+    fn test_get_for_update() {
+        use std::sync::atomic::AtomicBool;
+        use std::time::Duration;
+
+        let path = &util::temp("test_get_for_update");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        file.insert(b"key", b"one").unwrap();
+
+        // dropped without set: nothing changes
+        let guard = file.get_for_update(b"key").unwrap();
+        assert_eq!(guard.value(), Some(&b"one"[..]));
+        drop(guard);
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"one");
+
+        let guard = file.get_for_update(b"absent").unwrap();
+        assert_eq!(guard.value(), None);
+        guard.set(b"new").unwrap();
+        assert_eq!(file.lookup(b"absent").unwrap().unwrap(), b"new");
+
+        // a concurrent writer blocks until the guard is released
+        let written = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let guard = file.get_for_update(b"key").unwrap();
+            let (file, written) = (&file, &written);
+            scope.spawn(move || {
+                file.insert(b"key", b"three").unwrap();
+                written.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!written.load(Ordering::SeqCst));
+            let val = guard.value().unwrap().to_vec();
+            assert_eq!(val, b"one");
+            guard.set(b"two").unwrap();
+        });
+        assert!(written.load(Ordering::SeqCst));
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"three");
+
+        // the value set through the guard is flushed, nothing is left dirty
+        let guard = file.get_for_update(b"key").unwrap();
+        guard.set(b"four").unwrap();
+        assert!(file.dirty.read().is_empty());
+        drop(file);
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"four");
+        file.verify().unwrap();
+    }
 
-        let file: File<Block> = File::make(path, size).unwrap();
+    #[test]
+    fn test_compact_header() {
+        let path = &util::temp("test_compact_header");
 
-        for (k, v) in data.iter() {
-            file.insert(k, v).unwrap();
-        }
+        let size: u32 = 128;
+        let data = util::data(1000, 42);
+        {
+            let file: File<BlockCompact> = File::make(path, size).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.verify().unwrap();
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
-            file.remove(k).unwrap();
+            let root = file.root();
+            assert_eq!(root.id(), ROOT);
+            assert_eq!(root.cap(), size);
+            assert_ne!(&root.as_ref()[12..16], &0xC0DE1542u32.to_be_bytes());
+            drop(root);
+
+            for (k, _) in data.iter().step_by(2) {
+                file.remove(k).unwrap();
+            }
+            file.verify().unwrap();
         }
 
-        for (k, _) in data.iter() {
-            assert!(file.lookup(k).unwrap().is_none());
+        let file: File<BlockCompact> = File::open(path).unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let val = file.lookup(k).unwrap();
+            if i % 2 == 0 {
+                assert_eq!(val, None);
+            } else {
+                assert_eq!(val.unwrap().deref(), v);
+            }
         }
+        drop(file);
 
-        let root = file.root();
-        assert_eq!(root.copy(), vec![]);
+        assert!(File::<Block>::open(path).is_err());
     }
 
     #[test]
-    fn test_split() {
-        let path = Path::new("target/test_split.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
+    fn test_front_coded_keys() {
+        let path = &util::temp("test_front_coded_keys");
+        let full = &util::temp("test_front_coded_keys_full");
 
-        let size: u32 = 256;
-        let file: File<Block> = File::make(path, size).unwrap();
-
-        let count = 25;
-        let data = (0..count)
-            .into_iter()
+        let size: u32 = 512;
+        let data = (0..1000)
             .map(|i| {
-                let c = 'a' as u8 + (i % ('z' as u8 - 'a' as u8 + 1) as u8 as u64) as u8;
-                (vec![c; 8], vec![c; 8])
+                let key = format!("tenant/0042/events/{:08}", i * 7).into_bytes();
+                (key, format!("{}", i).into_bytes())
             })
             .collect::<Vec<_>>();
+        {
+            let file: File<BlockPrefix> = File::make(path, size).unwrap();
+            let other: File<Block> = File::make(full, size).unwrap();
+            for (k, v) in data.iter().rev() {
+                file.insert(k, v).unwrap();
+                other.insert(k, v).unwrap();
+            }
+            file.verify().unwrap();
+
+            let (prefix, other) = (file.stats().unwrap(), other.stats().unwrap());
+            assert_eq!(prefix.entry_count, other.entry_count);
+            assert!(
+                prefix.page_count * 3 < other.page_count * 2,
+                "prefix={} full={}",
+                prefix.page_count,
+                other.page_count
+            );
 
-        for (k, v) in data.iter() {
-            file.insert(k, v).unwrap();
+            for (k, _) in data.iter().step_by(2) {
+                file.remove(k).unwrap();
+            }
+            file.verify().unwrap();
         }
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        let file: File<BlockPrefix> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let val = file.lookup(k).unwrap();
+            if i % 2 == 0 {
+                assert_eq!(val, None);
+            } else {
+                assert_eq!(val.unwrap().deref(), v);
+            }
         }
+        let keys = file.keys().collect::<Vec<_>>();
+        let expected = data.iter().skip(1).step_by(2).map(|(k, _)| k.clone());
+        assert!(keys.into_iter().eq(expected));
+        assert_eq!(file.min().unwrap(), Some(data[1].0.clone()));
+        assert_eq!(file.above(&data[1].0).unwrap(), Some(data[3].0.clone()));
+        drop(file);
+
+        assert!(File::<Block>::open(path).is_err());
+        assert!(File::<BlockPrefix>::open(full).is_err());
     }
 
     #[test]
-    fn test_merge() {
-        let path = Path::new("target/test_merge.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    fn test_small_slots() {
+        let path = &util::temp("test_small_slots");
+        let full = &util::temp("test_small_slots_full");
+
+        let size: u32 = 256;
+        let data = util::data(1000, 42);
+        {
+            let file: File<BlockSmall> = File::make(path, size).unwrap();
+            let other: File<Block> = File::make(full, size).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+                other.insert(k, v).unwrap();
+            }
+            file.verify().unwrap();
+
+            let (small, other) = (file.stats().unwrap(), other.stats().unwrap());
+            assert_eq!(small.entry_count, other.entry_count);
+            assert!(
+                small.page_count * 4 < other.page_count * 3,
+                "small={} full={}",
+                small.page_count,
+                other.page_count
+            );
+
+            for (k, _) in data.iter().step_by(2) {
+                file.remove(k).unwrap();
+            }
+            file.verify().unwrap();
+        }
+
+        let file: File<BlockSmall> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            let val = file.lookup(k).unwrap();
+            if i % 2 == 0 {
+                assert_eq!(val, None);
+            } else {
+                assert_eq!(val.unwrap().deref(), v);
+            }
         }
+        drop(file);
+
+        assert!(File::<Block>::open(path).is_err());
+        assert!(File::<BlockSmall>::open(full).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = &util::temp("test_stats");
 
         let size: u32 = 256;
         let file: File<Block> = File::make(path, size).unwrap();
+        let stats = file.stats().unwrap();
+        assert_eq!(stats.page_count, 1);
+        assert_eq!(stats.entry_count, 0);
+        assert_eq!(stats.file_bytes, (HEAD + size as usize) as u64);
 
-        let count = 25;
-        let data = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = (0..count)
-                .into_iter()
-                .map(|i| {
-                    let c = 'a' as u8 + (i % ('z' as u8 - 'a' as u8 + 1) as u8 as u64) as u8;
-                    (vec![c; 8], vec![c; 8])
-                })
-                .collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
-        };
-
+        let data = util::data(1000, 42);
         for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
             file.insert(k, v).unwrap();
         }
-        debug!("{}", file.dump());
+        let stats = file.stats().unwrap();
+        assert_eq!(stats.entry_count, 1000);
+        assert!(
+            stats.page_count > 20 && stats.page_count < 300,
+            "{:?}",
+            stats
+        );
+        assert_eq!(
+            stats.file_bytes,
+            HEAD as u64 + stats.page_count as u64 * size as u64
+        );
+        assert!(
+            stats.avg_full > 25.0 && stats.avg_full <= 100.0,
+            "{:?}",
+            stats
+        );
 
-        let keys = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
-        };
+        for (k, _) in data.iter().take(900) {
+            file.remove(k).unwrap();
+        }
+        let after = file.stats().unwrap();
+        assert_eq!(after.entry_count, 100);
+        // only free-list pages are appended to keep track of emptied pages
+        let free_list = file.free_list.read().pages.len() as u32;
+        assert_eq!(after.page_count, stats.page_count + free_list);
+        assert!(after.empty_pages > 0, "{:?}", after);
+        // removal compacts a page once a quarter of it is lost to gaps
+        let usable = Block::create(0, size).usable() as u64;
+        assert!(
+            after.fragmented_bytes * 4 <= after.page_count as u64 * usable,
+            "{:?}",
+            after
+        );
+    }
 
-        let mut removed = HashSet::with_capacity(size as usize);
-        for key in keys.iter() {
-            debug!("remove: key={}", hex(key));
-            file.remove(key).unwrap();
-            removed.insert(key.to_vec());
+    #[test]
+    fn test_readonly() {
+        let path = &util::temp("test_readonly");
+
+        let data = util::data(500, 42);
+        {
+            let file: File<Block> = File::make(path, 256).unwrap();
             for (k, v) in data.iter() {
-                if removed.contains(k) {
-                    assert!(file.lookup(k).unwrap().is_none());
-                } else {
-                    assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
-                }
+                file.insert(k, v).unwrap();
             }
         }
-        debug!("{}", file.dump());
 
-        let root = file.root();
-        let copy = root.copy();
-        assert_eq!(copy, vec![]);
+        let a: File<Block> = File::open_readonly(path).unwrap();
+        let b: File<Block> = File::open_readonly(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(a.lookup(k).unwrap().unwrap().deref(), v);
+            assert_eq!(b.lookup(k).unwrap().unwrap().deref(), v);
+        }
+        a.verify().unwrap();
+
+        let (key, val) = &data[0];
+        assert!(matches!(a.insert(b"new", b"val"), Err(Error::Other(_))));
+        assert!(matches!(a.remove(key), Err(Error::Other(_))));
+        assert!(a.update(key, |_| None).is_err());
+        assert!(a.compare_and_swap(key, Some(val), None).is_err());
+        assert!(a.flush().is_err());
+        assert!(a.sync().is_err());
+        assert!(matches!(a.compact(), Err(Error::Other(_))));
+        assert_eq!(a.lookup(key).unwrap().unwrap().deref(), val);
+        assert_eq!(a.lookup(b"new").unwrap(), None);
+
+        assert!(File::<Block>::open_readonly(Path::new("target/test_readonly.none")).is_err());
+    }
+
+    #[test]
+    fn test_get_multi() {
+        let path = &util::temp("test_get_multi");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter().step_by(2) {
+            file.insert(k, v).unwrap();
+        }
+        file.flush().unwrap();
+
+        let mut keys = data.iter().map(|(k, _)| k.as_slice()).collect::<Vec<_>>();
+        keys.push(&[0xFF; 16]);
+        keys.shuffle(&mut StdRng::seed_from_u64(42));
+
+        let expected = keys
+            .iter()
+            .map(|key| file.lookup(key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(expected.iter().filter(|val| val.is_some()).count(), 500);
+
+        let before = file.counters.loaded.load(Ordering::Relaxed);
+        let found = file.get_multi(&keys).unwrap();
+        let loads = file.counters.loaded.load(Ordering::Relaxed) - before;
+        assert_eq!(found, expected);
+        assert!(loads < keys.len() as u64, "loads={}", loads);
+
+        assert!(file.get_multi(&[]).unwrap().is_empty());
+        assert!(file.get_multi(&[b"key", b""]).is_err());
     }
 
     #[test]
-    fn test_above() {
-        let path = Path::new("target/test_above.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    fn test_dump_to() {
+        let path = &util::temp("test_dump_to");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for (k, v) in util::data(300, 42).iter() {
+            file.insert(k, v).unwrap();
         }
 
-        let size: u32 = 256;
-        let file: File<Block> = File::make(path, size).unwrap();
+        let mut out = Vec::new();
+        file.dump_to(&mut out, None).unwrap();
+        let dump = file.dump();
+        assert_eq!(String::from_utf8(out).unwrap(), dump);
+        assert!(dump.starts_with("page=1: (parent=0) "));
+        assert!(
+            dump.contains("\n\t\tpage="),
+            "tree is at least 3 levels deep"
+        );
 
-        let count = 10;
-        let mut data = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = (0..count)
-                .into_iter()
-                .map(|i| {
-                    let b = (i + 1) * count as u8;
-                    (vec![b; 8], vec![b; 8])
-                })
-                .collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
-        };
+        let mut out = Vec::new();
+        file.dump_to(&mut out, Some(1)).unwrap();
+        let top = String::from_utf8(out).unwrap();
+        let pages = top.lines().filter(|line| line.contains("page=")).count();
+        assert_eq!(pages as u32, 1 + file.root().len());
+        assert!(!top.contains("\t\t"));
+        let shallow = dump
+            .lines()
+            .filter(|line| !line.starts_with("\t\t"))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        assert_eq!(top, shallow);
+
+        let mut out = Vec::new();
+        file.dump_to(&mut out, Some(0)).unwrap();
+        let root = String::from_utf8(out).unwrap();
+        assert_eq!(root.lines().count() as u32, 1 + file.root().len());
+        assert!(!root.contains('\t'));
+    }
 
-        for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
+    #[test]
+    fn test_dump_dot() {
+        let path = &util::temp("test_dump_dot");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for (k, v) in util::data(100, 42).iter() {
             file.insert(k, v).unwrap();
         }
 
-        data.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, data[0].0.to_vec());
-        assert_eq!(max, data.last().unwrap().0.to_vec());
-        assert!(file.above(&max).unwrap().is_none());
-
-        let asc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut val = file.min().unwrap().unwrap().to_vec();
-            result.push(val.clone());
-            loop {
-                if let Some(next) = file.above(&val).unwrap() {
-                    result.push(next.to_vec());
-                    val = next.to_vec();
-                } else {
-                    break;
+        let mut pages = vec![ROOT];
+        let mut stack = vec![ROOT];
+        while let Some(id) = stack.pop() {
+            let page = file.page(id).unwrap();
+            for idx in 0..page.len() {
+                let child = page.slot(idx).unwrap().page;
+                if child != 0 {
+                    pages.push(child);
+                    stack.push(child);
                 }
             }
-            result
-        };
-        assert_eq!(
-            asc,
-            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
-        );
+        }
+        assert!(pages.len() > 3);
 
-        assert_eq!(file.above(&max).unwrap(), None);
-        let mut max = max;
-        *max.last_mut().unwrap() += 1;
-        assert_eq!(file.above(&max).unwrap(), None);
+        let dot = file.dump_dot();
+        assert!(dot.starts_with("digraph tree {"));
+        assert!(dot.ends_with("}\n"));
+        for id in pages.iter() {
+            assert!(dot.contains(&format!("\tp{} [label=\"page={} (", id, id)));
+        }
+        assert_eq!(dot.matches(" -> ").count(), pages.len() - 1);
+        assert!(dot.contains(&hex(file.root().min())));
     }
 
     #[test]
-    fn test_below() {
-        let path = Path::new("target/test_below.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
+    fn test_empty_key() {
+        let path = &util::temp("test_empty_key");
 
-        let size: u32 = 256;
-        let file: File<Block> = File::make(path, size).unwrap();
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let data = util::data(100, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
 
-        let count = 10;
-        let mut data = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = (0..count)
-                .into_iter()
-                .map(|i| {
-                    let b = (i + 1) * count as u8;
-                    (vec![b; 8], vec![b; 8])
-                })
-                .collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
+        let is_empty_key = |res: Result<()>| match res {
+            Err(Error::Other(msg)) => msg == "empty key",
+            _ => false,
         };
-
+        assert!(is_empty_key(file.insert(b"", b"val")));
+        assert!(is_empty_key(file.remove(b"")));
+        assert!(is_empty_key(file.lookup(b"").map(|_| ())));
+        assert!(is_empty_key(file.get_ref(b"").map(|_| ())));
+        assert!(is_empty_key(file.update(b"", |_| Some(vec![1]))));
+        assert!(is_empty_key(
+            file.compare_and_swap(b"", None, Some(b"val")).map(|_| ())
+        ));
+
+        file.verify().unwrap();
+        let mut sorted = data.clone();
+        sorted.sort();
+        assert_eq!(file.min().unwrap(), Some(sorted[0].0.clone()));
+        assert_eq!(file.above(b"").unwrap(), Some(sorted[0].0.clone()));
         for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
-            file.insert(k, v).unwrap();
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
         }
+    }
 
-        data.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, data[0].0.to_vec());
-        assert_eq!(max, data.last().unwrap().0.to_vec());
-        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+    #[test]
+    fn test_make_failure() {
+        let path = &util::temp("test_make_failure");
+        let tmp = &path.with_extension("tmp.tmp");
+
+        let res = create_file(path, &[42u8; 64], |file, buf| {
+            file.write_all(&buf[..16])?;
+            Err(io::Error::other("disk full"))
+        });
+        assert_eq!(res.unwrap_err().to_string(), "disk full");
+        assert!(!path.exists());
+        assert!(!tmp.exists());
+
+        let missing = Path::new("target/test_make_failure/none/db.tmp");
+        assert!(File::<Block>::make(missing, 256).is_err());
+        assert!(!missing.exists());
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        file.insert(b"key", b"val").unwrap();
+        drop(file);
+        assert!(!tmp.exists());
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"val");
+    }
 
-        data.reverse();
-        let desc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut val = file.max().unwrap().unwrap().to_vec();
-            result.push(val.clone());
-            loop {
-                if let Some(next) = file.below(&val).unwrap() {
-                    result.push(next.to_vec());
-                    val = next.to_vec();
-                } else {
-                    break;
-                }
-            }
-            result
-        };
+    #[test]
+    fn test_remove_absent() {
+        let path = &util::temp("test_remove_absent");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        file.insert(b"b", b"1").unwrap();
+        file.insert(b"d", b"2").unwrap();
+        file.remove(b"a").unwrap();
+        file.remove(b"c").unwrap();
+        file.remove(b"e").unwrap();
+        assert_eq!(file.lookup(b"b").unwrap().unwrap(), b"1");
+        assert_eq!(file.lookup(b"d").unwrap().unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let path = &util::temp("test_remove_range");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        for i in 0..1000u32 {
+            file.insert(&i.to_be_bytes(), &[42u8; 8]).unwrap();
+        }
+
+        let before = file.stats().unwrap();
+        let (lo, hi) = (200u32.to_be_bytes(), 700u32.to_be_bytes());
+        assert_eq!(file.remove_range(&lo, &hi).unwrap(), 500);
+        file.verify().unwrap();
+        // Committed once (each dirty page saved once), leaves inside the range are freed.
+        let after = file.stats().unwrap();
+        assert!(file.last_op_pages().saved <= before.page_count as u64);
+        assert!(
+            after.empty_pages > before.empty_pages + 10,
+            "{:?} {:?}",
+            before,
+            after
+        );
+
+        let mut keys = Vec::with_capacity(500);
+        file.scan(None, |mut key, _| {
+            keys.push(key.get_u32());
+            true
+        })
+        .unwrap();
+        assert_eq!(keys, (0..200).chain(700..1000).collect::<Vec<_>>());
         assert_eq!(
-            desc,
-            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+            file.lookup(&199u32.to_be_bytes()).unwrap().unwrap(),
+            [42u8; 8]
+        );
+        assert_eq!(
+            file.lookup(&700u32.to_be_bytes()).unwrap().unwrap(),
+            [42u8; 8]
+        );
+        assert_eq!(
+            file.below(&700u32.to_be_bytes()).unwrap(),
+            Some(199u32.to_be_bytes().to_vec())
         );
 
-        assert_eq!(file.below(&min).unwrap(), None);
-        let mut min = min;
-        *min.last_mut().unwrap() -= 1;
-        assert_eq!(file.below(&min).unwrap(), None);
+        assert_eq!(file.remove_range(&lo, &hi).unwrap(), 0);
+        assert_eq!(file.remove_range(&hi, &lo).unwrap(), 0);
+        assert_eq!(file.remove_range(&[], &[0xFF; 8]).unwrap(), 500);
+        assert!(file.is_empty());
+        file.verify().unwrap();
     }
 
     #[test]
-    fn test_1k() {
-        let path = Path::new("target/test_1k.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    fn test_remove_prefix() {
+        let path = &util::temp("test_remove_prefix");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let spaces: [&[u8]; 5] = [b"user", b"users", b"usex", &[0xFE, 0xFF], &[0xFF, 0xFF]];
+        for space in spaces.iter() {
+            for i in 0..300u32 {
+                let key = [*space, &i.to_be_bytes()[..]].concat();
+                file.insert(&key, &[42u8; 8]).unwrap();
+            }
         }
 
-        let size: u32 = 4096;
-        let file: File<Block> = File::make(path, size).unwrap();
+        // "users" starts with "user" as well
+        assert_eq!(file.remove_prefix(b"user").unwrap(), 600);
+        file.verify().unwrap();
+        assert_eq!(file.count_prefix(b"user").unwrap(), 0);
+        assert_eq!(file.count_prefix(b"usex").unwrap(), 300);
+        assert_eq!(file.count_prefix(&[0xFE]).unwrap(), 300);
+        assert_eq!(file.count_prefix(&[0xFF]).unwrap(), 300);
+        assert_eq!(file.remove_prefix(b"user").unwrap(), 0);
+
+        // no successor for the all-0xFF prefix: removes to the end
+        assert_eq!(file.remove_prefix(&[0xFF, 0xFF]).unwrap(), 300);
+        assert_eq!(file.count_prefix(&[0xFE, 0xFF]).unwrap(), 300);
+        assert_eq!(file.max().unwrap().unwrap()[..2], [0xFE, 0xFF]);
+        assert_eq!(file.remove_prefix(&[0xFE]).unwrap(), 300);
+        assert_eq!(file.count_prefix(&[]).unwrap(), 300);
+        assert_eq!(file.remove_prefix(&[]).unwrap(), 300);
+        assert!(file.is_empty());
+        file.verify().unwrap();
+    }
 
-        let count = 1000;
-        let data = util::data(count, 42);
+    #[test]
+    fn test_count_prefix() {
+        let path = &util::temp("test_count_prefix");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        let prefixes = [(&b"post:"[..], 50), (b"user:", 120), (b"user:1", 0)];
+        for (prefix, n) in prefixes.iter().take(2) {
+            for i in 0..*n {
+                let mut key = prefix.to_vec();
+                key.extend_from_slice(format!("{:03}", i).as_bytes());
+                file.insert(&key, &[42u8; 16]).unwrap();
+            }
+        }
+        file.insert(b"zzz", b"last").unwrap();
+
+        assert_eq!(file.count_prefix(b"post:").unwrap(), 50);
+        assert_eq!(file.count_prefix(b"user:").unwrap(), 120);
+        assert_eq!(file.count_prefix(b"user:1").unwrap(), 20);
+        assert_eq!(file.count_prefix(b"user:11").unwrap(), 10);
+        assert_eq!(file.count_prefix(b"z").unwrap(), 1);
+        assert_eq!(file.count_prefix(b"none").unwrap(), 0);
+        assert_eq!(file.count_prefix(&[0xFF]).unwrap(), 0);
+        assert_eq!(file.count_prefix(&[]).unwrap(), 171);
+        for prefix in [&b"post:"[..], b"user:1", b"u", b""] {
+            assert_eq!(
+                file.count_prefix(prefix).unwrap(),
+                file.scan_prefix(prefix).unwrap().len()
+            );
+        }
+    }
 
-        for (i, (k, v)) in data.iter().enumerate() {
-            debug!("({:05}) insert: key={} val={}", i, hex(k), hex(v));
+    #[test]
+    fn test_load() {
+        let path = &util::temp("test_load");
+
+        let size: u32 = 256;
+        let file: File<Block> = File::make(path, size).unwrap();
+        for (k, v) in util::data(200, 42).iter() {
             file.insert(k, v).unwrap();
         }
+        file.flush().unwrap();
+
+        let raw = fs::read(path).unwrap();
+        let count = ((raw.len() - HEAD) / size as usize) as u32;
+        assert!(count > 1);
+        for id in 1..=count {
+            let offset = file.offset(id).unwrap() as usize;
+            let page = file.load(id).unwrap();
+            assert_eq!(page.as_ref(), &raw[offset..(offset + size as usize)]);
+        }
 
-        let file: File<Block> = File::open(path).unwrap();
+        #[cfg(unix)]
+        {
+            // Loading needs only shared access to the file: hold it while both threads load.
+            let barrier = std::sync::Barrier::new(2);
+            let _shared = file.file.read();
+            std::thread::scope(|scope| {
+                for _ in 0..2 {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        for id in 1..=count {
+                            let offset = file.offset(id).unwrap() as usize;
+                            let page = file.load(id).unwrap();
+                            assert_eq!(page.as_ref(), &raw[offset..(offset + size as usize)]);
+                        }
+                    });
+                }
+            });
+        }
+    }
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+    #[test]
+    fn test_get() {
+        let path = &util::temp("test_get");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        file.insert(b"key", b"val").unwrap();
+        assert_eq!(file.get(b"key").unwrap(), b"val");
+        match file.get(b"none") {
+            Err(Error::Other(msg)) => assert_eq!(msg, "not found"),
+            res => panic!("unexpected result: {:?}", res),
         }
+        assert!(file.get(b"").is_err());
+    }
 
-        let mut sorted = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
-        sorted.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, sorted[0]);
-        assert_eq!(max, sorted.last().cloned().unwrap());
-        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
-        assert_eq!(file.above(&max).unwrap().map(|v| v.to_vec()), None);
-        let asc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut this = file.min().unwrap().unwrap().to_vec();
-            result.push(this.clone());
-            loop {
-                if let Some(next) = file.above(&this).unwrap() {
-                    result.push(next.to_vec());
-                    this = next.to_vec();
-                } else {
-                    break;
-                }
+    #[test]
+    fn test_version() {
+        let path = &util::temp("test_version");
+
+        {
+            let file: File<Block> = File::make(path, 256).unwrap();
+            file.insert(b"key", b"val").unwrap();
+            assert_eq!(file.head.version, VERSION);
+        }
+        let raw = fs::read(path).unwrap();
+        assert_eq!(&raw[8..10], &VERSION.to_be_bytes());
+        assert!(raw[28..HEAD].iter().all(|b| *b == 0));
+        let file: File<Block> = File::open(path).unwrap();
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"val");
+        drop(file);
+
+        let mut bumped = raw.clone();
+        bumped[8..10].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        fs::write(path, &bumped).unwrap();
+        let err = File::<Block>::open(path).err().unwrap();
+        assert!(
+            err.to_string().contains("Unsupported format version"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_legacy_head() {
+        let path = &util::temp("test_legacy_head");
+        let legacy = &util::temp("test_legacy_head_v0");
+
+        let size: u32 = 256;
+        let data = util::data(500, 42);
+        {
+            let file: File<Block> = File::make(path, size).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
             }
-            result
-        };
-        let desc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut this = file.max().unwrap().unwrap().to_vec();
-            result.push(this.clone());
-            loop {
-                if let Some(next) = file.below(&this).unwrap() {
-                    result.push(next.to_vec());
-                    this = next.to_vec();
+            for (k, _) in data.iter().step_by(3) {
+                file.remove(k).unwrap();
+            }
+        }
+
+        // Baseline format: magic, page_bytes and page_count followed by the pages right away.
+        let raw = fs::read(path).unwrap();
+        let mut old = Vec::with_capacity(raw.len());
+        old.extend_from_slice(MAGIC);
+        old.extend_from_slice(&size.to_be_bytes());
+        old.extend_from_slice(&1u32.to_be_bytes());
+        old.extend_from_slice(&raw[HEAD..]);
+        fs::write(legacy, &old).unwrap();
+
+        let check = |file: &File<Block>, data: &[(Vec<u8>, Vec<u8>)]| {
+            file.verify().unwrap();
+            for (i, (k, v)) in data.iter().enumerate() {
+                let val = file.lookup(k).unwrap();
+                if i % 3 == 0 {
+                    assert_eq!(val, None);
                 } else {
-                    break;
+                    assert_eq!(val.unwrap().deref(), v);
                 }
             }
-            result
         };
 
-        for (i, (put, got)) in sorted.iter().zip(asc.iter()).enumerate() {
-            assert_eq!(
-                put,
-                got,
-                "ASC: index={}: expected '{}' but got '{}'",
-                i,
-                hex(put),
-                hex(got)
-            );
-        }
-        assert_eq!(asc, sorted);
+        let file: File<Block> = File::open_readonly(legacy).unwrap();
+        assert_eq!(file.head.version, 0);
+        assert_eq!(file.head.flags, 0);
+        check(&file, &data);
+        drop(file);
 
-        sorted.reverse();
-        for (i, (put, got)) in sorted.iter().zip(desc.iter()).enumerate() {
-            assert_eq!(
-                put,
-                got,
-                "DESC: index={}: expected '{}' but got '{}'",
-                i,
-                hex(put),
-                hex(got)
-            );
+        // Writable: the legacy header is kept as is (no free list, no tables).
+        {
+            let file: File<Block> = File::open(legacy).unwrap();
+            check(&file, &data);
+            file.insert(b"new", b"val").unwrap();
+            assert!(file.open_table("table").is_err());
         }
-        assert_eq!(desc, sorted);
+        assert_eq!(fs::read(legacy).unwrap()[..LEGACY_HEAD], old[..LEGACY_HEAD]);
+        let file: File<Block> = File::open(legacy).unwrap();
+        check(&file, &data);
+        assert_eq!(file.lookup(b"new").unwrap().unwrap(), b"val");
+        file.compact().unwrap();
+        check(&file, &data);
+        drop(file);
+        let file: File<Block> = File::open(legacy).unwrap();
+        check(&file, &data);
+
+        assert!(File::<BlockLE>::open(legacy).is_err());
+        assert!(File::<BlockCompact>::open(legacy).is_err());
+    }
 
-        for (i, (key, _)) in data.iter().enumerate() {
-            debug!("({:05}) remove: key={}", i, hex(key));
-            file.remove(key).unwrap();
+    #[test]
+    fn test_large_pages() {
+        let path = &util::temp("test_large_pages");
+        let huge = &util::temp("test_large_pages_huge");
+
+        let size: u32 = 256 * 1024;
+        // larger than u16::MAX, at most 3 values fit a page (leaves split once 80% full)
+        let mut data = util::data(30, 42);
+        for (i, (_, v)) in data.iter_mut().enumerate() {
+            *v = (0..70_000 + i).map(|j| (i + j) as u8).collect();
+        }
+        {
+            let file: File<Block> = File::make(path, size).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.verify().unwrap();
+            assert!(file.root().is_node());
         }
+        let file: File<Block> = File::open(path).unwrap();
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(&file.get(k).unwrap(), v);
+        }
+        drop(file);
+
+        let err = File::<Block>::make(huge, u32::MAX).err().unwrap();
+        assert!(err.to_string().contains("Page size too large"), "{}", err);
+        assert!(!huge.exists());
+        assert!(File::<BlockSmall>::make(huge, u16::MAX as u32 + 1).is_err());
+        assert!(!huge.exists());
+
+        // header of an existing file claims an absurd page size
+        let mut raw = fs::read(path).unwrap();
+        raw[12..16].copy_from_slice(&u32::MAX.to_be_bytes());
+        fs::write(path, &raw).unwrap();
+        let err = File::<Block>::open(path).err().unwrap();
+        assert!(err.to_string().contains("Page size too large"), "{}", err);
+    }
 
-        for (i, (key, _)) in data.iter().enumerate() {
-            debug!("({:05}) lookup: key={}", i, hex(key));
-            let found = file.lookup(key).unwrap().map(|v| hex(&v));
-            assert_eq!(found, None);
+    #[test]
+    fn test_entry() {
+        let path = &util::temp("test_entry");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert!(matches!(file.entry(b"key").unwrap(), Entry::Vacant(_)));
+        assert_eq!(
+            file.entry(b"key").unwrap().or_insert(b"one").unwrap(),
+            b"one"
+        );
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"one");
+        assert_eq!(
+            file.entry(b"key").unwrap().or_insert(b"two").unwrap(),
+            b"one"
+        );
+
+        let val = file
+            .entry(b"key")
+            .unwrap()
+            .and_modify(|val| val.extend_from_slice(b"+1"))
+            .unwrap()
+            .or_insert(b"none")
+            .unwrap();
+        assert_eq!(val, b"one+1");
+        assert_eq!(file.lookup(b"key").unwrap().unwrap(), b"one+1");
+
+        let entry = file
+            .entry(b"other")
+            .unwrap()
+            .and_modify(|_| panic!("vacant entry must not be modified"))
+            .unwrap();
+        assert_eq!(entry.key(), b"other");
+        assert_eq!(entry.or_insert_with(|| b"new".to_vec()).unwrap(), b"new");
+
+        match file.entry(b"key").unwrap() {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.get(), b"one+1");
+                assert_eq!(e.insert(b"three").unwrap(), b"one+1");
+                assert_eq!(e.remove().unwrap(), b"three");
+            }
+            Entry::Vacant(_) => panic!("entry must be occupied"),
         }
+        assert_eq!(file.lookup(b"key").unwrap(), None);
+        assert_eq!(file.lookup(b"other").unwrap().unwrap(), b"new");
+
+        // entry holds the write lock, so concurrent increments do not get lost
+        std::thread::scope(|scope| {
+            let file = &file;
+            for _ in 0..4 {
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        file.entry(b"counter")
+                            .unwrap()
+                            .and_modify(|val| val[0] = val[0].wrapping_add(1))
+                            .unwrap()
+                            .or_insert(&[1])
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(file.lookup(b"counter").unwrap().unwrap(), [144]); // 400 % 256
+        assert!(file.entry(b"").is_err());
+    }
 
-        let copy = file.root().copy();
-        debug!("{}", file.dump());
-        assert!(copy.is_empty());
+    #[test]
+    fn test_last_op_pages() {
+        let path = &util::temp("test_last_op_pages");
+
+        let file: File<Block> = File::make(path, 256).unwrap();
+        assert_eq!(file.last_op_pages(), OpPages::default());
+
+        let mut splits = Vec::new();
+        for i in 0..200u32 {
+            file.insert(&i.to_be_bytes(), &[42u8; 8]).unwrap();
+            let op = file.last_op_pages();
+            if op.split > 0 {
+                splits.push((i, op));
+            } else {
+                assert_eq!(op.allocated, 0, "i={} op={:?}", i, op);
+            }
+        }
+        assert!(splits.len() > 2, "{:?}", splits);
+
+        // the first split is the root split: the root entries move into two new pages
+        let (_, root_split) = splits[0];
+        assert_eq!(root_split.split, 1);
+        assert_eq!(root_split.allocated, 2);
+        assert!(root_split.saved >= 3, "{:?}", root_split);
+
+        // sequential inserts then keep splitting the rightmost leaf: one new peer page each
+        let (_, leaf_split) = splits[1];
+        assert_eq!(leaf_split.split, 1);
+        assert_eq!(leaf_split.allocated, 1);
+
+        // reads are not tracked
+        let last = file.last_op_pages();
+        file.lookup(&0u32.to_be_bytes()).unwrap();
+        assert_eq!(file.last_op_pages(), last);
     }
 
     #[test]
     fn test_large() {
-        let path = Path::new("target/test_large.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
+        let path = &util::temp("test_large");
 
         let page_bytes: u32 = 256;
         let file: File<Block> = File::make(path, page_bytes).unwrap();
 
         let big = vec![42u8; 1024];
         let res = file.insert(&big, &big);
-        assert!(res.is_err());
+        assert!(matches!(
+            res,
+            Err(Error::TooLarge {
+                key_len: 1024,
+                val_len: 1024,
+                ..
+            })
+        ));
+
+        file.insert(b"key", b"val").unwrap();
+        let res = file.insert(b"big", &big);
+        match res {
+            Err(Error::TooLarge {
+                key_len,
+                val_len,
+                free,
+            }) => {
+                assert_eq!((key_len, val_len), (3, 1024));
+                assert_eq!(free, file.root().free());
+            }
+            _ => panic!("unexpected result: {:?}", res),
+        }
     }
 }
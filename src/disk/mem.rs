@@ -0,0 +1,71 @@
+use crate::disk::block::Block;
+use crate::disk::file::File;
+
+/// In-memory store (created with `File::memory`): runs exactly the same tree logic as `File`,
+/// but keeps the file image in memory, so tests of the tree do not depend on the disk.
+pub type MemFile<P = Block> = File<P>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tree::Tree;
+    use crate::api::Store;
+    use crate::util;
+    use rand::prelude::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_split() {
+        let file: MemFile = File::memory(256);
+        let data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        file.verify().unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().as_ref(), Some(v));
+        }
+        assert!(file.stats().unwrap().page_count > 10);
+    }
+
+    #[test]
+    fn test_merge() {
+        let file: MemFile = File::memory(256);
+        let mut data = util::data(1000, 42);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        data.shuffle(&mut StdRng::seed_from_u64(3));
+        for (i, (k, _)) in data.iter().enumerate() {
+            file.remove(k).unwrap();
+            assert_eq!(file.lookup(k).unwrap(), None);
+            if i % 50 == 0 {
+                file.verify().unwrap();
+            }
+        }
+        file.verify().unwrap();
+        assert!(file.is_empty());
+        assert!(file.stats().unwrap().empty_pages > 0);
+
+        // free pages are reused, and compaction truncates the in-memory image
+        let pages = file.stats().unwrap().page_count;
+        for (k, v) in data.iter().take(100) {
+            file.insert(k, v).unwrap();
+        }
+        assert_eq!(file.stats().unwrap().page_count, pages);
+        file.compact().unwrap();
+        assert!(file.stats().unwrap().page_count < pages);
+        file.verify().unwrap();
+        for (k, v) in data.iter().take(100) {
+            assert_eq!(file.lookup(k).unwrap().as_ref(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_no_wal() {
+        let file: MemFile = File::memory(256);
+        assert!(file.with_wal().is_err());
+    }
+}
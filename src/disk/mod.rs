@@ -1,2 +1,9 @@
 pub mod block;
+pub mod buffered;
+pub mod entry;
 pub mod file;
+#[cfg(any(test, feature = "mem"))]
+pub mod mem;
+pub(crate) mod oplog;
+pub mod storage;
+pub(crate) mod wal;
@@ -0,0 +1,109 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+
+/// Logical mutation recorded in the `OpLog`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// Append-only log of logical mutations (inserted and removed entries), to be shipped to
+/// a replica and replayed there, see `File::with_oplog`. Unlike the `Wal` it is never truncated.
+///
+/// Layout: sequence of records `[op: u8][klen: u32][key][vlen: u32][val]` (value is empty for
+/// a removal). A torn record at the end of the log (e.g. after a crash) is ignored.
+pub(crate) struct OpLog {
+    file: fs::File,
+}
+
+impl OpLog {
+    /// Path of the oplog file that belongs to the database file at given path.
+    pub(crate) fn path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".oplog");
+        PathBuf::from(name)
+    }
+
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append the record (not synced to the disk, see `sync`).
+    pub(crate) fn append(&mut self, op: &Op) -> io::Result<()> {
+        let (code, key, val) = match op {
+            Op::Insert(key, val) => (INSERT, key.as_slice(), val.as_slice()),
+            Op::Remove(key) => (REMOVE, key.as_slice(), &[][..]),
+        };
+        let mut buf = Vec::with_capacity(9 + key.len() + val.len());
+        buf.push(code);
+        buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&(val.len() as u32).to_be_bytes());
+        buf.extend_from_slice(val);
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&buf)
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Read all complete records in the order they were appended.
+    pub(crate) fn records(&mut self) -> io::Result<Vec<Op>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+
+        let mut result = Vec::new();
+        let mut pos = 0;
+        while let Some((op, next)) = record(&buf, pos)? {
+            result.push(op);
+            pos = next;
+        }
+        Ok(result)
+    }
+}
+
+/// Parse the record at given position: the op and the position of the next record,
+/// none if the record is not complete.
+fn record(buf: &[u8], pos: usize) -> io::Result<Option<(Op, usize)>> {
+    let key = match chunk(buf, pos + 1) {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let at = pos + 5 + key.len();
+    let val = match chunk(buf, at) {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+    let op = match buf[pos] {
+        INSERT => Op::Insert(key.to_vec(), val.to_vec()),
+        REMOVE => Op::Remove(key.to_vec()),
+        code => {
+            return Err(io::Error::other(format!(
+                "Invalid oplog record at {}: {}",
+                pos, code
+            )))
+        }
+    };
+    Ok(Some((op, at + 4 + val.len())))
+}
+
+/// Length-prefixed chunk at given position, none if it does not fit the buffer.
+fn chunk(buf: &[u8], pos: usize) -> Option<&[u8]> {
+    let len = buf.get(pos..(pos + 4))?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    buf.get((pos + 4)..(pos + 4 + len))
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Backing storage of the `File`: all data is read and written at byte offsets.
+/// Implemented for `fs::File` (the default) and `Vec<u8>` (whole file image kept in memory),
+/// see `File::make_on` and `File::open_on` to run the tree over a custom implementation.
+pub trait Storage: Send + Sync {
+    fn len(&self) -> io::Result<u64>;
+
+    /// Positioned read of exactly `buf.len()` bytes: does not move the file cursor,
+    /// so shared access is enough. Reading past the end is an `UnexpectedEof` error.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Positioned write of the whole `buf`, the storage is extended if necessary.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let len = self.len()?;
+        self.write_at(buf, len)
+    }
+}
+
+impl Storage for fs::File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_at(self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        fs::File::sync_data(self)
+    }
+}
+
+impl Storage for Vec<u8> {
+    fn len(&self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let offset = offset as usize;
+        let src = self
+            .get(offset..offset + buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let offset = offset as usize;
+        let end = offset + buf.len();
+        if Vec::len(self) < end {
+            self.resize(end, 0);
+        }
+        self[offset..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.resize(len as usize, 0);
+        Ok(())
+    }
+}
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = file.seek_read(&mut buf[pos..], offset + pos as u64)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        pos += n;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(mut file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::Read;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
@@ -0,0 +1,96 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Page id used as commit marker: real page ids always start from 1.
+const COMMIT: u32 = 0;
+
+/// Write-ahead log of page images.
+///
+/// Layout: sequence of records `[id: u32][page: page_bytes]` followed by the commit
+/// marker `[0: u32][count: u32]`. Records without the matching commit marker are ignored.
+pub(crate) struct Wal {
+    file: fs::File,
+    page_bytes: u32,
+}
+
+impl Wal {
+    /// Path of the WAL file that belongs to the database file at given path.
+    pub(crate) fn path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".wal");
+        PathBuf::from(name)
+    }
+
+    pub(crate) fn open(path: &Path, page_bytes: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Self { file, page_bytes })
+    }
+
+    /// Append page images followed by the commit marker and sync the log to the disk.
+    pub(crate) fn append<'a, I: IntoIterator<Item = (u32, &'a [u8])>>(
+        &mut self,
+        pages: I,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let mut count = 0u32;
+        for (id, page) in pages {
+            buf.extend_from_slice(&id.to_be_bytes());
+            buf.extend_from_slice(page);
+            count += 1;
+        }
+        buf.extend_from_slice(&COMMIT.to_be_bytes());
+        buf.extend_from_slice(&count.to_be_bytes());
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()
+    }
+
+    /// Read all committed page images as (id, page) pairs, skipping any uncommitted tail.
+    pub(crate) fn committed(&mut self) -> io::Result<Vec<(u32, Vec<u8>)>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+
+        let len = self.page_bytes as usize;
+        let mut result = Vec::new();
+        let mut pending = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= buf.len() {
+            let id = get_u32(&buf, pos);
+            if id == COMMIT {
+                let count = get_u32(&buf, pos + 4) as usize;
+                if count != pending.len() {
+                    break;
+                }
+                result.append(&mut pending);
+                pos += 8;
+            } else {
+                if pos + 4 + len > buf.len() {
+                    break;
+                }
+                pending.push((id, buf[(pos + 4)..(pos + 4 + len)].to_vec()));
+                pos += 4 + len;
+            }
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()
+    }
+}
+
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    let mut src = [0u8; 4];
+    src.copy_from_slice(&buf[pos..(pos + 4)]);
+    u32::from_be_bytes(src)
+}
@@ -1,6 +1,15 @@
 use std::{marker::PhantomData, path::Path};
 
+use log::error;
+
 use crate::api::{self, Store as KVStore};
+use crate::util::hex::hex;
+
+#[cfg(feature = "serde")]
+pub mod serde_store;
+
+#[cfg(feature = "serde")]
+pub use serde_store::{OrderedKey, SerdeStore};
 
 pub struct Store<K, V>(api::KV, PhantomData<(K, V)>);
 
@@ -13,6 +22,9 @@ where
 
     fn contains(&self, key: &K) -> anyhow::Result<bool>;
     fn lookup(&self, key: &K) -> anyhow::Result<Option<V>>;
+
+    /// Strict `lookup`: absent key results in an error.
+    fn get(&self, key: &K) -> anyhow::Result<V>;
     fn remove(&mut self, key: &K) -> anyhow::Result<Option<V>>;
     fn insert(&mut self, key: &K, val: V) -> anyhow::Result<()>;
 
@@ -20,6 +32,123 @@ where
     fn max(&self) -> anyhow::Result<Option<K>>;
     fn above(&self, key: &K) -> anyhow::Result<Option<K>>;
     fn below(&self, key: &K) -> anyhow::Result<Option<K>>;
+
+    /// Iterate over all key-value pairs in ascending key order.
+    fn iter(&self) -> Iter<'_, K, V>;
+
+    /// Get all key-value pairs with keys in range `[lo, hi)` in ascending key order.
+    fn range(&self, lo: &K, hi: &K) -> anyhow::Result<Vec<(K, V)>>;
+}
+
+/// Ascending iterator over raw key-value pairs of the underlying store.
+/// No page lock is held between calls to `next`: each step looks up the successor key.
+pub(crate) struct Entries<'a> {
+    kv: &'a api::KV,
+    last: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    pub(crate) fn new(kv: &'a api::KV) -> Self {
+        Self {
+            kv,
+            last: None,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = match &self.last {
+            None => self.kv.min(),
+            Some(key) => self.kv.above(key),
+        };
+        let key = match next {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                error!("iter: {}", e);
+                self.done = true;
+                return None;
+            }
+        };
+        let val = match self.kv.lookup(&key) {
+            Ok(Some(val)) => val,
+            Ok(None) => {
+                error!("iter: key not found: {}", hex(&key));
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                error!("iter: {}", e);
+                self.done = true;
+                return None;
+            }
+        };
+        self.last = Some(key.clone());
+        Some((key, val))
+    }
+}
+
+/// Ascending iterator over typed key-value pairs of the `Store`.
+/// No page lock is held between calls to `next`: each step looks up the successor key.
+pub struct Iter<'a, K, V> {
+    inner: Entries<'a>,
+    _pd: PhantomData<(K, V)>,
+}
+
+impl<K, V> Iterator for Iter<'_, K, V>
+where
+    K: for<'b> From<&'b [u8]>,
+    V: for<'b> From<&'b [u8]>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next()
+            .map(|(key, val)| (K::from(&key), V::from(&val)))
+    }
+}
+
+impl<K, V> Store<K, V>
+where
+    K: AsRef<[u8]> + for<'a> From<&'a [u8]>,
+    V: AsRef<[u8]> + for<'a> From<&'a [u8]>,
+{
+    /// Open (or make) the store at given path and insert all key-value pairs from the iterator.
+    pub fn collect_into<I>(path: &Path, iter: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut store = Self::new(path);
+        for (key, val) in iter {
+            store.insert(&key, val)?;
+        }
+        Ok(store)
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a Store<K, V>
+where
+    K: AsRef<[u8]> + for<'b> From<&'b [u8]>,
+    V: AsRef<[u8]> + for<'b> From<&'b [u8]>,
+{
+    type Item = (K, V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 impl<K, V> DB<K, V> for Store<K, V>
@@ -44,6 +173,10 @@ where
         Ok(self.0.lookup(key.as_ref())?.map(|bytes| V::from(&bytes)))
     }
 
+    fn get(&self, key: &K) -> anyhow::Result<V> {
+        Ok(V::from(&self.0.get(key.as_ref())?))
+    }
+
     fn remove(&mut self, key: &K) -> anyhow::Result<Option<V>> {
         let val = self.lookup(key)?;
         self.0.remove(key.as_ref())?;
@@ -69,4 +202,99 @@ where
     fn below(&self, key: &K) -> anyhow::Result<Option<K>> {
         Ok(self.0.below(key.as_ref())?.map(|bytes| K::from(&bytes)))
     }
+
+    fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: Entries::new(&self.0),
+            _pd: PhantomData,
+        }
+    }
+
+    fn range(&self, lo: &K, hi: &K) -> anyhow::Result<Vec<(K, V)>> {
+        let hi = hi.as_ref();
+        let mut result = Vec::new();
+        let mut next = if self.0.lookup(lo.as_ref())?.is_some() {
+            Some(lo.as_ref().to_vec())
+        } else {
+            self.0.above(lo.as_ref())?
+        };
+        while let Some(key) = next {
+            if key.as_slice() >= hi {
+                break;
+            }
+            if let Some(val) = self.0.lookup(&key)? {
+                result.push((K::from(&key), V::from(&val)));
+            }
+            next = self.0.above(&key)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_iter() {
+        let path = &util::temp("test_typed_iter");
+
+        let mut store: Store<Vec<u8>, Vec<u8>> = Store::new(path);
+        let data = util::data(100, 42);
+        for (k, v) in data.iter() {
+            store.insert(k, v.clone()).unwrap();
+        }
+
+        let mut keys = Vec::with_capacity(data.len());
+        let mut next = store.0.min().unwrap();
+        while let Some(key) = next {
+            next = store.0.above(&key).unwrap();
+            keys.push(key);
+        }
+
+        let typed = store.iter().collect::<Vec<_>>();
+        assert_eq!(
+            typed.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            keys.iter().collect::<Vec<_>>()
+        );
+        for (k, v) in typed.iter() {
+            assert_eq!(store.0.lookup(k).unwrap().as_ref(), Some(v));
+        }
+
+        let mut sorted = data.clone();
+        sorted.sort();
+        assert_eq!(typed, sorted);
+
+        let (lo, hi) = (&sorted[10].0, &sorted[20].0);
+        assert_eq!(store.range(lo, hi).unwrap(), sorted[10..20].to_vec());
+        assert_eq!(store.range(hi, lo).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_collect() {
+        let path = &util::temp("test_typed_collect");
+
+        let map = util::data(100, 42).into_iter().collect::<BTreeMap<_, _>>();
+        let store: Store<Vec<u8>, Vec<u8>> = Store::collect_into(path, map.clone()).unwrap();
+
+        let mut copy = BTreeMap::new();
+        for (k, v) in &store {
+            copy.insert(k, v);
+        }
+        assert_eq!(copy, map);
+        assert_eq!((&store).into_iter().collect::<BTreeMap<_, _>>(), map);
+    }
+
+    #[test]
+    fn test_get() {
+        let path = &util::temp("test_typed_get");
+
+        let mut store: Store<Vec<u8>, Vec<u8>> = Store::new(path);
+        store.insert(&b"key".to_vec(), b"val".to_vec()).unwrap();
+        assert_eq!(store.get(&b"key".to_vec()).unwrap(), b"val");
+        let err = store.get(&b"none".to_vec()).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
 }
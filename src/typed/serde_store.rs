@@ -0,0 +1,254 @@
+use std::{convert::TryInto, marker::PhantomData, path::Path};
+
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::api::{self, Store as KVStore};
+use crate::util::hex::hex;
+
+use super::Entries;
+
+/// Key that can be encoded into bytes preserving its ordering:
+/// for any `a` and `b`, `a.cmp(b) == a.encode().cmp(&b.encode())`.
+///
+/// Keys are not serialized with `serde`: general-purpose formats (e.g. `bincode`)
+/// use length prefixes and little-endian integers that do not preserve ordering.
+pub trait OrderedKey: Ord + Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn encode(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                Some(<$t>::from_be_bytes(bytes.try_into().ok()?))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        // Flipping the sign bit maps signed range onto unsigned one keeping the order.
+        impl OrderedKey for $t {
+            fn encode(&self) -> Vec<u8> {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).to_be_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                let raw = <$u>::from_be_bytes(bytes.try_into().ok()?);
+                Some((raw ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl OrderedKey for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl OrderedKey for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+/// Typed store with `OrderedKey` keys and `serde` values (encoded with `bincode`).
+/// Only values go through `serde`, keys keep byte ordering via `OrderedKey`.
+pub struct SerdeStore<K, V>(api::KV, PhantomData<(K, V)>);
+
+impl<K, V> SerdeStore<K, V>
+where
+    K: OrderedKey,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new(path: &Path) -> Self {
+        let kv = if !path.exists() {
+            api::KV::make(path, 4096).unwrap()
+        } else {
+            api::KV::open(path).unwrap()
+        };
+        Self(kv, PhantomData)
+    }
+
+    pub fn contains(&self, key: &K) -> anyhow::Result<bool> {
+        Ok(self.0.lookup(&key.encode())?.is_some())
+    }
+
+    pub fn lookup(&self, key: &K) -> anyhow::Result<Option<V>> {
+        self.0
+            .lookup(&key.encode())?
+            .map(|bytes| Ok(bincode::deserialize(&bytes)?))
+            .transpose()
+    }
+
+    /// Strict `lookup`: absent key results in an error.
+    pub fn get(&self, key: &K) -> anyhow::Result<V> {
+        Ok(bincode::deserialize(&self.0.get(&key.encode())?)?)
+    }
+
+    pub fn remove(&mut self, key: &K) -> anyhow::Result<Option<V>> {
+        let val = self.lookup(key)?;
+        self.0.remove(&key.encode())?;
+        Ok(val)
+    }
+
+    pub fn insert(&mut self, key: &K, val: &V) -> anyhow::Result<()> {
+        let bytes = bincode::serialize(val)?;
+        Ok(self.0.insert(&key.encode(), &bytes)?)
+    }
+
+    pub fn min(&self) -> anyhow::Result<Option<K>> {
+        self.0.min()?.map(|bytes| key(&bytes)).transpose()
+    }
+
+    pub fn max(&self) -> anyhow::Result<Option<K>> {
+        self.0.max()?.map(|bytes| key(&bytes)).transpose()
+    }
+
+    pub fn above(&self, key: &K) -> anyhow::Result<Option<K>> {
+        self.0
+            .above(&key.encode())?
+            .map(|bytes| self::key(&bytes))
+            .transpose()
+    }
+
+    pub fn below(&self, key: &K) -> anyhow::Result<Option<K>> {
+        self.0
+            .below(&key.encode())?
+            .map(|bytes| self::key(&bytes))
+            .transpose()
+    }
+
+    /// Iterate over all key-value pairs in ascending key order.
+    /// Iteration stops (with an error logged) at the first entry that fails to decode.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        Entries::new(&self.0).map_while(|(key, val)| match entry(&key, &val) {
+            Ok(item) => Some(item),
+            Err(e) => {
+                error!("iter: {}: {}", hex(&key), e);
+                None
+            }
+        })
+    }
+
+    /// Get all key-value pairs with keys in range `[lo, hi)` in ascending key order.
+    pub fn range(&self, lo: &K, hi: &K) -> anyhow::Result<Vec<(K, V)>> {
+        let (lo, hi) = (lo.encode(), hi.encode());
+        let mut result = Vec::new();
+        let mut next = if self.0.lookup(&lo)?.is_some() {
+            Some(lo)
+        } else {
+            self.0.above(&lo)?
+        };
+        while let Some(key) = next {
+            if key >= hi {
+                break;
+            }
+            if let Some(val) = self.0.lookup(&key)? {
+                result.push(entry(&key, &val)?);
+            }
+            next = self.0.above(&key)?;
+        }
+        Ok(result)
+    }
+}
+
+fn key<K: OrderedKey>(bytes: &[u8]) -> anyhow::Result<K> {
+    K::decode(bytes).ok_or_else(|| anyhow::anyhow!("Invalid key: {}", hex(bytes)))
+}
+
+fn entry<K: OrderedKey, V: DeserializeOwned>(key: &[u8], val: &[u8]) -> anyhow::Result<(K, V)> {
+    Ok((self::key(key)?, bincode::deserialize(val)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_ordered_key() {
+        let ints = [i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX];
+        for pair in ints.windows(2) {
+            assert!(pair[0].encode() < pair[1].encode());
+        }
+        for x in ints {
+            assert_eq!(i64::decode(&x.encode()), Some(x));
+        }
+        assert_eq!(u32::decode(&[1, 2]), None);
+        assert!("abc".to_string().encode() < "abd".to_string().encode());
+    }
+
+    #[test]
+    fn test_serde_store() {
+        let path = &util::temp("test_serde_store");
+
+        let mut store: SerdeStore<i32, User> = SerdeStore::new(path);
+        let keys: [i32; 6] = [42, -7, 0, 1000, -1000, 3];
+        for id in keys {
+            let user = User {
+                name: format!("user-{id}"),
+                age: id.unsigned_abs(),
+                tags: vec!["a".to_string(); id.unsigned_abs() as usize % 4],
+            };
+            store.insert(&id, &user).unwrap();
+        }
+
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        let items = store.iter().collect::<Vec<_>>();
+        assert_eq!(items.iter().map(|(k, _)| *k).collect::<Vec<_>>(), sorted);
+        for (id, user) in items.iter() {
+            assert_eq!(user.name, format!("user-{id}"));
+            assert_eq!(store.lookup(id).unwrap().as_ref(), Some(user));
+        }
+
+        assert_eq!(store.min().unwrap(), Some(-1000));
+        assert_eq!(store.max().unwrap(), Some(1000));
+        assert_eq!(store.above(&0).unwrap(), Some(3));
+        assert_eq!(store.below(&0).unwrap(), Some(-7));
+        assert_eq!(
+            store
+                .range(&-7, &42)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            vec![-7, 0, 3]
+        );
+
+        assert_eq!(store.get(&42).unwrap().name, "user-42");
+        assert!(store.get(&43).is_err());
+
+        assert!(store.remove(&0).unwrap().is_some());
+        assert!(!store.contains(&0).unwrap());
+        assert_eq!(store.lookup(&0).unwrap(), None);
+    }
+}
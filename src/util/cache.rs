@@ -5,23 +5,36 @@ use std::fmt::Display;
 use std::hash::Hash;
 use std::sync::Arc;
 
-pub(crate) trait Cache<K: Clone + Eq + PartialEq + Hash, V> {
+/// Page cache policy, see `File::with_cache`. The cache decides which entry to evict once
+/// it is full, the evicted entry is handed back to the caller (to be saved if it is dirty).
+pub trait Cache<K: Clone + Eq + PartialEq + Hash, V> {
     fn has(&self, key: &K) -> bool;
     fn get(&self, key: &K) -> Option<&V>;
     fn get_mut(&mut self, key: &K) -> Option<&mut V>;
-    fn put(&mut self, key: K, value: V);
+    /// Put the entry into the cache, returns evicted entry (if any).
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)>;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn keys(&self) -> Vec<K>;
+    /// Max number of entries kept in the cache.
+    fn capacity(&self) -> usize;
+    /// Drop all entries (without evicting them).
+    fn clear(&mut self);
+    /// Release memory held beyond what the current entries need (e.g. after `clear`).
+    fn shrink_to_fit(&mut self) {}
 }
 
-pub(crate) struct LruCache<K, V> {
+/// Least-recently-used eviction policy (the default page cache of `File`).
+pub struct LruCache<K, V> {
     map: HashMap<K, V>,
     lru: Arc<RwLock<Vec<K>>>,
     cap: usize,
 }
 
 impl<K: Clone + Eq + Hash + Display, V> LruCache<K, V> {
-    pub(crate) fn new(size: usize) -> Self {
+    pub fn new(size: usize) -> Self {
         Self {
             map: HashMap::with_capacity(size),
             lru: Arc::new(RwLock::new(Vec::with_capacity(size))),
@@ -30,23 +43,20 @@ impl<K: Clone + Eq + Hash + Display, V> LruCache<K, V> {
     }
 
     fn touch(&self, key: &K) -> Option<K> {
+        // Readers touch the entries concurrently (see `get`), so the entry is found and moved
+        // under the same lock: the index found under a read lock might be stale by the time
+        // the write lock is taken.
+        let mut lru = self.lru.write();
         let existing = if !self.map.contains_key(key) {
             None
         } else {
-            self.lru
-                .read()
-                .iter()
-                .enumerate()
-                .find(|(_, x)| x == &key)
-                .map(|(i, _)| i)
+            lru.iter().position(|x| x == key)
         };
 
         if let Some(idx) = existing {
-            let mut lru = self.lru.write();
             lru.remove(idx);
             lru.push(key.clone());
         } else {
-            let mut lru = self.lru.write();
             if lru.len() == self.cap {
                 let evicted = lru.remove(0);
                 return Some(evicted);
@@ -80,12 +90,13 @@ impl<K: Clone + Hash + Eq + Display, V> Cache<K, V> for LruCache<K, V> {
         }
     }
 
-    fn put(&mut self, key: K, value: V) {
-        if let Some(evicted) = self.touch(&key) {
-            self.map.remove(&evicted);
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let evicted = self.touch(&key).and_then(|evicted| {
             debug!("Evicted page {}", evicted);
-        }
+            self.map.remove_entry(&evicted)
+        });
         self.map.insert(key, value);
+        evicted
     }
 
     fn len(&self) -> usize {
@@ -93,7 +104,21 @@ impl<K: Clone + Hash + Eq + Display, V> Cache<K, V> for LruCache<K, V> {
     }
 
     fn keys(&self) -> Vec<K> {
-        self.map.keys().into_iter().cloned().collect()
+        self.map.keys().cloned().collect()
+    }
+
+    fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.lru.write().clear();
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+        self.lru.write().shrink_to_fit();
     }
 }
 
@@ -113,4 +138,56 @@ mod tests {
         keys.sort();
         assert_eq!(keys, vec![2, 3, 4]);
     }
+
+    #[test]
+    fn test_clear() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, 0);
+        cache.put(2, 0);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 2);
+
+        assert_eq!(cache.put(3, 0), None);
+        assert_eq!(cache.put(4, 0), None);
+        assert_eq!(cache.put(5, 0), Some((3, 0)));
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut cache = LruCache::new(10_000);
+        for i in 0..10_000 {
+            cache.put(i, i);
+        }
+        cache.clear();
+        cache.put(42, 42);
+        cache.shrink_to_fit();
+        assert!(cache.map.capacity() < 100);
+        assert!(cache.lru.read().capacity() < 100);
+        assert_eq!(cache.get(&42), Some(&42));
+        assert_eq!(cache.capacity(), 10_000);
+    }
+
+    #[test]
+    fn test_concurrent_get() {
+        let mut cache = LruCache::new(8);
+        for i in 0..8 {
+            cache.put(i, i);
+        }
+        std::thread::scope(|scope| {
+            for t in 0..16 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..100_000 {
+                        assert_eq!(cache.get(&((t + i) % 8)), Some(&((t + i) % 8)));
+                    }
+                });
+            }
+        });
+
+        // Each key is still tracked exactly once.
+        let mut lru = cache.lru.read().clone();
+        lru.sort();
+        assert_eq!(lru, (0..8).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,46 @@
+use std::io;
+
+/// Values shorter than this are never compressed: the gain (if any) is not worth it.
+pub(crate) const MIN_LEN: usize = 64;
+
+const LEVEL: i32 = 3;
+
+#[cfg(feature = "zstd")]
+pub(crate) fn compress(src: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::encode_all(src, LEVEL)
+}
+
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress(src: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::decode_all(src)
+}
+
+/// Length of the value once decompressed: taken from the frame header if it is kept there,
+/// otherwise the value is decompressed (without keeping the output) to count the bytes.
+#[cfg(feature = "zstd")]
+pub(crate) fn decompressed_len(src: &[u8]) -> io::Result<u64> {
+    match zstd::zstd_safe::get_frame_content_size(src) {
+        Ok(Some(len)) => Ok(len),
+        _ => io::copy(&mut zstd::stream::Decoder::new(src)?, &mut io::sink()),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn compress(_src: &[u8]) -> io::Result<Vec<u8>> {
+    let _ = LEVEL;
+    Err(io::Error::other(
+        "Compression is not supported: enable 'zstd' feature",
+    ))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn decompress(_src: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::other(
+        "Compression is not supported: enable 'zstd' feature",
+    ))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn decompressed_len(src: &[u8]) -> io::Result<u64> {
+    decompress(src).map(|val| val.len() as u64)
+}
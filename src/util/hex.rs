@@ -4,3 +4,39 @@ pub fn hex(src: &[u8]) -> String {
         .collect::<Vec<_>>()
         .concat()
 }
+
+/// Parse a hex string (as produced by `hex`, either case) back to bytes,
+/// none if it has odd length or any non-hex char.
+pub fn unhex(src: &str) -> Option<Vec<u8>> {
+    if !src.len().is_multiple_of(2) || !src.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..src.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&src[i..(i + 2)], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for len in 0..100 {
+            let src = (0..len).map(|_| rng.gen::<u8>()).collect::<Vec<_>>();
+            assert_eq!(unhex(&hex(&src)), Some(src));
+        }
+        assert_eq!(unhex("00FFaB"), Some(vec![0x00, 0xff, 0xab]));
+    }
+
+    #[test]
+    fn test_malformed() {
+        for src in ["0", "abc", "0g", "zz", "+f", "-1", " 1", "0x00", "é1"] {
+            assert_eq!(unhex(src), None, "{:?}", src);
+        }
+    }
+}
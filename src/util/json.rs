@@ -0,0 +1,113 @@
+/// Minimal parser for JSON snapshots: array of objects with string fields `k` and `v`,
+/// e.g. `[{"k": "00ff", "v": "cafe"}]`. String escapes are not supported (hex never needs them).
+pub(crate) fn entries(src: &str) -> Option<Vec<(String, String)>> {
+    let mut p = Parser {
+        src: src.as_bytes(),
+        pos: 0,
+    };
+    let mut result = Vec::new();
+    p.expect(b'[')?;
+    if !p.accept(b']') {
+        loop {
+            result.push(p.object()?);
+            if p.accept(b']') {
+                break;
+            }
+            p.expect(b',')?;
+        }
+    }
+    p.skip();
+    if p.pos == p.src.len() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn accept(&mut self, c: u8) -> bool {
+        self.skip();
+        if self.src.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        if self.accept(c) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        self.expect(b'"')?;
+        let at = self.pos;
+        while *self.src.get(self.pos)? != b'"' {
+            if self.src[self.pos] == b'\\' {
+                return None;
+            }
+            self.pos += 1;
+        }
+        let s = std::str::from_utf8(&self.src[at..self.pos]).ok()?;
+        self.pos += 1;
+        Some(s)
+    }
+
+    fn object(&mut self) -> Option<(String, String)> {
+        let (mut k, mut v) = (None, None);
+        self.expect(b'{')?;
+        if !self.accept(b'}') {
+            loop {
+                let name = self.string()?;
+                self.expect(b':')?;
+                let value = self.string()?.to_string();
+                match name {
+                    "k" => k = Some(value),
+                    "v" => v = Some(value),
+                    _ => return None,
+                }
+                if self.accept(b'}') {
+                    break;
+                }
+                self.expect(b',')?;
+            }
+        }
+        Some((k?, v?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(entries(" [ ] "), Some(vec![]));
+        assert_eq!(
+            entries("[{\"k\": \"00\", \"v\": \"ff\"},\n {\"v\":\"\",\"k\":\"01\"}]"),
+            Some(vec![
+                ("00".to_string(), "ff".to_string()),
+                ("01".to_string(), "".to_string())
+            ])
+        );
+        assert_eq!(entries("[{\"k\": \"00\"}]"), None);
+        assert_eq!(entries("[{\"k\": \"00\", \"v\": \"ff\"}"), None);
+        assert_eq!(entries("[{\"k\": \"0\\\"0\", \"v\": \"ff\"}]"), None);
+        assert_eq!(entries("[] []"), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Operation latencies and page-level event counts emitted via the `metrics` crate (with the
+//! `metrics` feature), for whatever recorder the application installs. Without the feature
+//! all of it compiles to nothing.
+//!
+//! Emitted metrics:
+//! - `yakvdb_ops_total` (counter) and `yakvdb_op_seconds` (histogram), labeled with `op`:
+//!   `insert`, `remove`, `lookup` or `flush`;
+//! - `yakvdb_splits_total` and `yakvdb_merges_total` (counters);
+//! - `yakvdb_cache_hits_total` and `yakvdb_cache_misses_total` (counters).
+
+pub(crate) const SPLITS: &str = "yakvdb_splits_total";
+pub(crate) const MERGES: &str = "yakvdb_merges_total";
+pub(crate) const CACHE_HITS: &str = "yakvdb_cache_hits_total";
+pub(crate) const CACHE_MISSES: &str = "yakvdb_cache_misses_total";
+
+#[cfg(feature = "metrics")]
+const OPS: &str = "yakvdb_ops_total";
+#[cfg(feature = "metrics")]
+const OP_SECONDS: &str = "yakvdb_op_seconds";
+
+/// Increment the counter of given name.
+#[cfg(feature = "metrics")]
+pub(crate) fn count(name: &'static str) {
+    ::metrics::counter!(name).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn count(_name: &'static str) {}
+
+/// Count the operation and record its latency once the returned timer is dropped.
+#[cfg(feature = "metrics")]
+pub(crate) fn op(name: &'static str) -> Timer {
+    ::metrics::counter!(OPS, "op" => name).increment(1);
+    Timer {
+        name,
+        start: std::time::Instant::now(),
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn op(_name: &'static str) -> Timer {
+    Timer
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) struct Timer {
+    name: &'static str,
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        ::metrics::histogram!(OP_SECONDS, "op" => self.name).record(self.start.elapsed());
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct Timer;
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::api::Store;
+    use crate::disk::{block::Block, file::File};
+    use crate::util;
+    use ::metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Recorder keeping the counters only, by name with labels (e.g. `ops{op=insert}`).
+    #[derive(Default)]
+    struct Capture(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+    impl Capture {
+        fn get(&self, name: &str) -> u64 {
+            self.0
+                .lock()
+                .get(name)
+                .map(|counter| counter.load(Ordering::Relaxed))
+                .unwrap_or_default()
+        }
+    }
+
+    impl Recorder for Capture {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let labels = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect::<Vec<_>>();
+            let name = if labels.is_empty() {
+                key.name().to_string()
+            } else {
+                format!("{}{{{}}}", key.name(), labels.join(","))
+            };
+            let counter = self.0.lock().entry(name).or_default().clone();
+            Counter::from_arc(counter)
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let path = &util::temp("test_metrics");
+
+        let capture = Capture::default();
+        ::metrics::with_local_recorder(&capture, || {
+            let file: File<Block> = File::make(path, 256).unwrap();
+            file.insert(b"key", b"val").unwrap();
+            assert_eq!(capture.get("yakvdb_ops_total{op=insert}"), 1);
+            assert_eq!(capture.get("yakvdb_ops_total{op=flush}"), 1);
+
+            for (k, v) in util::data(100, 42).iter() {
+                file.insert(k, v).unwrap();
+            }
+            file.lookup(b"key").unwrap();
+            file.remove(b"key").unwrap();
+        });
+        assert_eq!(capture.get("yakvdb_ops_total{op=insert}"), 101);
+        assert_eq!(capture.get("yakvdb_ops_total{op=lookup}"), 1);
+        assert_eq!(capture.get("yakvdb_ops_total{op=remove}"), 1);
+        assert!(capture.get(SPLITS) > 0);
+        assert!(capture.get(CACHE_HITS) > 0);
+    }
+}
@@ -1,15 +1,23 @@
 use rand::prelude::SliceRandom;
 use rand::prelude::StdRng;
 use rand::{RngCore, SeedableRng};
+use std::convert::TryInto;
 
 pub mod bsearch;
 pub mod cache;
+pub(crate) mod compress;
 pub mod hex;
+pub(crate) mod json;
+pub(crate) mod metrics;
+#[cfg(test)]
+pub(crate) mod temp;
+
+#[cfg(test)]
+pub(crate) use temp::temp;
 
 pub fn data(count: usize, seed: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
     let mut rng = StdRng::seed_from_u64(seed);
     (0..count)
-        .into_iter()
         .map(|_| {
             (
                 rng.next_u64().to_be_bytes().to_vec(),
@@ -19,8 +27,53 @@ pub fn data(count: usize, seed: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
         .collect()
 }
 
+/// Smallest key greater than all keys starting with given prefix: the prefix with its last
+/// non-`0xFF` byte incremented (and the `0xFF` bytes after it dropped). None if there is no such
+/// key (the prefix is empty or made of `0xFF` bytes only), then all keys from the prefix on match.
+pub fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let end = prefix.iter().rposition(|b| *b != 0xFF)?;
+    let mut key = prefix[..=end].to_vec();
+    key[end] += 1;
+    Some(key)
+}
+
+/// Key following given big-endian `u64` key (e.g. for `Store::first_gap` over sequential ids).
+/// None if the key is not 8 bytes long or is `u64::MAX`.
+pub fn next_u64(key: &[u8]) -> Option<Vec<u8>> {
+    let n = u64::from_be_bytes(key.try_into().ok()?);
+    n.checked_add(1).map(|n| n.to_be_bytes().to_vec())
+}
+
 pub fn shuffle<T>(mut data: Vec<T>, seed: u64) -> Vec<T> {
     let mut rng = StdRng::seed_from_u64(seed);
     data.shuffle(&mut rng);
     data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_successor() {
+        assert_eq!(successor(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(successor(&[1, 0xFF, 0xFF]), Some(vec![2]));
+        assert_eq!(successor(&[0xFF, 0x00, 0xFF]), Some(vec![0xFF, 0x01]));
+        assert_eq!(successor(&[0xFF, 0xFF]), None);
+        assert_eq!(successor(&[]), None);
+    }
+
+    #[test]
+    fn test_next_u64() {
+        assert_eq!(
+            next_u64(&41u64.to_be_bytes()),
+            Some(42u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            next_u64(&0xFFu64.to_be_bytes()),
+            Some(0x100u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(next_u64(&u64::MAX.to_be_bytes()), None);
+        assert_eq!(next_u64(b"short"), None);
+    }
+}
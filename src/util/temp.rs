@@ -0,0 +1,56 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::disk::wal::Wal;
+
+/// Unique path for a temporary test file under `target/tmp`, so tests can run in parallel
+/// (and with several test processes at once). The file and its WAL are removed on drop.
+pub(crate) struct TempPath(PathBuf);
+
+pub(crate) fn temp(name: &str) -> TempPath {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dir = Path::new("target/tmp");
+    fs::create_dir_all(dir).unwrap();
+    TempPath(dir.join(format!("{}-{}-{}.tmp", name, std::process::id(), id)))
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        for path in [self.0.clone(), Wal::path(&self.0)] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp() {
+        let (a, b) = (temp("test_temp"), temp("test_temp"));
+        assert_ne!(a.deref(), b.deref());
+        fs::write(&a, b"data").unwrap();
+        let path = a.to_path_buf();
+        assert!(path.exists());
+        drop(a);
+        assert!(!path.exists());
+    }
+}